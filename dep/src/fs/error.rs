@@ -1,4 +1,122 @@
-pub const NOT_FOUND: i64 = -1;
-pub const ACCESS_VIOLATION: i64 = -2;
-pub const ILLEGAL: i64 = -3;
-pub const OTHER: i64 = -99;
+//! Error codes that syscalls return as negative numbers
+
+use core::convert::TryFrom;
+use core::fmt;
+
+/// Error of a syscall, returned as its negative code
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    /// file or directory not found
+    NotFound = -1,
+    /// no access rights
+    AccessViolation = -2,
+    /// an illegal operation, e.g. detaching the root file system
+    IllegalOperation = -3,
+    /// the file is locked by another open file
+    WouldBlock = -4,
+    /// a file or directory with the name exists already
+    AlreadyExists = -5,
+    /// a directory was expected
+    NotADirectory = -6,
+    /// a file was expected
+    IsADirectory = -7,
+    /// the file system is full
+    NoSpace = -8,
+    /// the file descriptor isn't open, or not for the operation
+    BadFileDescriptor = -9,
+    /// a pointer passed to the kernel is invalid
+    Fault = -10,
+    /// an argument is invalid, e.g. unknown flags or a buffer that is too small
+    InvalidArgument = -11,
+    /// the file system or device is in use
+    Busy = -12,
+    /// the paths are in different file systems
+    CrossDevice = -13,
+    /// a name or the path is too long
+    NameTooLong = -14,
+    /// reading or writing the device failed
+    Io = -15,
+    /// the data on the device is not a valid file system
+    Corrupted = -16,
+    /// the syscall doesn't exist
+    NotSupported = -17,
+    /// any other error
+    Other = -99,
+}
+
+const ERRNOS: [Errno; 18] = [
+    Errno::NotFound,
+    Errno::AccessViolation,
+    Errno::IllegalOperation,
+    Errno::WouldBlock,
+    Errno::AlreadyExists,
+    Errno::NotADirectory,
+    Errno::IsADirectory,
+    Errno::NoSpace,
+    Errno::BadFileDescriptor,
+    Errno::Fault,
+    Errno::InvalidArgument,
+    Errno::Busy,
+    Errno::CrossDevice,
+    Errno::NameTooLong,
+    Errno::Io,
+    Errno::Corrupted,
+    Errno::NotSupported,
+    Errno::Other,
+];
+
+impl Errno {
+    /// the negative number a syscall returns for the error
+    pub fn code(self) -> i64 {
+        self as i64
+    }
+
+    /// Turns the return value of a syscall into a result.
+    /// Negative values are errors, unknown codes are `Other`.
+    pub fn check(code: i64) -> Result<i64, Errno> {
+        if code < 0 {
+            Err(Errno::try_from(code).unwrap_or(Errno::Other))
+        } else {
+            Ok(code)
+        }
+    }
+
+    /// description of the error
+    pub fn message(self) -> &'static str {
+        match self {
+            Errno::NotFound => "no such file or directory",
+            Errno::AccessViolation => "permission denied",
+            Errno::IllegalOperation => "operation not permitted",
+            Errno::WouldBlock => "resource temporarily unavailable",
+            Errno::AlreadyExists => "file exists",
+            Errno::NotADirectory => "not a directory",
+            Errno::IsADirectory => "is a directory",
+            Errno::NoSpace => "no space left on device",
+            Errno::BadFileDescriptor => "bad file descriptor",
+            Errno::Fault => "bad address",
+            Errno::InvalidArgument => "invalid argument",
+            Errno::Busy => "device or resource busy",
+            Errno::CrossDevice => "invalid cross-device link",
+            Errno::NameTooLong => "file name too long",
+            Errno::Io => "input/output error",
+            Errno::Corrupted => "structure needs cleaning",
+            Errno::NotSupported => "function not implemented",
+            Errno::Other => "unknown error",
+        }
+    }
+}
+
+impl TryFrom<i64> for Errno {
+    type Error = ();
+
+    fn try_from(code: i64) -> Result<Self, Self::Error> {
+        ERRNOS.iter().cloned().find(|errno| errno.code() == code).ok_or(())
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
@@ -1,132 +1,39 @@
-use alloc::vec::Vec;
-use alloc::string::String;
-
 pub mod error;
+pub mod stats;
+pub mod watch;
+mod path;
 
-pub const SEPARATOR: u8 = b'/';
-
-pub type Filename = Vec<u8>;
-
-/// Represents a file path
-#[derive(Clone, PartialEq, Eq)]
-pub struct Path {
-    path: Vec<Filename>,
-}
-
-
-impl Path {
-
-    /// root of the file system
-    pub fn root() -> Self {
-        Self {
-            path: Vec::with_capacity(0),
-        }
-    }
-
-    /// `true` when the path is the root of the file system, i.e. `SEPARATOR`
-    pub fn is_root(&self) -> bool {
-        self.path.len() == 0
-    }
-
-    /// immediate parent directory if the path is not root
-    pub fn parent_dir(&self) -> Option<Self> {
-        if self.is_root() {
-            None
-        } else {
-            Some(Self{ path: self.path[..self.path.len()-1].to_vec() })
-        }
-    }
-
-    /// name of the file or directory the path describes
-    /// `None` if it is root
-    /// `Some(name)` otherwise
-    pub fn name(&self) -> Option<Filename> {
-        if self.is_root() {
-            None
-        } else {
-            Some(self.path[self.path.len() - 1][..].to_vec())
-        }
-    }
+pub use path::*;
 
-    pub fn new<S>(path: S) -> Option<Self>
-        where S: AsRef<str>
-    {
-        Self::from_str(path.as_ref())
-    }
-
-    pub fn from_str(string: &str) -> Option<Self> {
-        let string = string.as_bytes();
-        let mut path: Vec<Vec<u8>> = Vec::new();
-        let mut token = Vec::new();
-        for &ch in string {
-            if ch == SEPARATOR {
-                if !token.is_empty() {
-                    path.push(token);
-                    token = Vec::new();
-                }
-            } else {
-                token.push(ch);
-            }
-        }
-        if !token.is_empty() {
-            path.push(token);
-        }
-        Some(Self{path})
-    }
-
-    pub fn to_string(&self) -> String {
-        let mut path = String::new();
-
-        for part in self.path.iter() {
-            path.push(SEPARATOR as char);
-            path.push_str(&String::from_utf8(part.to_vec()).unwrap());
-        }
-
-        if path.len() == 0 {
-            path.push(SEPARATOR as char);
-        }
-
-        path
-    }
-
-    pub fn head_tail(mut self) -> (Option<Filename>, Self) {
-        if self.is_root() {
-            (None, self)
-        } else {
-            let tail = self.path.split_off(1);
-            let head = self.path[0].clone();
-            (Some(head), Self{path: tail})
-        }
-    }
-
-    /// concatenates a file or directory name to the path
-    pub fn concat(&self, child: Filename) -> Self {
-        let mut child_path = self.path.clone();
-        child_path.push(child);
-        Self {
-            path: child_path,
-        }
-    }
-
-    pub fn relative_to(self, ancestor: Self) -> Option<Self> {
-        if let (Some(anc_head), anc_tail) = ancestor.head_tail() {
-            if let (Some(head), tail) = self.head_tail() {
-                if anc_head == head {
-                    tail.relative_to(anc_tail)
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        } else {
-            Some(self)
-        }
-    }
-
-    pub fn len(&self) -> usize {
-        self.path.len()
-    }
+pub const SEPARATOR: u8 = b'/';
 
+/// Longest name of a single file or directory in bytes
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Longest path in bytes, including separators
+pub const MAX_PATH_LEN: usize = 4096;
+
+/// `FLOCK` operation: shared lock, other open files can hold shared locks as well
+pub const LOCK_SHARED: u64 = 1;
+/// `FLOCK` operation: exclusive lock, no other open file can hold a lock
+pub const LOCK_EXCLUSIVE: u64 = 2;
+/// `FLOCK` flag: fail with `Errno::WouldBlock` instead of waiting for the lock.
+/// Without it the process waits, but still fails if only this process runs and holds the lock.
+pub const LOCK_NONBLOCKING: u64 = 4;
+/// `FLOCK` operation: release the lock, closing the file or exiting the process releases it too
+pub const LOCK_UNLOCK: u64 = 8;
+
+/// `MOUNT` flag: an image file is mounted through a loop device that is written as well,
+/// by default it is only read. Block devices are always written.
+pub const MOUNT_WRITABLE: u64 = 1;
+
+/// Options of the `MOUNT` syscall, which gets a pointer to them
+#[repr(C)]
+pub struct MountOptions {
+    /// name of the file system padded with zeros, all zeros to probe the file systems
+    pub fs_type: [u8; stats::FS_NAME_LEN],
+    /// `MOUNT_` flags
+    pub flags: u64,
 }
 
+pub type Filename = alloc::vec::Vec<u8>;
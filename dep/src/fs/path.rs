@@ -0,0 +1,476 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+use super::*;
+
+/// Reason why a path or a name was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// a name contains a NUL byte
+    NulByte,
+    /// a name is longer than `MAX_NAME_LEN`
+    NameTooLong,
+    /// the path is longer than `MAX_PATH_LEN`
+    PathTooLong,
+    /// a name is empty, `.`, `..` or contains `SEPARATOR`
+    InvalidName,
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            PathError::NulByte => "path contains a NUL byte",
+            PathError::NameTooLong => "file name is too long",
+            PathError::PathTooLong => "path is too long",
+            PathError::InvalidName => "invalid file name",
+        })
+    }
+}
+
+/// checks a name that is added to a path as it is
+fn validate_name(name: &[u8]) -> Result<(), PathError> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&SEPARATOR) {
+        Err(PathError::InvalidName)
+    } else if name.contains(&0) {
+        Err(PathError::NulByte)
+    } else if name.len() > MAX_NAME_LEN {
+        Err(PathError::NameTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+/// Represents an absolute file path
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Path {
+    path: Vec<Filename>,
+}
+
+/// Borrowed part of a `Path`, e.g. the rest of a path below an attach point
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PathSlice<'a> {
+    path: &'a [Filename],
+}
+
+/// Iterator over the names of a path from root to its end
+#[derive(Clone)]
+pub struct Components<'a> {
+    inner: core::slice::Iter<'a, Filename>,
+}
+
+impl<'a> Iterator for Components<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.inner.next().map(|name| &name[..])
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a> DoubleEndedIterator for Components<'a> {
+    fn next_back(&mut self) -> Option<&'a [u8]> {
+        self.inner.next_back().map(|name| &name[..])
+    }
+}
+
+impl<'a> ExactSizeIterator for Components<'a> {}
+
+impl Path {
+
+    /// root of the file system
+    pub fn root() -> Self {
+        Self {
+            path: Vec::with_capacity(0),
+        }
+    }
+
+    /// `true` when the path is the root of the file system, i.e. `SEPARATOR`
+    pub fn is_root(&self) -> bool {
+        self.path.len() == 0
+    }
+
+    /// immediate parent directory if the path is not root
+    pub fn parent_dir(&self) -> Option<Self> {
+        self.as_slice().parent_dir().map(|parent| parent.to_path())
+    }
+
+    /// name of the file or directory the path describes
+    /// `None` if it is root
+    /// `Some(name)` otherwise
+    pub fn name(&self) -> Option<Filename> {
+        self.as_slice().name().map(|name| name.to_vec())
+    }
+
+    /// absolute path, `None` if it isn't valid
+    pub fn new<S>(path: S) -> Option<Self>
+        where S: AsRef<str>
+    {
+        Self::from_str(path.as_ref())
+    }
+
+    /// absolute path, `.` and `..` components are resolved, `None` if it isn't valid
+    pub fn from_str(string: &str) -> Option<Self> {
+        Self::parse(string).ok()
+    }
+
+    /// absolute path, `.` and `..` components are resolved
+    pub fn parse<B: AsRef<[u8]>>(path: B) -> Result<Self, PathError> {
+        Self::root().resolve(path)
+    }
+
+    /// `true` if the path starts at root and not at the working directory
+    pub fn is_absolute<B: AsRef<[u8]>>(path: B) -> bool {
+        path.as_ref().first() == Some(&SEPARATOR)
+    }
+
+    /// Resolves a path relative to `self`, or from root if it is absolute.
+    /// `.` stays in the same directory, `..` goes to the parent, which is root again for root.
+    pub fn resolve<B: AsRef<[u8]>>(&self, path: B) -> Result<Self, PathError> {
+        let path = path.as_ref();
+        let mut resolved = if Self::is_absolute(path) { Self::root() } else { self.clone() };
+        for component in path.split(|&ch| ch == SEPARATOR) {
+            match component {
+                b"" | b"." => (),
+                b".." => {
+                    resolved.pop();
+                },
+                _ => resolved.push(component)?,
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// path of a file or directory named `name` in this directory
+    pub fn join<B: AsRef<[u8]>>(&self, name: B) -> Result<Self, PathError> {
+        let mut path = self.clone();
+        path.push(name)?;
+        Ok(path)
+    }
+
+    /// adds a file or directory name to the end of the path
+    pub fn push<B: AsRef<[u8]>>(&mut self, name: B) -> Result<(), PathError> {
+        let name = name.as_ref();
+        validate_name(name)?;
+        if self.byte_len() + 1 + name.len() > MAX_PATH_LEN {
+            return Err(PathError::PathTooLong);
+        }
+        self.path.push(name.to_vec());
+        Ok(())
+    }
+
+    /// removes the last name and returns it, `None` for root
+    pub fn pop(&mut self) -> Option<Filename> {
+        self.path.pop()
+    }
+
+    /// name without its extension
+    pub fn file_stem(&self) -> Option<&[u8]> {
+        self.as_slice().file_stem()
+    }
+
+    /// part of the name after the last `.`, `None` for names like `file` or `.hidden`
+    pub fn extension(&self) -> Option<&[u8]> {
+        self.as_slice().extension()
+    }
+
+    /// names from root to the end of the path
+    pub fn components(&self) -> Components<'_> {
+        self.as_slice().components()
+    }
+
+    /// borrowed slice of the whole path
+    pub fn as_slice(&self) -> PathSlice<'_> {
+        PathSlice { path: &self.path[..] }
+    }
+
+    /// the path as bytes that `parse` reads back to the same path
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.as_slice().to_bytes()
+    }
+
+    /// length of `to_bytes` without the separator of root
+    fn byte_len(&self) -> usize {
+        self.path.iter().map(|name| name.len() + 1).sum()
+    }
+
+    /// first name and the path below it, the names were validated when they were added
+    pub fn head_tail(mut self) -> (Option<Filename>, Self) {
+        if self.is_root() {
+            (None, self)
+        } else {
+            let tail = self.path.split_off(1);
+            let head = self.path[0].clone();
+            (Some(head), Self{path: tail})
+        }
+    }
+
+    /// `true` if `ancestor` is the path itself or one of the directories containing it
+    pub fn starts_with(&self, ancestor: &Self) -> bool {
+        self.as_slice().starts_with(ancestor.as_slice())
+    }
+
+    /// part of the path below `ancestor`, `None` if `ancestor` doesn't contain `self`
+    pub fn strip_prefix(&self, ancestor: &Self) -> Option<PathSlice<'_>> {
+        self.as_slice().strip_prefix(ancestor.as_slice())
+    }
+
+    /// path from `ancestor` to `self`, `None` if `ancestor` doesn't contain `self`
+    pub fn relative_to(self, ancestor: Self) -> Option<Self> {
+        self.strip_prefix(&ancestor).map(|rest| rest.to_path())
+    }
+
+    pub fn len(&self) -> usize {
+        self.path.len()
+    }
+
+}
+
+impl<'a> PathSlice<'a> {
+    /// `true` when there are no names left
+    pub fn is_root(&self) -> bool {
+        self.path.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.path.len()
+    }
+
+    /// immediate parent directory if the slice is not root
+    pub fn parent_dir(&self) -> Option<PathSlice<'a>> {
+        if self.is_root() {
+            None
+        } else {
+            Some(PathSlice { path: &self.path[..self.path.len() - 1] })
+        }
+    }
+
+    /// last name, `None` if it is root
+    pub fn name(&self) -> Option<&'a [u8]> {
+        self.path.last().map(|name| &name[..])
+    }
+
+    /// name without its extension
+    pub fn file_stem(&self) -> Option<&'a [u8]> {
+        let name = self.name()?;
+        match name.iter().rposition(|&ch| ch == b'.') {
+            Some(0) | None => Some(name),
+            Some(dot) => Some(&name[..dot]),
+        }
+    }
+
+    /// part of the name after the last `.`, `None` for names like `file` or `.hidden`
+    pub fn extension(&self) -> Option<&'a [u8]> {
+        let name = self.name()?;
+        match name.iter().rposition(|&ch| ch == b'.') {
+            Some(0) | None => None,
+            Some(dot) => Some(&name[dot + 1..]),
+        }
+    }
+
+    /// names from the start to the end of the slice
+    pub fn components(&self) -> Components<'a> {
+        Components { inner: self.path.iter() }
+    }
+
+    /// first name and the rest of the slice, `None` if it is root
+    pub fn split_first(&self) -> Option<(&'a [u8], PathSlice<'a>)> {
+        if self.is_root() {
+            None
+        } else {
+            Some((&self.path[0][..], PathSlice { path: &self.path[1..] }))
+        }
+    }
+
+    /// `true` if the slice starts with all names of `ancestor`
+    pub fn starts_with(&self, ancestor: PathSlice) -> bool {
+        ancestor.len() <= self.len() && ancestor.path[..] == self.path[..ancestor.len()]
+    }
+
+    /// the slice without the names of `ancestor`, `None` if it doesn't start with them
+    pub fn strip_prefix(&self, ancestor: PathSlice) -> Option<PathSlice<'a>> {
+        if self.starts_with(ancestor) {
+            Some(PathSlice { path: &self.path[ancestor.len()..] })
+        } else {
+            None
+        }
+    }
+
+    /// owned path starting at root with the names of the slice
+    pub fn to_path(&self) -> Path {
+        Path { path: self.path.to_vec() }
+    }
+
+    /// the slice as an absolute path in bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for name in self.path.iter() {
+            bytes.push(SEPARATOR);
+            bytes.extend_from_slice(name);
+        }
+        if bytes.is_empty() {
+            bytes.push(SEPARATOR);
+        }
+        bytes
+    }
+
+    fn fmt_escaped(&self, f: &mut fmt::Formatter, debug: bool) -> fmt::Result {
+        if self.is_root() {
+            return write!(f, "{}", SEPARATOR as char);
+        }
+        for name in self.path.iter() {
+            write!(f, "{}", SEPARATOR as char)?;
+            write_escaped(f, name, debug)?;
+        }
+        Ok(())
+    }
+}
+
+/// writes the valid UTF-8 parts of `bytes` and the other bytes as `\xNN`, a `\` is written as
+/// `\\` so that the escapes can't be confused with names, for `debug` the valid parts are escaped
+/// as well
+fn write_escaped(f: &mut fmt::Formatter, mut bytes: &[u8], debug: bool) -> fmt::Result {
+    let write_str = |f: &mut fmt::Formatter, string: &str| {
+        if debug {
+            string.chars().try_for_each(|ch| write!(f, "{}", ch.escape_debug()))
+        } else {
+            string.split('\\').enumerate().try_for_each(|(i, part)| {
+                if i > 0 {
+                    f.write_str("\\\\")?;
+                }
+                f.write_str(part)
+            })
+        }
+    };
+
+    loop {
+        match core::str::from_utf8(bytes) {
+            Ok(string) => return write_str(f, string),
+            Err(err) => {
+                let (valid, invalid) = bytes.split_at(err.valid_up_to());
+                // the bytes up to `valid_up_to` are valid UTF-8
+                write_str(f, unsafe { core::str::from_utf8_unchecked(valid) })?;
+                let len = err.error_len().unwrap_or(invalid.len());
+                for byte in invalid[..len].iter() {
+                    write!(f, "\\x{:02x}", byte)?;
+                }
+                bytes = &invalid[len..];
+            },
+        }
+    }
+}
+
+impl<'a> fmt::Display for PathSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_escaped(f, false)
+    }
+}
+
+impl<'a> fmt::Debug for PathSlice<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "\"")?;
+        self.fmt_escaped(f, true)?;
+        write!(f, "\"")
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.as_slice(), f)
+    }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.as_slice(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+
+    fn path(string: &str) -> Path {
+        Path::parse(string).unwrap()
+    }
+
+    #[test]
+    fn resolve() {
+        let cwd = path("/home/user");
+        assert_eq!(cwd.resolve("docs/a.txt").unwrap(), path("/home/user/docs/a.txt"));
+        assert_eq!(cwd.resolve("/etc//conf/").unwrap(), path("/etc/conf"));
+        assert_eq!(cwd.resolve("./../other/.").unwrap(), path("/home/other"));
+        assert_eq!(cwd.resolve("../../../..").unwrap(), Path::root());
+        assert_eq!(cwd.resolve("").unwrap(), cwd);
+        assert_eq!(cwd.resolve("a\0b"), Err(PathError::NulByte));
+        assert_eq!(Path::parse("relative").unwrap(), path("/relative"));
+        assert!(Path::is_absolute("/a") && !Path::is_absolute("a"));
+
+        let long_name = [b'x'; MAX_NAME_LEN + 1];
+        assert_eq!(cwd.resolve(&long_name[..]), Err(PathError::NameTooLong));
+        let mut long_path = Vec::new();
+        while long_path.len() <= MAX_PATH_LEN {
+            long_path.extend_from_slice(b"/abcdefgh");
+        }
+        assert_eq!(Path::parse(&long_path), Err(PathError::PathTooLong));
+    }
+
+    #[test]
+    fn push_pop() {
+        let mut path = Path::root();
+        assert_eq!(path.pop(), None);
+        path.push("a").unwrap();
+        path.push(b"b").unwrap();
+        assert_eq!(path.to_bytes(), b"/a/b".to_vec());
+        assert_eq!(path.len(), 2);
+        assert_eq!(path.push(""), Err(PathError::InvalidName));
+        assert_eq!(path.push("."), Err(PathError::InvalidName));
+        assert_eq!(path.push(".."), Err(PathError::InvalidName));
+        assert_eq!(path.push("c/d"), Err(PathError::InvalidName));
+        assert_eq!(path.push("c\0"), Err(PathError::NulByte));
+        assert_eq!(path.join("c").unwrap().to_bytes(), b"/a/b/c".to_vec());
+        assert_eq!(path.pop(), Some(b"b".to_vec()));
+        assert_eq!(path.pop(), Some(b"a".to_vec()));
+        assert!(path.is_root());
+        assert_eq!(path.to_bytes(), b"/".to_vec());
+    }
+
+    #[test]
+    fn names() {
+        let file = path("/dir/archive.tar.gz");
+        assert_eq!(file.name(), Some(b"archive.tar.gz".to_vec()));
+        assert_eq!(file.file_stem(), Some(&b"archive.tar"[..]));
+        assert_eq!(file.extension(), Some(&b"gz"[..]));
+        assert_eq!(path("/.hidden").file_stem(), Some(&b".hidden"[..]));
+        assert_eq!(path("/.hidden").extension(), None);
+        assert_eq!(path("/file").extension(), None);
+        assert_eq!(path("/file.").extension(), Some(&b""[..]));
+        assert_eq!(Path::root().file_stem(), None);
+        assert_eq!(file.parent_dir(), Some(path("/dir")));
+        assert_eq!(Path::root().parent_dir(), None);
+
+        let (head, tail) = file.clone().head_tail();
+        assert_eq!(head, Some(b"dir".to_vec()));
+        assert_eq!(tail, path("/archive.tar.gz"));
+        assert_eq!(file.strip_prefix(&path("/dir")).map(|rest| rest.to_path()), Some(path("/archive.tar.gz")));
+        assert!(file.strip_prefix(&path("/di")).is_none());
+    }
+
+    #[test]
+    fn escaping() {
+        let mut path = path("/dir");
+        path.push(b"caf\xc3\xa9 \"x\"\xff\n").unwrap();
+        assert_eq!(format!("{}", path), "/dir/caf\u{e9} \"x\"\\xff\n");
+        assert_eq!(format!("{:?}", path), "\"/dir/caf\u{e9} \\\"x\\\"\\xff\\n\"");
+        assert_eq!(format!("{}", Path::root()), "/");
+        assert_eq!(format!("{:?}", Path::root()), "\"/\"");
+
+        // a name that looks like an escape differs from the escaped byte
+        let name = Path::from_str("/a\\xff\\").unwrap();
+        assert_eq!(format!("{}", name), "/a\\\\xff\\\\");
+        assert_eq!(format!("{:?}", name), "\"/a\\\\xff\\\\\"");
+    }
+}
@@ -0,0 +1,51 @@
+/// Maximum length of a file system name in `FsStats`
+pub const FS_NAME_LEN: usize = 32;
+
+/// Usage statistics of a mounted file system, as returned by `statfs`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct FsStats {
+    /// total number of blocks
+    pub total_blocks: u64,
+    /// number of blocks that are not in use
+    pub free_blocks: u64,
+    /// size of a block in bytes
+    pub block_size: u64,
+    /// length of `name`
+    pub name_len: u64,
+    /// name of the file system, e.g. "FFAT v0.1"
+    pub name: [u8; FS_NAME_LEN],
+}
+
+impl FsStats {
+    /// creates stats for a file system, names longer than `FS_NAME_LEN` are truncated
+    pub fn new(name: &str, total_blocks: u64, free_blocks: u64, block_size: u64) -> Self {
+        let len = name.len().min(FS_NAME_LEN);
+        let mut name_buf = [0u8; FS_NAME_LEN];
+        name_buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Self {
+            total_blocks,
+            free_blocks,
+            block_size,
+            name_len: len as u64,
+            name: name_buf,
+        }
+    }
+
+    /// name of the file system
+    pub fn name(&self) -> &[u8] {
+        let len = (self.name_len as usize).min(FS_NAME_LEN);
+        &self.name[..len]
+    }
+
+    /// number of blocks in use
+    pub fn used_blocks(&self) -> u64 {
+        self.total_blocks.saturating_sub(self.free_blocks)
+    }
+}
+
+impl Default for FsStats {
+    fn default() -> Self {
+        Self::new("", 0, 0, 0)
+    }
+}
@@ -0,0 +1,75 @@
+//! Events of file system watches as they are read from the file descriptor of a watch.
+//!
+//! Every event is encoded as its kind in one byte, the length of the path as a little endian
+//! `u16` and the bytes of the path.
+
+use alloc::vec::Vec;
+
+use super::{Path, MAX_PATH_LEN};
+
+/// Kind of change to a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EventKind {
+    /// file or directory was created
+    Create = 1,
+    /// file or directory was deleted
+    Delete = 2,
+    /// file was written or cleared
+    Modify = 3,
+    /// file or directory was renamed, the path is the old one
+    MovedFrom = 4,
+    /// file or directory was renamed, the path is the new one
+    MovedTo = 5,
+}
+
+impl EventKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        Some(match kind {
+            1 => EventKind::Create,
+            2 => EventKind::Delete,
+            3 => EventKind::Modify,
+            4 => EventKind::MovedFrom,
+            5 => EventKind::MovedTo,
+            _ => return None,
+        })
+    }
+}
+
+/// Change to the watched file or directory or to an entry of the watched directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub kind: EventKind,
+    pub path: Path,
+}
+
+/// size of the kind and the length of the path in front of the path
+const HEADER_LEN: usize = 3;
+
+/// longest encoded event, a buffer of this size can read any event
+pub const MAX_EVENT_LEN: usize = HEADER_LEN + MAX_PATH_LEN;
+
+impl Event {
+    /// the event as it is read from a watch
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let path = self.path.to_bytes();
+        let mut bytes = Vec::with_capacity(HEADER_LEN + path.len());
+        bytes.push(self.kind as u8);
+        bytes.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&path);
+        bytes
+    }
+
+    /// Reads the first event of `bytes` and returns it with the number of bytes it takes up,
+    /// `None` if `bytes` doesn't start with a complete event
+    pub fn from_bytes(bytes: &[u8]) -> Option<(Self, usize)> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let kind = EventKind::from_u8(bytes[0])?;
+        let len = u16::from_le_bytes([bytes[1], bytes[2]]) as usize;
+        let path = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+        let path = Path::parse(path).ok()?;
+        Some((Self { kind, path }, HEADER_LEN + len))
+    }
+}
@@ -1,4 +1,4 @@
-///! Syscall names matched to constants
+//! Syscall names matched to constants
 
 /// maps a page of virtual memory
 pub const VMAP: u64 = 0x1;
@@ -33,3 +33,27 @@ pub const RMDIR: u64 = 0x26;
 /// close file
 pub const CLOSE: u64 = 0x27;
 
+
+/// file system usage statistics
+pub const STATFS: u64 = 0x28;
+
+/// mount a block device at a directory
+pub const MOUNT: u64 = 0x29;
+
+/// unmount the file system attached at a directory
+pub const UMOUNT: u64 = 0x2a;
+
+/// change the working directory of the process
+pub const CHDIR: u64 = 0x2b;
+
+/// get the working directory of the process
+pub const GETCWD: u64 = 0x2c;
+
+/// acquire or release an advisory lock on an open file
+pub const FLOCK: u64 = 0x2d;
+
+/// watch a file or directory for changes, the events are read from the returned file descriptor
+pub const WATCH: u64 = 0x2e;
+
+/// end the process, its files are closed and their locks released
+pub const EXIT: u64 = 0x2f;
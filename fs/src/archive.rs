@@ -0,0 +1,492 @@
+//! cpio (newc) and tar (ustar) archives, used for the initramfs.
+//!
+//! Archives are unpacked into any `FunctionalFileSystem`. Only directories and regular files are
+//! extracted, symbolic links, devices and other special files are skipped.
+//! The initramfs is appended to the kernel binary, `elf_len` tells where the ELF data ends.
+
+extern crate alloc;
+use alloc::vec::Vec;
+
+use crate::error::*;
+use crate::filesystem::*;
+
+/// Magic of a cpio newc header, `070702` is the same format with checksums
+const NEWC_MAGIC: &[u8] = b"07070";
+const NEWC_HEADER: usize = 110;
+const NEWC_TRAILER: &[u8] = b"TRAILER!!!";
+
+/// Offset of the magic in a tar header
+const USTAR_MAGIC_OFFSET: usize = 257;
+const USTAR_MAGIC: &[u8] = b"ustar";
+const TAR_BLOCK: usize = 512;
+
+const MODE_TYPE: u32 = 0o170000;
+const MODE_DIR: u32 = 0o040000;
+const MODE_FILE: u32 = 0o100000;
+
+/// Kind of an archive member
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Dir,
+    File,
+    /// symbolic links, devices, ...
+    Other,
+}
+
+/// Member of an archive
+struct Entry<'a> {
+    name: Vec<u8>,
+    kind: Kind,
+    data: &'a [u8],
+}
+
+/// `true` if `data` begins with a cpio newc or ustar archive
+pub fn is_archive(data: &[u8]) -> bool {
+    data.starts_with(NEWC_MAGIC) || is_tar(data)
+}
+
+fn is_tar(data: &[u8]) -> bool {
+    data.len() >= TAR_BLOCK && data[USTAR_MAGIC_OFFSET..].starts_with(USTAR_MAGIC)
+}
+
+/// unpacks an archive into `fs` and returns the number of extracted files and directories
+pub fn unpack<FS: FunctionalFileSystem>(archive: &[u8], fs: &mut FS) -> FsResult<usize> {
+    let entries = if archive.starts_with(NEWC_MAGIC) {
+        read_newc(archive)?
+    } else if is_tar(archive) {
+        read_tar(archive)?
+    } else {
+        return Err(FsError::InvalidSuperBlock);
+    };
+
+    let mut extracted = 0;
+    for entry in entries {
+        let path = match normalize(&entry.name) {
+            Some(path) => path,
+            None => continue,
+        };
+        match entry.kind {
+            Kind::Dir => create_dir_all(fs, &path)?,
+            Kind::File => {
+                if let Some(parent) = path.parent_dir() {
+                    create_dir_all(fs, &parent)?;
+                }
+                if !fs.exists_file(path.clone())? {
+                    fs.create_file(path.clone())?;
+                }
+                let mut progress = fs.open_write(path)?;
+                fs.write(&mut progress, entry.data)?;
+            },
+            Kind::Other => continue,
+        }
+        extracted += 1;
+    }
+    Ok(extracted)
+}
+
+/// Path of a member without leading `/` and `./`, `None` for the root itself and for names that
+/// aren't valid paths or leave the root with `..`
+fn normalize(name: &[u8]) -> Option<Path> {
+    let mut path = Path::root();
+    for component in name.split(|&c| c == SEPARATOR) {
+        match component {
+            b"" | b"." => (),
+            b".." => {
+                path.pop()?;
+            },
+            _ => path.push(component).ok()?,
+        }
+    }
+    if path.is_root() { None } else { Some(path) }
+}
+
+/// creates a directory and all its missing parents
+fn create_dir_all<FS: FunctionalFileSystem>(fs: &mut FS, path: &Path) -> FsResult<()> {
+    if path.is_root() || fs.exists_dir(path.clone())? {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent_dir() {
+        create_dir_all(fs, &parent)?;
+    }
+    fs.create_dir(path.clone())
+}
+
+/// slice of `len` bytes at `offset`, `InvalidAddress` if the archive is too short
+fn slice(data: &[u8], offset: usize, len: usize) -> FsResult<&[u8]> {
+    let end = offset.checked_add(len).ok_or(FsError::InvalidAddress)?;
+    data.get(offset..end).ok_or(FsError::InvalidAddress)
+}
+
+/// parses a number in the given base, ignoring surrounding spaces and NUL bytes
+fn parse_number(field: &[u8], radix: u32) -> FsResult<usize> {
+    let digits = core::str::from_utf8(field)
+        .map_err(|_| FsError::InvalidSuperBlock)?
+        .trim_matches(|c| c == ' ' || c == '\0');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(digits, radix).map_err(|_| FsError::InvalidSuperBlock)
+}
+
+/// rounds `n` up to a multiple of `align`
+fn align_up(n: usize, align: usize) -> usize {
+    (n + align - 1) / align * align
+}
+
+fn read_newc(archive: &[u8]) -> FsResult<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let header = slice(archive, offset, NEWC_HEADER)?;
+        if !header.starts_with(NEWC_MAGIC) {
+            return Err(FsError::InvalidSuperBlock);
+        }
+        let field = |i: usize| parse_number(&header[6 + 8 * i..14 + 8 * i], 16);
+        let mode = field(1)? as u32;
+        let file_size = field(6)?;
+        let name_size = field(11)?;
+
+        // the name is NUL terminated, name and data are padded to four bytes
+        let name = slice(archive, offset + NEWC_HEADER, name_size)?;
+        let name = &name[..name_size.saturating_sub(1)];
+        let data_begin = align_up(offset + NEWC_HEADER + name_size, 4);
+        if name == NEWC_TRAILER {
+            break;
+        }
+        let data = slice(archive, data_begin, file_size)?;
+        offset = align_up(data_begin + file_size, 4);
+
+        let kind = match mode & MODE_TYPE {
+            MODE_DIR => Kind::Dir,
+            MODE_FILE => Kind::File,
+            _ => Kind::Other,
+        };
+        entries.push(Entry { name: name.to_vec(), kind, data });
+    }
+    Ok(entries)
+}
+
+fn read_tar(archive: &[u8]) -> FsResult<Vec<Entry<'_>>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    // name from a GNU long name or pax header for the next member
+    let mut long_name: Option<&[u8]> = None;
+
+    while offset + TAR_BLOCK <= archive.len() {
+        let header = &archive[offset..offset + TAR_BLOCK];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if !header[USTAR_MAGIC_OFFSET..].starts_with(USTAR_MAGIC) {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        // the checksum is computed with the checksum field filled with spaces
+        let checksum = parse_number(&header[148..156], 8)?;
+        let sum: usize = header.iter().enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' as usize } else { b as usize })
+            .sum();
+        if sum != checksum {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        let size = parse_number(&header[124..136], 8)?;
+        let data = slice(archive, offset + TAR_BLOCK, size)?;
+        offset += TAR_BLOCK + align_up(size, TAR_BLOCK);
+
+        let kind = match header[156] {
+            b'0' | 0 | b'7' => Kind::File,
+            b'5' => Kind::Dir,
+            b'L' => {
+                long_name = Some(until_nul(data));
+                continue;
+            },
+            b'x' => {
+                if let Some(path) = pax_path(data) {
+                    long_name = Some(path);
+                }
+                continue;
+            },
+            b'g' => continue,
+            _ => Kind::Other,
+        };
+
+        let name = match long_name.take() {
+            Some(name) => name.to_vec(),
+            None => {
+                // long names are split into a prefix and a name at a separator,
+                // old GNU archives store other fields in place of the prefix
+                let mut name = if header[USTAR_MAGIC_OFFSET..].starts_with(b"ustar\0") {
+                    until_nul(&header[345..500]).to_vec()
+                } else {
+                    Vec::new()
+                };
+                if !name.is_empty() {
+                    name.push(SEPARATOR);
+                }
+                name.extend_from_slice(until_nul(&header[..100]));
+                name
+            },
+        };
+        entries.push(Entry { name, kind, data });
+    }
+    Ok(entries)
+}
+
+/// bytes up to the first NUL byte
+fn until_nul(bytes: &[u8]) -> &[u8] {
+    match bytes.iter().position(|&b| b == 0) {
+        Some(end) => &bytes[..end],
+        None => bytes,
+    }
+}
+
+/// value of the `path` record of a pax extended header
+fn pax_path(data: &[u8]) -> Option<&[u8]> {
+    // records have the form "<length> <key>=<value>\n"
+    let mut rest = data;
+    while !rest.is_empty() {
+        let space = rest.iter().position(|&b| b == b' ')?;
+        let len = parse_number(&rest[..space], 10).ok()?;
+        let record = rest.get(space + 1..len)?;
+        let record = match record.last() {
+            Some(b'\n') => &record[..record.len() - 1],
+            _ => record,
+        };
+        if record.starts_with(b"path=") {
+            return Some(&record[5..]);
+        }
+        rest = &rest[len..];
+    }
+    None
+}
+
+/// Writer of cpio newc archives
+pub struct NewcWriter {
+    data: Vec<u8>,
+    next_inode: usize,
+}
+
+impl NewcWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            next_inode: 1,
+        }
+    }
+
+    /// adds a directory, `name` is relative to the root of the archive
+    pub fn add_dir(&mut self, name: &[u8]) {
+        self.add(name, MODE_DIR | 0o755, 2, &[]);
+    }
+
+    /// adds a regular file, `name` is relative to the root of the archive
+    pub fn add_file(&mut self, name: &[u8], content: &[u8]) {
+        self.add(name, MODE_FILE | 0o644, 1, content);
+    }
+
+    /// appends the trailer and returns the archive
+    pub fn finish(mut self) -> Vec<u8> {
+        self.add(NEWC_TRAILER, 0, 1, &[]);
+        self.data
+    }
+
+    fn add(&mut self, name: &[u8], mode: u32, links: usize, content: &[u8]) {
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        // inode, mode, uid, gid, links, mtime, file size, device major/minor,
+        // rdev major/minor, name size and checksum
+        let fields = [inode, mode as usize, 0, 0, links, 0, content.len(), 0, 0, 0, 0, name.len() + 1, 0];
+
+        self.data.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            for shift in (0..8).rev() {
+                self.data.push(b"0123456789ABCDEF"[(field >> (shift * 4)) & 0xf]);
+            }
+        }
+        self.data.extend_from_slice(name);
+        self.data.push(0);
+        self.pad();
+        self.data.extend_from_slice(content);
+        self.pad();
+    }
+
+    /// pads the archive to four bytes
+    fn pad(&mut self) {
+        let len = align_up(self.data.len(), 4);
+        self.data.resize(len, 0);
+    }
+}
+
+/// length of the ELF file at the beginning of `file`, `None` if it isn't a 64-bit ELF file
+///
+/// The file ends with the section header table or with the last segment, whichever is later.
+pub fn elf_len(file: &[u8]) -> Option<usize> {
+    const ELF_HEADER: usize = 64;
+    if file.len() < ELF_HEADER || !file.starts_with(b"\x7fELF") || file[4] != 2 || file[5] != 1 {
+        return None;
+    }
+    let u16_at = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]) as usize;
+    let u64_at = |offset: usize| {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(file.get(offset..offset + 8)?);
+        Some(u64::from_le_bytes(bytes) as usize)
+    };
+
+    let section_headers = u64_at(0x28)?;
+    let mut end = ELF_HEADER.max(section_headers + u16_at(0x3a) * u16_at(0x3c));
+
+    let program_headers = u64_at(0x20)?;
+    let program_header_size = u16_at(0x36);
+    end = end.max(program_headers + program_header_size * u16_at(0x38));
+    for i in 0..u16_at(0x38) {
+        let header = program_headers + i * program_header_size;
+        end = end.max(u64_at(header + 8)? + u64_at(header + 32)?);
+    }
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::format;
+    use alloc::string::ToString;
+    use crate::tmpfs::Tmpfs;
+
+    fn read_file(fs: &Tmpfs, path: &str) -> Vec<u8> {
+        let mut progress = fs.open_read(Path::from_str(path).unwrap()).unwrap();
+        let mut content = vec![0u8; fs.file_size(Path::from_str(path).unwrap()).unwrap() + 1];
+        let len = fs.read(&mut progress, &mut content).unwrap();
+        content.truncate(len);
+        content
+    }
+
+    /// tar header block with a valid checksum, `prefix` is only stored in ustar archives
+    fn tar_header(name: &[u8], prefix: &[u8], kind: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0u8; TAR_BLOCK];
+        header[..name.len()].copy_from_slice(name);
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET + 8].copy_from_slice(b"ustar\x0000");
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+        header[148..156].copy_from_slice(b"        ");
+        let sum: usize = header.iter().map(|&b| b as usize).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    /// tar member with its content padded to whole blocks
+    fn tar_member(tar: &mut Vec<u8>, name: &[u8], prefix: &[u8], kind: u8, content: &[u8]) {
+        tar.extend_from_slice(&tar_header(name, prefix, kind, content.len()));
+        tar.extend_from_slice(content);
+        tar.resize(align_up(tar.len(), TAR_BLOCK), 0);
+    }
+
+    fn tar() -> Vec<u8> {
+        let long: Vec<u8> = core::iter::repeat(b'x').take(150).collect();
+        let mut tar = Vec::new();
+        tar_member(&mut tar, b"etc/", b"", b'5', &[]);
+        tar_member(&mut tar, b"motd", b"./etc", b'0', b"hello\n");
+        tar_member(&mut tar, b"link", b"", b'2', &[]);
+        tar_member(&mut tar, b"././@LongLink", b"", b'L', &[long.as_slice(), b"\0"].concat());
+        tar_member(&mut tar, b"truncated", b"", b'0', b"long gnu\n");
+        tar_member(&mut tar, b"pax", b"", b'x', b"20 mtime=1600000000\n14 path=a/b/c\n");
+        tar_member(&mut tar, b"c", b"", b'0', b"pax\n");
+        tar.extend_from_slice(&[0u8; 2 * TAR_BLOCK]);
+        tar
+    }
+
+    #[test]
+    fn newc_roundtrip() {
+        let mut writer = NewcWriter::new();
+        writer.add_dir(b"bin");
+        writer.add_file(b"bin/x", b"abc");
+        writer.add_file(b"./y/z", b"12345");
+        writer.add(b"bin/link", 0o120777, 1, b"x");
+        writer.add_file(b"../escape", b"no");
+        let archive = writer.finish();
+        assert!(is_archive(&archive));
+        assert_eq!(archive.len() % 4, 0);
+
+        let mut fs = Tmpfs::new(1 << 20);
+        assert_eq!(unpack(&archive, &mut fs).unwrap(), 3);
+        assert_eq!(read_file(&fs, "/bin/x"), b"abc");
+        assert_eq!(read_file(&fs, "/y/z"), b"12345");
+        assert!(!fs.exists_file(Path::from_str("/bin/link").unwrap()).unwrap());
+        assert!(!fs.exists_file(Path::from_str("/escape").unwrap()).unwrap());
+    }
+
+    #[test]
+    fn tar_formats() {
+        let tar = tar();
+        assert!(is_archive(&tar));
+        let mut fs = Tmpfs::new(1 << 20);
+        assert_eq!(unpack(&tar, &mut fs).unwrap(), 4);
+        assert_eq!(read_file(&fs, "/etc/motd"), b"hello\n");
+        assert_eq!(read_file(&fs, &format!("/{}", "x".repeat(150))), b"long gnu\n");
+        assert_eq!(read_file(&fs, "/a/b/c"), b"pax\n");
+        assert!(!fs.exists_file(Path::from_str("/link").unwrap()).unwrap());
+        assert!(!fs.exists_file(Path::from_str("/truncated").unwrap()).unwrap());
+
+        let mut bad_checksum = tar.clone();
+        bad_checksum[TAR_BLOCK + 10] ^= 1;
+        assert!(unpack(&bad_checksum, &mut Tmpfs::new(1 << 20)).is_err());
+    }
+
+    #[test]
+    fn normalized_names() {
+        let name = |name: &[u8]| normalize(name).map(|path| path.to_string());
+        assert_eq!(name(b"./a/./b/"), Some("/a/b".into()));
+        assert_eq!(name(b"/a/../b"), Some("/b".into()));
+        assert_eq!(name(b"a/../../b"), None);
+        assert_eq!(name(b"./"), None);
+        assert_eq!(name(b"a/b\0c"), None);
+    }
+
+    #[test]
+    fn damaged_archives() {
+        let mut writer = NewcWriter::new();
+        writer.add_file(b"a/b", b"content");
+        let newc = writer.finish();
+        assert!(!is_archive(&newc[1..]));
+        assert!(unpack(&newc[1..], &mut Tmpfs::new(1 << 20)).is_err());
+
+        // truncated and corrupted archives are errors, not panics
+        for archive in &[newc, tar()] {
+            for len in 0..archive.len() {
+                let _ = unpack(&archive[..len], &mut Tmpfs::new(1 << 20));
+            }
+            for i in 0..archive.len().min(3 * TAR_BLOCK) {
+                let mut damaged = archive.clone();
+                damaged[i] ^= 0x55;
+                let _ = unpack(&damaged, &mut Tmpfs::new(1 << 20));
+            }
+        }
+    }
+
+    #[test]
+    fn elf_length() {
+        let mut elf = vec![0u8; 0x200];
+        elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+        // two section headers of 64 bytes at 0x100
+        elf[0x28..0x30].copy_from_slice(&0x100u64.to_le_bytes());
+        elf[0x3a..0x3c].copy_from_slice(&64u16.to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&2u16.to_le_bytes());
+        // one program header at 0x40 with a segment that ends at 0x1f0
+        elf[0x20..0x28].copy_from_slice(&0x40u64.to_le_bytes());
+        elf[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+        elf[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+        elf[0x48..0x50].copy_from_slice(&0x1c0u64.to_le_bytes());
+        elf[0x60..0x68].copy_from_slice(&0x30u64.to_le_bytes());
+        assert_eq!(elf_len(&elf), Some(0x1f0));
+
+        // the section headers end last
+        elf[0x48..0x50].copy_from_slice(&0x80u64.to_le_bytes());
+        let mut with_archive = elf.clone();
+        with_archive.extend_from_slice(&NewcWriter::new().finish());
+        assert_eq!(elf_len(&with_archive), Some(0x180));
+        assert_eq!(elf_len(&elf[..32]), None);
+        assert_eq!(elf_len(b"070701"), None);
+    }
+}
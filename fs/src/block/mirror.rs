@@ -0,0 +1,663 @@
+//! Mirror of block devices, also known as RAID-1.
+//!
+//! Every block is written to all members and read from the first one whose copy matches the
+//! CRC-32 of the block. A member that fails a read, a write or a checksum is not used anymore
+//! until it is replaced and resynchronized.
+//!
+//! On every member the blocks of the mirror are followed by the checksums of all blocks and the
+//! last block of the member is a header: `magic: [u8; 8]`, `blocks: u64`, `events: u64` and the
+//! CRC-32 of these fields. `events` counts the changes of the member states, members with an
+//! older count missed writes and are stale when the mirror is opened again.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::string::*;
+use alloc::vec;
+use core::cell::Cell;
+
+use crate::error::*;
+use super::*;
+
+const MAGIC: &[u8; 8] = b"bitOSmir";
+const HEADER_LEN: usize = 28;
+
+/// State of a member of a mirror
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemberState {
+    /// holds the data of the mirror, it is read and written
+    InSync,
+    /// failed a read, a write or a checksum, it isn't used anymore
+    Failed,
+    /// replaced a member, it is written but not read until `resync` copied the data onto it
+    Resyncing,
+}
+
+pub struct Mirror<D> {
+    members: Vec<D>,
+    /// state of each member, reads can mark a member as failed
+    states: Vec<Cell<MemberState>>,
+    block_size: usize,
+    blocks: usize,
+    /// CRC-32 of every block, the same on all members that are in sync
+    checksums: Vec<u32>,
+    /// number of changes of the member states
+    events: u64,
+    /// a read marked a member as failed, the headers are updated by the next write
+    changed: Cell<bool>,
+}
+
+/// number of checksums in a block
+fn per_block(block_size: usize) -> usize {
+    block_size / 4
+}
+
+/// blocks a member needs for a mirror with `blocks` blocks, including the checksums and header
+fn blocks_needed(blocks: usize, block_size: usize) -> usize {
+    let per_block = per_block(block_size);
+    blocks + (blocks + per_block - 1) / per_block + 1
+}
+
+/// number of blocks of a mirror on members with `blocks` blocks
+fn usable_blocks(blocks: usize, block_size: usize) -> usize {
+    let per_block = per_block(block_size);
+    let mut usable = blocks.saturating_sub(1) / (per_block + 1) * per_block;
+    while blocks_needed(usable + 1, block_size) <= blocks {
+        usable += 1;
+    }
+    usable
+}
+
+impl<D: RWBlockDevice> Mirror<D> {
+    /// Creates a new mirror on `members` that holds the data of the first member. The data is
+    /// copied onto the other members, the blocks at the end of the first member that are needed
+    /// for the checksums and the header are lost.
+    pub fn create(members: Vec<D>) -> FsResult<Self> {
+        let block_size = Self::block_size_of(&members)?;
+        let smallest = members.iter().map(|member| member.blocks()).min().unwrap_or(0);
+        let blocks = usable_blocks(smallest, block_size);
+
+        let mut buffer = vec![0u8; block_size];
+        let mut checksums = Vec::with_capacity(blocks);
+        for index in 0..blocks {
+            members[0].read_block(index, &mut buffer)?;
+            checksums.push(crc32(&buffer));
+        }
+
+        let states = (0..members.len())
+            .map(|i| Cell::new(if i == 0 { MemberState::InSync } else { MemberState::Resyncing }))
+            .collect();
+        let mut mirror = Self {
+            members,
+            states,
+            block_size,
+            blocks,
+            checksums,
+            events: 0,
+            changed: Cell::new(false),
+        };
+        mirror.write_checksums(0)?;
+        mirror.commit_states();
+        if mirror.state(0) != MemberState::InSync {
+            return Err(FsError::BlockDeviceError);
+        }
+        mirror.resync()?;
+        Ok(mirror)
+    }
+
+    /// Opens a mirror that was created before. Members without a valid header or with a header
+    /// that is older than the newest one are failed.
+    pub fn open(members: Vec<D>) -> FsResult<Self> {
+        let block_size = Self::block_size_of(&members)?;
+        let headers: Vec<_> = members
+            .iter()
+            .map(|member| Self::read_header(member).ok())
+            .collect();
+        let (blocks, events) = headers
+            .iter()
+            .filter_map(|header| *header)
+            .max_by_key(|&(_, events)| events)
+            .ok_or(FsError::InvalidSuperBlock)?;
+
+        let states: Vec<_> = members
+            .iter()
+            .zip(headers.iter())
+            .map(|(member, header)| {
+                let current = *header == Some((blocks, events)) && member.blocks() >= blocks_needed(blocks, block_size);
+                Cell::new(if current { MemberState::InSync } else { MemberState::Failed })
+            })
+            .collect();
+
+        let mut mirror = Self {
+            members,
+            states,
+            block_size,
+            blocks,
+            checksums: Vec::new(),
+            events,
+            changed: Cell::new(false),
+        };
+        mirror.checksums = mirror.read_checksums()?;
+        Ok(mirror)
+    }
+
+    /// state of each member
+    pub fn states(&self) -> Vec<MemberState> {
+        self.states.iter().map(Cell::get).collect()
+    }
+
+    pub fn members(&self) -> &[D] {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut [D] {
+        &mut self.members
+    }
+
+    /// returns the members
+    pub fn into_inner(self) -> Vec<D> {
+        self.members
+    }
+
+    /// Replaces the member at `index` with `member` and returns the old one. The new member is
+    /// written from now on, `resync` copies the data onto it.
+    pub fn replace(&mut self, index: usize, member: D) -> FsResult<D> {
+        if index >= self.members.len() {
+            return Err(FsError::IllegalOperation("no such member".to_string()));
+        }
+        if member.block_size() != self.block_size {
+            return Err(FsError::IllegalOperation("members have different block sizes".to_string()));
+        }
+        if member.blocks() < blocks_needed(self.blocks, self.block_size) {
+            return Err(FsError::NotEnoughSpace);
+        }
+        let old = core::mem::replace(&mut self.members[index], member);
+        // the old member is stale if it is opened together with the others again
+        self.set_state(index, MemberState::Resyncing);
+        self.commit_states();
+        Ok(old)
+    }
+
+    /// Copies the data onto the members that replaced others, they are in sync afterwards unless
+    /// writing fails. Fails if a block can't be read from any member that is in sync.
+    pub fn resync(&mut self) -> FsResult<()> {
+        let targets: Vec<_> = (0..self.members.len())
+            .filter(|&i| self.state(i) == MemberState::Resyncing)
+            .collect();
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        let mut buffer = vec![0u8; self.block_size];
+        for index in 0..self.blocks {
+            self.read_block(index, &mut buffer)?;
+            for &i in targets.iter() {
+                if self.state(i) == MemberState::Resyncing && self.members[i].write_block(index, &buffer).is_err() {
+                    self.set_state(i, MemberState::Failed);
+                }
+            }
+        }
+
+        for &i in targets.iter() {
+            if self.state(i) == MemberState::Resyncing {
+                let state = match self.write_checksums(i) {
+                    Ok(()) => MemberState::InSync,
+                    Err(_) => MemberState::Failed,
+                };
+                self.set_state(i, state);
+            }
+        }
+        self.commit_states();
+        Ok(())
+    }
+
+    fn block_size_of(members: &[D]) -> FsResult<usize> {
+        let block_size = match members.first() {
+            Some(member) => member.block_size(),
+            None => return Err(FsError::IllegalOperation("a mirror needs members".to_string())),
+        };
+        if block_size < HEADER_LEN {
+            return Err(FsError::IllegalOperation("blocks are too small for the header".to_string()));
+        }
+        if members.iter().any(|member| member.block_size() != block_size) {
+            return Err(FsError::IllegalOperation("members have different block sizes".to_string()));
+        }
+        Ok(block_size)
+    }
+
+    fn state(&self, member: usize) -> MemberState {
+        self.states[member].get()
+    }
+
+    fn set_state(&self, member: usize, state: MemberState) {
+        if self.state(member) != state {
+            self.states[member].set(state);
+            self.changed.set(true);
+        }
+    }
+
+    /// blocks of the mirror and events of the header of a member
+    fn read_header(member: &D) -> FsResult<(usize, u64)> {
+        let blocks = member.blocks();
+        if blocks == 0 {
+            return Err(FsError::InvalidSuperBlock);
+        }
+        let mut header = vec![0u8; member.block_size()];
+        member.read_block(blocks - 1, &mut header)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&header[8..16]);
+        let mirror_blocks = u64::from_le_bytes(bytes) as usize;
+        bytes.copy_from_slice(&header[16..24]);
+        let events = u64::from_le_bytes(bytes);
+        let mut checksum = [0u8; 4];
+        checksum.copy_from_slice(&header[24..28]);
+
+        if &header[..8] != MAGIC || crc32(&header[..24]) != u32::from_le_bytes(checksum) {
+            return Err(FsError::InvalidSuperBlock);
+        }
+        Ok((mirror_blocks, events))
+    }
+
+    fn write_header(&mut self, member: usize) -> FsResult<()> {
+        let mut header = vec![0u8; self.block_size];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..16].copy_from_slice(&(self.blocks as u64).to_le_bytes());
+        header[16..24].copy_from_slice(&self.events.to_le_bytes());
+        let checksum = crc32(&header[..24]);
+        header[24..28].copy_from_slice(&checksum.to_le_bytes());
+        let last = self.members[member].blocks() - 1;
+        self.members[member].write_block(last, &header)
+    }
+
+    /// Counts a change of the member states and writes it to the headers of the members that are
+    /// in sync. A member that can't be written is failed, which is written with the next change.
+    fn commit_states(&mut self) {
+        self.events += 1;
+        for i in 0..self.members.len() {
+            if self.state(i) == MemberState::InSync && self.write_header(i).is_err() {
+                self.states[i].set(MemberState::Failed);
+            }
+        }
+        self.changed.set(false);
+    }
+
+    /// the `number`-th block of the checksums
+    fn checksum_block(&self, number: usize) -> Vec<u8> {
+        let per_block = per_block(self.block_size);
+        let mut block = vec![0u8; self.block_size];
+        let first = number * per_block;
+        let last = (first + per_block).min(self.blocks);
+        for (i, checksum) in self.checksums[first..last].iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&checksum.to_le_bytes());
+        }
+        block
+    }
+
+    fn write_checksum_block(&mut self, member: usize, number: usize) -> FsResult<()> {
+        let block = self.checksum_block(number);
+        self.members[member].write_block(self.blocks + number, &block)
+    }
+
+    /// writes all checksums to a member
+    fn write_checksums(&mut self, member: usize) -> FsResult<()> {
+        let per_block = per_block(self.block_size);
+        for number in 0..(self.blocks + per_block - 1) / per_block {
+            self.write_checksum_block(member, number)?;
+        }
+        Ok(())
+    }
+
+    /// reads the checksums from the first member that is in sync and can be read
+    fn read_checksums(&self) -> FsResult<Vec<u32>> {
+        let per_block = per_block(self.block_size);
+        let mut block = vec![0u8; self.block_size];
+        'members: for i in 0..self.members.len() {
+            if self.state(i) != MemberState::InSync {
+                continue;
+            }
+            let mut checksums = Vec::with_capacity(self.blocks);
+            for number in 0..(self.blocks + per_block - 1) / per_block {
+                if self.members[i].read_block(self.blocks + number, &mut block).is_err() {
+                    self.set_state(i, MemberState::Failed);
+                    continue 'members;
+                }
+                for chunk in block.chunks(4).take(self.blocks - number * per_block) {
+                    let mut bytes = [0u8; 4];
+                    bytes.copy_from_slice(chunk);
+                    checksums.push(u32::from_le_bytes(bytes));
+                }
+            }
+            return Ok(checksums);
+        }
+        Err(FsError::BlockDeviceError)
+    }
+
+    fn check(&self, index: usize) -> FsResult<()> {
+        self.check_range(index, 1)
+    }
+
+    /// checks that the `count` blocks from `first` are in the mirror
+    fn check_range(&self, first: usize, count: usize) -> FsResult<()> {
+        if first.checked_add(count).map_or(true, |end| end > self.blocks) {
+            Err(FsError::InternalError("out of bounds block address".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<D: RWBlockDevice> BlockDevice for Mirror<D> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn blocks(&self) -> usize {
+        self.blocks
+    }
+}
+
+impl<D: RWBlockDevice> ReadBlockDevice for Mirror<D> {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.check(index)?;
+        let mut broken = Vec::new();
+        for i in 0..self.members.len() {
+            if self.state(i) != MemberState::InSync {
+                continue;
+            }
+            match self.members[i].read_block(index, buffer) {
+                Ok(()) if crc32(buffer) == self.checksums[index] => {
+                    // the other copies are only given up once there is a good one
+                    for &member in broken.iter() {
+                        self.set_state(member, MemberState::Failed);
+                    }
+                    return Ok(());
+                },
+                _ => broken.push(i),
+            }
+        }
+        Err(FsError::BlockDeviceError)
+    }
+
+    /// reads the blocks at once from the first member in sync, block by block if a copy is bad
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        let count = block_count(self.block_size, buffer.len())?;
+        self.check_range(first, count)?;
+        if let Some(member) = (0..self.members.len()).find(|&i| self.state(i) == MemberState::InSync) {
+            let read = self.members[member].read_blocks(first, buffer).is_ok()
+                && buffer.chunks(self.block_size)
+                    .zip(self.checksums[first..first + count].iter())
+                    .all(|(block, &checksum)| crc32(block) == checksum);
+            if read {
+                return Ok(());
+            }
+        }
+        for (i, block) in buffer.chunks_mut(self.block_size).enumerate() {
+            self.read_block(first + i, block)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: RWBlockDevice> WriteBlockDevice for Mirror<D> {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        if buffer.len() != self.block_size {
+            return Err(FsError::InternalError("invalid buffer size".to_string()));
+        }
+        self.write_blocks(index, buffer)
+    }
+
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        let count = block_count(self.block_size, buffer.len())?;
+        self.check_range(first, count)?;
+        if count == 0 {
+            return Ok(());
+        }
+        // the checksums are only kept once a member that is in sync holds the blocks
+        let old: Vec<u32> = self.checksums[first..first + count].to_vec();
+        for (i, block) in buffer.chunks(self.block_size).enumerate() {
+            self.checksums[first + i] = crc32(block);
+        }
+        let per_block = per_block(self.block_size);
+        let numbers = first / per_block..(first + count - 1) / per_block + 1;
+        let checksum_blocks: Vec<_> = numbers.clone().map(|number| self.checksum_block(number)).collect();
+
+        let mut written = false;
+        for i in 0..self.members.len() {
+            if self.state(i) == MemberState::Failed {
+                continue;
+            }
+            let blocks = self.blocks;
+            let member = &mut self.members[i];
+            let result = member.write_blocks(first, buffer).and_then(|_| {
+                numbers.clone()
+                    .zip(checksum_blocks.iter())
+                    .try_for_each(|(number, block)| member.write_block(blocks + number, block))
+            });
+            match result {
+                Ok(()) => written |= self.state(i) == MemberState::InSync,
+                Err(_) => self.set_state(i, MemberState::Failed),
+            }
+        }
+        if !written {
+            self.checksums[first..first + count].copy_from_slice(&old);
+        }
+        if self.changed.get() {
+            self.commit_states();
+        }
+        if written {
+            Ok(())
+        } else {
+            Err(FsError::BlockDeviceError)
+        }
+    }
+
+    /// flushes the members that weren't given up, a member that fails to flush is given up
+    fn flush(&mut self) -> FsResult<()> {
+        let mut flushed = false;
+        for i in 0..self.members.len() {
+            if self.state(i) == MemberState::Failed {
+                continue;
+            }
+            match self.members[i].flush() {
+                Ok(()) => flushed |= self.state(i) == MemberState::InSync,
+                Err(_) => self.set_state(i, MemberState::Failed),
+            }
+        }
+        if self.changed.get() {
+            self.commit_states();
+        }
+        if flushed {
+            Ok(())
+        } else {
+            Err(FsError::BlockDeviceError)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_devices::*;
+
+    /// block `i` of the test data
+    fn block(i: usize) -> Vec<u8> {
+        (0..MEMORY_BLOCK_SIZE).map(|j| (i * 31 + j) as u8).collect()
+    }
+
+    /// member whose writes fail
+    struct ReadOnly(OwnedDisk);
+
+    impl BlockDevice for ReadOnly {
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+
+        fn blocks(&self) -> usize {
+            self.0.blocks()
+        }
+    }
+
+    impl ReadBlockDevice for ReadOnly {
+        fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+            self.0.read_block(index, buffer)
+        }
+    }
+
+    impl WriteBlockDevice for ReadOnly {
+        fn write_block(&mut self, _index: usize, _buffer: &[u8]) -> FsResult<()> {
+            Err(FsError::BlockDeviceError)
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        // 128 checksums fit into a block of 512 bytes
+        assert_eq!(blocks_needed(0, 512), 1);
+        assert_eq!(blocks_needed(1, 512), 3);
+        assert_eq!(blocks_needed(128, 512), 130);
+        assert_eq!(blocks_needed(129, 512), 132);
+        assert_eq!(usable_blocks(0, 512), 0);
+        assert_eq!(usable_blocks(2, 512), 0);
+        assert_eq!(usable_blocks(3, 512), 1);
+        assert_eq!(usable_blocks(130, 512), 128);
+        assert_eq!(usable_blocks(131, 512), 128);
+        assert_eq!(usable_blocks(132, 512), 129);
+        for blocks in 0..1000 {
+            let usable = usable_blocks(blocks, 512);
+            assert!(usable == 0 || blocks_needed(usable, 512) <= blocks);
+            assert!(blocks_needed(usable + 1, 512) > blocks);
+        }
+    }
+
+    #[test]
+    fn create_and_open() {
+        let mut a = vec![0u8; 512 * 300];
+        let mut b = vec![0u8; 512 * 310];
+        for i in 0..300 {
+            a[i * 512..(i + 1) * 512].copy_from_slice(&block(i));
+        }
+        {
+            let mirror = Mirror::create(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+            assert_eq!(mirror.states(), vec![MemberState::InSync, MemberState::InSync]);
+            assert_eq!(mirror.blocks(), 296);
+        }
+        // the data of the first member was copied
+        assert_eq!(&a[..512 * 296], &b[..512 * 296]);
+
+        let mirror = Mirror::open(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+        assert_eq!(mirror.states(), vec![MemberState::InSync, MemberState::InSync]);
+        assert_eq!(mirror.blocks(), 296);
+        let mut buffer = vec![0u8; 512];
+        for i in 0..296 {
+            mirror.read_block(i, &mut buffer).unwrap();
+            assert_eq!(buffer, block(i));
+        }
+        assert!(mirror.read_block(296, &mut buffer).is_err());
+        drop(mirror);
+
+        // something that is not a mirror
+        assert!(Mirror::open(vec![OwnedDisk { data: vec![0u8; 512 * 10] }]).is_err());
+    }
+
+    #[test]
+    fn corrupted_copy() {
+        let mut a = vec![0u8; 512 * 100];
+        let mut b = vec![0u8; 512 * 100];
+        {
+            let mut mirror = Mirror::create(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+            mirror.write_block(7, &block(7)).unwrap();
+            mirror.write_block(9, &block(9)).unwrap();
+        }
+        a[512 * 7] ^= 1;
+        let mut buffer = vec![0u8; 512];
+        {
+            let mirror = Mirror::open(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+            mirror.read_block(7, &mut buffer).unwrap();
+            assert_eq!(buffer, block(7));
+            assert_eq!(mirror.states(), vec![MemberState::Failed, MemberState::InSync]);
+        }
+
+        // no good copy is left, the members are kept
+        b[512 * 9] ^= 1;
+        a[512 * 9] ^= 1;
+        let mirror = Mirror::open(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+        assert!(mirror.read_block(9, &mut buffer).is_err());
+        assert_eq!(mirror.states(), vec![MemberState::InSync, MemberState::InSync]);
+    }
+
+    #[test]
+    fn failed_write_keeps_checksum() {
+        let mut mirror = Mirror::create(vec![OwnedDisk { data: vec![0u8; 512 * 100] }]).unwrap();
+        mirror.write_block(3, &block(3)).unwrap();
+        let disk = mirror.into_inner().pop().unwrap();
+
+        let mut mirror = Mirror::open(vec![ReadOnly(disk)]).unwrap();
+        assert!(mirror.write_block(3, &block(4)).is_err());
+        assert_eq!(mirror.checksums[3], crc32(&block(3)));
+    }
+
+    #[test]
+    fn replace_and_resync() {
+        let mut a = vec![0u8; 512 * 100];
+        let mut b = vec![0u8; 512 * 100];
+        let mut c = vec![0u8; 512 * 100];
+        let mut small = vec![0u8; 512 * 90];
+        let mut buffer = vec![0u8; 512];
+        {
+            let mut mirror = Mirror::create(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+            for i in 0..mirror.blocks() {
+                mirror.write_block(i, &block(i)).unwrap();
+            }
+            assert!(mirror.replace(0, RamDisk { data: &mut small }).is_err());
+            mirror.replace(0, RamDisk { data: &mut c }).unwrap();
+            assert_eq!(mirror.states(), vec![MemberState::Resyncing, MemberState::InSync]);
+
+            // writes reach the new member before it is in sync
+            mirror.write_block(4, &block(1000)).unwrap();
+            mirror.resync().unwrap();
+            assert_eq!(mirror.states(), vec![MemberState::InSync, MemberState::InSync]);
+        }
+
+        // the replaced member missed the changes of the states and is stale
+        let mirror = Mirror::open(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }, RamDisk { data: &mut c }]).unwrap();
+        assert_eq!(mirror.states(), vec![MemberState::Failed, MemberState::InSync, MemberState::InSync]);
+        drop(mirror);
+
+        // the new member holds all data on its own
+        let mirror = Mirror::open(vec![RamDisk { data: &mut c }]).unwrap();
+        for i in 0..mirror.blocks() {
+            mirror.read_block(i, &mut buffer).unwrap();
+            assert_eq!(buffer, if i == 4 { block(1000) } else { block(i) });
+        }
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let mut a = vec![0u8; 512 * 300];
+        let mut b = vec![0u8; 512 * 300];
+        // crosses the end of the first checksum block
+        let data: Vec<u8> = (120..140).flat_map(block).collect();
+        {
+            let mut mirror = Mirror::create(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+            mirror.write_blocks(120, &data).unwrap();
+        }
+        a[512 * 130] ^= 1;
+        let mirror = Mirror::open(vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }]).unwrap();
+        let mut read = vec![0u8; data.len()];
+        // the bad copy is replaced by the one of the other member
+        mirror.read_blocks(120, &mut read).unwrap();
+        assert_eq!(read, data);
+        assert_eq!(mirror.states(), vec![MemberState::Failed, MemberState::InSync]);
+        assert!(mirror.read_blocks(290, &mut read).is_err());
+    }
+
+    #[test]
+    fn failed_multiple_write_keeps_checksums() {
+        let mut mirror = Mirror::create(vec![OwnedDisk { data: vec![0u8; 512 * 100] }]).unwrap();
+        mirror.write_blocks(3, &[block(3), block(4)].concat()).unwrap();
+        let disk = mirror.into_inner().pop().unwrap();
+
+        let mut mirror = Mirror::open(vec![ReadOnly(disk)]).unwrap();
+        assert!(mirror.write_blocks(3, &[block(5), block(6)].concat()).is_err());
+        assert_eq!(mirror.checksums[3..5], [crc32(&block(3)), crc32(&block(4))]);
+    }
+}
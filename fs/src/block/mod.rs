@@ -0,0 +1,136 @@
+extern crate alloc;
+use alloc::string::*;
+use alloc::vec;
+use crate::error::*;
+
+mod mirror;
+mod stripe;
+
+pub use mirror::{Mirror, MemberState};
+pub use stripe::Stripe;
+
+/// Generic device that can be read from or written to on a block by block basis
+pub trait BlockDevice: Send {
+    /// block size of device
+    fn block_size(&self) -> usize;
+    /// Number of blocks on the device
+    fn blocks(&self) -> usize;
+}
+
+pub trait ReadBlockDevice : BlockDevice {
+    /// Basic read operation:
+    /// reads block from `self` into `buffer`
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()>;
+
+    /// Reads consecutive blocks starting at `first` into `buffer`, a multiple of the block size.
+    /// Devices that can have several requests in flight override it to read them at once.
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        let block_size = self.block_size();
+        block_count(block_size, buffer.len())?;
+        for (i, block) in buffer.chunks_mut(block_size).enumerate() {
+            self.read_block(first + i, block)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait WriteBlockDevice : BlockDevice {
+    /// Basic write operation:
+    /// writes `buffer` to `self`
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()>;
+
+    /// Writes `buffer`, a multiple of the block size, to consecutive blocks starting at `first`.
+    /// Devices that can have several requests in flight override it to write them at once.
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        let block_size = self.block_size();
+        block_count(block_size, buffer.len())?;
+        for (i, block) in buffer.chunks(block_size).enumerate() {
+            self.write_block(first + i, block)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the blocks written so far persistent, e.g. by flushing the write cache of a disk.
+    /// Blocks may reach the medium in any order until they are flushed.
+    fn flush(&mut self) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+pub trait RWBlockDevice : ReadBlockDevice + WriteBlockDevice {}
+impl<D> RWBlockDevice for D where D: ReadBlockDevice + WriteBlockDevice {}
+
+pub fn check_args<D: BlockDevice>(device: &D, buffer: &[u8], index: usize) -> FsResult<()> {
+    if buffer.len() != device.block_size() {
+        Err(FsError::InternalError("invalid buffer size".to_string()))
+    } else if index > device.blocks() {
+        Err(FsError::InternalError("out of bounds block address".to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// number of blocks in a buffer of `len` bytes for `read_blocks` and `write_blocks`,
+/// which must be a multiple of the block size
+pub fn block_count(block_size: usize, len: usize) -> FsResult<usize> {
+    if block_size == 0 || len % block_size != 0 {
+        Err(FsError::InternalError("invalid buffer size".to_string()))
+    } else {
+        Ok(len / block_size)
+    }
+}
+
+/// Reads `buffer.len()` bytes starting at the byte offset `offset` of the device,
+/// independent of its block size
+pub fn read_bytes<B: ?Sized + ReadBlockDevice>(device: &B, offset: usize, buffer: &mut [u8]) -> FsResult<()> {
+    let block_size = device.block_size();
+    let mut block = vec![0u8; block_size];
+    let mut done = 0;
+    while done < buffer.len() {
+        let index = (offset + done) / block_size;
+        let within = (offset + done) % block_size;
+        let len = (block_size - within).min(buffer.len() - done);
+        if len == block_size {
+            device.read_block(index, &mut buffer[done..done + len])?;
+        } else {
+            device.read_block(index, &mut block)?;
+            buffer[done..done + len].copy_from_slice(&block[within..within + len]);
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+/// Writes `buffer` starting at the byte offset `offset` of the device,
+/// blocks that are only partially written are read first
+pub fn write_bytes<B: ?Sized + RWBlockDevice>(device: &mut B, offset: usize, buffer: &[u8]) -> FsResult<()> {
+    let block_size = device.block_size();
+    let mut block = vec![0u8; block_size];
+    let mut done = 0;
+    while done < buffer.len() {
+        let index = (offset + done) / block_size;
+        let within = (offset + done) % block_size;
+        let len = (block_size - within).min(buffer.len() - done);
+        if len == block_size {
+            device.write_block(index, &buffer[done..done + len])?;
+        } else {
+            device.read_block(index, &mut block)?;
+            block[within..within + len].copy_from_slice(&buffer[done..done + len]);
+            device.write_block(index, &block)?;
+        }
+        done += len;
+    }
+    Ok(())
+}
+
+/// CRC-32 as used by GPT and by the checksums of mirrors
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,199 @@
+//! Stripe set of block devices, also known as RAID-0.
+//!
+//! Consecutive chunks of blocks are spread over the members in turn, so that large reads and
+//! writes use all of them. There is no redundancy, losing a member loses the data of all of them.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::string::*;
+
+use crate::error::*;
+use super::*;
+
+pub struct Stripe<D> {
+    members: Vec<D>,
+    /// number of consecutive blocks on the same member
+    chunk: usize,
+    block_size: usize,
+    blocks: usize,
+}
+
+impl<D: BlockDevice> Stripe<D> {
+    /// Stripes `members` in chunks of `chunk` blocks. The members need the same block size,
+    /// only as many whole chunks of each member are used as fit on the smallest one.
+    pub fn new(members: Vec<D>, chunk: usize) -> FsResult<Self> {
+        let block_size = match members.first() {
+            Some(member) => member.block_size(),
+            None => return Err(FsError::IllegalOperation("a stripe set needs members".to_string())),
+        };
+        if chunk == 0 {
+            return Err(FsError::IllegalOperation("chunks can't be empty".to_string()));
+        }
+        if members.iter().any(|member| member.block_size() != block_size) {
+            return Err(FsError::IllegalOperation("members have different block sizes".to_string()));
+        }
+        let smallest = members.iter().map(|member| member.blocks()).min().unwrap_or(0);
+        let blocks = smallest / chunk * chunk * members.len();
+        Ok(Self { members, chunk, block_size, blocks })
+    }
+
+    pub fn members(&self) -> &[D] {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut [D] {
+        &mut self.members
+    }
+
+    /// returns the members
+    pub fn into_inner(self) -> Vec<D> {
+        self.members
+    }
+
+    /// member and block on it that hold the block `index` of the stripe set
+    fn locate(&self, index: usize) -> FsResult<(usize, usize)> {
+        if index >= self.blocks {
+            return Err(FsError::InternalError("out of bounds block address".to_string()));
+        }
+        let chunk = index / self.chunk;
+        let member = chunk % self.members.len();
+        let block = chunk / self.members.len() * self.chunk + index % self.chunk;
+        Ok((member, block))
+    }
+
+    /// splits the blocks `first..first + count` into runs that are consecutive on one member,
+    /// as the member, the block on it, the offset of the run in the blocks and its length
+    fn runs(&self, first: usize, count: usize) -> FsResult<Vec<(usize, usize, usize, usize)>> {
+        let mut runs = Vec::new();
+        let mut done = 0;
+        while done < count {
+            let index = first + done;
+            let (member, block) = self.locate(index)?;
+            let len = (self.chunk - index % self.chunk).min(count - done);
+            runs.push((member, block, done, len));
+            done += len;
+        }
+        Ok(runs)
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for Stripe<D> {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn blocks(&self) -> usize {
+        self.blocks
+    }
+}
+
+impl<D: ReadBlockDevice> ReadBlockDevice for Stripe<D> {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        let (member, block) = self.locate(index)?;
+        self.members[member].read_block(block, buffer)
+    }
+
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        let count = block_count(self.block_size, buffer.len())?;
+        for (member, block, offset, len) in self.runs(first, count)? {
+            let bytes = &mut buffer[offset * self.block_size..(offset + len) * self.block_size];
+            self.members[member].read_blocks(block, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: WriteBlockDevice> WriteBlockDevice for Stripe<D> {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        let (member, block) = self.locate(index)?;
+        self.members[member].write_block(block, buffer)
+    }
+
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        let count = block_count(self.block_size, buffer.len())?;
+        for (member, block, offset, len) in self.runs(first, count)? {
+            let bytes = &buffer[offset * self.block_size..(offset + len) * self.block_size];
+            self.members[member].write_blocks(block, bytes)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        for member in self.members.iter_mut() {
+            member.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use crate::memory_devices::*;
+
+    fn block(i: usize) -> Vec<u8> {
+        (0..MEMORY_BLOCK_SIZE).map(|j| (i * 31 + j) as u8).collect()
+    }
+
+    #[test]
+    fn locate() {
+        let members = vec![OwnedDisk { data: vec![0u8; 512 * 10] }, OwnedDisk { data: vec![0u8; 512 * 9] }];
+        let stripe = Stripe::new(members, 4).unwrap();
+        // two whole chunks fit on the smaller member
+        assert_eq!(stripe.blocks(), 16);
+        assert_eq!(stripe.locate(0).unwrap(), (0, 0));
+        assert_eq!(stripe.locate(3).unwrap(), (0, 3));
+        assert_eq!(stripe.locate(4).unwrap(), (1, 0));
+        assert_eq!(stripe.locate(9).unwrap(), (0, 5));
+        assert_eq!(stripe.locate(15).unwrap(), (1, 7));
+        assert!(stripe.locate(16).is_err());
+
+        assert!(Stripe::<OwnedDisk>::new(Vec::new(), 4).is_err());
+        assert!(Stripe::new(vec![OwnedDisk { data: Vec::new() }], 0).is_err());
+    }
+
+    #[test]
+    fn read_write() {
+        let mut a = vec![0u8; 512 * 100];
+        let mut b = vec![0u8; 512 * 90];
+        let mut c = vec![0u8; 512 * 95];
+        {
+            let members = vec![RamDisk { data: &mut a }, RamDisk { data: &mut b }, RamDisk { data: &mut c }];
+            let mut stripe = Stripe::new(members, 4).unwrap();
+            assert_eq!(stripe.blocks(), 88 * 3);
+            for i in 0..stripe.blocks() {
+                stripe.write_block(i, &block(i)).unwrap();
+            }
+            let mut buffer = vec![0u8; 512];
+            for i in 0..stripe.blocks() {
+                stripe.read_block(i, &mut buffer).unwrap();
+                assert_eq!(buffer, block(i));
+            }
+        }
+        // block 5 is in the second chunk, which is the first chunk of the second member
+        assert_eq!(&b[512..1024], &block(5)[..]);
+        // block 13 is in the fourth chunk, which is the second chunk of the first member
+        assert_eq!(&a[512 * 5..512 * 6], &block(13)[..]);
+    }
+
+    #[test]
+    fn multiple_blocks() {
+        let members = vec![OwnedDisk { data: vec![0u8; 512 * 20] }, OwnedDisk { data: vec![0u8; 512 * 20] }];
+        let mut stripe = Stripe::new(members, 4).unwrap();
+        // spans parts of three chunks on both members
+        let data: Vec<u8> = (3..14).flat_map(block).collect();
+        stripe.write_blocks(3, &data).unwrap();
+        let mut buffer = vec![0u8; 512];
+        for i in 3..14 {
+            stripe.read_block(i, &mut buffer).unwrap();
+            assert_eq!(buffer, block(i));
+        }
+        let mut read = vec![0u8; data.len()];
+        stripe.read_blocks(3, &mut read).unwrap();
+        assert_eq!(read, data);
+
+        assert!(stripe.read_blocks(35, &mut read).is_err());
+        assert!(stripe.write_blocks(0, &data[..100]).is_err());
+    }
+}
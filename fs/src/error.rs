@@ -1,5 +1,7 @@
 extern crate alloc;
-use alloc::string::String;
+use alloc::string::{String, ToString};
+use dep::fs::PathError;
+use dep::fs::error::Errno;
 
 /// Error type for file system errors
 #[derive(Debug)]
@@ -27,6 +29,73 @@ pub enum FsError {
 
     /// Something that shouldn't be done
     IllegalOperation(String),
+
+    /// A lock is held by another open file
+    WouldBlock,
+
+    /// A file or directory with the name exists already
+    AlreadyExists,
+
+    /// The path refers to a file where a directory is needed
+    NotADirectory,
+
+    /// The path refers to a directory where a file is needed
+    IsADirectory,
+
+    /// The file descriptor isn't open
+    BadFileDescriptor,
+
+    /// The file system or device is in use
+    Busy,
+
+    /// The paths are in different file systems
+    CrossDevice,
+
+    /// A name or the path is too long
+    NameTooLong,
+
+    /// An argument isn't valid, e.g. a name with a NUL byte or a buffer that is too small
+    InvalidArgument(String),
+}
+
+impl FsError {
+    /// error code that a syscall returns for the error
+    pub fn errno(&self) -> Errno {
+        match self {
+            FsError::BlockDeviceError => Errno::Io,
+            FsError::NotFound => Errno::NotFound,
+            FsError::AccessViolation => Errno::AccessViolation,
+            FsError::InvalidSuperBlock => Errno::Corrupted,
+            FsError::InvalidAddress => Errno::Corrupted,
+            FsError::NotEnoughSpace => Errno::NoSpace,
+            FsError::InternalError(_) => Errno::Other,
+            FsError::IllegalOperation(_) => Errno::IllegalOperation,
+            FsError::WouldBlock => Errno::WouldBlock,
+            FsError::AlreadyExists => Errno::AlreadyExists,
+            FsError::NotADirectory => Errno::NotADirectory,
+            FsError::IsADirectory => Errno::IsADirectory,
+            FsError::BadFileDescriptor => Errno::BadFileDescriptor,
+            FsError::Busy => Errno::Busy,
+            FsError::CrossDevice => Errno::CrossDevice,
+            FsError::NameTooLong => Errno::NameTooLong,
+            FsError::InvalidArgument(_) => Errno::InvalidArgument,
+        }
+    }
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        err.errno()
+    }
+}
+
+impl From<PathError> for FsError {
+    fn from(err: PathError) -> Self {
+        match err {
+            PathError::NameTooLong | PathError::PathTooLong => FsError::NameTooLong,
+            PathError::NulByte | PathError::InvalidName => FsError::InvalidArgument(err.to_string()),
+        }
+    }
 }
 
 /// Result type for file system operations
@@ -0,0 +1,559 @@
+//! Read-only ext2.
+//!
+//! Reads images made by Linux tools such as `mke2fs -d`. Files are located through the direct
+//! and indirect block pointers of their inodes, holes in sparse files read as zeros.
+//! Symbolic links are followed when resolving paths, absolute link targets are resolved from the
+//! root of the mounted file system.
+
+extern crate alloc;
+use alloc::vec::*;
+use alloc::string::*;
+use alloc::boxed::*;
+use alloc::*;
+
+use crate::error::*;
+use crate::block::*;
+use crate::filesystem::*;
+
+mod structs;
+use structs::*;
+
+const NAME: &str = "ext2";
+
+/// Inode of the root directory
+const ROOT_INODE: u32 = 2;
+
+/// Maximum number of symbolic links followed while resolving a path
+const MAX_SYMLINKS: usize = 8;
+
+pub struct Ext2<B: ?Sized + BlockDevice> {
+    dev: Box<B>,
+    superblock: Superblock,
+    groups: Vec<GroupDesc>,
+}
+
+/// Position in a file that is read
+pub struct ReadProgress {
+    inode: Inode,
+    /// offset from the beginning of the file
+    offset: usize,
+}
+
+/// ext2 is mounted read-only, files can't be opened for writing
+pub struct WriteProgress;
+
+impl<B: ?Sized + RWBlockDevice> MountedFileSystem<B> for Ext2<B> {
+    fn name() -> &'static str {
+        NAME
+    }
+
+    fn inner(self) -> Box<B> {
+        self.dev
+    }
+
+    fn mount(dev: Box<B>) -> Result<Self, Box<B>> {
+        let dev_block_size = dev.block_size();
+        if dev_block_size == 0 || SUPERBLOCK_OFFSET % dev_block_size != 0 {
+            return Err(dev);
+        }
+
+        let mut bytes = vec![0u8; SUPERBLOCK_SIZE];
+        if read_bytes(&*dev, SUPERBLOCK_OFFSET, &mut bytes).is_err() {
+            return Err(dev);
+        }
+        let superblock = match Superblock::decode(&bytes) {
+            Ok(superblock) => superblock,
+            Err(_) => return Err(dev),
+        };
+        if superblock.blocks_count as usize * superblock.block_size > dev.blocks() * dev_block_size {
+            return Err(dev);
+        }
+
+        let groups = superblock.groups();
+        let mut table = vec![0u8; GroupDesc::table_size(groups)];
+        if read_bytes(&*dev, superblock.group_desc_block() * superblock.block_size, &mut table).is_err() {
+            return Err(dev);
+        }
+        let groups = GroupDesc::decode_table(&table, groups);
+
+        // every inode table has to be on the device
+        let table_blocks = superblock.inodes_per_group as usize * superblock.inode_size / superblock.block_size;
+        if groups.iter().any(|group| group.inode_table as usize + table_blocks > superblock.blocks_count as usize) {
+            return Err(dev);
+        }
+
+        Ok(Self {
+            dev,
+            superblock,
+            groups,
+        })
+    }
+
+    fn format(dev: Box<B>) -> Result<Self, Box<B>> {
+        // images are made by other tools
+        Err(dev)
+    }
+}
+
+impl<B> Ext2<B>
+where B: ?Sized + ReadBlockDevice {
+    /// reads `buffer.len()` bytes from `block`, starting at `offset` inside the block
+    fn read_block_bytes(&self, block: u32, offset: usize, buffer: &mut [u8]) -> FsResult<()> {
+        if block >= self.superblock.blocks_count {
+            return Err(FsError::InvalidAddress);
+        }
+        read_bytes(&*self.dev, block as usize * self.superblock.block_size + offset, buffer)
+    }
+
+    /// reads the inode with number `number`
+    fn inode(&self, number: u32) -> FsResult<Inode> {
+        if number == 0 || number > self.superblock.inodes_count {
+            return Err(FsError::InvalidAddress);
+        }
+        let index = (number - 1) as usize;
+        let per_group = self.superblock.inodes_per_group as usize;
+        let group = self.groups.get(index / per_group).ok_or(FsError::InvalidAddress)?;
+
+        let inode_size = self.superblock.inode_size;
+        let offset = (index % per_group) * inode_size;
+        let block_size = self.superblock.block_size;
+        let mut bytes = vec![0u8; inode_size];
+        self.read_block_bytes(group.inode_table + (offset / block_size) as u32, offset % block_size, &mut bytes)?;
+        Ok(Inode::decode(&bytes, &self.superblock))
+    }
+
+    /// device block that holds the `index`-th block of the inode, `0` for holes
+    fn map_block(&self, inode: &Inode, index: usize) -> FsResult<u32> {
+        let per_block = self.superblock.block_size / 4;
+        if index < DIRECT_BLOCKS {
+            return Ok(inode.block[index]);
+        }
+
+        // walk down the single, double or triple indirect block
+        let mut index = index - DIRECT_BLOCKS;
+        let mut span = per_block;
+        for level in 0..3 {
+            if index < span {
+                let mut block = inode.block[DIRECT_BLOCKS + level];
+                for _ in 0..=level {
+                    if block == 0 {
+                        return Ok(0);
+                    }
+                    span /= per_block;
+                    let mut pointer = [0u8; 4];
+                    self.read_block_bytes(block, (index / span) * 4, &mut pointer)?;
+                    block = u32::from_le_bytes(pointer);
+                    index %= span;
+                }
+                return Ok(block);
+            }
+            index -= span;
+            span *= per_block;
+        }
+        Err(FsError::InvalidAddress)
+    }
+
+    /// reads `buffer.len()` bytes of the inode's content starting at `offset`
+    fn read_inode(&self, inode: &Inode, offset: usize, buffer: &mut [u8]) -> FsResult<usize> {
+        let block_size = self.superblock.block_size;
+        let end = inode.size.min(offset.saturating_add(buffer.len()));
+        let mut position = offset;
+
+        while position < end {
+            let within = position % block_size;
+            let len = (block_size - within).min(end - position);
+            let target = &mut buffer[position - offset..position - offset + len];
+            match self.map_block(inode, position / block_size)? {
+                0 => target.iter_mut().for_each(|byte| *byte = 0),
+                block => self.read_block_bytes(block, within, target)?,
+            }
+            position += len;
+        }
+
+        Ok(end.saturating_sub(offset))
+    }
+
+    /// reads the complete content of a directory or symbolic link
+    fn read_all(&self, inode: &Inode) -> FsResult<Vec<u8>> {
+        if inode.size > self.superblock.blocks_count as usize * self.superblock.block_size {
+            return Err(FsError::InvalidAddress);
+        }
+        let mut data = vec![0u8; inode.size];
+        self.read_inode(inode, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// all entries of a directory as inode number and name, including `.` and `..`
+    fn raw_entries(&self, inode: &Inode) -> FsResult<Vec<(u32, Filename)>> {
+        if inode.inode_type() != InodeType::Dir {
+            return Err(FsError::NotADirectory);
+        }
+
+        let data = self.read_all(inode)?;
+        let block_size = self.superblock.block_size;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + DIR_ENTRY_HEADER <= data.len() {
+            let number = read_u32(&data, offset);
+            let rec_len = read_u16(&data, offset + 4) as usize;
+            let name_len = if self.superblock.has_filetype() {
+                data[offset + 6] as usize
+            } else {
+                read_u16(&data, offset + 6) as usize
+            };
+
+            // entries never span blocks
+            let block_end = (offset / block_size + 1) * block_size;
+            if rec_len < DIR_ENTRY_HEADER || offset + rec_len > block_end.min(data.len()) || DIR_ENTRY_HEADER + name_len > rec_len {
+                return Err(FsError::InvalidAddress);
+            }
+
+            if number != 0 {
+                let name = &data[offset + DIR_ENTRY_HEADER..offset + DIR_ENTRY_HEADER + name_len];
+                entries.push((number, name.to_vec()));
+            }
+            offset += rec_len;
+        }
+        Ok(entries)
+    }
+
+    /// entries of a directory as inode number and name, without `.` and `..`
+    fn read_entries(&self, inode: &Inode) -> FsResult<Vec<(u32, Filename)>> {
+        let mut entries = self.raw_entries(inode)?;
+        entries.retain(|(_, name)| name != b"." && name != b"..");
+        Ok(entries)
+    }
+
+    /// inode number of the entry called `name` in a directory
+    fn find_entry(&self, dir: &Inode, name: &[u8]) -> FsResult<u32> {
+        self.raw_entries(dir)?
+            .into_iter()
+            .find(|(_, entry)| &entry[..] == name)
+            .map(|(number, _)| number)
+            .ok_or(FsError::NotFound)
+    }
+
+    /// target of a symbolic link
+    fn read_link(&self, inode: &Inode) -> FsResult<Vec<u8>> {
+        if let Some(target) = inode.fast_symlink(self.superblock.block_size) {
+            return Ok(target);
+        }
+        self.read_all(inode)
+    }
+
+    /// resolves a path to an inode, following symbolic links
+    fn resolve(&self, path: &Path) -> FsResult<Inode> {
+        // components that are still to be resolved, the next one last
+        let mut pending = Vec::new();
+        let mut rest = path.clone();
+        while let (Some(name), tail) = rest.head_tail() {
+            pending.push(name);
+            rest = tail;
+        }
+        pending.reverse();
+
+        let mut dir = self.inode(ROOT_INODE)?;
+        let mut current = dir.clone();
+        let mut links = 0;
+
+        while let Some(name) = pending.pop() {
+            if current.inode_type() != InodeType::Dir {
+                return Err(FsError::NotFound);
+            }
+            dir = current;
+            let inode = self.inode(self.find_entry(&dir, &name)?)?;
+
+            if inode.inode_type() == InodeType::Symlink {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(FsError::IllegalOperation(String::from("too many levels of symbolic links")));
+                }
+
+                let target = self.read_link(&inode)?;
+                pending.extend(target.split(|&c| c == SEPARATOR).filter(|name| !name.is_empty()).rev().map(|name| name.to_vec()));
+                current = if target.first() == Some(&SEPARATOR) { self.inode(ROOT_INODE)? } else { dir.clone() };
+            } else {
+                current = inode;
+            }
+        }
+
+        Ok(current)
+    }
+
+    /// `true` if the path resolves to an inode of type `inode_type`
+    fn exists(&self, path: &Path, inode_type: InodeType) -> FsResult<bool> {
+        match self.resolve(path) {
+            Ok(inode) => Ok(inode.inode_type() == inode_type),
+            Err(FsError::NotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<B> BaseFileSystem for Ext2<B>
+where B: ?Sized + ReadBlockDevice
+{
+    fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        let inode = self.resolve(&path)?;
+        Ok(self.read_entries(&inode)?.into_iter().map(|(_, name)| name).collect())
+    }
+
+    fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        self.exists(&path, InodeType::Dir)
+    }
+
+    fn exists_file(&self, path: Path) -> FsResult<bool> {
+        self.exists(&path, InodeType::File)
+    }
+
+    fn statfs(&self) -> FsResult<stats::FsStats> {
+        Ok(stats::FsStats::new(
+            NAME,
+            self.superblock.blocks_count as u64,
+            self.superblock.free_blocks_count as u64,
+            self.superblock.block_size as u64,
+        ))
+    }
+}
+
+impl<B> ReadFileSystem for Ext2<B>
+where B: ?Sized + ReadBlockDevice
+{
+    type ReadProgress = ReadProgress;
+
+    fn open_read(&self, path: Path) -> FsResult<ReadProgress> {
+        let inode = self.resolve(&path)?;
+        if inode.inode_type() != InodeType::File {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(ReadProgress { inode, offset: 0 })
+    }
+
+    fn read(&self, progress: &mut ReadProgress, buffer: &mut [u8]) -> FsResult<usize> {
+        let read = self.read_inode(&progress.inode, progress.offset, buffer)?;
+        progress.offset += read;
+        Ok(read)
+    }
+
+    fn seek(&self, progress: &mut ReadProgress, seek: usize) -> FsResult<()> {
+        progress.offset += seek;
+        Ok(())
+    }
+
+    fn file_size(&self, path: Path) -> FsResult<usize> {
+        let inode = self.resolve(&path)?;
+        if inode.inode_type() != InodeType::File {
+            return Err(FsError::IsADirectory);
+        }
+        Ok(inode.size)
+    }
+}
+
+impl<B> WriteFileSystem for Ext2<B>
+where B: ?Sized + RWBlockDevice
+{
+    type WriteProgress = WriteProgress;
+}
+
+impl<B> ManageFileSystem for Ext2<B>
+where B: ?Sized + RWBlockDevice {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_devices::*;
+
+    const BLOCK_SIZE: usize = 1024;
+    const BLOCKS: usize = 64;
+    const INODE_TABLE: usize = 4;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    /// content of data block `n` of the test image
+    fn block(n: u32) -> Vec<u8> {
+        (0..BLOCK_SIZE).map(|i| (i as u32 * 7 + n) as u8).collect()
+    }
+
+    /// Image with 64 blocks of 1 KiB and 16 inodes in a single group:
+    /// `/file` with 10 bytes, `/big` with direct, single and double indirect blocks and holes,
+    /// and `/sub` with a symbolic link `link` to `../file`
+    struct Image(Vec<u8>);
+
+    impl Image {
+        fn u16(&mut self, offset: usize, value: u16) {
+            self.0[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn u32(&mut self, offset: usize, value: u32) {
+            self.0[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn inode(&mut self, number: usize, mode: u16, size: usize, sectors: u32, blocks: &[u32]) {
+            let offset = INODE_TABLE * BLOCK_SIZE + (number - 1) * 128;
+            self.u16(offset, mode);
+            self.u32(offset + 4, size as u32);
+            self.u32(offset + 28, sectors);
+            for (i, &block) in blocks.iter().enumerate() {
+                self.u32(offset + 40 + 4 * i, block);
+            }
+        }
+
+        fn dir(&mut self, block: usize, entries: &[(u32, &str, u8)]) {
+            let mut offset = block * BLOCK_SIZE;
+            for (i, &(number, name, file_type)) in entries.iter().enumerate() {
+                // the last entry takes up the rest of the block
+                let rec_len = if i + 1 == entries.len() {
+                    (block + 1) * BLOCK_SIZE - offset
+                } else {
+                    (DIR_ENTRY_HEADER + name.len() + 3) / 4 * 4
+                };
+                self.u32(offset, number);
+                self.u16(offset + 4, rec_len as u16);
+                self.0[offset + 6] = name.len() as u8;
+                self.0[offset + 7] = file_type;
+                self.0[offset + 8..offset + 8 + name.len()].copy_from_slice(name.as_bytes());
+                offset += rec_len;
+            }
+        }
+
+        fn data(&mut self, number: u32) {
+            let offset = number as usize * BLOCK_SIZE;
+            self.0[offset..offset + BLOCK_SIZE].copy_from_slice(&block(number));
+        }
+
+        fn new() -> Self {
+            let mut image = Image(vec![0u8; BLOCKS * BLOCK_SIZE]);
+            let sb = SUPERBLOCK_OFFSET;
+            image.u32(sb, 16);
+            image.u32(sb + 4, BLOCKS as u32);
+            image.u32(sb + 12, 20);
+            image.u32(sb + 20, 1);
+            image.u32(sb + 32, 8192);
+            image.u32(sb + 40, 16);
+            image.u16(sb + 56, 0xef53);
+            image.u32(sb + 76, 1);
+            image.u16(sb + 88, 128);
+            image.u32(sb + 96, INCOMPAT_FILETYPE);
+            // group descriptor table in the block after the superblock
+            image.u32(2 * BLOCK_SIZE + 8, INODE_TABLE as u32);
+
+            image.inode(2, MODE_DIR | 0o755, BLOCK_SIZE, 2, &[10]);
+            image.dir(10, &[(2, ".", 2), (2, "..", 2), (12, "file", 1), (13, "big", 1), (14, "sub", 2)]);
+
+            image.inode(12, MODE_FILE | 0o644, 10, 2, &[11]);
+            image.0[11 * BLOCK_SIZE..11 * BLOCK_SIZE + 10].copy_from_slice(b"0123456789");
+
+            // blocks 0 to 11 are direct, block 12 is in the single indirect block 32,
+            // blocks 13 to 267 are a hole and block 268 is the first of the double indirect block 34
+            let mut pointers: Vec<u32> = (20..32).collect();
+            pointers.extend_from_slice(&[32, 34]);
+            image.inode(13, MODE_FILE | 0o644, 268 * BLOCK_SIZE + 100, 2 * 17, &pointers);
+            for number in (20..32).chain(Some(33)).chain(Some(36)) {
+                image.data(number);
+            }
+            image.u32(32 * BLOCK_SIZE, 33);
+            image.u32(34 * BLOCK_SIZE, 35);
+            image.u32(35 * BLOCK_SIZE, 36);
+
+            image.inode(14, MODE_DIR | 0o755, BLOCK_SIZE, 2, &[37]);
+            image.dir(37, &[(14, ".", 2), (2, "..", 2), (15, "link", 7)]);
+
+            // a fast symlink keeps its target in the block pointers
+            let offset = INODE_TABLE * BLOCK_SIZE + 14 * 128;
+            image.inode(15, MODE_SYMLINK | 0o777, 7, 0, &[]);
+            image.0[offset + 40..offset + 47].copy_from_slice(b"../file");
+            image
+        }
+
+        fn mount(self) -> Result<Ext2<OwnedDisk>, Box<OwnedDisk>> {
+            Ext2::mount(Box::new(OwnedDisk { data: self.0 }))
+        }
+    }
+
+    fn read_all(fs: &Ext2<OwnedDisk>, name: &str) -> Vec<u8> {
+        let mut progress = fs.open_read(path(name)).unwrap();
+        let mut content = vec![0u8; fs.file_size(path(name)).unwrap() + 1];
+        let mut len = 0;
+        loop {
+            match fs.read(&mut progress, &mut content[len..]).unwrap() {
+                0 => break,
+                read => len += read,
+            }
+        }
+        content.truncate(len);
+        content
+    }
+
+    #[test]
+    fn superblock() {
+        let fs = Image::new().mount().ok().unwrap();
+        assert_eq!(fs.superblock.block_size, BLOCK_SIZE);
+        assert_eq!(fs.superblock.groups(), 1);
+        assert_eq!(fs.groups[0].inode_table, INODE_TABLE as u32);
+        let stats = fs.statfs().unwrap();
+        assert_eq!((stats.total_blocks, stats.free_blocks, stats.block_size), (64, 20, 1024));
+
+        let mut image = Image::new();
+        image.u16(SUPERBLOCK_OFFSET + 56, 0);
+        assert!(image.mount().is_err());
+
+        // features this driver doesn't know change the layout
+        let mut image = Image::new();
+        image.u32(SUPERBLOCK_OFFSET + 96, INCOMPAT_FILETYPE | 0x40);
+        assert!(image.mount().is_err());
+
+        // the file system is larger than the device
+        let mut image = Image::new();
+        image.u32(SUPERBLOCK_OFFSET + 4, 65);
+        assert!(image.mount().is_err());
+    }
+
+    #[test]
+    fn direct_and_indirect_blocks() {
+        let fs = Image::new().mount().ok().unwrap();
+        assert_eq!(read_all(&fs, "/file"), b"0123456789");
+
+        let big = read_all(&fs, "/big");
+        assert_eq!(big.len(), 268 * BLOCK_SIZE + 100);
+        for i in 0..12 {
+            assert_eq!(&big[i * BLOCK_SIZE..(i + 1) * BLOCK_SIZE], &block(20 + i as u32)[..]);
+        }
+        assert_eq!(&big[12 * BLOCK_SIZE..13 * BLOCK_SIZE], &block(33)[..]);
+        assert!(big[13 * BLOCK_SIZE..268 * BLOCK_SIZE].iter().all(|&byte| byte == 0));
+        assert_eq!(&big[268 * BLOCK_SIZE..], &block(36)[..100]);
+
+        // a read that starts in the middle of a block and crosses into the indirect block
+        let mut progress = fs.open_read(path("/big")).unwrap();
+        fs.seek(&mut progress, 12 * BLOCK_SIZE - 10).unwrap();
+        let mut buffer = [0u8; 20];
+        assert_eq!(fs.read(&mut progress, &mut buffer).unwrap(), 20);
+        assert_eq!(&buffer[..10], &block(31)[BLOCK_SIZE - 10..]);
+        assert_eq!(&buffer[10..], &block(33)[..10]);
+    }
+
+    #[test]
+    fn directories() {
+        let fs = Image::new().mount().ok().unwrap();
+        assert_eq!(fs.read_dir(path("/")).unwrap(), vec![b"file".to_vec(), b"big".to_vec(), b"sub".to_vec()]);
+        assert_eq!(fs.read_dir(path("/sub")).unwrap(), vec![b"link".to_vec()]);
+        assert!(fs.exists_dir(path("/sub")).unwrap());
+        assert!(fs.exists_file(path("/file")).unwrap());
+        assert!(!fs.exists_file(path("/missing")).unwrap());
+        assert!(!fs.exists_dir(path("/file")).unwrap());
+
+        // the link is followed
+        assert!(fs.exists_file(path("/sub/link")).unwrap());
+        assert_eq!(read_all(&fs, "/sub/link"), b"0123456789");
+
+        match fs.open_read(path("/sub")) {
+            Err(FsError::IsADirectory) => (),
+            _ => panic!("opened a directory"),
+        }
+        match fs.read_dir(path("/file")) {
+            Err(FsError::NotADirectory) => (),
+            _ => panic!("listed a file"),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! On-disk structures of ext2, all fields are little-endian.
+
+use super::*;
+
+/// Byte offset of the superblock on the device
+pub(super) const SUPERBLOCK_OFFSET: usize = 1024;
+/// Size of the superblock
+pub(super) const SUPERBLOCK_SIZE: usize = 1024;
+
+const MAGIC: u16 = 0xef53;
+
+/// Directory entries carry the type of the file
+pub(super) const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// Incompatible features this driver can read
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+/// Size of a group descriptor
+const GROUP_DESC_SIZE: usize = 32;
+
+/// Number of block pointers in an inode
+pub(super) const INODE_BLOCKS: usize = 15;
+/// Number of direct block pointers in an inode
+pub(super) const DIRECT_BLOCKS: usize = 12;
+
+const MODE_TYPE: u16 = 0xf000;
+pub(super) const MODE_DIR: u16 = 0x4000;
+pub(super) const MODE_FILE: u16 = 0x8000;
+pub(super) const MODE_SYMLINK: u16 = 0xa000;
+
+/// Size of the fixed part of a directory entry
+pub(super) const DIR_ENTRY_HEADER: usize = 8;
+
+/// Fields of the superblock that are needed to read the file system
+#[derive(Copy, Clone, Debug)]
+pub(super) struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub first_data_block: u32,
+    pub block_size: usize,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub rev_level: u32,
+    pub inode_size: usize,
+    pub feature_incompat: u32,
+}
+
+impl Superblock {
+    /// decodes and sanity checks the superblock
+    pub fn decode(bytes: &[u8]) -> FsResult<Self> {
+        if bytes.len() < SUPERBLOCK_SIZE || read_u16(bytes, 56) != MAGIC {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        let log_block_size = read_u32(bytes, 24);
+        if log_block_size > 6 {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        let rev_level = read_u32(bytes, 76);
+        let (inode_size, feature_incompat) = if rev_level == 0 {
+            (128, 0)
+        } else {
+            (read_u16(bytes, 88) as usize, read_u32(bytes, 96))
+        };
+
+        let superblock = Self {
+            inodes_count: read_u32(bytes, 0),
+            blocks_count: read_u32(bytes, 4),
+            free_blocks_count: read_u32(bytes, 12),
+            first_data_block: read_u32(bytes, 20),
+            block_size: 1024 << log_block_size,
+            blocks_per_group: read_u32(bytes, 32),
+            inodes_per_group: read_u32(bytes, 40),
+            rev_level,
+            inode_size,
+            feature_incompat,
+        };
+
+        let valid = superblock.blocks_per_group != 0
+            && superblock.inodes_per_group != 0
+            && superblock.first_data_block < superblock.blocks_count
+            && superblock.inode_size >= 128
+            && superblock.inode_size.is_power_of_two()
+            && superblock.inode_size <= superblock.block_size
+            && superblock.groups() as u64 * superblock.inodes_per_group as u64 >= superblock.inodes_count as u64;
+
+        if !valid || superblock.feature_incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsError::InvalidSuperBlock);
+        }
+        Ok(superblock)
+    }
+
+    /// number of block groups
+    pub fn groups(&self) -> usize {
+        let blocks = (self.blocks_count - self.first_data_block) as usize;
+        let per_group = self.blocks_per_group as usize;
+        (blocks + per_group - 1) / per_group
+    }
+
+    /// block in which the group descriptor table begins
+    pub fn group_desc_block(&self) -> usize {
+        self.first_data_block as usize + 1
+    }
+
+    /// `true` if directory entries store the file type instead of the high byte of the name length
+    pub fn has_filetype(&self) -> bool {
+        self.feature_incompat & INCOMPAT_FILETYPE != 0
+    }
+}
+
+/// Fields of a group descriptor that are needed to read the file system
+#[derive(Copy, Clone, Debug)]
+pub(super) struct GroupDesc {
+    pub inode_table: u32,
+}
+
+impl GroupDesc {
+    /// decodes all group descriptors from the group descriptor table
+    pub fn decode_table(bytes: &[u8], groups: usize) -> Vec<Self> {
+        (0..groups)
+            .map(|i| Self { inode_table: read_u32(bytes, i * GROUP_DESC_SIZE + 8) })
+            .collect()
+    }
+
+    /// size of the table for `groups` groups in bytes
+    pub fn table_size(groups: usize) -> usize {
+        groups * GROUP_DESC_SIZE
+    }
+}
+
+/// Type of an inode
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum InodeType {
+    File,
+    Dir,
+    Symlink,
+    /// devices, pipes and sockets
+    Other,
+}
+
+/// Fields of an inode that are needed to read its content
+#[derive(Clone, Debug)]
+pub struct Inode {
+    pub(super) mode: u16,
+    pub(super) size: usize,
+    /// number of 512 byte sectors allocated to the inode
+    pub(super) sectors: u32,
+    /// block with extended attributes, `0` if there is none
+    pub(super) file_acl: u32,
+    pub(super) block: [u32; INODE_BLOCKS],
+}
+
+impl Inode {
+    pub(super) fn decode(bytes: &[u8], superblock: &Superblock) -> Self {
+        let mode = read_u16(bytes, 0);
+        let mut size = read_u32(bytes, 4) as usize;
+        // with revision 1 regular files store the upper 32 bits of their size
+        if superblock.rev_level > 0 && mode & MODE_TYPE == MODE_FILE {
+            size |= (read_u32(bytes, 108) as usize) << 32;
+        }
+
+        let mut block = [0u32; INODE_BLOCKS];
+        for (i, pointer) in block.iter_mut().enumerate() {
+            *pointer = read_u32(bytes, 40 + 4 * i);
+        }
+
+        Self {
+            mode,
+            size,
+            sectors: read_u32(bytes, 28),
+            file_acl: read_u32(bytes, 104),
+            block,
+        }
+    }
+
+    pub(super) fn inode_type(&self) -> InodeType {
+        match self.mode & MODE_TYPE {
+            MODE_FILE => InodeType::File,
+            MODE_DIR => InodeType::Dir,
+            MODE_SYMLINK => InodeType::Symlink,
+            _ => InodeType::Other,
+        }
+    }
+
+    /// content of a fast symlink, whose target is stored in place of the block pointers
+    pub(super) fn fast_symlink(&self, block_size: usize) -> Option<Vec<u8>> {
+        let acl_sectors = if self.file_acl != 0 { (block_size / 512) as u32 } else { 0 };
+        if self.inode_type() != InodeType::Symlink || self.sectors != acl_sectors {
+            return None;
+        }
+
+        let mut target = Vec::with_capacity(INODE_BLOCKS * 4);
+        for pointer in self.block.iter() {
+            target.extend_from_slice(&pointer.to_le_bytes());
+        }
+        target.truncate(self.size.min(INODE_BLOCKS * 4));
+        Some(target)
+    }
+}
+
+pub(super) fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(super) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
@@ -0,0 +1,351 @@
+//! Boot sector (BIOS parameter block) of FAT12, FAT16 and FAT32 volumes.
+
+use super::*;
+
+/// Width of the entries in the file allocation table
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// name as it is written into the boot sector and reported by `statfs`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Fat12 => "FAT12",
+            Self::Fat16 => "FAT16",
+            Self::Fat32 => "FAT32",
+        }
+    }
+
+    /// type that a volume with `clusters` clusters must have
+    fn from_clusters(clusters: usize) -> Self {
+        if clusters < 4085 {
+            Self::Fat12
+        } else if clusters < 65525 {
+            Self::Fat16
+        } else {
+            Self::Fat32
+        }
+    }
+
+    /// size of the table needed for `clusters` data clusters in bytes
+    fn table_size(&self, clusters: usize) -> usize {
+        let entries = clusters + 2;
+        match self {
+            Self::Fat12 => (entries * 3 + 1) / 2,
+            Self::Fat16 => entries * 2,
+            Self::Fat32 => entries * 4,
+        }
+    }
+
+    /// table entry that marks the end of a cluster chain
+    pub(super) fn end_of_chain(&self) -> u32 {
+        match self {
+            Self::Fat12 => 0xfff,
+            Self::Fat16 => 0xffff,
+            Self::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// `true` if the table entry ends a cluster chain
+    pub(super) fn is_end_of_chain(&self, entry: u32) -> bool {
+        match self {
+            Self::Fat12 => entry >= 0xff8,
+            Self::Fat16 => entry >= 0xfff8,
+            Self::Fat32 => entry >= 0x0fff_fff8,
+        }
+    }
+}
+
+/// Offset of the boot sector signature `0x55 0xaa`
+const SIGNATURE_OFFSET: usize = 510;
+
+/// Media descriptor for fixed disks
+const MEDIA: u8 = 0xf8;
+
+/// Signature at the beginning of the FAT32 FS information sector
+const FS_INFO_LEAD: u32 = 0x4161_5252;
+/// Signature in the middle of the FAT32 FS information sector
+const FS_INFO_STRUCT: u32 = 0x6141_7272;
+/// Signature at the end of the FAT32 FS information sector
+const FS_INFO_TRAIL: u32 = 0xaa55_0000;
+
+/// Contents of the boot sector that describe the layout of the volume
+#[derive(Copy, Clone, Debug)]
+pub(super) struct BootSector {
+    pub bytes_per_sector: usize,
+    pub sectors_per_cluster: usize,
+    pub reserved_sectors: usize,
+    /// number of copies of the allocation table
+    pub fats: usize,
+    /// entries of the fixed root directory, `0` on FAT32
+    pub root_entries: usize,
+    pub total_sectors: usize,
+    /// sectors of one allocation table
+    pub fat_sectors: usize,
+    /// first cluster of the root directory, FAT32 only
+    pub root_cluster: u32,
+    /// sector of the FS information sector, FAT32 only, `0` if there is none
+    pub fs_info: usize,
+    pub fat_type: FatType,
+}
+
+impl BootSector {
+    /// decodes and sanity checks a boot sector
+    pub fn decode(bytes: &[u8]) -> FsResult<Self> {
+        if bytes.len() < SECTOR_SIZE
+            || bytes[SIGNATURE_OFFSET] != 0x55
+            || bytes[SIGNATURE_OFFSET + 1] != 0xaa
+            || (bytes[0] != 0xeb && bytes[0] != 0xe9) {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        let bytes_per_sector = read_u16(bytes, 11) as usize;
+        let sectors_per_cluster = bytes[13] as usize;
+        let reserved_sectors = read_u16(bytes, 14) as usize;
+        let fats = bytes[16] as usize;
+        let root_entries = read_u16(bytes, 17) as usize;
+        let total_sectors = match read_u16(bytes, 19) {
+            0 => read_u32(bytes, 32) as usize,
+            total => total as usize,
+        };
+        let fat16_sectors = read_u16(bytes, 22) as usize;
+        let fat_sectors = match fat16_sectors {
+            0 => read_u32(bytes, 36) as usize,
+            sectors => sectors,
+        };
+
+        if !bytes_per_sector.is_power_of_two()
+            || bytes_per_sector < SECTOR_SIZE
+            || bytes_per_sector > 4096
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || fats == 0
+            || fat_sectors == 0 {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        let mut boot_sector = Self {
+            bytes_per_sector,
+            sectors_per_cluster,
+            reserved_sectors,
+            fats,
+            root_entries,
+            total_sectors,
+            fat_sectors,
+            root_cluster: 0,
+            fs_info: 0,
+            fat_type: FatType::Fat12,
+        };
+
+        let data_begin = boot_sector.data_begin();
+        if data_begin >= total_sectors {
+            return Err(FsError::InvalidSuperBlock);
+        }
+        // like Linux, a volume is FAT32 if the 16 bit table size is not set,
+        // otherwise the number of clusters decides between FAT12 and FAT16
+        boot_sector.fat_type = match (fat16_sectors, FatType::from_clusters(boot_sector.clusters())) {
+            (0, _) => FatType::Fat32,
+            (_, FatType::Fat12) => FatType::Fat12,
+            _ => FatType::Fat16,
+        };
+
+        if boot_sector.fat_type == FatType::Fat32 {
+            boot_sector.root_cluster = read_u32(bytes, 44) & 0x0fff_ffff;
+            boot_sector.fs_info = read_u16(bytes, 48) as usize;
+            if root_entries != 0
+                || boot_sector.root_cluster < 2
+                || boot_sector.root_cluster as usize >= boot_sector.clusters() + 2 {
+                return Err(FsError::InvalidSuperBlock);
+            }
+            if boot_sector.fs_info == 0 || boot_sector.fs_info >= reserved_sectors {
+                boot_sector.fs_info = 0;
+            }
+        } else if root_entries == 0 {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        // every cluster needs an entry in the table
+        if boot_sector.fat_type.table_size(boot_sector.clusters()) > fat_sectors * bytes_per_sector {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        Ok(boot_sector)
+    }
+
+    /// encodes the boot sector, `label` is padded with spaces to 11 bytes
+    pub fn encode(&self, label: &[u8; LABEL_LEN]) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.bytes_per_sector];
+        let fat32 = self.fat_type == FatType::Fat32;
+
+        bytes[0] = 0xeb;
+        bytes[1] = if fat32 { 0x58 } else { 0x3c };
+        bytes[2] = 0x90;
+        copy_offset(b"bitOS   ", &mut bytes, 8, 0, 3);
+        write_u16(&mut bytes, 11, self.bytes_per_sector as u16);
+        bytes[13] = self.sectors_per_cluster as u8;
+        write_u16(&mut bytes, 14, self.reserved_sectors as u16);
+        bytes[16] = self.fats as u8;
+        write_u16(&mut bytes, 17, self.root_entries as u16);
+        if self.total_sectors < 0x10000 && !fat32 {
+            write_u16(&mut bytes, 19, self.total_sectors as u16);
+        } else {
+            write_u32(&mut bytes, 32, self.total_sectors as u32);
+        }
+        bytes[21] = MEDIA;
+        if !fat32 {
+            write_u16(&mut bytes, 22, self.fat_sectors as u16);
+        }
+        // sectors per track and heads, only used by BIOS CHS addressing
+        write_u16(&mut bytes, 24, 63);
+        write_u16(&mut bytes, 26, 255);
+
+        // extended BIOS parameter block
+        let ebpb = if fat32 {
+            write_u32(&mut bytes, 36, self.fat_sectors as u32);
+            write_u32(&mut bytes, 44, self.root_cluster);
+            write_u16(&mut bytes, 48, self.fs_info as u16);
+            write_u16(&mut bytes, 50, BACKUP_BOOT_SECTOR as u16);
+            64
+        } else {
+            36
+        };
+        bytes[ebpb] = 0x80;
+        bytes[ebpb + 2] = 0x29;
+        write_u32(&mut bytes, ebpb + 3, 0x6269_744f ^ self.total_sectors as u32);
+        copy_offset(label, &mut bytes, LABEL_LEN, 0, ebpb + 7);
+        let fs_type: &[u8; 8] = match self.fat_type {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        };
+        copy_offset(fs_type, &mut bytes, 8, 0, ebpb + 18);
+
+        bytes[SIGNATURE_OFFSET] = 0x55;
+        bytes[SIGNATURE_OFFSET + 1] = 0xaa;
+        bytes
+    }
+
+    /// computes the layout for a volume of `total_sectors` sectors with 512 bytes,
+    /// the type is chosen by size if it isn't given
+    pub fn layout(total_sectors: usize, fat_type: Option<FatType>) -> FsResult<Self> {
+        let fat_type = fat_type.unwrap_or_else(|| {
+            if total_sectors < 8 * 1024 {
+                FatType::Fat12
+            } else if total_sectors < 1024 * 1024 {
+                FatType::Fat16
+            } else {
+                FatType::Fat32
+            }
+        });
+        let fat32 = fat_type == FatType::Fat32;
+
+        // use the smallest clusters that give a valid volume of the requested type
+        for shift in 0..8 {
+            let mut boot_sector = Self {
+                bytes_per_sector: SECTOR_SIZE,
+                sectors_per_cluster: 1 << shift,
+                reserved_sectors: if fat32 { 32 } else { 1 },
+                fats: 2,
+                root_entries: if fat32 { 0 } else { 512 },
+                total_sectors,
+                fat_sectors: 1,
+                root_cluster: if fat32 { 2 } else { 0 },
+                fs_info: if fat32 { 1 } else { 0 },
+                fat_type,
+            };
+
+            // a bigger table leaves less space for clusters, so this converges
+            loop {
+                if boot_sector.data_begin() >= total_sectors {
+                    break;
+                }
+                let needed = (fat_type.table_size(boot_sector.clusters()) + SECTOR_SIZE - 1) / SECTOR_SIZE;
+                if needed <= boot_sector.fat_sectors {
+                    break;
+                }
+                boot_sector.fat_sectors = needed;
+            }
+
+            if boot_sector.data_begin() < total_sectors
+                && boot_sector.clusters() > 0
+                && FatType::from_clusters(boot_sector.clusters()) == fat_type {
+                return Ok(boot_sector);
+            }
+        }
+
+        Err(FsError::IllegalOperation(format!("{} sectors can't be formatted as {}", total_sectors, fat_type.name())))
+    }
+
+    /// first sector of the fixed root directory
+    pub fn root_dir_begin(&self) -> usize {
+        self.reserved_sectors + self.fats * self.fat_sectors
+    }
+
+    /// first sector of the data region, which begins with cluster 2
+    pub fn data_begin(&self) -> usize {
+        let root_dir_bytes = self.root_entries * DIR_ENTRY_SIZE;
+        self.root_dir_begin() + (root_dir_bytes + self.bytes_per_sector - 1) / self.bytes_per_sector
+    }
+
+    /// number of data clusters
+    pub fn clusters(&self) -> usize {
+        (self.total_sectors - self.data_begin()) / self.sectors_per_cluster
+    }
+
+    /// size of a cluster in bytes
+    pub fn cluster_size(&self) -> usize {
+        self.bytes_per_sector * self.sectors_per_cluster
+    }
+}
+
+/// Sector of the backup copy of the FAT32 boot sector
+pub(super) const BACKUP_BOOT_SECTOR: usize = 6;
+
+/// FAT32 FS information sector, which caches the allocation state
+pub(super) struct FsInfo {
+    pub free_clusters: u32,
+    pub next_free: u32,
+}
+
+impl FsInfo {
+    /// `None` if the sector doesn't carry the signatures
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if read_u32(bytes, 0) != FS_INFO_LEAD
+            || read_u32(bytes, 484) != FS_INFO_STRUCT
+            || read_u32(bytes, 508) != FS_INFO_TRAIL {
+            return None;
+        }
+        Some(Self {
+            free_clusters: read_u32(bytes, 488),
+            next_free: read_u32(bytes, 492),
+        })
+    }
+
+    pub fn encode(&self, bytes: &mut [u8]) {
+        write_u32(bytes, 0, FS_INFO_LEAD);
+        write_u32(bytes, 484, FS_INFO_STRUCT);
+        write_u32(bytes, 488, self.free_clusters);
+        write_u32(bytes, 492, self.next_free);
+        write_u32(bytes, 508, FS_INFO_TRAIL);
+    }
+}
+
+pub(super) fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+pub(super) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+pub(super) fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+pub(super) fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,566 @@
+//! Directory entries including VFAT long file names.
+//!
+//! Every file has an 8.3 short entry. Names that don't fit into it are additionally stored in
+//! long name entries that precede the short entry in reverse order, each holding 13 UCS-2 code
+//! units and a checksum of the short name they belong to.
+
+use super::*;
+
+/// Size of a directory entry in bytes
+pub(super) const DIR_ENTRY_SIZE: usize = 32;
+
+pub(super) const ATTR_READ_ONLY: u8 = 0x01;
+pub(super) const ATTR_HIDDEN: u8 = 0x02;
+pub(super) const ATTR_SYSTEM: u8 = 0x04;
+pub(super) const ATTR_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTR_DIRECTORY: u8 = 0x10;
+pub(super) const ATTR_ARCHIVE: u8 = 0x20;
+/// Attributes of a long name entry
+const ATTR_LONG_NAME: u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+/// First byte of a deleted entry
+const DELETED: u8 = 0xe5;
+/// Marks the last long name entry, which is stored first
+const LAST_LONG_ENTRY: u8 = 0x40;
+/// Code units of a name in a long name entry
+const LONG_ENTRY_CHARS: usize = 13;
+/// Offsets of the code units in a long name entry
+const LONG_ENTRY_OFFSETS: [usize; LONG_ENTRY_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// Longest name that can be stored in long name entries
+const MAX_NAME_LEN: usize = 255;
+
+/// Flag in byte 12 of a short entry, the base name is lower case
+const LOWER_BASE: u8 = 0x08;
+/// Flag in byte 12 of a short entry, the extension is lower case
+const LOWER_EXT: u8 = 0x10;
+
+/// Date of all entries, 1980-01-01, as there is no clock to get the real one
+const DATE: u16 = (1 << 5) | 1;
+
+/// A directory of the volume
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum Dir {
+    /// fixed size root directory of FAT12 and FAT16
+    Root,
+    /// directory stored in a cluster chain
+    Cluster(u32),
+}
+
+/// Short entry together with its long name
+#[derive(Clone, Debug)]
+pub(super) struct DirEntry {
+    /// long name if there is one, short name otherwise
+    pub name: Filename,
+    /// name as stored in the short entry
+    pub short_name: [u8; 11],
+    pub attributes: u8,
+    /// first cluster, `0` for empty files
+    pub cluster: u32,
+    pub size: usize,
+    /// device offsets of the slots used by the entry, the short entry is the last one
+    pub slots: Vec<usize>,
+}
+
+impl DirEntry {
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+
+    /// device offset of the short entry
+    pub fn short_slot(&self) -> usize {
+        self.slots[self.slots.len() - 1]
+    }
+
+    /// `true` if `name` refers to this entry, names are compared case insensitively
+    fn matches(&self, name: &[u8]) -> bool {
+        self.name.eq_ignore_ascii_case(name) || display_short_name(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+impl<B> FAT<B>
+where B: ?Sized + ReadBlockDevice {
+    /// directory that a cluster number refers to, `..` entries use `0` for the root directory
+    pub(super) fn dir_at(&self, cluster: u32) -> Dir {
+        match (cluster, self.boot.fat_type) {
+            (0, FatType::Fat32) => Dir::Cluster(self.boot.root_cluster),
+            (0, _) => Dir::Root,
+            (cluster, _) => Dir::Cluster(cluster),
+        }
+    }
+
+    /// cluster number of a directory as stored in `..` entries
+    pub(super) fn dir_cluster(&self, dir: Dir) -> u32 {
+        match dir {
+            Dir::Root => 0,
+            Dir::Cluster(cluster) if self.boot.fat_type == FatType::Fat32 && cluster == self.boot.root_cluster => 0,
+            Dir::Cluster(cluster) => cluster,
+        }
+    }
+
+    /// reads all slots of a directory with their device offsets
+    fn read_slots(&self, dir: Dir) -> FsResult<Vec<(usize, [u8; DIR_ENTRY_SIZE])>> {
+        let regions = match dir {
+            Dir::Root => {
+                let offset = self.boot.root_dir_begin() * self.boot.bytes_per_sector;
+                vec![(offset, self.boot.root_entries * DIR_ENTRY_SIZE)]
+            },
+            Dir::Cluster(first) => self.chain(first)?
+                .into_iter()
+                .map(|cluster| (self.cluster_offset(cluster), self.boot.cluster_size()))
+                .collect(),
+        };
+
+        let mut slots = Vec::new();
+        for (offset, size) in regions {
+            let mut data = vec![0u8; size];
+            self.read_bytes(offset, &mut data)?;
+            for (i, chunk) in data.chunks(DIR_ENTRY_SIZE).enumerate() {
+                let mut slot = [0u8; DIR_ENTRY_SIZE];
+                slot.copy_from_slice(chunk);
+                slots.push((offset + i * DIR_ENTRY_SIZE, slot));
+            }
+        }
+        Ok(slots)
+    }
+
+    /// reads the entries of a directory, without `.` and `..`
+    pub(super) fn read_entries(&self, dir: Dir) -> FsResult<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        // long name entries seen since the last short entry
+        let mut long: Vec<(usize, [u8; DIR_ENTRY_SIZE])> = Vec::new();
+
+        for (offset, slot) in self.read_slots(dir)? {
+            match slot[0] {
+                0 => break,
+                DELETED => {
+                    long.clear();
+                    continue;
+                },
+                _ => (),
+            }
+
+            let attributes = slot[11];
+            if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                if slot[0] & LAST_LONG_ENTRY != 0 {
+                    long.clear();
+                }
+                long.push((offset, slot));
+                continue;
+            }
+            if attributes & ATTR_VOLUME_ID != 0 || slot[0] == b'.' {
+                long.clear();
+                continue;
+            }
+
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&slot[..11]);
+            if short_name[0] == 0x05 {
+                short_name[0] = DELETED;
+            }
+
+            let cluster_high = if self.boot.fat_type == FatType::Fat32 { read_u16(&slot, 20) as u32 } else { 0 };
+            let cluster = (cluster_high << 16) | read_u16(&slot, 26) as u32;
+            if cluster != 0 && !self.is_data_cluster(cluster) {
+                return Err(FsError::InvalidAddress);
+            }
+
+            let mut slots: Vec<usize> = Vec::new();
+            let name = match long_name(&long, checksum(&short_name)) {
+                Some(name) => {
+                    slots.extend(long.iter().map(|(offset, _)| *offset));
+                    name
+                },
+                None => display_short_name(&short_name, slot[12]),
+            };
+            slots.push(offset);
+            long.clear();
+
+            entries.push(DirEntry {
+                name,
+                short_name,
+                attributes,
+                cluster,
+                size: read_u32(&slot, 28) as usize,
+                slots,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// finds the entry called `name` in a directory
+    pub(super) fn find_entry(&self, dir: Dir, name: &[u8]) -> FsResult<DirEntry> {
+        self.read_entries(dir)?
+            .into_iter()
+            .find(|entry| entry.matches(name))
+            .ok_or(FsError::NotFound)
+    }
+}
+
+impl<B> FAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// creates an entry called `name` in a directory and returns it
+    pub(super) fn insert_entry(&mut self, dir: Dir, name: &[u8], attributes: u8, cluster: u32, size: usize) -> FsResult<DirEntry> {
+        let name_str = validate_name(name)?;
+        let entries = self.read_entries(dir)?;
+        if entries.iter().any(|entry| entry.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (short_name, case, needs_long) = match exact_short_name(name_str) {
+            Some((short_name, case)) => (short_name, case, false),
+            None => (generate_short_name(name_str, &entries)?, 0, true),
+        };
+
+        let mut short = [0u8; DIR_ENTRY_SIZE];
+        copy(&short_name, &mut short, 11);
+        if short[0] == DELETED {
+            short[0] = 0x05;
+        }
+        short[11] = attributes;
+        short[12] = case;
+        write_u16(&mut short, 16, DATE);
+        write_u16(&mut short, 18, DATE);
+        write_u16(&mut short, 20, (cluster >> 16) as u16);
+        write_u16(&mut short, 24, DATE);
+        write_u16(&mut short, 26, cluster as u16);
+        write_u32(&mut short, 28, size as u32);
+
+        let mut new_slots = if needs_long { long_entries(name_str, checksum(&short_name)) } else { Vec::new() };
+        new_slots.push(short);
+
+        let offsets = self.find_free_slots(dir, new_slots.len())?;
+        for (offset, slot) in offsets.iter().zip(new_slots.iter()) {
+            self.write_bytes(*offset, slot)?;
+        }
+
+        Ok(DirEntry {
+            name: name.to_vec(),
+            short_name,
+            attributes,
+            cluster,
+            size,
+            slots: offsets,
+        })
+    }
+
+    /// marks all slots of an entry as deleted
+    pub(super) fn remove_entry(&mut self, entry: &DirEntry) -> FsResult<()> {
+        for offset in entry.slots.iter() {
+            self.write_bytes(*offset, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// updates first cluster and size in the short entry
+    pub(super) fn update_entry(&mut self, short_slot: usize, cluster: u32, size: usize) -> FsResult<()> {
+        let mut slot = [0u8; DIR_ENTRY_SIZE];
+        self.read_bytes(short_slot, &mut slot)?;
+        write_u16(&mut slot, 20, (cluster >> 16) as u16);
+        write_u16(&mut slot, 26, cluster as u16);
+        write_u32(&mut slot, 28, size as u32);
+        self.write_bytes(short_slot, &slot)
+    }
+
+    /// writes the `.` and `..` entries into the first cluster of a new directory
+    pub(super) fn write_dot_entries(&mut self, cluster: u32, parent: u32) -> FsResult<()> {
+        for (i, (name, target)) in [(b".          ", cluster), (b"..         ", parent)].iter().enumerate() {
+            let mut slot = [0u8; DIR_ENTRY_SIZE];
+            copy(*name, &mut slot, 11);
+            slot[11] = ATTR_DIRECTORY;
+            write_u16(&mut slot, 16, DATE);
+            write_u16(&mut slot, 18, DATE);
+            write_u16(&mut slot, 20, (target >> 16) as u16);
+            write_u16(&mut slot, 24, DATE);
+            write_u16(&mut slot, 26, *target as u16);
+            self.write_bytes(self.cluster_offset(cluster) + i * DIR_ENTRY_SIZE, &slot)?;
+        }
+        Ok(())
+    }
+
+    /// device offsets of `count` consecutive free slots, growing the directory if needed
+    fn find_free_slots(&mut self, dir: Dir, count: usize) -> FsResult<Vec<usize>> {
+        loop {
+            let mut run = Vec::new();
+            for (offset, slot) in self.read_slots(dir)? {
+                if slot[0] == 0 || slot[0] == DELETED {
+                    run.push(offset);
+                    if run.len() == count {
+                        return Ok(run);
+                    }
+                } else {
+                    run.clear();
+                }
+            }
+
+            match dir {
+                Dir::Root => return Err(FsError::NotEnoughSpace),
+                Dir::Cluster(first) => {
+                    let last = *self.chain(first)?.last().unwrap();
+                    self.allocate_zeroed_cluster(Some(last))?;
+                },
+            }
+        }
+    }
+}
+
+/// checksum of a short name that is stored in its long name entries
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+/// assembles the long name from its entries, `None` if they don't belong to the short entry
+fn long_name(long: &[(usize, [u8; DIR_ENTRY_SIZE])], checksum: u8) -> Option<Filename> {
+    if long.is_empty() || long[0].1[0] & LAST_LONG_ENTRY == 0 {
+        return None;
+    }
+
+    // entries are stored from the last part of the name to the first
+    let count = (long[0].1[0] & 0x1f) as usize;
+    if count != long.len() {
+        return None;
+    }
+
+    let mut units = Vec::with_capacity(count * LONG_ENTRY_CHARS);
+    for (i, (_, slot)) in long.iter().rev().enumerate() {
+        if (slot[0] & 0x1f) as usize != i + 1 || slot[13] != checksum {
+            return None;
+        }
+        units.extend(LONG_ENTRY_OFFSETS.iter().map(|&offset| read_u16(slot, offset)));
+    }
+    if let Some(end) = units.iter().position(|&unit| unit == 0) {
+        units.truncate(end);
+    }
+
+    let name: String = core::char::decode_utf16(units.into_iter())
+        .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+        .collect();
+    Some(name.into_bytes())
+}
+
+/// long name entries for `name` in the order they are stored
+fn long_entries(name: &str, checksum: u8) -> Vec<[u8; DIR_ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = (units.len() + LONG_ENTRY_CHARS - 1) / LONG_ENTRY_CHARS;
+    // the name is terminated with 0 if it doesn't fill the last entry and padded with 0xffff
+    if units.len() % LONG_ENTRY_CHARS != 0 {
+        units.push(0);
+    }
+    units.resize(count * LONG_ENTRY_CHARS, 0xffff);
+
+    (0..count).rev().map(|i| {
+        let mut slot = [0u8; DIR_ENTRY_SIZE];
+        slot[0] = (i + 1) as u8 | if i + 1 == count { LAST_LONG_ENTRY } else { 0 };
+        slot[11] = ATTR_LONG_NAME;
+        slot[13] = checksum;
+        for (j, &offset) in LONG_ENTRY_OFFSETS.iter().enumerate() {
+            write_u16(&mut slot, offset, units[i * LONG_ENTRY_CHARS + j]);
+        }
+        slot
+    }).collect()
+}
+
+/// name of a short entry as `NAME.EXT`, with the case flags of byte 12 applied
+fn display_short_name(short_name: &[u8; 11], case: u8) -> Filename {
+    let part = |bytes: &[u8], lower: bool| -> Vec<u8> {
+        let len = bytes.iter().rposition(|&b| b != b' ').map(|i| i + 1).unwrap_or(0);
+        bytes[..len].iter().map(|b| if lower { b.to_ascii_lowercase() } else { *b }).collect()
+    };
+
+    let mut name = part(&short_name[..8], case & LOWER_BASE != 0);
+    let ext = part(&short_name[8..], case & LOWER_EXT != 0);
+    if !ext.is_empty() {
+        name.push(b'.');
+        name.extend(ext);
+    }
+    name
+}
+
+/// checks that `name` can be stored in a directory
+fn validate_name(name: &[u8]) -> FsResult<&str> {
+    let name = core::str::from_utf8(name)
+        .map_err(|_| FsError::IllegalOperation(String::from("file name is not valid UTF-8")))?;
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::IllegalOperation(String::from("invalid file name")));
+    }
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+        return Err(FsError::IllegalOperation(String::from("file name contains a character that is not allowed")));
+    }
+    Ok(name)
+}
+
+/// `true` for the characters that are allowed in short names besides upper case letters and digits
+fn is_short_special(c: u8) -> bool {
+    b"!#$%&'()-@^_`{}~".contains(&c)
+}
+
+/// short name and case flags if `name` can be stored without long name entries
+fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let bytes = name.as_bytes();
+    let (base, ext) = match bytes.iter().position(|&c| c == b'.') {
+        Some(dot) => (&bytes[..dot], &bytes[dot + 1..]),
+        None => (bytes, &[][..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || (ext.is_empty() && bytes.len() != base.len()) {
+        return None;
+    }
+
+    // each part must be either completely lower or completely upper case
+    let mut case = 0;
+    for (part, flag) in [(base, LOWER_BASE), (ext, LOWER_EXT)].iter() {
+        if !part.iter().all(|&c| c.is_ascii_alphanumeric() || is_short_special(c)) {
+            return None;
+        }
+        let lower = part.iter().any(|c| c.is_ascii_lowercase());
+        let upper = part.iter().any(|c| c.is_ascii_uppercase());
+        if lower && upper {
+            return None;
+        }
+        if lower {
+            case |= flag;
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    for (i, c) in base.iter().enumerate() {
+        short_name[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in ext.iter().enumerate() {
+        short_name[8 + i] = c.to_ascii_uppercase();
+    }
+    Some((short_name, case))
+}
+
+/// generates a unique short name of the form `BASE~N.EXT` for a long name
+fn generate_short_name(name: &str, entries: &[DirEntry]) -> FsResult<[u8; 11]> {
+    let convert = |part: &str, len: usize| -> Vec<u8> {
+        part.chars()
+            .filter(|&c| c != ' ' && c != '.')
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || (c.is_ascii() && is_short_special(c as u8)) {
+                    (c as u8).to_ascii_uppercase()
+                } else {
+                    b'_'
+                }
+            })
+            .take(len)
+            .collect()
+    };
+
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rfind('.') {
+        Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+        None => (trimmed, ""),
+    };
+    let mut base = convert(base, 8);
+    if base.is_empty() {
+        base.push(b'_');
+    }
+    let ext = convert(ext, 3);
+
+    for n in 1..1_000_000usize {
+        let tail = format!("~{}", n);
+        let base_len = base.len().min(8 - tail.len());
+
+        let mut short_name = [b' '; 11];
+        copy(&base, &mut short_name, base_len);
+        copy_offset(tail.as_bytes(), &mut short_name, tail.len(), 0, base_len);
+        copy_offset(&ext, &mut short_name, ext.len(), 0, 8);
+
+        if !entries.iter().any(|entry| entry.short_name == short_name) {
+            return Ok(short_name);
+        }
+    }
+    Err(FsError::NotEnoughSpace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// long name entries as `long_name` gets them from `read_slots`
+    fn slots(entries: &[[u8; DIR_ENTRY_SIZE]]) -> Vec<(usize, [u8; DIR_ENTRY_SIZE])> {
+        entries.iter().enumerate().map(|(i, entry)| (i * DIR_ENTRY_SIZE, *entry)).collect()
+    }
+
+    fn entry(short_name: &[u8; 11]) -> DirEntry {
+        DirEntry {
+            name: Vec::new(),
+            short_name: *short_name,
+            attributes: 0,
+            cluster: 0,
+            size: 0,
+            slots: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn short_name_checksum() {
+        assert_eq!(checksum(b"README  TXT"), 0x73);
+        assert_eq!(checksum(b"ALONGF~1TXT"), 0x02);
+        assert_eq!(checksum(b"FILENA~1TXT"), 0x5b);
+    }
+
+    #[test]
+    fn long_name_roundtrip() {
+        let long: String = core::iter::repeat('n').take(MAX_NAME_LEN).collect();
+        for name in &["a", "exactly 13 ch", "fourteen chars", "grüße 😀.txt", long.as_str()] {
+            let entries = long_entries(name, 0x42);
+            let units = name.encode_utf16().count();
+            assert_eq!(entries.len(), (units + LONG_ENTRY_CHARS - 1) / LONG_ENTRY_CHARS);
+            assert_eq!(entries[0][0], entries.len() as u8 | LAST_LONG_ENTRY);
+            assert!(entries.iter().all(|entry| entry[11] == ATTR_LONG_NAME && entry[13] == 0x42));
+            assert_eq!(long_name(&slots(&entries), 0x42), Some(name.as_bytes().to_vec()));
+        }
+    }
+
+    #[test]
+    fn long_name_mismatch() {
+        let entries = long_entries("a name of three entries.txt", 0x42);
+        assert_eq!(entries.len(), 3);
+        // the short entry was changed by a system that doesn't know long names
+        assert_eq!(long_name(&slots(&entries), 0x43), None);
+        // orphaned, reordered or incomplete entries
+        assert_eq!(long_name(&slots(&entries[1..]), 0x42), None);
+        assert_eq!(long_name(&slots(&entries[..2]), 0x42), None);
+        assert_eq!(long_name(&slots(&[entries[0], entries[2], entries[1]]), 0x42), None);
+        assert_eq!(long_name(&[], 0x42), None);
+        let mut damaged = entries.clone();
+        damaged[1][13] ^= 1;
+        assert_eq!(long_name(&slots(&damaged), 0x42), None);
+    }
+
+    #[test]
+    fn short_names() {
+        let (short_name, case) = exact_short_name("readme.txt").unwrap();
+        assert_eq!(&short_name, b"README  TXT");
+        assert_eq!(display_short_name(&short_name, case), b"readme.txt");
+        let (short_name, case) = exact_short_name("MAKEFILE").unwrap();
+        assert_eq!(display_short_name(&short_name, case), b"MAKEFILE");
+        let (short_name, case) = exact_short_name("NOTES.md").unwrap();
+        assert_eq!(display_short_name(&short_name, case), b"NOTES.md");
+
+        for name in &["MiXeD.c", "toolongname", "a.text", "two.dots.c", "space .c", "dot."] {
+            assert!(exact_short_name(name).is_none(), "{}", name);
+        }
+
+        let short_name = generate_short_name("A Long File Name.txt", &[]).unwrap();
+        assert_eq!(&short_name, b"ALONGF~1TXT");
+        let taken = [entry(b"ALONGF~1TXT"), entry(b"ALONGF~2TXT")];
+        assert_eq!(&generate_short_name("A Long File Name.txt", &taken).unwrap(), b"ALONGF~3TXT");
+        assert_eq!(&generate_short_name(".hidden", &[]).unwrap(), b"HIDDEN~1   ");
+        assert_eq!(&generate_short_name("ünïcode.tär", &[]).unwrap(), b"_N_COD~1T_R");
+    }
+
+    #[test]
+    fn names() {
+        assert!(validate_name(b"a normal name.txt").is_ok());
+        let long: Vec<u8> = core::iter::repeat(b'n').take(MAX_NAME_LEN + 1).collect();
+        for name in [&b""[..], b".", b"..", b"a:b", b"a*", b"tab\t", b"\xff", &long].iter() {
+            assert!(validate_name(name).is_err());
+        }
+    }
+}
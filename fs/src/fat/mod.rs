@@ -0,0 +1,516 @@
+//! FAT12, FAT16 and FAT32 with VFAT long file names.
+//!
+//! Volumes are compatible with other implementations, e.g. images made with `mkfs.vfat` and
+//! `mcopy` can be mounted and files written here can be read on Linux.
+//! Timestamps are not maintained, all entries are dated 1980-01-01.
+
+extern crate alloc;
+use alloc::vec::*;
+use alloc::string::*;
+use alloc::boxed::*;
+use alloc::*;
+
+use crate::error::*;
+use crate::block::*;
+use crate::filesystem::*;
+use crate::copy::*;
+
+mod boot;
+mod table;
+mod dir;
+use boot::*;
+use dir::*;
+
+pub use boot::FatType;
+
+/// Size of the boot sector, the smallest supported sector size
+const SECTOR_SIZE: usize = 512;
+/// Length of the volume label in the boot sector
+const LABEL_LEN: usize = 11;
+const NAME: &str = "FAT";
+
+pub struct FAT<B: ?Sized + BlockDevice> {
+    dev: Box<B>,
+    boot: BootSector,
+    /// number of free clusters
+    free_clusters: usize,
+    /// cluster where the search for a free cluster starts
+    next_free: u32,
+}
+
+/// Position in a file that is read
+pub struct ReadProgress {
+    first_cluster: u32,
+    size: usize,
+    /// offset from the beginning of the file
+    offset: usize,
+    /// index in the chain and number of the cluster that was read last
+    current: Option<(usize, u32)>,
+}
+
+/// Position at the end of a file that is written
+pub struct WriteProgress {
+    /// device offset of the short entry of the file
+    short_slot: usize,
+    first_cluster: u32,
+    last_cluster: u32,
+    size: usize,
+}
+
+impl<B: ?Sized + RWBlockDevice> MountedFileSystem<B> for FAT<B> {
+    fn name() -> &'static str {
+        NAME
+    }
+
+    fn inner(self) -> Box<B> {
+        self.dev
+    }
+
+    fn mount(dev: Box<B>) -> Result<Self, Box<B>> {
+        let dev_block_size = dev.block_size();
+        if dev_block_size == 0 || dev_block_size > SECTOR_SIZE || SECTOR_SIZE % dev_block_size != 0 {
+            return Err(dev);
+        }
+
+        let mut first_sector = vec![0u8; SECTOR_SIZE];
+        for (i, chunk) in first_sector.chunks_mut(dev_block_size).enumerate() {
+            if dev.read_block(i, chunk).is_err() {
+                return Err(dev);
+            }
+        }
+        let boot = match BootSector::decode(&first_sector) {
+            Ok(boot) => boot,
+            Err(_) => return Err(dev),
+        };
+        if boot.total_sectors * boot.bytes_per_sector > dev.blocks() * dev_block_size {
+            return Err(dev);
+        }
+
+        let mut fs = Self {
+            dev,
+            boot,
+            free_clusters: 0,
+            next_free: 2,
+        };
+
+        fs.free_clusters = match fs.count_free_clusters() {
+            Ok(free) => free,
+            Err(_) => return Err(fs.dev),
+        };
+        if let Ok(sector) = fs.read_fs_info_sector() {
+            if let Some(info) = FsInfo::decode(&sector) {
+                fs.next_free = info.next_free;
+            }
+        }
+
+        Ok(fs)
+    }
+
+    fn format(dev: Box<B>) -> Result<Self, Box<B>> {
+        Self::format_as(dev, None)
+    }
+}
+
+impl<B> FAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// formats the device as FAT12, FAT16 or FAT32,
+    /// the type is chosen by the size of the device if it isn't given
+    pub fn format_as(dev: Box<B>, fat_type: Option<FatType>) -> Result<Self, Box<B>> {
+        let dev_block_size = dev.block_size();
+        if dev_block_size == 0 || dev_block_size > SECTOR_SIZE || SECTOR_SIZE % dev_block_size != 0 {
+            return Err(dev);
+        }
+        let total_sectors = (dev.blocks() * dev_block_size / SECTOR_SIZE).min(u32::MAX as usize);
+        let boot = match BootSector::layout(total_sectors, fat_type) {
+            Ok(boot) => boot,
+            Err(_) => return Err(dev),
+        };
+
+        let clusters = boot.clusters();
+        let mut fs = Self {
+            dev,
+            boot,
+            free_clusters: clusters,
+            next_free: 2,
+        };
+        match fs.write_layout() {
+            Ok(_) => Ok(fs),
+            Err(_) => Err(fs.dev),
+        }
+    }
+
+    fn write_layout(&mut self) -> FsResult<()> {
+        let boot = self.boot;
+        let sector_size = boot.bytes_per_sector;
+
+        // clear reserved sectors, tables and the fixed root directory
+        let zeros = vec![0u8; sector_size];
+        for sector in 0..boot.data_begin() {
+            self.write_bytes(sector * sector_size, &zeros)?;
+        }
+
+        let mut label = [b' '; LABEL_LEN];
+        copy(b"NO NAME", &mut label, 7);
+        let boot_sector = boot.encode(&label);
+        self.write_bytes(0, &boot_sector)?;
+
+        // the first two entries hold the media descriptor and the end of chain marker
+        let end_of_chain = boot.fat_type.end_of_chain();
+        self.set_fat_entry(0, end_of_chain & !0xff | 0xf8)?;
+        self.set_fat_entry(1, end_of_chain)?;
+
+        if boot.fat_type == FatType::Fat32 {
+            let mut fs_info = vec![0u8; sector_size];
+            FsInfo { free_clusters: 0, next_free: 2 }.encode(&mut fs_info);
+            self.write_bytes(boot.fs_info * sector_size, &fs_info)?;
+            self.write_bytes(BACKUP_BOOT_SECTOR * sector_size, &boot_sector)?;
+            self.write_bytes((BACKUP_BOOT_SECTOR + 1) * sector_size, &fs_info)?;
+
+            let root = self.allocate_zeroed_cluster(None)?;
+            self.boot.root_cluster = root;
+        }
+
+        self.write_fs_info()
+    }
+}
+
+impl<B> FAT<B>
+where B: ?Sized + ReadBlockDevice {
+    /// type of the table
+    pub fn fat_type(&self) -> FatType {
+        self.boot.fat_type
+    }
+
+    /// device offset of the first byte of a cluster
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        let sector = self.boot.data_begin() + (cluster as usize - 2) * self.boot.sectors_per_cluster;
+        sector * self.boot.bytes_per_sector
+    }
+
+    /// reads `buffer.len()` bytes starting at the device offset `offset`
+    fn read_bytes(&self, offset: usize, buffer: &mut [u8]) -> FsResult<()> {
+        read_bytes(&*self.dev, offset, buffer)
+    }
+
+    fn read_fs_info_sector(&self) -> FsResult<Vec<u8>> {
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        if self.boot.fs_info != 0 {
+            self.read_bytes(self.boot.fs_info * self.boot.bytes_per_sector, &mut sector)?;
+        }
+        Ok(sector)
+    }
+
+    /// finds the directory a path refers to
+    fn walk_dir(&self, path: &Path) -> FsResult<Dir> {
+        let mut dir = self.dir_at(0);
+        let mut path = path.clone();
+        loop {
+            let (head, tail) = path.head_tail();
+            match head {
+                Some(name) => {
+                    let entry = self.find_entry(dir, &name)?;
+                    if !entry.is_dir() {
+                        return Err(FsError::NotFound);
+                    }
+                    dir = self.dir_at(entry.cluster);
+                },
+                None => return Ok(dir),
+            }
+            path = tail;
+        }
+    }
+
+    /// finds the entry a path refers to and the directory it is in, fails for the root
+    fn lookup(&self, path: &Path) -> FsResult<(Dir, DirEntry)> {
+        match (path.parent_dir(), path.name()) {
+            (Some(parent), Some(name)) => {
+                let dir = self.walk_dir(&parent)?;
+                Ok((dir, self.find_entry(dir, &name)?))
+            },
+            _ => Err(FsError::IllegalOperation(String::from("the root directory has no entry"))),
+        }
+    }
+
+    /// looks up the file at `path`
+    fn lookup_file(&self, path: &Path) -> FsResult<DirEntry> {
+        let (_, entry) = self.lookup(path)?;
+        if entry.is_dir() {
+            Err(FsError::IsADirectory)
+        } else {
+            Ok(entry)
+        }
+    }
+
+    /// cluster with index `index` in the chain of the file that is read
+    fn cluster_at(&self, progress: &mut ReadProgress, index: usize) -> FsResult<u32> {
+        let (mut i, mut cluster) = match progress.current {
+            Some((i, cluster)) if i <= index => (i, cluster),
+            _ => (0, progress.first_cluster),
+        };
+        if !self.is_data_cluster(cluster) {
+            return Err(FsError::InvalidAddress);
+        }
+        while i < index {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None => return Err(FsError::InternalError(String::from("File data ended preemptively"))),
+            };
+            i += 1;
+        }
+        progress.current = Some((i, cluster));
+        Ok(cluster)
+    }
+}
+
+impl<B> FAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// writes `buffer` starting at the device offset `offset`
+    fn write_bytes(&mut self, offset: usize, buffer: &[u8]) -> FsResult<()> {
+        write_bytes(&mut *self.dev, offset, buffer)
+    }
+
+    /// deletes an entry, including everything below it if it is a directory
+    fn delete_entry(&mut self, entry: &DirEntry) -> FsResult<()> {
+        if entry.is_dir() {
+            self.clear_dir(self.dir_at(entry.cluster))?;
+        }
+        self.free_chain(entry.cluster)?;
+        self.remove_entry(entry)
+    }
+
+    /// deletes everything in a directory
+    fn clear_dir(&mut self, dir: Dir) -> FsResult<()> {
+        for entry in self.read_entries(dir)? {
+            self.delete_entry(&entry)?;
+        }
+        Ok(())
+    }
+
+    /// runs an operation that changes the allocation and updates the FS information afterwards
+    fn modify<R, F>(&mut self, operation: F) -> FsResult<R>
+        where F: FnOnce(&mut Self) -> FsResult<R>
+    {
+        let result = operation(self);
+        self.write_fs_info()?;
+        result
+    }
+}
+
+impl<B> BaseFileSystem for FAT<B>
+where B: ?Sized + ReadBlockDevice
+{
+    fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        let dir = self.walk_dir(&path)?;
+        Ok(self.read_entries(dir)?.into_iter().map(|entry| entry.name).collect())
+    }
+
+    fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        match self.walk_dir(&path) {
+            Ok(_) => Ok(true),
+            Err(FsError::NotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn exists_file(&self, path: Path) -> FsResult<bool> {
+        if path.is_root() {
+            return Ok(false);
+        }
+        match self.lookup(&path) {
+            Ok((_, entry)) => Ok(!entry.is_dir()),
+            Err(FsError::NotFound) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn statfs(&self) -> FsResult<stats::FsStats> {
+        Ok(stats::FsStats::new(
+            self.boot.fat_type.name(),
+            self.boot.clusters() as u64,
+            self.free_clusters as u64,
+            self.boot.cluster_size() as u64,
+        ))
+    }
+}
+
+impl<B> ReadFileSystem for FAT<B>
+where B: ?Sized + ReadBlockDevice
+{
+    type ReadProgress = ReadProgress;
+
+    fn open_read(&self, path: Path) -> FsResult<ReadProgress> {
+        let entry = self.lookup_file(&path)?;
+        Ok(ReadProgress {
+            first_cluster: entry.cluster,
+            size: entry.size,
+            offset: 0,
+            current: None,
+        })
+    }
+
+    fn read(&self, progress: &mut ReadProgress, buffer: &mut [u8]) -> FsResult<usize> {
+        let cluster_size = self.boot.cluster_size();
+        let mut buffer_idx = 0;
+
+        while buffer_idx < buffer.len() && progress.offset < progress.size {
+            let cluster = self.cluster_at(progress, progress.offset / cluster_size)?;
+            let within = progress.offset % cluster_size;
+            let len = (cluster_size - within)
+                .min(buffer.len() - buffer_idx)
+                .min(progress.size - progress.offset);
+
+            self.read_bytes(self.cluster_offset(cluster) + within, &mut buffer[buffer_idx..buffer_idx + len])?;
+
+            buffer_idx += len;
+            progress.offset += len;
+        }
+
+        Ok(buffer_idx)
+    }
+
+    fn seek(&self, progress: &mut ReadProgress, seek: usize) -> FsResult<()> {
+        progress.offset += seek;
+        Ok(())
+    }
+
+    fn file_size(&self, path: Path) -> FsResult<usize> {
+        Ok(self.lookup_file(&path)?.size)
+    }
+}
+
+impl<B> WriteFileSystem for FAT<B>
+where B: ?Sized + RWBlockDevice
+{
+    type WriteProgress = WriteProgress;
+
+    fn open_write(&mut self, path: Path) -> FsResult<WriteProgress> {
+        let entry = self.lookup_file(&path)?;
+        self.modify(|fs| {
+            fs.free_chain(entry.cluster)?;
+            fs.update_entry(entry.short_slot(), 0, 0)
+        })?;
+        Ok(WriteProgress {
+            short_slot: entry.short_slot(),
+            first_cluster: 0,
+            last_cluster: 0,
+            size: 0,
+        })
+    }
+
+    fn write(&mut self, progress: &mut WriteProgress, buffer: &[u8]) -> FsResult<()> {
+        if progress.size + buffer.len() > u32::MAX as usize {
+            return Err(FsError::NotEnoughSpace);
+        }
+
+        self.modify(|fs| {
+            let cluster_size = fs.boot.cluster_size();
+            let mut buffer_idx = 0;
+
+            while buffer_idx < buffer.len() {
+                let within = progress.size % cluster_size;
+                if within == 0 {
+                    // the last cluster is full
+                    let prev = if progress.first_cluster == 0 { None } else { Some(progress.last_cluster) };
+                    let cluster = fs.allocate_cluster(prev)?;
+                    if progress.first_cluster == 0 {
+                        progress.first_cluster = cluster;
+                    }
+                    progress.last_cluster = cluster;
+                }
+
+                let len = (cluster_size - within).min(buffer.len() - buffer_idx);
+                let offset = fs.cluster_offset(progress.last_cluster) + within;
+                fs.write_bytes(offset, &buffer[buffer_idx..buffer_idx + len])?;
+
+                buffer_idx += len;
+                progress.size += len;
+            }
+
+            fs.update_entry(progress.short_slot, progress.first_cluster, progress.size)
+        })
+    }
+}
+
+impl<B> ManageFileSystem for FAT<B>
+where B: ?Sized + RWBlockDevice
+{
+    fn delete(&mut self, path: Path) -> FsResult<()> {
+        let (_, entry) = self.lookup(&path)?;
+        self.modify(|fs| fs.delete_entry(&entry))
+    }
+
+    fn clear(&mut self, path: Path) -> FsResult<()> {
+        if self.exists_dir(path.clone())? {
+            let dir = self.walk_dir(&path)?;
+            self.modify(|fs| fs.clear_dir(dir))
+        } else {
+            let entry = self.lookup_file(&path)?;
+            self.modify(|fs| {
+                fs.free_chain(entry.cluster)?;
+                fs.update_entry(entry.short_slot(), 0, 0)
+            })
+        }
+    }
+
+    fn create_file(&mut self, path: Path) -> FsResult<()> {
+        let (parent, name) = match (path.parent_dir(), path.name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(FsError::IllegalOperation(String::from("Can't create the root directory"))),
+        };
+        let dir = self.walk_dir(&parent)?;
+        self.insert_entry(dir, &name, ATTR_ARCHIVE, 0, 0)?;
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: Path) -> FsResult<()> {
+        let (parent, name) = match (path.parent_dir(), path.name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(FsError::IllegalOperation(String::from("Can't create the root directory"))),
+        };
+        let dir = self.walk_dir(&parent)?;
+        if self.find_entry(dir, &name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+
+        self.modify(|fs| {
+            let cluster = fs.allocate_zeroed_cluster(None)?;
+            let parent_cluster = fs.dir_cluster(dir);
+            fs.write_dot_entries(cluster, parent_cluster)?;
+            match fs.insert_entry(dir, &name, ATTR_DIRECTORY, cluster, 0) {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    fs.free_chain(cluster)?;
+                    Err(err)
+                },
+            }
+        })
+    }
+
+    fn rename(&mut self, from: Path, to: Path) -> FsResult<()> {
+        let (from_dir, entry) = self.lookup(&from)?;
+        let (to_parent, to_name) = match (to.parent_dir(), to.name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(FsError::IllegalOperation(String::from("Can't rename to the root directory"))),
+        };
+        if entry.is_dir() && to.clone().relative_to(from.clone()).is_some() {
+            return Err(FsError::IllegalOperation(String::from("Can't move a directory into itself")));
+        }
+        let to_dir = self.walk_dir(&to_parent)?;
+
+        self.modify(|fs| {
+            // the new entry is written first, so the file is never lost
+            fs.insert_entry(to_dir, &to_name, entry.attributes, entry.cluster, entry.size)?;
+            fs.remove_entry(&entry)?;
+
+            if entry.is_dir() && from_dir != to_dir {
+                let parent_cluster = fs.dir_cluster(to_dir);
+                fs.write_dot_entries(entry.cluster, parent_cluster)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        self.dev.flush()
+    }
+}
@@ -0,0 +1,218 @@
+//! Access to the file allocation table.
+//!
+//! The table is not cached, every entry is read from the first copy of the table and written to
+//! all copies. FAT12 entries are 12 bits wide and may span two sectors.
+
+use super::*;
+
+/// Table entry of a free cluster
+const FREE: u32 = 0;
+
+impl<B> FAT<B>
+where B: ?Sized + ReadBlockDevice {
+    /// `true` if `cluster` refers to a data cluster of the volume
+    pub(super) fn is_data_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && (cluster as usize) < self.boot.clusters() + 2
+    }
+
+    /// byte offset of the entry of `cluster` in a table
+    fn entry_offset(&self, cluster: u32) -> usize {
+        let cluster = cluster as usize;
+        match self.boot.fat_type {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// byte offset of the `copy`-th table on the device
+    fn table_offset(&self, copy: usize) -> usize {
+        (self.boot.reserved_sectors + copy * self.boot.fat_sectors) * self.boot.bytes_per_sector
+    }
+
+    /// decodes the entry of `cluster` from the bytes starting at its entry offset
+    fn decode_entry(&self, cluster: u32, bytes: &[u8]) -> u32 {
+        match self.boot.fat_type {
+            FatType::Fat12 => {
+                let value = read_u16(bytes, 0) as u32;
+                if cluster % 2 == 0 { value & 0xfff } else { value >> 4 }
+            },
+            FatType::Fat16 => read_u16(bytes, 0) as u32,
+            FatType::Fat32 => read_u32(bytes, 0) & 0x0fff_ffff,
+        }
+    }
+
+    /// number of bytes an entry occupies in the table, FAT12 entries share bytes
+    fn entry_bytes(&self) -> usize {
+        match self.boot.fat_type {
+            FatType::Fat12 | FatType::Fat16 => 2,
+            FatType::Fat32 => 4,
+        }
+    }
+
+    /// reads the table entry of `cluster`
+    pub(super) fn fat_entry(&self, cluster: u32) -> FsResult<u32> {
+        let mut bytes = [0u8; 4];
+        let len = self.entry_bytes();
+        self.read_bytes(self.table_offset(0) + self.entry_offset(cluster), &mut bytes[..len])?;
+        Ok(self.decode_entry(cluster, &bytes))
+    }
+
+    /// cluster that follows `cluster` in its chain, `None` at the end of the chain
+    pub(super) fn next_cluster(&self, cluster: u32) -> FsResult<Option<u32>> {
+        let entry = self.fat_entry(cluster)?;
+        if self.boot.fat_type.is_end_of_chain(entry) {
+            Ok(None)
+        } else if self.is_data_cluster(entry) {
+            Ok(Some(entry))
+        } else {
+            // free or bad clusters can't be part of a chain
+            Err(FsError::InvalidAddress)
+        }
+    }
+
+    /// all clusters of the chain starting at `first`, which is empty if `first` is `0`
+    pub(super) fn chain(&self, first: u32) -> FsResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        if first == 0 {
+            return Ok(chain);
+        }
+        if !self.is_data_cluster(first) {
+            return Err(FsError::InvalidAddress);
+        }
+
+        let mut cluster = first;
+        loop {
+            chain.push(cluster);
+            // a chain that is longer than the volume has a loop
+            if chain.len() > self.boot.clusters() {
+                return Err(FsError::InvalidAddress);
+            }
+            match self.next_cluster(cluster)? {
+                Some(next) => cluster = next,
+                None => return Ok(chain),
+            }
+        }
+    }
+
+    /// counts the free clusters by reading the first table sector by sector
+    pub(super) fn count_free_clusters(&self) -> FsResult<usize> {
+        let table_size = self.boot.fat_sectors * self.boot.bytes_per_sector;
+        let mut table = vec![0u8; table_size];
+        self.read_bytes(self.table_offset(0), &mut table)?;
+
+        let last = self.boot.clusters() as u32 + 2;
+        let len = self.entry_bytes();
+        let free = (2..last)
+            .filter(|&cluster| {
+                let offset = self.entry_offset(cluster);
+                let mut bytes = [0u8; 4];
+                bytes[..len].copy_from_slice(&table[offset..offset + len]);
+                self.decode_entry(cluster, &bytes) == FREE
+            })
+            .count();
+        Ok(free)
+    }
+}
+
+impl<B> FAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// writes the table entry of `cluster` to all copies of the table
+    pub(super) fn set_fat_entry(&mut self, cluster: u32, value: u32) -> FsResult<()> {
+        let len = self.entry_bytes();
+        for copy in 0..self.boot.fats {
+            let offset = self.table_offset(copy) + self.entry_offset(cluster);
+            let mut bytes = [0u8; 4];
+            self.read_bytes(offset, &mut bytes[..len])?;
+
+            match self.boot.fat_type {
+                FatType::Fat12 => {
+                    let old = read_u16(&bytes, 0);
+                    let new = if cluster % 2 == 0 {
+                        (old & 0xf000) | (value as u16 & 0xfff)
+                    } else {
+                        (old & 0x000f) | ((value as u16) << 4)
+                    };
+                    write_u16(&mut bytes, 0, new);
+                },
+                FatType::Fat16 => write_u16(&mut bytes, 0, value as u16),
+                FatType::Fat32 => {
+                    // the upper four bits are reserved and must be preserved
+                    let old = read_u32(&bytes, 0);
+                    write_u32(&mut bytes, 0, (old & 0xf000_0000) | (value & 0x0fff_ffff));
+                },
+            }
+
+            self.write_bytes(offset, &bytes[..len])?;
+        }
+        Ok(())
+    }
+
+    /// allocates a free cluster and appends it to the chain ending in `prev`, if given
+    pub(super) fn allocate_cluster(&mut self, prev: Option<u32>) -> FsResult<u32> {
+        if self.free_clusters == 0 {
+            return Err(FsError::NotEnoughSpace);
+        }
+
+        let clusters = self.boot.clusters() as u32;
+        let start = if self.is_data_cluster(self.next_free) { self.next_free } else { 2 };
+        let mut cluster = start;
+        loop {
+            if self.fat_entry(cluster)? == FREE {
+                break;
+            }
+            cluster = if cluster + 1 < clusters + 2 { cluster + 1 } else { 2 };
+            if cluster == start {
+                // the free counter was wrong
+                self.free_clusters = 0;
+                return Err(FsError::NotEnoughSpace);
+            }
+        }
+
+        let end_of_chain = self.boot.fat_type.end_of_chain();
+        self.set_fat_entry(cluster, end_of_chain)?;
+        if let Some(prev) = prev {
+            self.set_fat_entry(prev, cluster)?;
+        }
+
+        self.free_clusters -= 1;
+        self.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    /// allocates a cluster that is filled with zeros
+    pub(super) fn allocate_zeroed_cluster(&mut self, prev: Option<u32>) -> FsResult<u32> {
+        let cluster = self.allocate_cluster(prev)?;
+        let zeros = vec![0u8; self.boot.cluster_size()];
+        self.write_bytes(self.cluster_offset(cluster), &zeros)?;
+        Ok(cluster)
+    }
+
+    /// frees the chain starting at `first`
+    pub(super) fn free_chain(&mut self, first: u32) -> FsResult<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, FREE)?;
+            self.free_clusters += 1;
+        }
+        Ok(())
+    }
+
+    /// writes the allocation state to the FAT32 FS information sector
+    pub(super) fn write_fs_info(&mut self) -> FsResult<()> {
+        if self.boot.fat_type != FatType::Fat32 || self.boot.fs_info == 0 {
+            return Ok(());
+        }
+
+        let offset = self.boot.fs_info * self.boot.bytes_per_sector;
+        let mut sector = vec![0u8; SECTOR_SIZE];
+        self.read_bytes(offset, &mut sector)?;
+        if FsInfo::decode(&sector).is_none() {
+            return Ok(());
+        }
+        FsInfo {
+            free_clusters: self.free_clusters as u32,
+            next_free: self.next_free,
+        }.encode(&mut sector);
+        self.write_bytes(offset, &sector)
+    }
+}
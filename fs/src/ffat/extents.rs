@@ -0,0 +1,256 @@
+//! Extent-based file layout.
+//!
+//! The first sector of a file doesn't hold data, it holds an `ExtentTable` that lists the runs of
+//! consecutive data sectors of the file. If a file has more extents than fit into one sector,
+//! the table continues in `Index` sectors that are linked through the `next` field of the
+//! allocation table, starting at the file's first sector.
+//! Data sectors themselves are not linked, the position of any byte in the file can be computed
+//! from the extent list alone.
+
+use super::*;
+
+impl<B> FFAT<B>
+where B: ?Sized + ReadBlockDevice {
+    /// reads the complete extent list of the file starting at `head`
+    pub(super) fn read_extents(&self, head: usize) -> FsResult<Vec<Extent>> {
+        let mut extents = Vec::new();
+        let mut addr = head;
+        loop {
+            let table = ExtentTable::from_sector(&self.read_sector(addr)?)?;
+            for extent in table.extents.iter() {
+                if !self.is_data_extent(extent) {
+                    return Err(FsError::InvalidAddress);
+                }
+            }
+            extents.extend_from_slice(&table.extents);
+
+            match self.next_sector(addr)? {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+        Ok(extents)
+    }
+
+    /// whether the extent lies completely inside the data section
+    fn is_data_extent(&self, extent: &Extent) -> bool {
+        match extent.start.checked_add(extent.length) {
+            Some(end) => extent.length == 0 || (
+                self.sector_to_table_location(extent.start).is_some()
+                && self.sector_to_table_location(end - 1).is_some()),
+            None => false,
+        }
+    }
+}
+
+impl<B> FFAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// writes the extent list of the file starting at `head`,
+    /// allocating or freeing `Index` sectors as needed
+    pub(super) fn write_extents(&mut self, head: usize, extents: &[Extent]) -> FsResult<()> {
+        let mut addr = head;
+        let mut chunks = extents.chunks(extents_per_sector(self.block_size)).peekable();
+
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let table = ExtentTable { extents: chunk.to_vec() };
+            self.write_sector(addr, table.to_sector(self.block_size))?;
+
+            if chunks.peek().is_none() {
+                break;
+            }
+
+            let next = match self.next_sector(addr)? {
+                Some(next) => next,
+                None => {
+                    let next = self.allocate_sector()?;
+                    self.write_sector_meta(next, Sector {
+                        sector_type: SectorType::Index,
+                        size: 0,
+                        next: 0,
+                    })?;
+                    let mut meta = self.read_sector_meta(addr)?;
+                    meta.next = next;
+                    self.write_sector_meta(addr, meta)?;
+                    next
+                },
+            };
+            addr = next;
+        }
+
+        // free index sectors that are not needed anymore
+        let mut meta = self.read_sector_meta(addr)?;
+        if meta.next != 0 {
+            let unused = meta.next;
+            meta.next = 0;
+            self.write_sector_meta(addr, meta)?;
+            self.free_sectors(unused)?;
+        }
+
+        Ok(())
+    }
+
+    /// allocates a data sector for the file and appends it to its extents
+    pub(super) fn allocate_data_sector(&mut self, progress: &mut FileProgress) -> FsResult<usize> {
+        let sector = self.allocate_sector()?;
+        self.write_sector_meta(sector, Sector {
+            sector_type: SectorType::Data,
+            size: 0,
+            next: 0,
+        })?;
+        progress.push_sector(sector);
+        Ok(sector)
+    }
+
+    /// frees all data sectors of the file starting at `head` and empties its extent table
+    pub(super) fn clear_file_at_addr(&mut self, head: usize) -> FsResult<()> {
+        for extent in self.read_extents(head)? {
+            self.free_extent(extent)?;
+        }
+        self.write_extents(head, &[])?;
+
+        let mut meta = self.read_sector_meta(head)?;
+        meta.size = 0;
+        self.write_sector_meta(head, meta)
+    }
+
+    /// frees the data sectors of the file starting at `head` from its end,
+    /// at most `sectors_per_transaction` sectors per transaction
+    pub(super) fn release_file_data(&mut self, head: usize) -> FsResult<()> {
+        let step = self.sectors_per_transaction();
+        loop {
+            let done = self.transaction(|fs| {
+                let mut extents = fs.read_extents(head)?;
+                let last = match extents.pop() {
+                    Some(last) => last,
+                    None => return Ok(true),
+                };
+
+                let freed = last.length.min(step);
+                let kept = Extent { start: last.start, length: last.length - freed };
+                if kept.length > 0 {
+                    extents.push(kept);
+                }
+                fs.free_extent(Extent { start: kept.start + kept.length, length: freed })?;
+                fs.write_extents(head, &extents)?;
+
+                // the file is cut off after the sectors it still has
+                let sectors: usize = extents.iter().map(|extent| extent.length).sum();
+                let mut meta = fs.read_sector_meta(head)?;
+                meta.size = meta.size.min(sectors * fs.block_size);
+                fs.write_sector_meta(head, meta)?;
+                Ok(false)
+            })?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// frees the file starting at `head` including its extent table
+    pub(super) fn free_file(&mut self, head: usize) -> FsResult<()> {
+        for extent in self.read_extents(head)? {
+            self.free_extent(extent)?;
+        }
+        self.free_sectors(head)
+    }
+
+    /// links the sectors of the extent and frees them as one chain
+    fn free_extent(&mut self, extent: Extent) -> FsResult<()> {
+        if extent.length == 0 {
+            return Ok(());
+        }
+        for sector in extent.start..(extent.start + extent.length) {
+            let next = if sector + 1 < extent.start + extent.length { sector + 1 } else { 0 };
+            self.write_sector_meta(sector, Sector {
+                sector_type: SectorType::Data,
+                size: 0,
+                next,
+            })?;
+        }
+        self.free_sectors(extent.start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_devices::*;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    fn read_all(fs: &FFAT<OwnedDisk>, name: &str) -> Vec<u8> {
+        let mut progress = fs.open_read(path(name)).unwrap();
+        let mut content = vec![0u8; fs.file_size(path(name)).unwrap() + 1];
+        let mut len = 0;
+        loop {
+            match fs.read(&mut progress, &mut content[len..]).unwrap() {
+                0 => break,
+                read => len += read,
+            }
+        }
+        content.truncate(len);
+        content
+    }
+
+    #[test]
+    fn locate() {
+        let mut progress = FileProgress { head: 1, extents: Vec::new(), byte_offset: 0 };
+        for &sector in &[10, 11, 12, 20, 5, 6] {
+            progress.push_sector(sector);
+        }
+        assert_eq!(progress.extents, vec![
+            Extent { start: 10, length: 3 },
+            Extent { start: 20, length: 1 },
+            Extent { start: 5, length: 2 },
+        ]);
+        assert_eq!(progress.locate(0), Some((10, 3)));
+        assert_eq!(progress.locate(2), Some((12, 1)));
+        assert_eq!(progress.locate(3), Some((20, 1)));
+        assert_eq!(progress.locate(5), Some((6, 1)));
+        assert_eq!(progress.locate(6), None);
+    }
+
+    #[test]
+    fn fragmented_files() {
+        let options = FormatOptions { block_size: MEMORY_BLOCK_SIZE, label: Vec::new() };
+        let mut fs = match FFAT::format_with(Box::new(OwnedDisk { data: vec![0u8; 512 * 2048] }), &options) {
+            Ok(fs) => fs,
+            Err(_) => panic!("format failed"),
+        };
+        let free = fs.statfs().unwrap().free_blocks;
+
+        // writing two files in turns interleaves their sectors, so each sector is an extent and
+        // the extent tables need index sectors
+        let a: Vec<u8> = (0..200 * 512 + 123u32).map(|i| (i / 7) as u8).collect();
+        let b: Vec<u8> = (0..200 * 512 + 5u32).map(|i| (i / 3) as u8).collect();
+        fs.create_file(path("/a")).unwrap();
+        fs.create_file(path("/b")).unwrap();
+        let mut write_a = fs.open_write(path("/a")).unwrap();
+        let mut write_b = fs.open_write(path("/b")).unwrap();
+        for (chunk_a, chunk_b) in a.chunks(512).zip(b.chunks(512)) {
+            fs.write(&mut write_a, chunk_a).unwrap();
+            fs.write(&mut write_b, chunk_b).unwrap();
+        }
+        assert!(fs.read_extents(write_a.0.head).unwrap().len() > extents_per_sector(512));
+
+        for &(name, content) in &[("/a", &a), ("/b", &b)] {
+            assert_eq!(&read_all(&fs, name), content);
+            let mut progress = fs.open_read(path(name)).unwrap();
+            fs.seek(&mut progress, 100_003).unwrap();
+            let mut buffer = [0u8; 1000];
+            let read = fs.read(&mut progress, &mut buffer).unwrap();
+            assert_eq!(&buffer[..read], &content[100_003..100_003 + read]);
+        }
+
+        // rewriting and deleting return all data and index sectors
+        let mut write_a = fs.open_write(path("/a")).unwrap();
+        fs.write(&mut write_a, b"short").unwrap();
+        assert_eq!(read_all(&fs, "/a"), b"short");
+        fs.delete(path("/b")).unwrap();
+        fs.delete(path("/a")).unwrap();
+        assert_eq!(fs.statfs().unwrap().free_blocks, free);
+    }
+}
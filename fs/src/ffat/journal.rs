@@ -0,0 +1,384 @@
+//! Write-ahead journal for FFAT metadata.
+//!
+//! Every operation that changes metadata (the allocation table, the root sector and directory
+//! data) runs inside a transaction. Sectors written during a transaction are buffered in memory
+//! and only reach the device on commit:
+//!
+//! 1. the buffered sectors are copied into the journal region
+//! 2. the journal header is written, which is the commit point
+//! 3. the sectors are written to their home locations (checkpoint)
+//! 4. the journal header is cleared
+//!
+//! If the system goes down between 2 and 4 the transaction is replayed at the next mount,
+//! if it goes down before 2 the transaction is lost as a whole.
+//! File content is not journaled, it is written directly before the metadata that references it
+//! is committed.
+//!
+//! A transaction that changes more sectors than the journal holds fails with `NotEnoughSpace`
+//! before anything is written. Writes and deletes of large files are therefore split into steps
+//! of `sectors_per_transaction` sectors, each step is atomic and leaves a consistent (partly
+//! written or partly truncated) file behind, but the operation as a whole is not.
+
+use super::*;
+
+impl<B> FFAT<B>
+where B: ?Sized + ReadBlockDevice {
+    /// reads a sector, taking writes of the running transaction into account
+    pub(super) fn read_sector(&self, addr: usize) -> FsResult<Vec<u8>> {
+        if let Some(Some(data)) = self.transaction.as_ref().map(|t| t.get(&addr)) {
+            Ok(data.clone())
+        } else {
+            let mut data = vec![0u8; self.block_size];
+            self.read_raw(addr, &mut data)?;
+            Ok(data)
+        }
+    }
+
+    /// maximum number of sectors a single journal commit can hold
+    fn journal_capacity(&self) -> usize {
+        if self.journal_sectors < 2 {
+            0
+        } else {
+            (self.journal_sectors - 1).min(journal_targets(self.block_size))
+        }
+    }
+
+    /// number of data sectors a single step of a large write or delete may allocate or free,
+    /// the rest of the journal is left for the allocation table, root and extent sectors
+    pub(super) fn sectors_per_transaction(&self) -> usize {
+        match self.journal_capacity() {
+            0 => usize::MAX,
+            capacity => (capacity / 4).max(1),
+        }
+    }
+}
+
+impl<B> FFAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// writes a metadata sector as part of the running transaction
+    /// or directly if there is none
+    pub(super) fn write_sector(&mut self, addr: usize, data: Vec<u8>) -> FsResult<()> {
+        let capacity = self.journal_capacity();
+        if let Some(transaction) = self.transaction.as_mut() {
+            if !transaction.contains_key(&addr) && transaction.len() >= capacity {
+                // the transaction does not fit into the journal, committing a part of it would
+                // leave half an operation on the device, so the whole operation fails
+                return Err(FsError::NotEnoughSpace);
+            }
+            transaction.insert(addr, data);
+            Ok(())
+        } else {
+            self.write_raw(addr, &data)
+        }
+    }
+
+    /// writes file content, which is never journaled
+    pub(super) fn write_data(&mut self, addr: usize, data: &[u8]) -> FsResult<()> {
+        self.write_raw(addr, data)
+    }
+
+    /// runs `operation` as one transaction,
+    /// its metadata changes are either all written or none of them are
+    pub(super) fn transaction<R, F>(&mut self, operation: F) -> FsResult<R>
+        where F: FnOnce(&mut Self) -> FsResult<R>
+    {
+        if self.transaction.is_some() || self.journal_capacity() == 0 {
+            // nested transactions are part of the outer one
+            return operation(self);
+        }
+
+        self.transaction = Some(BTreeMap::new());
+        let result = operation(self);
+        let blocks = self.transaction.take().unwrap();
+
+        // on error the buffered changes are simply dropped
+        let result = result?;
+        self.commit(blocks)?;
+        Ok(result)
+    }
+
+    /// Writes the sectors to the journal, then to their home locations.
+    /// The device is flushed between the steps, so that none of them reaches the disk before the
+    /// one it depends on, and file content written before the commit is persistent with it.
+    fn commit(&mut self, blocks: BTreeMap<usize, Vec<u8>>) -> FsResult<()> {
+        if blocks.is_empty() {
+            return Ok(());
+        }
+
+        // the copies follow the header, so they are written at once
+        let mut header = JournalHeader::default();
+        let mut copies = Vec::with_capacity(blocks.len() * self.block_size);
+        for (addr, data) in blocks.iter() {
+            copies.extend_from_slice(data);
+            header.targets.push(*addr);
+            header.checksum = checksum(header.checksum, data);
+        }
+        header.magic = JOURNAL_MAGIC;
+        self.write_raw(self.journal_begin + 1, &copies)?;
+        self.dev.flush()?;
+
+        // commit point
+        self.write_raw(self.journal_begin, &header.to_sector(self.block_size))?;
+        self.dev.flush()?;
+
+        for (addr, data) in blocks.iter() {
+            self.write_raw(*addr, data)?;
+        }
+        self.dev.flush()?;
+
+        self.write_raw(self.journal_begin, &JournalHeader::default().to_sector(self.block_size))
+    }
+
+    /// replays a committed but not yet checkpointed transaction
+    pub(super) fn replay_journal(&mut self) -> FsResult<()> {
+        if self.journal_capacity() == 0 {
+            return Ok(());
+        }
+
+        let mut sector = vec![0u8; self.block_size];
+        self.read_raw(self.journal_begin, &mut sector)?;
+        let header = JournalHeader::from_sector(&sector)?;
+
+        if header.magic != JOURNAL_MAGIC {
+            return Ok(());
+        }
+        if header.targets.len() > self.journal_capacity() {
+            return Err(FsError::InvalidSuperBlock);
+        }
+
+        let mut copies = vec![0u8; header.targets.len() * self.block_size];
+        self.read_raw(self.journal_begin + 1, &mut copies)?;
+        let mut blocks = Vec::with_capacity(header.targets.len());
+        let mut sum = 0;
+        for (target, data) in header.targets.iter().zip(copies.chunks(self.block_size)) {
+            sum = checksum(sum, data);
+            blocks.push((*target, data.to_vec()));
+        }
+
+        // a mismatch means the journal copies are damaged, the transaction can't be trusted
+        if sum == header.checksum {
+            for (addr, data) in blocks {
+                if addr >= self.sectors() {
+                    return Err(FsError::InvalidAddress);
+                }
+                self.write_raw(addr, &data)?;
+            }
+            self.dev.flush()?;
+        }
+
+        self.write_raw(self.journal_begin, &JournalHeader::default().to_sector(self.block_size))
+    }
+}
+
+/// FNV-1a over the sector data, chained with the previous checksum
+fn checksum(init: u64, data: &[u8]) -> u64 {
+    let mut hash = init ^ 0xcbf2_9ce4_8422_2325;
+    for &byte in data {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_devices::*;
+
+    /// disk that fails all writes after `budget` blocks were written, like a disk losing power
+    struct Crashing {
+        disk: OwnedDisk,
+        budget: usize,
+    }
+
+    impl BlockDevice for Crashing {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn blocks(&self) -> usize {
+            self.disk.blocks()
+        }
+    }
+
+    impl ReadBlockDevice for Crashing {
+        fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+            self.disk.read_block(index, buffer)
+        }
+    }
+
+    impl WriteBlockDevice for Crashing {
+        fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+            if self.budget == 0 {
+                return Err(FsError::BlockDeviceError);
+            }
+            self.budget -= 1;
+            self.disk.write_block(index, buffer)
+        }
+    }
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    fn mount<B: RWBlockDevice>(dev: B) -> FFAT<B> {
+        match FFAT::mount(Box::new(dev)) {
+            Ok(fs) => fs,
+            Err(_) => panic!("mount failed"),
+        }
+    }
+
+    /// disk with a directory of files, formatted with sectors of the size of a disk block
+    fn formatted() -> OwnedDisk {
+        let options = FormatOptions { block_size: MEMORY_BLOCK_SIZE, label: Vec::new() };
+        let mut fs = match FFAT::format_with(Box::new(OwnedDisk { data: vec![0u8; 512 * 512] }), &options) {
+            Ok(fs) => fs,
+            Err(_) => panic!("format failed"),
+        };
+        fs.create_dir(path("/a")).unwrap();
+        for i in 0..20 {
+            fs.create_file(path(&format!("/a/file{}", i))).unwrap();
+        }
+        *fs.dev
+    }
+
+    #[test]
+    fn crash_anywhere() {
+        let base = formatted();
+        let (mut saw_old, mut saw_new) = (false, false);
+        for budget in 0..64 {
+            let mut fs = mount(Crashing { disk: OwnedDisk { data: base.data.clone() }, budget });
+            let renamed = fs.rename(path("/a/file3"), path("/moved")).is_ok();
+
+            // the rename is either there as a whole or not at all
+            let fs = mount(fs.dev.disk);
+            let dir = fs.read_dir(path("/a")).unwrap();
+            let moved = fs.exists_file(path("/moved")).unwrap();
+            assert_eq!(moved, !dir.contains(&b"file3".to_vec()), "budget {}", budget);
+            assert_eq!(dir.len(), 20 - moved as usize);
+            if renamed {
+                assert!(moved);
+            }
+            saw_old |= !moved;
+            saw_new |= moved;
+        }
+        assert!(saw_old && saw_new);
+    }
+
+    #[test]
+    fn replay_committed() {
+        let base = formatted();
+        let mut fs = mount(OwnedDisk { data: base.data.clone() });
+        fs.transaction = Some(BTreeMap::new());
+        fs.rename(path("/a/file3"), path("/moved")).unwrap();
+        let transaction = fs.transaction.take().unwrap();
+
+        // crash right after the commit point, before any sector reached its home location
+        let mut fs = mount(Crashing { disk: *fs.dev, budget: transaction.len() + 1 });
+        assert!(fs.commit(transaction).is_err());
+        let fs = mount(fs.dev.disk);
+        assert!(fs.exists_file(path("/moved")).unwrap());
+        assert!(!fs.exists_file(path("/a/file3")).unwrap());
+
+        // the journal was cleared by the replay
+        let mut header = vec![0u8; fs.block_size];
+        fs.read_raw(fs.journal_begin, &mut header).unwrap();
+        assert!(JournalHeader::from_sector(&header).unwrap().magic != JOURNAL_MAGIC);
+    }
+
+    #[test]
+    fn damaged_journal_is_discarded() {
+        let base = formatted();
+        let mut fs = mount(OwnedDisk { data: base.data.clone() });
+        fs.transaction = Some(BTreeMap::new());
+        fs.rename(path("/a/file3"), path("/moved")).unwrap();
+        let transaction = fs.transaction.take().unwrap();
+
+        let journal_begin = fs.journal_begin;
+        let mut fs = mount(Crashing { disk: *fs.dev, budget: transaction.len() + 1 });
+        assert!(fs.commit(transaction).is_err());
+        let mut disk = fs.dev.disk;
+        disk.data[(journal_begin + 1) * 512] ^= 0xff;
+
+        let fs = mount(disk);
+        assert!(!fs.exists_file(path("/moved")).unwrap());
+        assert!(fs.exists_file(path("/a/file3")).unwrap());
+    }
+
+    #[test]
+    fn oversized_transaction() {
+        let mut fs = mount(formatted());
+        let capacity = fs.journal_capacity();
+        let result = fs.transaction(|fs| {
+            for addr in 0..capacity + 1 {
+                fs.write_sector(addr, vec![0xff; fs.block_size])?;
+            }
+            Ok(())
+        });
+        match result {
+            Err(FsError::NotEnoughSpace) => (),
+            _ => panic!("the transaction must not fit into the journal"),
+        }
+        // nothing was written
+        assert_eq!(fs.read_dir(path("/a")).unwrap().len(), 20);
+    }
+
+    /// disk that records the sectors written and the flushes, `None` for a flush
+    struct Recording {
+        disk: OwnedDisk,
+        log: Vec<Option<usize>>,
+    }
+
+    impl BlockDevice for Recording {
+        fn block_size(&self) -> usize {
+            self.disk.block_size()
+        }
+
+        fn blocks(&self) -> usize {
+            self.disk.blocks()
+        }
+    }
+
+    impl ReadBlockDevice for Recording {
+        fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+            self.disk.read_block(index, buffer)
+        }
+    }
+
+    impl WriteBlockDevice for Recording {
+        fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+            self.log.push(Some(index));
+            self.disk.write_block(index, buffer)
+        }
+
+        fn flush(&mut self) -> FsResult<()> {
+            self.log.push(None);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flushed_between_steps() {
+        let mut fs = mount(Recording { disk: formatted(), log: Vec::new() });
+        fs.rename(path("/a/file3"), path("/moved")).unwrap();
+        let journal_begin = fs.journal_begin;
+        let log = &fs.dev.log;
+
+        // journal copies, flush, header, flush, home locations, flush, cleared header
+        let header = log.iter().position(|&entry| entry == Some(journal_begin)).unwrap();
+        assert!(log[..header - 1].iter().all(|&entry| entry.map_or(false, |addr| addr > journal_begin)));
+        assert_eq!(log[header - 1], None);
+        assert_eq!(log[header + 1], None);
+        assert_eq!(log[log.len() - 2], None);
+        assert_eq!(log[log.len() - 1], Some(journal_begin));
+        assert!(log[header + 2..log.len() - 2].iter().all(|entry| entry.is_some()));
+    }
+
+    #[test]
+    fn checksum_order() {
+        let (a, b) = ([1u8; 16], [2u8; 16]);
+        assert_eq!(checksum(checksum(0, &a), &b), checksum(checksum(0, &a), &b));
+        assert!(checksum(checksum(0, &a), &b) != checksum(checksum(0, &b), &a));
+        assert!(checksum(0, &a) != checksum(0, &[1u8; 15]));
+    }
+}
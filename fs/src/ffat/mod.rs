@@ -3,6 +3,7 @@ use alloc::vec::*;
 use alloc::string::*;
 use alloc::boxed::*;
 use alloc::*;
+use alloc::collections::BTreeMap;
 
 use crate::error::*;
 use crate::block::*;
@@ -10,18 +11,118 @@ use crate::filesystem::*;
 use crate::copy::*;
 
 mod structs;
+mod journal;
+mod extents;
 use structs::*;
 
-pub const BLOCK_SIZE: usize = 4096;
-const FAT_ENTRIES_PER_SECTOR: usize = BLOCK_SIZE / 32;
+pub use structs::LABEL_LEN;
+
+/// Smallest supported sector size
+pub const MIN_BLOCK_SIZE: usize = 512;
+/// Largest supported sector size
+pub const MAX_BLOCK_SIZE: usize = 64 * 1024;
+/// Sector size used by `format`
+pub const DEFAULT_BLOCK_SIZE: usize = 4096;
+const NAME: &str = "FFAT v0.1";
+/// Smallest journal, enough for the metadata operations on directories of a few sectors
+const MIN_JOURNAL_SECTORS: usize = 16;
 
 pub struct FFAT<B: ?Sized + BlockDevice> {
     dev: Box<B>,
+    /// size of a sector in bytes, a multiple of the device block size
+    block_size: usize,
+    /// first sector of the journal
+    journal_begin: usize,
+    /// number of sectors of the journal
+    journal_sectors: usize,
+    /// metadata sectors written by the running transaction, if there is one
+    transaction: Option<BTreeMap<usize, Vec<u8>>>,
+}
+
+/// Options for formatting a device with FFAT
+#[derive(Clone, Debug)]
+pub struct FormatOptions {
+    /// size of a sector in bytes, a power of two between `MIN_BLOCK_SIZE` and `MAX_BLOCK_SIZE`
+    /// that is a multiple of the device block size
+    pub block_size: usize,
+    /// volume label, at most `LABEL_LEN` bytes
+    pub label: Vec<u8>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        Self {
+            block_size: DEFAULT_BLOCK_SIZE,
+            label: Vec::new(),
+        }
+    }
+}
+
+impl FormatOptions {
+    /// checks if the options can be used to format `dev`
+    pub fn validate<B: ?Sized + BlockDevice>(&self, dev: &B) -> FsResult<()> {
+        let block_size = self.block_size;
+        if !block_size.is_power_of_two() || block_size < MIN_BLOCK_SIZE || block_size > MAX_BLOCK_SIZE {
+            return Err(FsError::IllegalOperation(format!(
+                "block size must be a power of two between {} and {}", MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)));
+        }
+        if dev.block_size() == 0 || block_size % dev.block_size() != 0 {
+            return Err(FsError::IllegalOperation(format!(
+                "block size must be a multiple of the device block size {}", dev.block_size())));
+        }
+        if self.label.len() > LABEL_LEN {
+            return Err(FsError::IllegalOperation(format!(
+                "label must not be longer than {} bytes", LABEL_LEN)));
+        }
+        if Layout::new(dev.blocks() / (block_size / dev.block_size()), block_size).is_none() {
+            return Err(FsError::NotEnoughSpace);
+        }
+        Ok(())
+    }
+}
+
+/// Position of the different regions on a device
+struct Layout {
+    sectors: usize,
+    fat_sectors: usize,
+    journal_begin: usize,
+    journal_sectors: usize,
+    /// first sector after the journal, which holds the root directory
+    data_begin: usize,
+    /// number of sectors in the free list after formatting
+    free_sectors: usize,
+}
+
+impl Layout {
+    /// computes the layout for a device with `sectors` sectors,
+    /// `None` if the device is too small
+    fn new(sectors: usize, block_size: usize) -> Option<Self> {
+        let fat_entries_per_sector = block_size / FAT_ENTRY_SIZE;
+        // one entry for each sector of the device
+        let fat_sectors = (sectors + fat_entries_per_sector - 1) / fat_entries_per_sector;
+        let journal_sectors = (sectors / 32).max(MIN_JOURNAL_SECTORS).min(journal_targets(block_size) + 1);
+        let journal_begin = fat_sectors + 1;
+        let data_begin = journal_begin + journal_sectors; // root sector + the file allocation table + the journal
+
+        // the root directory and at least one free sector are needed
+        if data_begin + 2 > sectors {
+            return None;
+        }
+
+        Some(Self {
+            sectors,
+            fat_sectors,
+            journal_begin,
+            journal_sectors,
+            data_begin,
+            free_sectors: sectors - data_begin - 1,
+        })
+    }
 }
 
 impl<B: ?Sized + RWBlockDevice> MountedFileSystem<B> for FFAT<B> {
     fn name() -> &'static str {
-        "FFAT v0.1"
+        NAME
     }
 
     fn inner(self) -> Box<B> {
@@ -29,30 +130,76 @@ impl<B: ?Sized + RWBlockDevice> MountedFileSystem<B> for FFAT<B> {
     }
 
     fn mount(dev: Box<B>) -> Result<Self, Box<B>> {
-        Ok(Self {
-            dev: dev,
-        })
+        // the root sector fits into the first device block, whatever the sector size is
+        let mut first_block = vec![0u8; dev.block_size()];
+        if first_block.len() < ROOT_SECTOR_SIZE || dev.read_block(0, &mut first_block).is_err() {
+            return Err(dev);
+        }
+        let root_sector = match RootSector::decode(&first_block) {
+            Ok(root_sector) => root_sector,
+            Err(_) => return Err(dev),
+        };
+
+        let block_size = root_sector.block_size;
+        if block_size % dev.block_size() != 0
+            || root_sector.sectors > dev.blocks() / (block_size / dev.block_size()) {
+            return Err(dev);
+        }
+
+        let mut fs = Self {
+            dev,
+            block_size,
+            journal_begin: root_sector.journal_begin,
+            journal_sectors: root_sector.journal_sectors,
+            transaction: None,
+        };
+
+        // finish a transaction that was interrupted after it had been committed
+        match fs.replay_journal() {
+            Ok(_) => Ok(fs),
+            Err(_) => Err(fs.dev),
+        }
     }
 
     fn format(dev: Box<B>) -> Result<Self, Box<B>> {
-        if dev.blocks() < 8 {
+        Self::format_with(dev, &FormatOptions::default())
+    }
+}
+
+impl<B: ?Sized + RWBlockDevice> FFAT<B> {
+    /// formats the given BlockDevice with the given options
+    pub fn format_with(dev: Box<B>, options: &FormatOptions) -> Result<Self, Box<B>> {
+        if options.validate(&*dev).is_err() {
             return Err(dev);
         }
 
-        let mut dev = dev;
+        let block_size = options.block_size;
+        let layout = match Layout::new(dev.blocks() / (block_size / dev.block_size()), block_size) {
+            Some(layout) => layout,
+            None => return Err(dev),
+        };
+
+        let mut fs = Self {
+            dev,
+            block_size,
+            journal_begin: layout.journal_begin,
+            journal_sectors: layout.journal_sectors,
+            transaction: None,
+        };
 
-        let sectors = dev.blocks();
-        assert!(core::mem::size_of::<Sector>() == 32);
-        let fat_entry_size = 32;
-        let fat_entries_per_sector = BLOCK_SIZE / fat_entry_size;
+        match fs.write_layout(&layout, &options.label) {
+            Ok(_) => Ok(fs),
+            Err(_) => Err(fs.dev),
+        }
+    }
 
-        let mut fat_sectors = sectors / fat_entries_per_sector;
-        if BLOCK_SIZE * fat_sectors < sectors { fat_sectors += 1; } 
-        let fat_sectors = fat_sectors;
-        let free_sectors = sectors - fat_sectors - 2;
-        let data_begin = fat_sectors + 1; // root sector + the file allocation table
+    /// writes the allocation table, the journal, the root sector and the root directory
+    fn write_layout(&mut self, layout: &Layout, label: &[u8]) -> FsResult<()> {
+        let fat_entries_per_sector = self.block_size / FAT_ENTRY_SIZE;
+        let data_begin = layout.data_begin;
+        let free_sectors = layout.free_sectors;
 
-        let mut fat_table = Vec::with_capacity(sectors as usize);
+        let mut fat_table = Vec::with_capacity(layout.fat_sectors * fat_entries_per_sector);
 
         let reserved_fat_entry = Sector {
             sector_type: SectorType::Reserved, 
@@ -60,8 +207,8 @@ impl<B: ?Sized + RWBlockDevice> MountedFileSystem<B> for FFAT<B> {
             next: 0,
         };
 
-        // push a reserved entry for each fat-table sector and one for the root sector
-        for _ in 0..(fat_sectors+1) {
+        // push a reserved entry for each fat-table sector, each journal sector and one for the root sector
+        for _ in 0..data_begin {
             fat_table.push(reserved_fat_entry);
         }
 
@@ -83,45 +230,76 @@ impl<B: ?Sized + RWBlockDevice> MountedFileSystem<B> for FFAT<B> {
         }
 
         // pad fat table entries with reserved sectors (which are outside of the dev)
-        while fat_table.len() % (BLOCK_SIZE as usize / fat_entry_size as usize) > 0 {
+        while fat_table.len() < layout.fat_sectors * fat_entries_per_sector {
             fat_table.push(reserved_fat_entry);
         }
 
         // write the fat table to the dev
-        for i in 0..fat_sectors {
-            let mut table = AllocationTable::default();
-            copy_offset(&fat_table, &mut table.entries, fat_entries_per_sector as usize, (i * fat_entries_per_sector) as usize, 0);
-            let res = dev.write(1 + i, &table);
-            if let Err(_) = res {
-                return Err(dev);
+        for (i, entries) in fat_table.chunks(fat_entries_per_sector).enumerate() {
+            let mut table = vec![0u8; self.block_size];
+            for (j, entry) in entries.iter().enumerate() {
+                entry.encode(&mut table[j * FAT_ENTRY_SIZE..]);
             }
+            self.write_raw(1 + i, &table)?;
         }
 
+        // write an empty journal to the dev
+        self.write_raw(layout.journal_begin, &JournalHeader::default().to_sector(self.block_size))?;
+
         // write the root sector to the dev
+        let mut name = [0u8; LABEL_LEN];
+        copy(label, &mut name, label.len());
         let root_sector = RootSector {
-            name: [b'X'; 64],
+            name,
+            block_size: self.block_size,
             table_begin: 1,
-            sectors,
+            sectors: layout.sectors,
             root: data_begin,
             free: data_begin+1,
+            free_sectors,
+            journal_begin: layout.journal_begin,
+            journal_sectors: layout.journal_sectors,
         };
-        let res = dev.write(0usize, &root_sector);
+        self.write_root_sector(&root_sector)?;
 
-        if let Err(_) = res {
-            return Err(dev);
-        }
+        self.write_dir_at_addr(root_sector.root, &Vec::new())
+    }
+}
 
-        let mut fs = Self { 
-            dev,
-        };
+impl<B> FFAT<B>
+where B: ?Sized + ReadBlockDevice {
+    /// size of a sector in bytes
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// volume label
+    pub fn label(&self) -> FsResult<Vec<u8>> {
+        let name = self.root_sector()?.name;
+        let len = name.iter().position(|&c| c == 0).unwrap_or(LABEL_LEN);
+        Ok(name[..len].to_vec())
+    }
 
-        let res = fs.write_dir_at_addr(root_sector.root, &Vec::new());
+    /// number of sectors on the device
+    fn sectors(&self) -> usize {
+        self.dev.blocks() / self.dev_blocks_per_sector()
+    }
 
-        if let Err(_) = res {
-            Err(fs.dev)
-        } else {
-            Ok(fs)
-        }
+    fn dev_blocks_per_sector(&self) -> usize {
+        self.block_size / self.dev.block_size()
+    }
+
+    /// reads whole consecutive sectors starting at `addr` directly from the device
+    fn read_raw(&self, addr: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.dev.read_blocks(addr * self.dev_blocks_per_sector(), buffer)
+    }
+}
+
+impl<B> FFAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// writes whole consecutive sectors starting at `addr` directly to the device
+    fn write_raw(&mut self, addr: usize, buffer: &[u8]) -> FsResult<()> {
+        self.dev.write_blocks(addr * self.dev_blocks_per_sector(), buffer)
     }
 }
 
@@ -142,6 +320,16 @@ where B: ?Sized + ReadBlockDevice
     fn exists_file(&self, path: Path) -> FsResult<bool> {
         self.exists(&path, SectorType::File)
     }
+
+    fn statfs(&self) -> FsResult<stats::FsStats> {
+        let root_sector = self.root_sector()?;
+        Ok(stats::FsStats::new(
+            NAME,
+            root_sector.sectors as u64,
+            root_sector.free_sectors as u64,
+            self.block_size as u64,
+        ))
+    }
 }
 
 impl<B> ReadFileSystem for FFAT<B>
@@ -160,41 +348,38 @@ where B: ?Sized + ReadBlockDevice
         let ReadProgress(progress, file_size) = progress;
         let file_size = *file_size;
 
-        if progress.sector == 0 {
-            return Ok(0usize);
-        }
-
         let mut buffer_idx = 0;
 
-        let mut buf = [0u8; BLOCK_SIZE];
+        let block_size = self.block_size;
 
         while buffer_idx < buffer.len() && progress.byte_offset < file_size {
-            let bytes_from_sector_start = progress.byte_offset as usize % BLOCK_SIZE;
-            let bytes_to_sector_end = BLOCK_SIZE - bytes_from_sector_start;
+            let (sector, contiguous) = match progress.locate(progress.byte_offset / block_size) {
+                Some(location) => location,
+                None => return Err(FsError::InternalError(String::from("File data ended preemptively"))),
+            };
+
+            let bytes_from_sector_start = progress.byte_offset as usize % block_size;
             let bytes_to_buffer_end = buffer.len() - buffer_idx;
             let bytes_to_file_end = file_size - progress.byte_offset;
-            let read_bytes = bytes_to_sector_end.min(bytes_to_buffer_end).min(bytes_to_file_end as usize);
 
-            self.dev.read(progress.sector, &mut buf)?;
-            copy_offset(&buf, buffer, read_bytes, bytes_from_sector_start, buffer_idx);
+            let read_bytes = if bytes_from_sector_start == 0 && bytes_to_buffer_end >= block_size && bytes_to_file_end >= block_size {
+                // read whole sectors of the extent straight into the buffer
+                let sectors = contiguous
+                    .min(bytes_to_buffer_end / block_size)
+                    .min(bytes_to_file_end / block_size);
+                let len = sectors * block_size;
+                self.read_raw(sector, &mut buffer[buffer_idx..buffer_idx + len])?;
+                len
+            } else {
+                let bytes_to_sector_end = block_size - bytes_from_sector_start;
+                let read_bytes = bytes_to_sector_end.min(bytes_to_buffer_end).min(bytes_to_file_end);
+                let buf = self.read_sector(sector)?;
+                copy_offset(&buf, buffer, read_bytes, bytes_from_sector_start, buffer_idx);
+                read_bytes
+            };
 
             buffer_idx += read_bytes;
-
-            let offset = progress.byte_offset as usize;
-            if offset / BLOCK_SIZE < (offset + read_bytes) / BLOCK_SIZE {
-                // arrived at next sector
-                if let Some(next) = self.next_sector(progress.sector)? {
-                    // there is a next sector
-                    progress.sector = next;
-                } else {
-                    // at end of file, return number of read bytes
-                    progress.sector = 0;
-                    progress.byte_offset += read_bytes as usize;
-                    return Ok(buffer_idx as usize);
-                }
-            }
-
-            progress.byte_offset += read_bytes as usize;
+            progress.byte_offset += read_bytes;
         }
 
         Ok(buffer_idx as usize)
@@ -204,6 +389,15 @@ where B: ?Sized + ReadBlockDevice
         progress.0.byte_offset += seeking;
         Ok(())
     }
+
+    fn file_size(&self, path: Path) -> FsResult<usize> {
+        let addr = self.walk(&path)?;
+        let meta = self.read_sector_meta(addr)?;
+        match meta.sector_type {
+            SectorType::File => Ok(meta.size),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
 }
 
 impl<B> WriteFileSystem for FFAT<B>
@@ -217,58 +411,106 @@ where B: ?Sized + RWBlockDevice
         Ok(WriteProgress(self.open(&path)?))
     }
 
+    fn open_update(&mut self, path: Path, offset: usize) -> FsResult<WriteProgress> {
+        self.exists(&path, SectorType::File)?;
+        let mut progress = self.open(&path)?;
+        // the sectors between the end of the file and the offset would be missing
+        if offset > self.read_sector_meta(progress.head)?.size {
+            return Err(FsError::IllegalOperation(String::from("offset is past the end of the file")));
+        }
+        progress.byte_offset = offset;
+        Ok(WriteProgress(progress))
+    }
+
     fn write(&mut self, progress: &mut WriteProgress, buffer: &[u8]) -> FsResult<()> {
-        let progress = &mut progress.0;
-        let initial_progress = progress.byte_offset;
+        // a large write is split into transactions that each fit into the journal
+        let step = self.sectors_per_transaction().saturating_mul(self.block_size);
+        for chunk in buffer.chunks(step) {
+            let mut next = progress.0.clone();
+            self.transaction(|fs| fs.write_at_progress(&mut next, chunk))?;
+            progress.0 = next;
+        }
+        Ok(())
+    }
+}
 
+impl<B> FFAT<B>
+where B: ?Sized + RWBlockDevice
+{
+    /// writes `buffer` to the file at the current write progress, overwriting the sectors the file
+    /// already has and appending new ones after its end
+    fn write_at_progress(&mut self, progress: &mut FileProgress, buffer: &[u8]) -> FsResult<()> {
         let mut buffer_idx = 0;
-        let mut buf = [0u8; BLOCK_SIZE];
+        let block_size = self.block_size;
+        // whole sectors at consecutive addresses are written at once:
+        // first sector, its index in the buffer and number of sectors
+        let mut run: Option<(usize, usize, usize)> = None;
 
         while buffer_idx < buffer.len() {
-            let bytes_from_sector_start = progress.byte_offset as usize % BLOCK_SIZE;
-            let bytes_to_sector_end = BLOCK_SIZE - bytes_from_sector_start;
+            let bytes_from_sector_start = progress.byte_offset as usize % block_size;
+            let bytes_to_sector_end = block_size - bytes_from_sector_start;
             let bytes_to_buffer_end = buffer.len() - buffer_idx;
             let write_bytes = bytes_to_sector_end.min(bytes_to_buffer_end);
 
-            self.dev.read(progress.sector, &mut buf)?;
-            copy_offset(buffer, &mut buf, write_bytes, buffer_idx, bytes_from_sector_start);
-            self.dev.write(progress.sector, &buf)?;
-
-            buffer_idx += write_bytes;
-
-            let offset = progress.byte_offset as usize;
-
-            if offset / BLOCK_SIZE < (offset + write_bytes) / BLOCK_SIZE {
-                // need new sector for next data
-                let new_sector = self.allocate_sector()?;
-
-                // write new sector metadata
-                let new_meta = Sector {
-                    sector_type: SectorType::Data,
-                    size: 0,
-                    next: 0,
-                };
-                self.write_sector_meta(new_sector, new_meta)?;
-
-                // link previous data block with this one
-                let mut old_meta = self.read_sector_meta(progress.sector)?;
-                old_meta.next = new_sector;
-                self.write_sector_meta(progress.sector, old_meta)?;
-
-                progress.sector = new_sector;
+            match progress.locate(progress.byte_offset / block_size) {
+                Some((sector, _)) if write_bytes == block_size => {
+                    run = self.extend_run(run, sector, buffer_idx, buffer)?;
+                },
+                Some((sector, _)) => {
+                    // fill up or overwrite part of a sector of the file
+                    let mut buf = self.read_sector(sector)?;
+                    copy_offset(buffer, &mut buf, write_bytes, buffer_idx, bytes_from_sector_start);
+                    self.write_data(sector, &buf)?;
+                },
+                None if bytes_from_sector_start == 0 => {
+                    // need new sector for next data
+                    let sector = self.allocate_data_sector(progress)?;
+                    if write_bytes == block_size {
+                        run = self.extend_run(run, sector, buffer_idx, buffer)?;
+                    } else {
+                        let mut buf = vec![0u8; block_size];
+                        copy_offset(buffer, &mut buf, write_bytes, buffer_idx, 0);
+                        self.write_data(sector, &buf)?;
+                    }
+                },
+                None => return Err(FsError::InternalError(String::from("File data ended preemptively"))),
             }
 
+            buffer_idx += write_bytes;
             progress.byte_offset += write_bytes as usize;
         }
+        self.write_run(run, buffer)?;
+
+        // update extents and size of file
+        self.write_extents(progress.head, &progress.extents)?;
 
-        // update size of file
-        let bytes_written = progress.byte_offset - initial_progress;
         let mut meta = self.read_sector_meta(progress.head)?;
-        meta.size += bytes_written;
+        meta.size = meta.size.max(progress.byte_offset);
         self.write_sector_meta(progress.head, meta)?;
 
         Ok(())
     }
+
+    /// adds the whole sector at `buffer_idx` to the run if it follows it,
+    /// otherwise writes the run and starts a new one with the sector
+    fn extend_run(&mut self, run: Option<(usize, usize, usize)>, sector: usize, buffer_idx: usize, buffer: &[u8])
+        -> FsResult<Option<(usize, usize, usize)>> {
+        match run {
+            Some((first, begin, count)) if first + count == sector => Ok(Some((first, begin, count + 1))),
+            _ => {
+                self.write_run(run, buffer)?;
+                Ok(Some((sector, buffer_idx, 1)))
+            },
+        }
+    }
+
+    /// writes the sectors of a run with a single write
+    fn write_run(&mut self, run: Option<(usize, usize, usize)>, buffer: &[u8]) -> FsResult<()> {
+        match run {
+            Some((sector, begin, count)) => self.write_data(sector, &buffer[begin..begin + count * self.block_size]),
+            None => Ok(()),
+        }
+    }
 }
 
 impl<B> ManageFileSystem for FFAT<B> 
@@ -280,14 +522,64 @@ where B: ?Sized + RWBlockDevice {
             size: 0, 
             next: 0,
         };
-        self.create(&path, meta)?;
-        Ok(())
+        self.transaction(|fs| {
+            let addr = fs.create(&path, meta)?;
+            fs.write_sector(addr, ExtentTable { extents: Vec::new() }.to_sector(fs.block_size))
+        })
     }
 
     fn create_dir(&mut self, path: Path) -> FsResult<()> {
+        self.transaction(|fs| fs.create_dir_in_transaction(path))
+    }
+
+    fn delete(&mut self, path: Path) -> FsResult<()> {
+        if path.name().is_none() {
+            return Err(FsError::IllegalOperation(String::from("Can't delete root")));
+        }
+        self.release(&path)?;
+        self.transaction(|fs| fs.delete_in_transaction(path))
+    }
+
+    fn clear(&mut self, path: Path) -> FsResult<()> {
+        self.release(&path)?;
+        self.transaction(|fs| fs.clear_in_transaction(path))
+    }
+
+    fn rename(&mut self, from: Path, to: Path) -> FsResult<()> {
+        self.transaction(|fs| fs.rename_in_transaction(from, to))
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        self.dev.flush()
+    }
+}
+
+impl<B> FFAT<B>
+where B: ?Sized + RWBlockDevice {
+    /// frees the data of a file or deletes the children of a directory in steps that each fit
+    /// into the journal, so removing the entry itself afterwards is a small transaction
+    fn release(&mut self, path: &Path) -> FsResult<()> {
+        let addr = self.walk(path)?;
+        match self.read_sector_meta(addr)?.sector_type {
+            SectorType::Dir => {
+                let children = self.read_dir_at_addr(addr)?
+                    .into_iter()
+                    .map(|entry| path.join(entry.1))
+                    .collect::<Result<Vec<Path>, _>>()?;
+                for child in children {
+                    self.delete(child)?;
+                }
+                Ok(())
+            },
+            SectorType::File => self.release_file_data(addr),
+            _ => Ok(()),
+        }
+    }
+
+    fn create_dir_in_transaction(&mut self, path: Path) -> FsResult<()> {
         // create an empty list of directories
         let dir_entries = DirData::new();
-        let (buffer, size) = raw_dir_data(&dir_entries);
+        let (buffer, size) = raw_dir_data(&dir_entries, self.block_size);
 
         let meta = Sector {
             sector_type: SectorType::Dir,
@@ -296,12 +588,12 @@ where B: ?Sized + RWBlockDevice {
         };
 
         let addr = self.create(&path, meta)?;
-        self.dev.write(addr, &buffer[0])?;
+        self.write_sector(addr, buffer[0].clone())?;
 
         Ok(())
     }
 
-    fn delete(&mut self, path: Path) -> FsResult<()> {
+    fn delete_in_transaction(&mut self, path: Path) -> FsResult<()> {
         let addr = self.walk(&path)?;
         let meta = self.read_sector_meta(addr)?;
 
@@ -328,7 +620,11 @@ where B: ?Sized + RWBlockDevice {
 
         if let Some((addr, _)) = child_addr {
             // free child sectors
-            self.free_sectors(*addr)?;
+            if meta.sector_type == SectorType::File {
+                self.free_file(*addr)?;
+            } else {
+                self.free_sectors(*addr)?;
+            }
 
             // remove child entry from parent
             let dir_data = dir_data.into_iter().filter(|entry| entry.1 != name).collect();
@@ -338,7 +634,7 @@ where B: ?Sized + RWBlockDevice {
         Ok(())
     }
 
-    fn clear(&mut self, path: Path) -> FsResult<()> {
+    fn clear_in_transaction(&mut self, path: Path) -> FsResult<()> {
         let addr = self.walk(&path)?;
         let meta = self.read_sector_meta(addr)?;
 
@@ -353,24 +649,64 @@ where B: ?Sized + RWBlockDevice {
                 self.write_dir_at_addr(addr, &dir_data)?;
             },
             SectorType::File => {
-                self.clear_at_addr(addr)?;
+                self.clear_file_at_addr(addr)?;
             },
-            SectorType::Data | 
+            SectorType::Index |
+                SectorType::Data | 
                 SectorType::Free | 
                 SectorType::Reserved => return Err(FsError::IllegalOperation(String::from("Can only clear files or directories"))),
         }
 
         Ok(())
     }
+
+    fn rename_in_transaction(&mut self, from: Path, to: Path) -> FsResult<()> {
+        let (from_name, from_parent) = match (from.name(), from.parent_dir()) {
+            (Some(name), Some(parent)) => (name, parent),
+            _ => return Err(FsError::IllegalOperation(String::from("Can't rename root"))),
+        };
+        let (to_name, to_parent) = match (to.name(), to.parent_dir()) {
+            (Some(name), Some(parent)) => (name, parent),
+            _ => return Err(FsError::IllegalOperation(String::from("Can't rename to root"))),
+        };
+        if to.clone().relative_to(from.clone()).is_some() {
+            return Err(FsError::IllegalOperation(String::from("Can't move a directory into itself")));
+        }
+
+        let from_parent_addr = self.walk(&from_parent)?;
+        let mut from_dir = self.read_dir_at_addr(from_parent_addr)?;
+        let position = from_dir.iter().position(|entry| entry.1 == from_name).ok_or(FsError::NotFound)?;
+
+        let to_parent_addr = self.walk(&to_parent)?;
+        let mut to_dir = if to_parent_addr == from_parent_addr {
+            from_dir.clone()
+        } else {
+            self.read_dir_at_addr(to_parent_addr)?
+        };
+        if to_dir.iter().any(|entry| entry.1 == to_name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (addr, _) = from_dir.remove(position);
+        if to_parent_addr == from_parent_addr {
+            from_dir.push((addr, to_name));
+            self.write_dir_at_addr(from_parent_addr, &from_dir)?;
+        } else {
+            to_dir.push((addr, to_name));
+            self.write_dir_at_addr(from_parent_addr, &from_dir)?;
+            self.write_dir_at_addr(to_parent_addr, &to_dir)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<B> FFAT<B>
 where B: ?Sized + ReadBlockDevice {
     fn read_sector_meta(&self, addr: usize) -> FsResult<Sector> {
         if let Some((table_addr, table_idx)) = self.sector_to_table_location(addr) {
-            let mut table = AllocationTable::default();
-            self.dev.read(table_addr, &mut table)?;
-            Ok(table.entries[table_idx as usize])
+            let table = self.read_sector(table_addr)?;
+            Sector::decode(&table[table_idx * FAT_ENTRY_SIZE..])
         } else {
             Err(FsError::IllegalOperation(String::from("read_sector_meta:: Specified sector is not in data section")))
         }
@@ -411,13 +747,16 @@ where B: ?Sized + ReadBlockDevice {
         let entry = self.read_sector_meta(addr)?;
 
         if entry.sector_type == SectorType::Dir {
-            let sectors = (entry.size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE;
-            let mut buffers = vec![[0u8; BLOCK_SIZE]; sectors];
+            if entry.size > self.sectors() * self.block_size {
+                return Err(FsError::InvalidAddress);
+            }
+            let sectors = (entry.size as usize + self.block_size - 1) / self.block_size;
+            let mut buffers = vec![Vec::new(); sectors];
             let size = entry.size;
 
             let mut addr = addr;
             for i in 0..sectors {
-                self.dev.read(addr, &mut buffers[i])?;
+                buffers[i] = self.read_sector(addr)?;
                 if let Some(a) = self.next_sector(addr)? {
                     addr = a;
                 } else if i < sectors-1 {
@@ -427,10 +766,13 @@ where B: ?Sized + ReadBlockDevice {
                 }
             }
 
-            let dir_data = dir_data_from_raw(&buffers, size);
+            let dir_data = dir_data_from_raw(&buffers, size)?;
+            if dir_data.iter().any(|entry| self.sector_to_table_location(entry.0).is_none()) {
+                return Err(FsError::InvalidAddress);
+            }
             Ok(dir_data)
         } else {
-            Err(FsError::IllegalOperation(String::from("Address does not refer to a directory")))
+            Err(FsError::NotADirectory)
         }
     }
 
@@ -438,8 +780,10 @@ where B: ?Sized + ReadBlockDevice {
     fn next_sector(&self, sector: usize) -> FsResult<Option<usize>> {
         let next = self.read_sector_meta(sector)?.next;
 
-        if next <= 0 {
+        if next == 0 {
             Ok(None)
+        } else if self.sector_to_table_location(next).is_none() {
+            Err(FsError::InvalidAddress)
         } else {
             Ok(Some(next))
         }
@@ -452,7 +796,8 @@ where B: ?Sized + ReadBlockDevice {
             if sector < root_sector.root || sector >= root_sector.sectors {
                 None
             } else {
-                Some((sector / FAT_ENTRIES_PER_SECTOR + root_sector.table_begin, sector % FAT_ENTRIES_PER_SECTOR))
+                let fat_entries_per_sector = self.block_size / FAT_ENTRY_SIZE;
+                Some((sector / fat_entries_per_sector + root_sector.table_begin, sector % fat_entries_per_sector))
             }
         } else {
             None
@@ -460,9 +805,7 @@ where B: ?Sized + ReadBlockDevice {
     }
 
     fn root_sector(&self) -> FsResult<RootSector> {
-        let mut root_sector = RootSector::default();
-        self.dev.read(0usize, &mut root_sector)?;
-        Ok(root_sector)
+        RootSector::decode(&self.read_sector(0)?)
     }
 
     fn exists(&self, path: &Path, sector_type: SectorType) -> FsResult<bool> {
@@ -486,21 +829,26 @@ where B: ?Sized + ReadBlockDevice {
                 Ok(FileProgress {
                     byte_offset: 0,
                     head: addr,
-                    sector: addr,
+                    extents: self.read_extents(addr)?,
                 }),
-            _ => Err(FsError::IllegalOperation(String::from("Can't open a non-file"))),
+            _ => Err(FsError::IsADirectory),
         }
     }
 }
 
 impl<B> FFAT<B>
 where B: ?Sized + RWBlockDevice {
+    fn write_root_sector(&mut self, root_sector: &RootSector) -> FsResult<()> {
+        let mut sector = self.read_sector(0)?;
+        root_sector.encode(&mut sector);
+        self.write_sector(0, sector)
+    }
+
     fn write_sector_meta(&mut self, addr: usize, meta: Sector) -> FsResult<()> {
         if let Some((table_addr, table_idx)) = self.sector_to_table_location(addr) {
-            let mut table = AllocationTable::default();
-            self.dev.read(table_addr, &mut table)?;
-            table.entries[table_idx as usize] = meta;
-            self.dev.write(table_addr, &table)?;
+            let mut table = self.read_sector(table_addr)?;
+            meta.encode(&mut table[table_idx * FAT_ENTRY_SIZE..]);
+            self.write_sector(table_addr, table)?;
             Ok(())
         } else {
             Err(FsError::IllegalOperation(String::from("write_sector_mega:: Specified sector is not in data section")))
@@ -509,16 +857,14 @@ where B: ?Sized + RWBlockDevice {
 
     /// gets a free sector and returns it
     fn allocate_sector(&mut self) -> FsResult<usize> {
-        let mut root_sector = RootSector::default();
-        self.dev.read(0usize, &mut root_sector)?;
+        let mut root_sector = self.root_sector()?;
 
         let addr = root_sector.free;
-        let next = self.next_sector(addr)?;
-        if let Some(next) = next {
-            root_sector.free = next;
-        } else {
+        if addr == 0 {
             return Err(FsError::NotEnoughSpace);
         }
+        root_sector.free = self.next_sector(addr)?.unwrap_or(0);
+        root_sector.free_sectors = root_sector.free_sectors.saturating_sub(1);
 
         // change metadata
         self.write_sector_meta(addr, Sector { 
@@ -527,7 +873,7 @@ where B: ?Sized + RWBlockDevice {
             next: 0 
         })?;
 
-        self.dev.write(0usize, &root_sector)?;
+        self.write_root_sector(&root_sector)?;
         Ok(addr)
     }
 
@@ -545,7 +891,7 @@ where B: ?Sized + RWBlockDevice {
 
     /// writes directory data at specified address
     fn write_dir_at_addr(&mut self, addr: usize, dir_data: &DirData) -> FsResult<()> {
-        let (raw_data, size) = raw_dir_data(&dir_data);
+        let (raw_data, size) = raw_dir_data(&dir_data, self.block_size);
         let mut addr = addr;
 
         let mut meta = self.read_sector_meta(addr)?;
@@ -553,7 +899,7 @@ where B: ?Sized + RWBlockDevice {
         self.write_sector_meta(addr, meta)?;
 
         for raw in raw_data {
-            self.dev.write(addr, &raw)?;
+            self.write_sector(addr, raw)?;
 
             // get next address 
             let next = if let Some(addr) = self.next_sector(addr)? {
@@ -587,7 +933,7 @@ where B: ?Sized + RWBlockDevice {
 
             for dir_entry in dir_data.iter() {
                 if dir_entry.1 == filename {
-                    return Err(FsError::IllegalOperation(String::from("File or directory with this name already exists")));
+                    return Err(FsError::AlreadyExists);
                 }
             }
 
@@ -611,7 +957,7 @@ where B: ?Sized + RWBlockDevice {
     fn delete_children(&mut self, path: &Path) -> FsResult<()> {
         let addr = self.walk(path)?;
         let dir_data = self.read_dir_at_addr(addr)?;
-        let children: Vec<Path> = dir_data.into_iter().map(|entry| path.concat(entry.1)).collect();
+        let children = dir_data.into_iter().map(|entry| path.join(entry.1)).collect::<Result<Vec<Path>, _>>()?;
         for child in children {
             self.delete(child)?;
         }
@@ -622,7 +968,9 @@ where B: ?Sized + RWBlockDevice {
     fn free_sectors(&mut self, addr: usize) -> FsResult<()> {
         let mut root_sector = self.root_sector()?;
         let mut end_addr = addr;
+        let mut freed = 0;
         loop {
+            freed += 1;
             let mut meta = self.read_sector_meta(end_addr)?;
             meta.sector_type = SectorType::Free;
             meta.size = 0;
@@ -639,8 +987,9 @@ where B: ?Sized + RWBlockDevice {
         end_meta.next = root_sector.free;
         self.write_sector_meta(end_addr, end_meta)?;
 
-        root_sector.free = end_addr;
-        self.dev.write(0usize, &root_sector)?;
+        root_sector.free = addr;
+        root_sector.free_sectors += freed;
+        self.write_root_sector(&root_sector)?;
 
         Ok(())
     }
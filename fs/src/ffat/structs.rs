@@ -1,89 +1,298 @@
 use super::*;
 
-use bytevec::*;
-
 pub struct ReadProgress(pub FileProgress, pub usize);
 pub struct WriteProgress(pub FileProgress);
 
+#[derive(Clone)]
 pub struct FileProgress {
     /// begin of file that stores the files metadata
     pub head: usize,
-    
-    /// current sector where data is being read from or written to
-    pub sector: usize,
+
+    /// data sectors of the file
+    pub extents: Vec<Extent>,
 
     /// offset from begin of file
     pub byte_offset: usize,
 }
 
 impl FileProgress {
-    /// Bytes that have already been read/written in the current sector
-    pub fn current_bytes_processed(&self) -> usize {
-        (self.byte_offset % BLOCK_SIZE) as usize
+    /// Returns the sector that holds the `block`-th block of the file
+    /// and the number of sectors that follow it contiguously (including itself)
+    pub fn locate(&self, block: usize) -> Option<(usize, usize)> {
+        let mut first_block = 0;
+        for extent in self.extents.iter() {
+            if block < first_block + extent.length {
+                let skip = block - first_block;
+                return Some((extent.start + skip, extent.length - skip));
+            }
+            first_block += extent.length;
+        }
+        None
+    }
+
+    /// Appends a sector to the file, growing the last extent if possible
+    pub fn push_sector(&mut self, sector: usize) {
+        if let Some(last) = self.extents.last_mut() {
+            if last.start + last.length == sector {
+                last.length += 1;
+                return;
+            }
+        }
+        self.extents.push(Extent {
+            start: sector,
+            length: 1,
+        });
+    }
+}
+
+/// Reads the little-endian `u64` at `offset`
+pub fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+/// Writes `value` as little-endian `u64` at `offset`
+pub fn write_u64(bytes: &mut [u8], offset: usize, value: u64) {
+    bytes[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Reads the little-endian `u64` at `offset` as address or size
+fn read_usize(bytes: &[u8], offset: usize) -> FsResult<usize> {
+    let value = read_u64(bytes, offset);
+    if value > usize::MAX as u64 {
+        Err(FsError::InvalidAddress)
+    } else {
+        Ok(value as usize)
+    }
+}
+
+/// Run of consecutive data sectors of a file
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct Extent {
+    /// first sector of the run
+    pub start: usize,
+    /// number of sectors in the run
+    pub length: usize,
+}
+
+/// Size of the header of an `ExtentTable` sector
+const EXTENT_TABLE_HEADER: usize = 16;
+
+/// Size of an encoded `Extent`
+const EXTENT_SIZE: usize = 16;
+
+/// Number of extents that fit into one sector of an extent table
+pub fn extents_per_sector(block_size: usize) -> usize {
+    (block_size - EXTENT_TABLE_HEADER) / EXTENT_SIZE
+}
+
+/// Content of the first sector of a file and of its `Index` sectors.
+/// Together they list the extents that make up the file's data in order.
+///
+/// On disk: `count: u64`, 8 reserved bytes, then `count` times `start: u64, length: u64`.
+pub struct ExtentTable {
+    pub extents: Vec<Extent>,
+}
+
+impl ExtentTable {
+    pub fn from_sector(sector: &[u8]) -> FsResult<Self> {
+        let count = read_usize(sector, 0)?;
+        if count > extents_per_sector(sector.len()) {
+            return Err(FsError::InvalidAddress);
+        }
+        let extents = (0..count)
+            .map(|i| {
+                let offset = EXTENT_TABLE_HEADER + i * EXTENT_SIZE;
+                Ok(Extent {
+                    start: read_usize(sector, offset)?,
+                    length: read_usize(sector, offset + 8)?,
+                })
+            })
+            .collect::<FsResult<_>>()?;
+        Ok(Self { extents })
+    }
+
+    pub fn to_sector(&self, block_size: usize) -> Vec<u8> {
+        let mut sector = vec![0u8; block_size];
+        write_u64(&mut sector, 0, self.extents.len() as u64);
+        for (i, extent) in self.extents.iter().enumerate() {
+            let offset = EXTENT_TABLE_HEADER + i * EXTENT_SIZE;
+            write_u64(&mut sector, offset, extent.start as u64);
+            write_u64(&mut sector, offset + 8, extent.length as u64);
+        }
+        sector
     }
 }
 
+/// Length of the volume label
+pub const LABEL_LEN: usize = 64;
 
-#[repr(align(4096))]
+/// Identifies a device formatted with FFAT
+pub const ROOT_SECTOR_MAGIC: u64 = 0x3130_5441_4646_7462; // "btFFAT01"
+
+/// Size of the encoded root sector
+pub const ROOT_SECTOR_SIZE: usize = 8 + LABEL_LEN + 8 * 8;
+
+/// Stored at the beginning of the first sector of the device.
+/// Everything the file system needs to know fits into the first 512 bytes,
+/// so it can be read before the block size is known.
+///
+/// On disk: `ROOT_SECTOR_MAGIC: u64`, the label, then the remaining fields as `u64` in
+/// declaration order.
+#[derive(Copy, Clone)]
 pub struct RootSector {
-    pub name: [u8; 64],
+    /// volume label, padded with zeros
+    pub name: [u8; LABEL_LEN],
+    /// size of a sector in bytes
+    pub block_size: usize,
     pub table_begin: usize,
     pub sectors: usize,
     pub root: usize,
     pub free: usize,
+    /// number of sectors in the free list
+    pub free_sectors: usize,
+    /// first sector of the metadata journal
+    pub journal_begin: usize,
+    /// number of sectors reserved for the journal, `0` if journaling is disabled
+    pub journal_sectors: usize,
 }
 
-impl Default for RootSector {
-    fn default() -> Self {
-        Self {
-            name: [b'0'; 64],
-            table_begin: 1,
-            sectors: 0,
-            root: 0,
-            free: 0,
+impl RootSector {
+    /// decodes and sanity checks the root sector at the beginning of `bytes`
+    pub fn decode(bytes: &[u8]) -> FsResult<Self> {
+        if bytes.len() < ROOT_SECTOR_SIZE || read_u64(bytes, 0) != ROOT_SECTOR_MAGIC {
+            return Err(FsError::InvalidSuperBlock);
         }
-    }
-}
 
-#[derive(Copy, Clone)]
-#[repr(align(4096))]
-pub struct AllocationTable {
-    pub entries: [Sector; BLOCK_SIZE / 32],    
-}
+        let mut name = [0u8; LABEL_LEN];
+        copy_offset(bytes, &mut name, LABEL_LEN, 8, 0);
 
-impl Default for AllocationTable {
-    fn default() -> Self {
-        Self { 
-            entries: [Sector {
-                sector_type: SectorType::Reserved,
-                size: 0,
-                next: 0,
-            }; BLOCK_SIZE / 32] 
+        let field = |i: usize| read_usize(bytes, 8 + LABEL_LEN + 8 * i).map_err(|_| FsError::InvalidSuperBlock);
+        let root_sector = Self {
+            name,
+            block_size: field(0)?,
+            table_begin: field(1)?,
+            sectors: field(2)?,
+            root: field(3)?,
+            free: field(4)?,
+            free_sectors: field(5)?,
+            journal_begin: field(6)?,
+            journal_sectors: field(7)?,
+        };
+
+        let block_size = root_sector.block_size;
+        let valid = block_size.is_power_of_two()
+            && block_size >= MIN_BLOCK_SIZE
+            && block_size <= MAX_BLOCK_SIZE
+            && root_sector.table_begin == 1
+            && root_sector.journal_begin > root_sector.table_begin
+            && root_sector.journal_begin.checked_add(root_sector.journal_sectors) == Some(root_sector.root)
+            && root_sector.root < root_sector.sectors
+            && root_sector.free < root_sector.sectors
+            && root_sector.free_sectors < root_sector.sectors;
+
+        if valid {
+            Ok(root_sector)
+        } else {
+            Err(FsError::InvalidSuperBlock)
+        }
+    }
+
+    /// encodes the root sector into the beginning of `bytes`
+    pub fn encode(&self, bytes: &mut [u8]) {
+        write_u64(bytes, 0, ROOT_SECTOR_MAGIC);
+        copy_offset(&self.name, bytes, LABEL_LEN, 0, 8);
+        let fields = [
+            self.block_size,
+            self.table_begin,
+            self.sectors,
+            self.root,
+            self.free,
+            self.free_sectors,
+            self.journal_begin,
+            self.journal_sectors,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            write_u64(bytes, 8 + LABEL_LEN + 8 * i, *field as u64);
         }
     }
 }
 
+/// Size of an entry in the allocation table
+pub const FAT_ENTRY_SIZE: usize = 32;
+
+/// Entry in the allocation table
+///
+/// On disk: `sector_type: u8`, 7 reserved bytes, `size: u64`, `next: u64`, 8 reserved bytes.
 #[derive(Copy, Clone, Default, Debug)]
-#[repr(align(32))]
 pub struct Sector {
     pub sector_type: SectorType,
     pub size: usize,
-    pub next: usize, 
+    pub next: usize,
+}
+
+impl Sector {
+    /// decodes the entry at the beginning of `bytes`
+    pub fn decode(bytes: &[u8]) -> FsResult<Self> {
+        Ok(Self {
+            sector_type: SectorType::decode(bytes[0])?,
+            size: read_usize(bytes, 8)?,
+            next: read_usize(bytes, 16)?,
+        })
+    }
+
+    /// encodes the entry into the beginning of `bytes`
+    pub fn encode(&self, bytes: &mut [u8]) {
+        for byte in bytes[..FAT_ENTRY_SIZE].iter_mut() {
+            *byte = 0;
+        }
+        bytes[0] = self.sector_type.encode();
+        write_u64(bytes, 8, self.size as u64);
+        write_u64(bytes, 16, self.next as u64);
+    }
 }
 
 #[derive(Eq, PartialEq, Copy, Clone, Debug)]
-#[repr(C)]
 pub enum SectorType {
     /// Unused sector
     Free,
     /// Reserved for special purposes, e.g. the FAT table
     Reserved,
     /// In a data segment that is not the first sector of a file or directory
-    Data, 
+    Data,
     /// First sector of a file
     File,
     /// First sector of a directory
     Dir,
+    /// Continuation of the extent table of a file
+    Index,
+}
+
+impl SectorType {
+    pub fn decode(byte: u8) -> FsResult<Self> {
+        match byte {
+            0 => Ok(Self::Free),
+            1 => Ok(Self::Reserved),
+            2 => Ok(Self::Data),
+            3 => Ok(Self::File),
+            4 => Ok(Self::Dir),
+            5 => Ok(Self::Index),
+            _ => Err(FsError::InvalidAddress),
+        }
+    }
+
+    pub fn encode(&self) -> u8 {
+        match self {
+            Self::Free => 0,
+            Self::Reserved => 1,
+            Self::Data => 2,
+            Self::File => 3,
+            Self::Dir => 4,
+            Self::Index => 5,
+        }
+    }
 }
 
 impl Default for SectorType {
@@ -92,18 +301,116 @@ impl Default for SectorType {
     }
 }
 
+/// Size of the header of a `JournalHeader` sector
+const JOURNAL_HEADER: usize = 32;
+
+/// Number of target addresses that fit into a journal header
+pub fn journal_targets(block_size: usize) -> usize {
+    (block_size - JOURNAL_HEADER) / 8
+}
+
+/// Marks a journal header that describes a committed transaction
+pub const JOURNAL_MAGIC: u64 = 0x4c4e_524a_5441_4646; // "FFATJRNL"
+
+/// First sector of the journal, followed by the journaled copies of the sectors listed in
+/// `targets`
+///
+/// On disk: `magic: u64`, `count: u64`, `checksum: u64`, 8 reserved bytes,
+/// then `count` target addresses as `u64`.
+#[derive(Default)]
+pub struct JournalHeader {
+    /// `JOURNAL_MAGIC` if the journal holds a committed transaction, anything else otherwise
+    pub magic: u64,
+    /// checksum over the journaled sector copies
+    pub checksum: u64,
+    /// home locations of the journaled sectors
+    pub targets: Vec<usize>,
+}
+
+impl JournalHeader {
+    pub fn from_sector(sector: &[u8]) -> FsResult<Self> {
+        let magic = read_u64(sector, 0);
+        if magic != JOURNAL_MAGIC {
+            return Ok(Self::default());
+        }
+        let count = read_usize(sector, 8).map_err(|_| FsError::InvalidSuperBlock)?;
+        let checksum = read_u64(sector, 16);
+        if count > journal_targets(sector.len()) {
+            return Err(FsError::InvalidSuperBlock);
+        }
+        let targets = (0..count)
+            .map(|i| read_usize(sector, JOURNAL_HEADER + i * 8))
+            .collect::<FsResult<_>>()?;
+        Ok(Self { magic, checksum, targets })
+    }
+
+    pub fn to_sector(&self, block_size: usize) -> Vec<u8> {
+        let mut sector = vec![0u8; block_size];
+        write_u64(&mut sector, 0, self.magic);
+        write_u64(&mut sector, 8, self.targets.len() as u64);
+        write_u64(&mut sector, 16, self.checksum);
+        for (i, target) in self.targets.iter().enumerate() {
+            write_u64(&mut sector, JOURNAL_HEADER + i * 8, *target as u64);
+        }
+        sector
+    }
+}
+
 pub type DirEntry = (usize, Filename);
 pub type DirData = Vec<DirEntry>;
 
-pub fn raw_dir_data(data: &DirData) -> (Vec<[u8; BLOCK_SIZE]>, usize) {
-    let bytes = data.encode::<u64>().unwrap();
-    let sectors = (bytes.len() + BLOCK_SIZE - 1) / BLOCK_SIZE;
+/// Encodes directory data
+///
+/// On disk: `count: u64`, then for each entry `addr: u64`, `name_len: u64` and the name.
+pub fn encode_dir_data(data: &DirData) -> Vec<u8> {
+    let size = 8 + data.iter().map(|entry| 16 + entry.1.len()).sum::<usize>();
+    let mut bytes = vec![0u8; size];
+    write_u64(&mut bytes, 0, data.len() as u64);
+    let mut offset = 8;
+    for (addr, name) in data.iter() {
+        write_u64(&mut bytes, offset, *addr as u64);
+        write_u64(&mut bytes, offset + 8, name.len() as u64);
+        copy_offset(name, &mut bytes, name.len(), 0, offset + 16);
+        offset += 16 + name.len();
+    }
+    bytes
+}
+
+/// Decodes directory data, rejecting entries that run past the end of `bytes`
+pub fn decode_dir_data(bytes: &[u8]) -> FsResult<DirData> {
+    let field = |offset: usize| {
+        if offset + 8 > bytes.len() {
+            Err(FsError::InvalidAddress)
+        } else {
+            read_usize(bytes, offset)
+        }
+    };
+
+    let count = field(0)?;
+    let mut data = DirData::new();
+    let mut offset = 8;
+    for _ in 0..count {
+        let addr = field(offset)?;
+        let name_len = field(offset + 8)?;
+        let name_begin = offset + 16;
+        if name_len > bytes.len() || name_begin + name_len > bytes.len() {
+            return Err(FsError::InvalidAddress);
+        }
+        data.push((addr, bytes[name_begin..name_begin + name_len].to_vec()));
+        offset = name_begin + name_len;
+    }
+    Ok(data)
+}
+
+pub fn raw_dir_data(data: &DirData, block_size: usize) -> (Vec<Vec<u8>>, usize) {
+    let bytes = encode_dir_data(data);
+    let sectors = (bytes.len() + block_size - 1) / block_size;
     let mut raw = Vec::with_capacity(sectors as usize);
 
     let mut bytes_processed = 0;
     for _ in 0..sectors {
-        let mut sector = [0u8; BLOCK_SIZE];
-        let bytes_to_copy = (bytes.len() - bytes_processed).min(BLOCK_SIZE);
+        let mut sector = vec![0u8; block_size];
+        let bytes_to_copy = (bytes.len() - bytes_processed).min(block_size);
         copy_offset(&bytes, &mut sector, bytes_to_copy, bytes_processed, 0);
         bytes_processed += bytes_to_copy;
         raw.push(sector);
@@ -111,10 +418,147 @@ pub fn raw_dir_data(data: &DirData) -> (Vec<[u8; BLOCK_SIZE]>, usize) {
     (raw, bytes.len() as usize)
 }
 
-pub fn dir_data_from_raw(raw: &Vec<[u8; BLOCK_SIZE]>, size: usize) -> DirData {
-    let size = size as usize;
+pub fn dir_data_from_raw(raw: &Vec<Vec<u8>>, size: usize) -> FsResult<DirData> {
     let raw: Vec<u8> = raw.iter().flat_map(|sector| sector.iter()).map(|v| *v).collect();
-    let data = DirData::decode::<u64>(&raw[..size]).unwrap();
-    data
+    if size > raw.len() {
+        return Err(FsError::InvalidAddress);
+    }
+    decode_dir_data(&raw[..size])
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn root_sector() -> RootSector {
+        let mut name = [0u8; LABEL_LEN];
+        name[..4].copy_from_slice(b"root");
+        RootSector {
+            name,
+            block_size: 4096,
+            table_begin: 1,
+            sectors: 1000,
+            root: 20,
+            free: 21,
+            free_sectors: 979,
+            journal_begin: 4,
+            journal_sectors: 16,
+        }
+    }
+
+    #[test]
+    fn root_sector_roundtrip() {
+        let mut bytes = vec![0u8; 512];
+        root_sector().encode(&mut bytes);
+        assert_eq!(read_u64(&bytes, 0), ROOT_SECTOR_MAGIC);
+        assert_eq!(&bytes[8..12], b"root");
+
+        let decoded = RootSector::decode(&bytes).unwrap();
+        let mut encoded = vec![0u8; 512];
+        decoded.encode(&mut encoded);
+        assert_eq!(bytes, encoded);
+        assert_eq!((decoded.block_size, decoded.root, decoded.free_sectors), (4096, 20, 979));
+    }
+
+    #[test]
+    fn root_sector_corrupted() {
+        let mut bytes = vec![0u8; 512];
+        root_sector().encode(&mut bytes);
+        assert!(RootSector::decode(&bytes[..ROOT_SECTOR_SIZE - 1]).is_err());
+
+        let mut magic = bytes.clone();
+        magic[0] ^= 1;
+        assert!(RootSector::decode(&magic).is_err());
+
+        // every field is checked against the others
+        let field = |i: usize| 8 + LABEL_LEN + 8 * i;
+        for &(i, value) in &[(0, 4000), (0, 256), (1, 0), (2, 20), (3, 21), (4, 1000), (5, 1000), (6, 1), (7, 17)] {
+            let mut bytes = bytes.clone();
+            write_u64(&mut bytes, field(i), value);
+            assert!(RootSector::decode(&bytes).is_err(), "field {} = {}", i, value);
+        }
+        let mut bytes = bytes.clone();
+        write_u64(&mut bytes, field(7), u64::MAX);
+        assert!(RootSector::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn sector_roundtrip() {
+        let mut bytes = vec![0xffu8; FAT_ENTRY_SIZE * 2];
+        let sector = Sector { sector_type: SectorType::File, size: 12345, next: 42 };
+        sector.encode(&mut bytes);
+        assert_eq!(bytes[0], 3);
+        assert!(bytes[1..8].iter().chain(&bytes[24..32]).all(|&b| b == 0));
+        // the next entry is left alone
+        assert!(bytes[FAT_ENTRY_SIZE..].iter().all(|&b| b == 0xff));
+
+        let decoded = Sector::decode(&bytes).unwrap();
+        assert_eq!((decoded.sector_type, decoded.size, decoded.next), (SectorType::File, 12345, 42));
+
+        for byte in 0..6 {
+            assert_eq!(SectorType::decode(byte).unwrap().encode(), byte);
+        }
+        bytes[0] = 6;
+        assert!(Sector::decode(&bytes).is_err());
+    }
+
+    #[test]
+    fn journal_header_roundtrip() {
+        let header = JournalHeader { magic: JOURNAL_MAGIC, checksum: 0xdead_beef, targets: vec![7, 3, 100] };
+        let sector = header.to_sector(512);
+        let decoded = JournalHeader::from_sector(&sector).unwrap();
+        assert_eq!((decoded.magic, decoded.checksum), (JOURNAL_MAGIC, 0xdead_beef));
+        assert_eq!(decoded.targets, vec![7, 3, 100]);
+
+        // a header without the magic is an empty journal, whatever else it holds
+        let mut empty = sector.clone();
+        empty[0] = 0;
+        assert!(JournalHeader::from_sector(&empty).unwrap().targets.is_empty());
+
+        let mut too_many = sector.clone();
+        write_u64(&mut too_many, 8, journal_targets(512) as u64 + 1);
+        assert!(JournalHeader::from_sector(&too_many).is_err());
+
+        let full = JournalHeader { magic: JOURNAL_MAGIC, checksum: 0, targets: (0..journal_targets(512)).collect() };
+        assert_eq!(JournalHeader::from_sector(&full.to_sector(512)).unwrap().targets.len(), 60);
+    }
+
+    #[test]
+    fn extent_table_roundtrip() {
+        let extents = vec![Extent { start: 10, length: 5 }, Extent { start: 100, length: 1 }];
+        let table = ExtentTable { extents: extents.clone() };
+        let sector = table.to_sector(512);
+        assert_eq!(ExtentTable::from_sector(&sector).unwrap().extents, extents);
+
+        let full = ExtentTable { extents: vec![Extent::default(); extents_per_sector(512)] };
+        assert_eq!(ExtentTable::from_sector(&full.to_sector(512)).unwrap().extents.len(), 31);
+
+        let mut too_many = sector.clone();
+        write_u64(&mut too_many, 0, extents_per_sector(512) as u64 + 1);
+        assert!(ExtentTable::from_sector(&too_many).is_err());
+    }
+
+    #[test]
+    fn dir_data_roundtrip() {
+        let data: DirData = vec![(5, b"a".to_vec()), (9, b"longer name".to_vec()), (12, Vec::new())];
+        let (raw, size) = raw_dir_data(&data, 512);
+        assert_eq!(raw.len(), 1);
+        assert_eq!(dir_data_from_raw(&raw, size).unwrap(), data);
+
+        // entries spanning several sectors
+        let many: DirData = (0..100).map(|i| (i, format!("file{}", i).into_bytes())).collect();
+        let (raw, size) = raw_dir_data(&many, 512);
+        assert!(raw.len() > 1);
+        assert_eq!(dir_data_from_raw(&raw, size).unwrap(), many);
+        assert!(dir_data_from_raw(&raw, raw.len() * 512 + 1).is_err());
+
+        // truncated data and names running past the end are errors, not panics
+        let bytes = encode_dir_data(&data);
+        for len in 0..bytes.len() {
+            assert!(decode_dir_data(&bytes[..len]).is_err());
+        }
+        let mut long_name = bytes.clone();
+        write_u64(&mut long_name, 16, u64::MAX);
+        assert!(decode_dir_data(&long_name).is_err());
+    }
+}
@@ -15,6 +15,8 @@ pub trait BaseFileSystem {
     fn exists_dir(&self, path: Path) -> FsResult<bool> { Err(FsError::AccessViolation) }
     /// check wether a file exists or not
     fn exists_file(&self, path: Path) -> FsResult<bool> { Err(FsError::AccessViolation) }
+    /// usage statistics of the file system
+    fn statfs(&self) -> FsResult<stats::FsStats> { Err(FsError::AccessViolation) }
 }
 
 /// Functions for a file system that can be mounted
@@ -41,6 +43,8 @@ pub trait ReadFileSystem {
     fn read(&self, progress: &mut Self::ReadProgress, buffer: &mut [u8]) -> FsResult<usize> { Err(FsError::AccessViolation) }
     /// seeks forward in the file
     fn seek(&self, progress: &mut Self::ReadProgress, seek: usize) -> FsResult<()> { Err(FsError::AccessViolation) }
+    /// size of a file in bytes
+    fn file_size(&self, path: Path) -> FsResult<usize> { Err(FsError::AccessViolation) }
 }
 
 /// Functions for a file system that supports writing files
@@ -50,6 +54,9 @@ pub trait WriteFileSystem {
     type WriteProgress: Send;
     /// opens a file and returns a write handle to that file
     fn open_write(&mut self, path: Path) -> FsResult<Self::WriteProgress> { Err(FsError::AccessViolation) }
+    /// opens an existing file for writing at `offset` without clearing it, writes overwrite its
+    /// content and grow it at the end
+    fn open_update(&mut self, path: Path, _offset: usize) -> FsResult<Self::WriteProgress> { Err(FsError::AccessViolation) }
     /// writes to a file and updates the progress
     fn write(&mut self, progress: &mut Self::WriteProgress, buffer: &[u8]) -> FsResult<()> { Err(FsError::AccessViolation) }
 }
@@ -64,6 +71,10 @@ pub trait ManageFileSystem {
     fn create_file(&mut self,  path: Path) -> FsResult<()> { Err(FsError::AccessViolation) }
     /// creates a new, empty directory
     fn create_dir(&mut self,  path: Path) -> FsResult<()> { Err(FsError::AccessViolation) }
+    /// moves a file or directory to a new path inside the same file system
+    fn rename(&mut self, from: Path, to: Path) -> FsResult<()> { Err(FsError::AccessViolation) }
+    /// writes everything that is cached to the device, nothing to do for file systems without caches
+    fn flush(&mut self) -> FsResult<()> { Ok(()) }
 }
 
 pub trait FunctionalFileSystem : BaseFileSystem + ReadFileSystem + WriteFileSystem + ManageFileSystem {}
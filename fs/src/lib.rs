@@ -8,9 +8,15 @@ pub mod block;
 pub mod memory_devices;
 pub mod filesystem;
 pub mod ffat;
+pub mod fat;
+pub mod ext2;
+pub mod tmpfs;
+pub mod archive;
+pub mod overlay;
+pub mod partition;
 
 pub mod path {
-    pub use dep::fs::Path;
+    pub use dep::fs::{Path, PathSlice, PathError, Components};
 }
 
 /// Blanket trait that is implemented for every `Sized` type.
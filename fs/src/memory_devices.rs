@@ -6,6 +6,9 @@ use crate::block::*;
 use crate::copy::*;
 use crate::error::*;
 
+/// Block size of the memory devices, the size of a disk sector
+pub const MEMORY_BLOCK_SIZE: usize = 512;
+
 pub struct RamDisk<'a> {
     pub data: &'a mut [u8],
 }
@@ -22,7 +25,7 @@ macro_rules! impl_read_block {
     ($type: ty) => (
         impl BlockDevice for $type {
             fn block_size(&self) -> usize {
-                4096
+                MEMORY_BLOCK_SIZE
             }
             fn blocks(&self) -> usize {
                 self.data.len() / self.block_size()
@@ -61,3 +64,10 @@ impl_read_block!(OwnedDisk);
 impl_write_block!(RamDisk<'_>);
 impl_write_block!(OwnedDisk);
 
+
+/// Writes are refused, but read-only file systems can mount a `RomDisk` as an `RWBlockDevice`
+impl WriteBlockDevice for RomDisk<'_> {
+    fn write_block(&mut self, _index: usize, _buffer: &[u8]) -> FsResult<()> {
+        Err(FsError::AccessViolation)
+    }
+}
@@ -0,0 +1,610 @@
+//! Writable file system on top of a read-only one.
+//!
+//! The lower file system is never written, all changes go to the upper file system. Directories
+//! that exist in both layers are merged. A file of the lower layer is copied up into the upper
+//! layer before it is changed, deleting an entry of the lower layer leaves a whiteout in the upper
+//! layer that hides it. Whiteouts are empty files called `.wh.<name>`, a directory of the upper
+//! layer that contains `.wh..wh..opq` is opaque and hides the lower directory at the same path.
+//!
+//! Files that are open for reading keep the content of the layer they were opened in.
+
+extern crate alloc;
+use alloc::vec::*;
+use alloc::string::*;
+use alloc::vec;
+
+use crate::error::*;
+use crate::filesystem::*;
+
+const NAME: &str = "overlay";
+
+/// Prefix of whiteouts, names with this prefix can't be created in the overlay
+const WHITEOUT_PREFIX: &[u8] = b".wh.";
+/// Marker of an opaque directory
+const OPAQUE: &[u8] = b".wh..wh..opq";
+
+/// Size of the chunks in which files are copied up
+const COPY_CHUNK: usize = 4096;
+
+pub struct Overlay<L, U> {
+    lower: L,
+    upper: U,
+}
+
+/// Position in a file that is read, in the layer the file was opened in
+pub enum ReadProgress<L: ReadFileSystem, U: ReadFileSystem> {
+    Lower(L::ReadProgress),
+    Upper(U::ReadProgress),
+}
+
+/// Layer an entry of the overlay comes from
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Layer {
+    Lower,
+    Upper,
+}
+
+/// `Ok(false)` for paths that don't exist, file systems may report those as errors
+fn exists(result: FsResult<bool>) -> FsResult<bool> {
+    match result {
+        Err(FsError::NotFound) => Ok(false),
+        result => result,
+    }
+}
+
+fn whiteout_name(name: &[u8]) -> Filename {
+    let mut whiteout = WHITEOUT_PREFIX.to_vec();
+    whiteout.extend_from_slice(name);
+    whiteout
+}
+
+/// path of the whiteout that hides `path`, `None` for the root
+fn whiteout(path: &Path) -> FsResult<Option<Path>> {
+    match (path.parent_dir(), path.name()) {
+        (Some(parent), Some(name)) => Ok(Some(parent.join(whiteout_name(&name))?)),
+        _ => Ok(None),
+    }
+}
+
+fn reserved(path: &Path) -> FsResult<()> {
+    match path.name() {
+        Some(name) if name.starts_with(WHITEOUT_PREFIX) =>
+            Err(FsError::IllegalOperation(String::from("names beginning with .wh. are reserved by the overlay"))),
+        _ => Ok(()),
+    }
+}
+
+impl<L, U> Overlay<L, U>
+where L: BaseFileSystem + ReadFileSystem, U: FunctionalFileSystem {
+    /// combines a read-only `lower` file system with a writable `upper` one
+    pub fn new(lower: L, upper: U) -> Self {
+        Self { lower, upper }
+    }
+
+    /// returns the lower and the upper file system
+    pub fn into_inner(self) -> (L, U) {
+        (self.lower, self.upper)
+    }
+
+    fn upper_dir(&self, path: &Path) -> FsResult<bool> {
+        exists(self.upper.exists_dir(path.clone()))
+    }
+
+    fn upper_file(&self, path: &Path) -> FsResult<bool> {
+        exists(self.upper.exists_file(path.clone()))
+    }
+
+    fn opaque(&self, path: &Path) -> FsResult<bool> {
+        self.upper_file(&path.join(OPAQUE)?)
+    }
+
+    /// `true` if no whiteout, opaque directory or file of the upper layer hides `path` in the
+    /// lower layer
+    fn lower_visible(&self, path: &Path) -> FsResult<bool> {
+        let mut current = Path::root();
+        let mut rest = path.clone();
+        while let (Some(name), tail) = rest.head_tail() {
+            if self.opaque(&current)? || self.upper_file(&current.join(whiteout_name(&name))?)? {
+                return Ok(false);
+            }
+            current = current.join(name)?;
+            if !tail.is_root() && self.upper_file(&current)? {
+                return Ok(false);
+            }
+            rest = tail;
+        }
+        Ok(true)
+    }
+
+    /// `true` if `path` is a visible directory of the lower layer
+    fn lower_dir(&self, path: &Path) -> FsResult<bool> {
+        Ok(self.lower_visible(path)? && exists(self.lower.exists_dir(path.clone()))?)
+    }
+
+    /// `true` if `path` is a visible entry of the lower layer
+    fn in_lower(&self, path: &Path) -> FsResult<bool> {
+        Ok(self.lower_visible(path)?
+            && (exists(self.lower.exists_dir(path.clone()))? || exists(self.lower.exists_file(path.clone()))?))
+    }
+
+    /// layer that provides `path` and whether it is a directory, entries of the upper layer
+    /// hide those of the lower layer
+    fn lookup(&self, path: &Path) -> FsResult<Option<(Layer, bool)>> {
+        if reserved(path).is_err() {
+            return Ok(None);
+        }
+        if self.upper_dir(path)? {
+            return Ok(Some((Layer::Upper, true)));
+        }
+        if self.upper_file(path)? {
+            return Ok(Some((Layer::Upper, false)));
+        }
+        if self.lower_visible(path)? {
+            if exists(self.lower.exists_dir(path.clone()))? {
+                return Ok(Some((Layer::Lower, true)));
+            }
+            if exists(self.lower.exists_file(path.clone()))? {
+                return Ok(Some((Layer::Lower, false)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// checks that `path` is a directory of the overlay
+    fn expect_dir(&self, path: &Path) -> FsResult<()> {
+        match self.lookup(path)? {
+            Some((_, true)) => Ok(()),
+            Some((_, false)) => Err(FsError::NotADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    /// creates the directory `path` and its parents in the upper layer if they are missing
+    fn copy_up_dirs(&mut self, path: &Path) -> FsResult<()> {
+        let mut current = Path::root();
+        let mut rest = path.clone();
+        while let (Some(name), tail) = rest.head_tail() {
+            current = current.join(name)?;
+            if !self.upper_dir(&current)? {
+                self.upper.create_dir(current.clone())?;
+            }
+            rest = tail;
+        }
+        Ok(())
+    }
+
+    /// copies a file of the lower layer into the upper layer
+    fn copy_up_file(&mut self, path: &Path) -> FsResult<()> {
+        if let Some(parent) = path.parent_dir() {
+            self.copy_up_dirs(&parent)?;
+        }
+        let mut reader = self.lower.open_read(path.clone())?;
+        self.upper.create_file(path.clone())?;
+        let mut writer = self.upper.open_write(path.clone())?;
+
+        let mut buffer = vec![0u8; COPY_CHUNK];
+        loop {
+            let read = self.lower.read(&mut reader, &mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            self.upper.write(&mut writer, &buffer[..read])?;
+        }
+    }
+
+    /// copies a file or a complete directory tree into the upper layer
+    fn copy_up_tree(&mut self, path: &Path) -> FsResult<()> {
+        match self.lookup(path)? {
+            Some((Layer::Lower, false)) => self.copy_up_file(path),
+            Some((_, true)) => {
+                self.copy_up_dirs(path)?;
+                for name in self.read_dir(path.clone())? {
+                    self.copy_up_tree(&path.join(name)?)?;
+                }
+                Ok(())
+            },
+            Some((Layer::Upper, false)) => Ok(()),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    /// makes an upper directory hide the lower directory at the same path
+    fn set_opaque(&mut self, path: &Path) -> FsResult<()> {
+        if !self.opaque(path)? {
+            self.upper.create_file(path.join(OPAQUE)?)?;
+        }
+        Ok(())
+    }
+
+    /// hides `path` in the lower layer
+    fn add_whiteout(&mut self, path: &Path) -> FsResult<()> {
+        if let (Some(parent), Some(whiteout)) = (path.parent_dir(), whiteout(path)?) {
+            self.copy_up_dirs(&parent)?;
+            if !self.upper_file(&whiteout)? {
+                self.upper.create_file(whiteout)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_whiteout(&mut self, path: &Path) -> FsResult<()> {
+        if let Some(whiteout) = whiteout(path)? {
+            if self.upper_file(&whiteout)? {
+                self.upper.delete(whiteout)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// prepares the upper layer for a new entry at `path`
+    fn prepare_new(&mut self, path: &Path) -> FsResult<()> {
+        reserved(path)?;
+        if self.lookup(path)?.is_some() {
+            return Err(FsError::AlreadyExists);
+        }
+        let parent = path.parent_dir().ok_or(FsError::AlreadyExists)?;
+        self.expect_dir(&parent)?;
+        self.copy_up_dirs(&parent)?;
+        self.remove_whiteout(path)
+    }
+}
+
+impl<L, U> BaseFileSystem for Overlay<L, U>
+where L: BaseFileSystem + ReadFileSystem, U: FunctionalFileSystem {
+    fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        self.expect_dir(&path)?;
+
+        let mut names = Vec::new();
+        if self.upper_dir(&path)? {
+            names.extend(self.upper.read_dir(path.clone())?.into_iter().filter(|name| !name.starts_with(WHITEOUT_PREFIX)));
+        }
+        if self.lower_dir(&path)? && !self.opaque(&path)? {
+            for name in self.lower.read_dir(path.clone())? {
+                if !name.starts_with(WHITEOUT_PREFIX)
+                    && !names.contains(&name)
+                    && !self.upper_file(&path.join(whiteout_name(&name))?)? {
+                    names.push(name);
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        Ok(self.lookup(&path)?.map(|(_, dir)| dir).unwrap_or(false))
+    }
+
+    fn exists_file(&self, path: Path) -> FsResult<bool> {
+        Ok(self.lookup(&path)?.map(|(_, dir)| !dir).unwrap_or(false))
+    }
+
+    fn statfs(&self) -> FsResult<stats::FsStats> {
+        // only the upper layer has space for changes
+        let upper = self.upper.statfs()?;
+        Ok(stats::FsStats::new(NAME, upper.total_blocks, upper.free_blocks, upper.block_size))
+    }
+}
+
+impl<L, U> ReadFileSystem for Overlay<L, U>
+where L: BaseFileSystem + ReadFileSystem, U: FunctionalFileSystem {
+    type ReadProgress = ReadProgress<L, U>;
+
+    fn open_read(&self, path: Path) -> FsResult<Self::ReadProgress> {
+        match self.lookup(&path)? {
+            Some((Layer::Upper, false)) => Ok(ReadProgress::Upper(self.upper.open_read(path)?)),
+            Some((Layer::Lower, false)) => Ok(ReadProgress::Lower(self.lower.open_read(path)?)),
+            Some((_, true)) => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn read(&self, progress: &mut Self::ReadProgress, buffer: &mut [u8]) -> FsResult<usize> {
+        match progress {
+            ReadProgress::Lower(progress) => self.lower.read(progress, buffer),
+            ReadProgress::Upper(progress) => self.upper.read(progress, buffer),
+        }
+    }
+
+    fn seek(&self, progress: &mut Self::ReadProgress, seek: usize) -> FsResult<()> {
+        match progress {
+            ReadProgress::Lower(progress) => self.lower.seek(progress, seek),
+            ReadProgress::Upper(progress) => self.upper.seek(progress, seek),
+        }
+    }
+
+    fn file_size(&self, path: Path) -> FsResult<usize> {
+        match self.lookup(&path)? {
+            Some((Layer::Upper, false)) => self.upper.file_size(path),
+            Some((Layer::Lower, false)) => self.lower.file_size(path),
+            Some((_, true)) => Err(FsError::IsADirectory),
+            None => Err(FsError::NotFound),
+        }
+    }
+}
+
+impl<L, U> WriteFileSystem for Overlay<L, U>
+where L: BaseFileSystem + ReadFileSystem, U: FunctionalFileSystem {
+    type WriteProgress = U::WriteProgress;
+
+    fn open_write(&mut self, path: Path) -> FsResult<Self::WriteProgress> {
+        match self.lookup(&path)? {
+            Some((Layer::Upper, false)) => (),
+            // opening truncates the file, so an empty file in the upper layer is a complete copy
+            Some((Layer::Lower, false)) => {
+                if let Some(parent) = path.parent_dir() {
+                    self.copy_up_dirs(&parent)?;
+                }
+                self.upper.create_file(path.clone())?;
+            },
+            Some((_, true)) => return Err(FsError::IsADirectory),
+            None => return Err(FsError::NotFound),
+        }
+        self.upper.open_write(path)
+    }
+
+    fn open_update(&mut self, path: Path, offset: usize) -> FsResult<Self::WriteProgress> {
+        match self.lookup(&path)? {
+            Some((Layer::Upper, false)) => (),
+            // the part of the file that isn't overwritten has to be in the upper layer as well
+            Some((Layer::Lower, false)) => self.copy_up_file(&path)?,
+            Some((_, true)) => return Err(FsError::IsADirectory),
+            None => return Err(FsError::NotFound),
+        }
+        self.upper.open_update(path, offset)
+    }
+
+    fn write(&mut self, progress: &mut Self::WriteProgress, buffer: &[u8]) -> FsResult<()> {
+        self.upper.write(progress, buffer)
+    }
+}
+
+impl<L, U> ManageFileSystem for Overlay<L, U>
+where L: BaseFileSystem + ReadFileSystem, U: FunctionalFileSystem {
+    fn flush(&mut self) -> FsResult<()> {
+        self.upper.flush()
+    }
+
+    fn delete(&mut self, path: Path) -> FsResult<()> {
+        if path.is_root() {
+            return Err(FsError::IllegalOperation(String::from("Can't delete the root directory")));
+        }
+        if self.lookup(&path)?.is_none() {
+            return Err(FsError::NotFound);
+        }
+        let in_lower = self.in_lower(&path)?;
+        if self.upper_dir(&path)? || self.upper_file(&path)? {
+            self.upper.delete(path.clone())?;
+        }
+        if in_lower {
+            self.add_whiteout(&path)?;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, path: Path) -> FsResult<()> {
+        match self.lookup(&path)? {
+            Some((Layer::Upper, false)) => self.upper.clear(path),
+            Some((Layer::Lower, false)) => {
+                if let Some(parent) = path.parent_dir() {
+                    self.copy_up_dirs(&parent)?;
+                }
+                self.upper.create_file(path)
+            },
+            Some((layer, true)) => {
+                let lower_dir = self.lower_dir(&path)?;
+                if layer == Layer::Upper {
+                    self.upper.clear(path.clone())?;
+                } else {
+                    self.copy_up_dirs(&path)?;
+                }
+                if lower_dir {
+                    self.set_opaque(&path)?;
+                }
+                Ok(())
+            },
+            None => Err(FsError::NotFound),
+        }
+    }
+
+    fn create_file(&mut self, path: Path) -> FsResult<()> {
+        self.prepare_new(&path)?;
+        self.upper.create_file(path)
+    }
+
+    fn create_dir(&mut self, path: Path) -> FsResult<()> {
+        self.prepare_new(&path)?;
+        self.upper.create_dir(path.clone())?;
+        // a directory that was deleted from the lower layer must not come back
+        if self.lower_dir(&path)? {
+            self.set_opaque(&path)?;
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, from: Path, to: Path) -> FsResult<()> {
+        if from.is_root() {
+            return Err(FsError::IllegalOperation(String::from("Can't move the root directory")));
+        }
+        if to.clone().relative_to(from.clone()).is_some() {
+            return Err(FsError::IllegalOperation(String::from("Can't move a directory into itself")));
+        }
+        let is_dir = match self.lookup(&from)? {
+            Some((_, is_dir)) => is_dir,
+            None => return Err(FsError::NotFound),
+        };
+        let in_lower = self.in_lower(&from)?;
+
+        // the upper layer gets a complete copy, which is then moved
+        self.prepare_new(&to)?;
+        self.copy_up_tree(&from)?;
+        self.upper.rename(from.clone(), to.clone())?;
+
+        if is_dir && self.lower_dir(&to)? {
+            self.set_opaque(&to)?;
+        }
+        if in_lower {
+            self.add_whiteout(&from)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use crate::ffat::*;
+    use crate::memory_devices::*;
+    use crate::tmpfs::Tmpfs;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    /// image of a lower layer with `/a`, `/dir/b`, `/dir/c` and `/dir/sub/d`
+    fn lower_image() -> Vec<u8> {
+        let options = FormatOptions { block_size: MEMORY_BLOCK_SIZE, label: Vec::new() };
+        let mut fs = match FFAT::format_with(Box::new(OwnedDisk { data: vec![0u8; 512 * 256] }), &options) {
+            Ok(fs) => fs,
+            Err(_) => panic!("format failed"),
+        };
+        fs.create_dir(path("/dir")).unwrap();
+        fs.create_dir(path("/dir/sub")).unwrap();
+        for &(name, content) in &[("/a", &b"lower a"[..]), ("/dir/b", b"lower b"), ("/dir/c", b"lower c"), ("/dir/sub/d", b"lower d")] {
+            fs.create_file(path(name)).unwrap();
+            let mut progress = fs.open_write(path(name)).unwrap();
+            fs.write(&mut progress, content).unwrap();
+        }
+        fs.inner().data
+    }
+
+    fn overlay(image: &[u8]) -> Overlay<FFAT<RomDisk>, Tmpfs> {
+        let lower = match FFAT::mount(Box::new(RomDisk { data: image })) {
+            Ok(fs) => fs,
+            Err(_) => panic!("mount failed"),
+        };
+        Overlay::new(lower, Tmpfs::new(64 * 1024))
+    }
+
+    fn read_all<F: ReadFileSystem>(fs: &F, name: &str) -> Vec<u8> {
+        let mut progress = fs.open_read(path(name)).unwrap();
+        let mut content = vec![0u8; fs.file_size(path(name)).unwrap() + 1];
+        let mut len = 0;
+        loop {
+            match fs.read(&mut progress, &mut content[len..]).unwrap() {
+                0 => break,
+                read => len += read,
+            }
+        }
+        content.truncate(len);
+        content
+    }
+
+    fn sorted(mut names: Vec<Filename>) -> Vec<Filename> {
+        names.sort();
+        names
+    }
+
+    fn names(names: &[&str]) -> Vec<Filename> {
+        names.iter().map(|name| name.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn copy_up_on_write() {
+        let image = lower_image();
+        let mut fs = overlay(&image);
+        assert_eq!(read_all(&fs, "/dir/b"), b"lower b");
+
+        // an update keeps the part of the lower file that isn't overwritten
+        let mut progress = fs.open_update(path("/dir/b"), 6).unwrap();
+        fs.write(&mut progress, b"B!").unwrap();
+        assert_eq!(read_all(&fs, "/dir/b"), b"lower B!");
+
+        // opening for writing truncates, nothing has to be copied
+        let mut progress = fs.open_write(path("/a")).unwrap();
+        fs.write(&mut progress, b"upper a").unwrap();
+        assert_eq!(read_all(&fs, "/a"), b"upper a");
+
+        let (lower, upper) = fs.into_inner();
+        assert_eq!(read_all(&upper, "/dir/b"), b"lower B!");
+        assert_eq!(read_all(&upper, "/a"), b"upper a");
+        assert!(!upper.exists_file(path("/dir/c")).unwrap());
+        // the lower layer is unchanged
+        assert_eq!(read_all(&lower, "/dir/b"), b"lower b");
+        assert_eq!(read_all(&lower, "/a"), b"lower a");
+    }
+
+    #[test]
+    fn whiteouts() {
+        let image = lower_image();
+        let mut fs = overlay(&image);
+        fs.delete(path("/dir/c")).unwrap();
+        assert!(!fs.exists_file(path("/dir/c")).unwrap());
+        match fs.open_read(path("/dir/c")) {
+            Err(FsError::NotFound) => (),
+            _ => panic!("deleted file was found"),
+        }
+        assert_eq!(sorted(fs.read_dir(path("/dir")).unwrap()), names(&["b", "sub"]));
+
+        // whiteouts can't be created or seen through the overlay
+        assert!(fs.create_file(path("/dir/.wh.b")).is_err());
+        assert!(!fs.exists_file(path("/dir/.wh.c")).unwrap());
+
+        // a new file of the same name replaces the whiteout
+        fs.create_file(path("/dir/c")).unwrap();
+        assert_eq!(read_all(&fs, "/dir/c"), b"");
+
+        fs.delete(path("/a")).unwrap();
+        let (_, upper) = fs.into_inner();
+        assert!(upper.exists_file(path("/.wh.a")).unwrap());
+        assert!(!upper.exists_file(path("/dir/.wh.c")).unwrap());
+    }
+
+    #[test]
+    fn opaque_dirs() {
+        let image = lower_image();
+        let mut fs = overlay(&image);
+
+        // a directory that is deleted and created again is empty
+        fs.delete(path("/dir")).unwrap();
+        assert!(!fs.exists_dir(path("/dir")).unwrap());
+        fs.create_dir(path("/dir")).unwrap();
+        assert!(fs.read_dir(path("/dir")).unwrap().is_empty());
+        assert!(!fs.exists_file(path("/dir/b")).unwrap());
+        assert!(!fs.exists_file(path("/dir/sub/d")).unwrap());
+        fs.create_file(path("/dir/new")).unwrap();
+        assert_eq!(fs.read_dir(path("/dir")).unwrap(), names(&["new"]));
+
+        let (_, upper) = fs.into_inner();
+        assert!(upper.exists_file(path("/dir/.wh..wh..opq")).unwrap());
+        assert!(!upper.exists_file(path("/.wh.dir")).unwrap());
+
+        // clearing a lower directory makes it opaque as well
+        let mut fs = overlay(&image);
+        fs.clear(path("/dir/sub")).unwrap();
+        assert!(fs.read_dir(path("/dir/sub")).unwrap().is_empty());
+        assert_eq!(sorted(fs.read_dir(path("/dir")).unwrap()), names(&["b", "c", "sub"]));
+    }
+
+    #[test]
+    fn merged_dirs() {
+        let image = lower_image();
+        let mut fs = overlay(&image);
+        fs.create_file(path("/dir/e")).unwrap();
+        fs.create_dir(path("/dir/sub/deeper")).unwrap();
+        let mut progress = fs.open_update(path("/dir/b"), 0).unwrap();
+        fs.write(&mut progress, b"L").unwrap();
+
+        // entries of both layers are listed once
+        assert_eq!(sorted(fs.read_dir(path("/dir")).unwrap()), names(&["b", "c", "e", "sub"]));
+        assert_eq!(sorted(fs.read_dir(path("/dir/sub")).unwrap()), names(&["d", "deeper"]));
+        assert_eq!(sorted(fs.read_dir(path("/")).unwrap()), names(&["a", "dir"]));
+
+        // a renamed lower directory takes its lower content with it
+        fs.rename(path("/dir/sub"), path("/moved")).unwrap();
+        assert_eq!(sorted(fs.read_dir(path("/moved")).unwrap()), names(&["d", "deeper"]));
+        assert_eq!(read_all(&fs, "/moved/d"), b"lower d");
+        assert!(!fs.exists_dir(path("/dir/sub")).unwrap());
+        assert_eq!(sorted(fs.read_dir(path("/")).unwrap()), names(&["a", "dir", "moved"]));
+    }
+}
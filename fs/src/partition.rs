@@ -0,0 +1,463 @@
+//! Partition tables: MBR, including extended partitions, and GPT.
+//!
+//! `read_table` finds the partitions of a device and a `PartitionDevice` is the view of one of
+//! them that a file system can mount like a whole device.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use alloc::string::*;
+use alloc::vec;
+
+use crate::block::*;
+use crate::error::*;
+
+/// Size of the MBR and of a sector as the partition tables see it
+const SECTOR_SIZE: usize = 512;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_ENTRIES: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+
+const TYPE_EMPTY: u8 = 0x00;
+const TYPE_EXTENDED_CHS: u8 = 0x05;
+const TYPE_EXTENDED_LBA: u8 = 0x0f;
+const TYPE_EXTENDED_LINUX: u8 = 0x85;
+const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+/// Maximum number of logical partitions, the chain of extended boot records could be a loop
+const MAX_LOGICAL: usize = 128;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_HEADER_SIZE: usize = 92;
+const GPT_ENTRY_SIZE: usize = 128;
+/// Maximum number of GPT entries that are read, the usual table has 128 of them
+const GPT_MAX_ENTRIES: usize = 1024;
+
+/// Type of a partition as the partition table stores it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    /// system id of an MBR entry
+    Mbr(u8),
+    /// type GUID of a GPT entry, in the byte order it is stored in
+    Gpt([u8; 16]),
+}
+
+/// Partition of a device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// first block of the partition
+    pub first: usize,
+    /// number of blocks
+    pub blocks: usize,
+    pub kind: PartitionType,
+    /// name of a GPT partition, MBR partitions don't have one
+    pub name: String,
+}
+
+/// Entry of the partition table in an MBR or an extended boot record
+struct MbrEntry {
+    kind: u8,
+    first: usize,
+    blocks: usize,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+fn is_extended(kind: u8) -> bool {
+    kind == TYPE_EXTENDED_CHS || kind == TYPE_EXTENDED_LBA || kind == TYPE_EXTENDED_LINUX
+}
+
+/// reads the sector at the address `lba` in units of the block size of the device
+fn read_sector<B: ?Sized + ReadBlockDevice>(device: &B, lba: usize, buffer: &mut [u8]) -> FsResult<()> {
+    if lba >= device.blocks() {
+        return Err(FsError::InvalidAddress);
+    }
+    read_bytes(device, lba * device.block_size(), buffer)
+}
+
+/// the four entries of an MBR or extended boot record, `None` if the sector doesn't hold a table
+fn mbr_entries(sector: &[u8]) -> Option<Vec<MbrEntry>> {
+    if sector[SECTOR_SIZE - 2..SECTOR_SIZE] != MBR_SIGNATURE {
+        return None;
+    }
+    let mut entries = Vec::new();
+    for i in 0..4 {
+        let entry = &sector[MBR_ENTRIES + i * MBR_ENTRY_SIZE..MBR_ENTRIES + (i + 1) * MBR_ENTRY_SIZE];
+        // boot sectors of file systems have the signature as well, but code where the table is
+        if entry[0] != 0x00 && entry[0] != 0x80 {
+            return None;
+        }
+        entries.push(MbrEntry {
+            kind: entry[4],
+            first: u32_at(entry, 8) as usize,
+            blocks: u32_at(entry, 12) as usize,
+        });
+    }
+    Some(entries)
+}
+
+/// Reads the partition table of a device.
+/// Returns `None` if the device has no partition table or if the table has no partitions.
+pub fn read_table<B: ?Sized + ReadBlockDevice>(device: &B) -> FsResult<Option<Vec<Partition>>> {
+    if device.block_size() < SECTOR_SIZE || device.blocks() == 0 {
+        return Ok(None);
+    }
+    let mut sector = vec![0u8; SECTOR_SIZE];
+    read_sector(device, 0, &mut sector)?;
+    let entries = match mbr_entries(&sector) {
+        Some(entries) => entries,
+        None => return Ok(None),
+    };
+
+    let partitions = if entries.iter().any(|entry| entry.kind == TYPE_GPT_PROTECTIVE) {
+        match read_gpt(device, 1)? {
+            Some(partitions) => partitions,
+            // the backup header is in the last block
+            None => read_gpt(device, device.blocks() - 1)?.ok_or(FsError::InvalidSuperBlock)?,
+        }
+    } else {
+        read_mbr(device, &entries)?
+    };
+
+    // a table with partitions outside of the device is garbage that happens to look like one
+    if partitions.is_empty() || partitions.iter().any(|p| p.blocks == 0 || p.first + p.blocks > device.blocks()) {
+        return Ok(None);
+    }
+    Ok(Some(partitions))
+}
+
+fn mbr_partition(first: usize, blocks: usize, kind: u8) -> Partition {
+    Partition {
+        first,
+        blocks,
+        kind: PartitionType::Mbr(kind),
+        name: String::new(),
+    }
+}
+
+/// primary partitions and the logical partitions in extended partitions
+fn read_mbr<B: ?Sized + ReadBlockDevice>(device: &B, entries: &[MbrEntry]) -> FsResult<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    for entry in entries.iter().filter(|entry| entry.kind != TYPE_EMPTY) {
+        if is_extended(entry.kind) {
+            read_logical(device, entry.first, &mut partitions)?;
+        } else {
+            partitions.push(mbr_partition(entry.first, entry.blocks, entry.kind));
+        }
+    }
+    Ok(partitions)
+}
+
+/// Follows the chain of extended boot records of the extended partition at `extended`.
+/// Logical partitions are relative to their record, the next record is relative to `extended`.
+fn read_logical<B: ?Sized + ReadBlockDevice>(device: &B, extended: usize, partitions: &mut Vec<Partition>) -> FsResult<()> {
+    let mut sector = vec![0u8; SECTOR_SIZE];
+    let mut record = extended;
+    for _ in 0..MAX_LOGICAL {
+        read_sector(device, record, &mut sector)?;
+        let entries = mbr_entries(&sector).ok_or(FsError::InvalidSuperBlock)?;
+
+        if entries[0].kind != TYPE_EMPTY {
+            partitions.push(mbr_partition(record + entries[0].first, entries[0].blocks, entries[0].kind));
+        }
+        if !is_extended(entries[1].kind) || entries[1].first == 0 {
+            return Ok(());
+        }
+        record = extended + entries[1].first;
+    }
+    Err(FsError::InvalidSuperBlock)
+}
+
+/// partitions of the GPT with the header at `lba`, `None` if the header or the entries are invalid
+fn read_gpt<B: ?Sized + ReadBlockDevice>(device: &B, lba: usize) -> FsResult<Option<Vec<Partition>>> {
+    let mut header = vec![0u8; device.block_size()];
+    read_sector(device, lba, &mut header)?;
+
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[0..8] != GPT_SIGNATURE || header_size < GPT_HEADER_SIZE || header_size > header.len() {
+        return Ok(None);
+    }
+    let checksum = u32_at(&header, 16);
+    header[16..20].copy_from_slice(&[0; 4]);
+    if crc32(&header[..header_size]) != checksum || u64_at(&header, 24) != lba as u64 {
+        return Ok(None);
+    }
+
+    let first_usable = u64_at(&header, 40) as usize;
+    let last_usable = u64_at(&header, 48) as usize;
+    let entries_lba = u64_at(&header, 72) as usize;
+    let count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    if entry_size < GPT_ENTRY_SIZE || entry_size % 8 != 0 || count > GPT_MAX_ENTRIES {
+        return Ok(None);
+    }
+
+    let mut entries = vec![0u8; count * entry_size];
+    read_sector(device, entries_lba, &mut entries)?;
+    if crc32(&entries) != u32_at(&header, 88) {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for entry in entries.chunks(entry_size) {
+        let mut kind = [0u8; 16];
+        kind.copy_from_slice(&entry[0..16]);
+        if kind == [0; 16] {
+            continue;
+        }
+        let first = u64_at(entry, 32) as usize;
+        let last = u64_at(entry, 40) as usize;
+        if first < first_usable || last > last_usable || last < first {
+            return Ok(None);
+        }
+
+        // the name is UTF-16 and padded with zeros
+        let units: Vec<u16> = entry[56..128].chunks(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|&unit| unit != 0)
+            .collect();
+        let name = core::char::decode_utf16(units.iter().cloned())
+            .map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER))
+            .collect();
+
+        partitions.push(Partition {
+            first,
+            blocks: last - first + 1,
+            kind: PartitionType::Gpt(kind),
+            name,
+        });
+    }
+    Ok(Some(partitions))
+}
+
+/// View of a partition as a device of its own, block indices are relative to its first block
+pub struct PartitionDevice<D> {
+    device: D,
+    first: usize,
+    blocks: usize,
+}
+
+impl<D: BlockDevice> PartitionDevice<D> {
+    /// view of `partition` of `device`, fails if the partition doesn't fit on the device
+    pub fn new(device: D, partition: &Partition) -> FsResult<Self> {
+        if partition.first.checked_add(partition.blocks).map_or(true, |end| end > device.blocks()) {
+            return Err(FsError::InvalidAddress);
+        }
+        Ok(Self {
+            device,
+            first: partition.first,
+            blocks: partition.blocks,
+        })
+    }
+
+    /// returns the whole device
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+
+    fn check(&self, index: usize) -> FsResult<()> {
+        self.check_range(index, 1)
+    }
+
+    /// checks that the `count` blocks from `first` are in the partition
+    fn check_range(&self, first: usize, count: usize) -> FsResult<()> {
+        if first.checked_add(count).map_or(true, |end| end > self.blocks) {
+            Err(FsError::InternalError("out of bounds block address".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<D: BlockDevice> BlockDevice for PartitionDevice<D> {
+    fn block_size(&self) -> usize {
+        self.device.block_size()
+    }
+
+    fn blocks(&self) -> usize {
+        self.blocks
+    }
+}
+
+impl<D: ReadBlockDevice> ReadBlockDevice for PartitionDevice<D> {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.check(index)?;
+        self.device.read_block(self.first + index, buffer)
+    }
+
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        let count = block_count(self.device.block_size(), buffer.len())?;
+        self.check_range(first, count)?;
+        self.device.read_blocks(self.first + first, buffer)
+    }
+}
+
+impl<D: WriteBlockDevice> WriteBlockDevice for PartitionDevice<D> {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        self.check(index)?;
+        self.device.write_block(self.first + index, buffer)
+    }
+
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        let count = block_count(self.device.block_size(), buffer.len())?;
+        self.check_range(first, count)?;
+        self.device.write_blocks(self.first + first, buffer)
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        self.device.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_devices::*;
+
+    /// writes an MBR entry into the table of the sector at `sector`
+    fn mbr_entry(data: &mut [u8], sector: usize, index: usize, kind: u8, first: u32, blocks: u32) {
+        let offset = sector * SECTOR_SIZE + MBR_ENTRIES + index * MBR_ENTRY_SIZE;
+        data[offset + 4] = kind;
+        data[offset + 8..offset + 12].copy_from_slice(&first.to_le_bytes());
+        data[offset + 12..offset + 16].copy_from_slice(&blocks.to_le_bytes());
+        data[(sector + 1) * SECTOR_SIZE - 2..(sector + 1) * SECTOR_SIZE].copy_from_slice(&MBR_SIGNATURE);
+    }
+
+    /// writes a GPT header at `lba` with its entries at `entries_lba`
+    fn gpt(data: &mut [u8], lba: usize, entries_lba: usize, partitions: &[(u64, u64, &str)]) {
+        let mut entries = vec![0u8; 4 * GPT_ENTRY_SIZE];
+        for (i, &(first, last, name)) in partitions.iter().enumerate() {
+            let entry = &mut entries[i * GPT_ENTRY_SIZE..(i + 1) * GPT_ENTRY_SIZE];
+            entry[0] = 0xaf;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, unit) in name.encode_utf16().enumerate() {
+                entry[56 + 2 * j..58 + 2 * j].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+
+        let mut header = vec![0u8; SECTOR_SIZE];
+        header[0..8].copy_from_slice(GPT_SIGNATURE);
+        header[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&(lba as u64).to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&((data.len() / SECTOR_SIZE - 34) as u64).to_le_bytes());
+        header[72..80].copy_from_slice(&(entries_lba as u64).to_le_bytes());
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let checksum = crc32(&header[..GPT_HEADER_SIZE]);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+
+        data[lba * SECTOR_SIZE..(lba + 1) * SECTOR_SIZE].copy_from_slice(&header);
+        data[entries_lba * SECTOR_SIZE..entries_lba * SECTOR_SIZE + entries.len()].copy_from_slice(&entries);
+    }
+
+    fn gpt_disk() -> OwnedDisk {
+        let mut data = vec![0u8; SECTOR_SIZE * 4096];
+        mbr_entry(&mut data, 0, 0, TYPE_GPT_PROTECTIVE, 1, 4095);
+        let partitions = [(34, 1033, "root"), (2000, 2999, "data")];
+        gpt(&mut data, 1, 2, &partitions);
+        gpt(&mut data, 4095, 4094, &partitions);
+        OwnedDisk { data }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn mbr_with_logical_partitions() {
+        let mut data = vec![0u8; SECTOR_SIZE * 4096];
+        mbr_entry(&mut data, 0, 0, 0x83, 2048, 1000);
+        mbr_entry(&mut data, 0, 1, TYPE_EXTENDED_LBA, 3008, 1000);
+        // logical partitions are relative to their record, the next record to the extended one
+        mbr_entry(&mut data, 3008, 0, 0x83, 2, 100);
+        mbr_entry(&mut data, 3008, 1, TYPE_EXTENDED_CHS, 200, 60);
+        mbr_entry(&mut data, 3208, 0, 0x0c, 2, 50);
+        data[2048 * SECTOR_SIZE..2048 * SECTOR_SIZE + 5].copy_from_slice(b"hello");
+
+        let disk = OwnedDisk { data };
+        let partitions = read_table(&disk).unwrap().unwrap();
+        let found: Vec<_> = partitions.iter().map(|p| (p.first, p.blocks, p.kind)).collect();
+        assert_eq!(found, vec![
+            (2048, 1000, PartitionType::Mbr(0x83)),
+            (3010, 100, PartitionType::Mbr(0x83)),
+            (3210, 50, PartitionType::Mbr(0x0c)),
+        ]);
+
+        let mut partition = PartitionDevice::new(disk, &partitions[0]).unwrap();
+        let mut block = [0u8; SECTOR_SIZE];
+        partition.read_block(0, &mut block).unwrap();
+        assert_eq!(&block[..5], b"hello");
+        assert!(partition.read_block(1000, &mut block).is_err());
+        assert!(partition.write_block(1000, &block).is_err());
+        partition.write_block(999, &[7u8; SECTOR_SIZE]).unwrap();
+        assert_eq!(partition.into_inner().data[3047 * SECTOR_SIZE], 7);
+    }
+
+    #[test]
+    fn logical_partition_loop() {
+        let mut data = vec![0u8; SECTOR_SIZE * 64];
+        mbr_entry(&mut data, 0, 0, TYPE_EXTENDED_LBA, 8, 50);
+        mbr_entry(&mut data, 8, 0, 0x83, 1, 1);
+        mbr_entry(&mut data, 8, 1, TYPE_EXTENDED_LBA, 10, 40);
+        // the record at 18 links to itself
+        mbr_entry(&mut data, 18, 0, 0x83, 1, 1);
+        mbr_entry(&mut data, 18, 1, TYPE_EXTENDED_LBA, 10, 40);
+        assert!(read_table(&OwnedDisk { data }).is_err());
+    }
+
+    #[test]
+    fn gpt_and_backup() {
+        let disk = gpt_disk();
+        let partitions = read_table(&disk).unwrap().unwrap();
+        assert_eq!(partitions.len(), 2);
+        assert_eq!((partitions[0].first, partitions[0].blocks, partitions[0].name.as_str()), (34, 1000, "root"));
+        assert_eq!((partitions[1].first, partitions[1].blocks, partitions[1].name.as_str()), (2000, 1000, "data"));
+
+        // a damaged primary header or damaged entries fall back to the backup
+        for &offset in &[SECTOR_SIZE + 30, 2 * SECTOR_SIZE + 40] {
+            let mut disk = gpt_disk();
+            disk.data[offset] ^= 1;
+            assert_eq!(read_table(&disk).unwrap().unwrap(), partitions);
+        }
+
+        let mut disk = gpt_disk();
+        disk.data[SECTOR_SIZE] ^= 1;
+        disk.data[4095 * SECTOR_SIZE] ^= 1;
+        assert!(read_table(&disk).is_err());
+    }
+
+    #[test]
+    fn no_table() {
+        assert_eq!(read_table(&OwnedDisk { data: vec![0; SECTOR_SIZE * 64] }).unwrap(), None);
+
+        // boot sector of a file system: signature present, but code where the table is
+        let mut data = vec![0u8; SECTOR_SIZE * 64];
+        data[510..512].copy_from_slice(&MBR_SIGNATURE);
+        data[446] = 0x33;
+        assert_eq!(read_table(&OwnedDisk { data: data.clone() }).unwrap(), None);
+
+        // partitions that don't fit on the device
+        data[446] = 0;
+        mbr_entry(&mut data, 0, 0, 0x83, 10, 100);
+        assert_eq!(read_table(&OwnedDisk { data }).unwrap(), None);
+
+        let partition = Partition { first: 4, blocks: 5, kind: PartitionType::Mbr(0x83), name: String::new() };
+        assert!(PartitionDevice::new(OwnedDisk { data: vec![0; SECTOR_SIZE * 8] }, &partition).is_err());
+    }
+}
@@ -0,0 +1,503 @@
+//! In-memory file system.
+//!
+//! Files and directories live in a table of nodes that is indexed by node number, directories
+//! map names to node numbers. Open files refer to their node number, so they keep working when
+//! the file is renamed. The total size of all file contents is limited.
+
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::BTreeMap;
+
+use crate::error::*;
+use crate::filesystem::*;
+
+const NAME: &str = "tmpfs";
+
+/// Unit in which `statfs` reports the size
+const BLOCK_SIZE: usize = 4096;
+
+/// Node number of the root directory
+const ROOT: u64 = 1;
+
+/// Content of a node
+enum Content {
+    File(Vec<u8>),
+    Dir(BTreeMap<Filename, u64>),
+}
+
+struct Node {
+    content: Content,
+    created: u64,
+    modified: u64,
+}
+
+/// Metadata of a file or directory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// size of a file in bytes, number of entries of a directory
+    pub len: usize,
+    /// time of creation as reported by the clock of the file system
+    pub created: u64,
+    /// time of the last change of the content
+    pub modified: u64,
+}
+
+pub struct Tmpfs {
+    nodes: BTreeMap<u64, Node>,
+    next_node: u64,
+    /// maximum number of bytes of all files together
+    limit: usize,
+    /// number of bytes of all files together
+    used: usize,
+    /// source of the timestamps in `Metadata`
+    clock: fn() -> u64,
+}
+
+/// Position in a file that is read
+pub struct ReadProgress {
+    node: u64,
+    offset: usize,
+}
+
+/// Position in a file that is written
+pub struct WriteProgress {
+    node: u64,
+    offset: usize,
+}
+
+impl Tmpfs {
+    /// creates an empty file system that can hold `limit` bytes of file content
+    pub fn new(limit: usize) -> Self {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(ROOT, Node {
+            content: Content::Dir(BTreeMap::new()),
+            created: 0,
+            modified: 0,
+        });
+        Self {
+            nodes,
+            next_node: ROOT + 1,
+            limit,
+            used: 0,
+            clock: || 0,
+        }
+    }
+
+    /// uses `clock` for the timestamps of files and directories
+    pub fn with_clock(mut self, clock: fn() -> u64) -> Self {
+        let now = clock();
+        let root = self.nodes.get_mut(&ROOT).unwrap();
+        root.created = now;
+        root.modified = now;
+        self.clock = clock;
+        self
+    }
+
+    /// metadata of the file or directory at `path`
+    pub fn metadata(&self, path: Path) -> FsResult<Metadata> {
+        let node = self.node(self.walk(&path)?)?;
+        let (is_dir, len) = match &node.content {
+            Content::File(data) => (false, data.len()),
+            Content::Dir(entries) => (true, entries.len()),
+        };
+        Ok(Metadata {
+            is_dir,
+            len,
+            created: node.created,
+            modified: node.modified,
+        })
+    }
+
+    fn node(&self, number: u64) -> FsResult<&Node> {
+        self.nodes.get(&number).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, number: u64) -> FsResult<&mut Node> {
+        self.nodes.get_mut(&number).ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self, number: u64) -> FsResult<&BTreeMap<Filename, u64>> {
+        match &self.node(number)?.content {
+            Content::Dir(entries) => Ok(entries),
+            Content::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn entries_mut(&mut self, number: u64) -> FsResult<&mut BTreeMap<Filename, u64>> {
+        match &mut self.node_mut(number)?.content {
+            Content::Dir(entries) => Ok(entries),
+            Content::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// node number of the file or directory at `path`
+    fn walk(&self, path: &Path) -> FsResult<u64> {
+        let mut number = ROOT;
+        let mut path = path.clone();
+        while let (Some(name), tail) = path.head_tail() {
+            number = match &self.node(number)?.content {
+                Content::Dir(entries) => *entries.get(&name).ok_or(FsError::NotFound)?,
+                Content::File(_) => return Err(FsError::NotFound),
+            };
+            path = tail;
+        }
+        Ok(number)
+    }
+
+    /// directory that contains `path` and the name of `path` in it
+    fn parent(&self, path: &Path) -> FsResult<(u64, Filename)> {
+        match (path.parent_dir(), path.name()) {
+            (Some(parent), Some(name)) => {
+                let parent = self.walk(&parent)?;
+                self.entries(parent)?;
+                Ok((parent, name))
+            },
+            _ => Err(FsError::IllegalOperation(String::from("the root directory has no parent"))),
+        }
+    }
+
+    /// node number of the file at `path`
+    fn file(&self, path: &Path) -> FsResult<u64> {
+        let number = self.walk(path)?;
+        match self.node(number)?.content {
+            Content::File(_) => Ok(number),
+            Content::Dir(_) => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// creates a node and adds it to a directory
+    fn insert(&mut self, path: &Path, content: Content) -> FsResult<()> {
+        let (parent, name) = self.parent(path)?;
+        if self.entries(parent)?.contains_key(&name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let now = (self.clock)();
+        let number = self.next_node;
+        self.next_node += 1;
+        self.nodes.insert(number, Node { content, created: now, modified: now });
+        self.entries_mut(parent)?.insert(name, number);
+        self.touch(parent)
+    }
+
+    /// updates the modification time of a node
+    fn touch(&mut self, number: u64) -> FsResult<()> {
+        let now = (self.clock)();
+        self.node_mut(number)?.modified = now;
+        Ok(())
+    }
+
+    /// removes a node and everything below it from the table
+    fn remove_node(&mut self, number: u64) {
+        match self.nodes.remove(&number).map(|node| node.content) {
+            Some(Content::File(data)) => self.used -= data.len(),
+            Some(Content::Dir(entries)) => {
+                for child in entries.values() {
+                    self.remove_node(*child);
+                }
+            },
+            None => (),
+        }
+    }
+
+    /// empties a file or directory
+    fn clear_node(&mut self, number: u64) -> FsResult<()> {
+        let (freed, children): (usize, Vec<u64>) = match &mut self.node_mut(number)?.content {
+            Content::File(data) => (core::mem::replace(data, Vec::new()).len(), Vec::new()),
+            Content::Dir(entries) => (0, core::mem::replace(entries, BTreeMap::new()).into_iter().map(|(_, child)| child).collect()),
+        };
+        self.used -= freed;
+        for child in children {
+            self.remove_node(child);
+        }
+        self.touch(number)
+    }
+}
+
+impl BaseFileSystem for Tmpfs {
+    fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        Ok(self.entries(self.walk(&path)?)?.keys().cloned().collect())
+    }
+
+    fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        Ok(self.metadata(path).map(|meta| meta.is_dir).unwrap_or(false))
+    }
+
+    fn exists_file(&self, path: Path) -> FsResult<bool> {
+        Ok(self.metadata(path).map(|meta| !meta.is_dir).unwrap_or(false))
+    }
+
+    fn statfs(&self) -> FsResult<stats::FsStats> {
+        let total = self.limit / BLOCK_SIZE;
+        let used = (self.used + BLOCK_SIZE - 1) / BLOCK_SIZE;
+        Ok(stats::FsStats::new(NAME, total as u64, total.saturating_sub(used) as u64, BLOCK_SIZE as u64))
+    }
+}
+
+impl ReadFileSystem for Tmpfs {
+    type ReadProgress = ReadProgress;
+
+    fn open_read(&self, path: Path) -> FsResult<ReadProgress> {
+        Ok(ReadProgress {
+            node: self.file(&path)?,
+            offset: 0,
+        })
+    }
+
+    fn read(&self, progress: &mut ReadProgress, buffer: &mut [u8]) -> FsResult<usize> {
+        let data = match &self.node(progress.node)?.content {
+            Content::File(data) => data,
+            Content::Dir(_) => return Err(FsError::InternalError(String::from("file became a directory"))),
+        };
+        if progress.offset >= data.len() {
+            return Ok(0);
+        }
+        let len = buffer.len().min(data.len() - progress.offset);
+        buffer[..len].copy_from_slice(&data[progress.offset..progress.offset + len]);
+        progress.offset += len;
+        Ok(len)
+    }
+
+    fn seek(&self, progress: &mut ReadProgress, seek: usize) -> FsResult<()> {
+        progress.offset += seek;
+        Ok(())
+    }
+
+    fn file_size(&self, path: Path) -> FsResult<usize> {
+        match &self.node(self.file(&path)?)?.content {
+            Content::File(data) => Ok(data.len()),
+            Content::Dir(_) => Err(FsError::IsADirectory),
+        }
+    }
+}
+
+impl WriteFileSystem for Tmpfs {
+    type WriteProgress = WriteProgress;
+
+    fn open_write(&mut self, path: Path) -> FsResult<WriteProgress> {
+        let node = self.file(&path)?;
+        self.clear_node(node)?;
+        Ok(WriteProgress { node, offset: 0 })
+    }
+
+    fn open_update(&mut self, path: Path, offset: usize) -> FsResult<WriteProgress> {
+        Ok(WriteProgress { node: self.file(&path)?, offset })
+    }
+
+    fn write(&mut self, progress: &mut WriteProgress, buffer: &[u8]) -> FsResult<()> {
+        let end = progress.offset + buffer.len();
+        let now = (self.clock)();
+        let (limit, used) = (self.limit, self.used);
+
+        let node = self.node_mut(progress.node)?;
+        let data = match &mut node.content {
+            Content::File(data) => data,
+            Content::Dir(_) => return Err(FsError::InternalError(String::from("file became a directory"))),
+        };
+
+        let grow = end.saturating_sub(data.len());
+        if used + grow > limit {
+            return Err(FsError::NotEnoughSpace);
+        }
+        if grow > 0 {
+            data.resize(end, 0);
+        }
+        data[progress.offset..end].copy_from_slice(buffer);
+        node.modified = now;
+
+        self.used += grow;
+        progress.offset = end;
+        Ok(())
+    }
+}
+
+impl ManageFileSystem for Tmpfs {
+    fn delete(&mut self, path: Path) -> FsResult<()> {
+        let (parent, name) = self.parent(&path)?;
+        let number = self.entries_mut(parent)?.remove(&name).ok_or(FsError::NotFound)?;
+        self.remove_node(number);
+        self.touch(parent)
+    }
+
+    fn clear(&mut self, path: Path) -> FsResult<()> {
+        let number = self.walk(&path)?;
+        self.clear_node(number)
+    }
+
+    fn create_file(&mut self, path: Path) -> FsResult<()> {
+        self.insert(&path, Content::File(Vec::new()))
+    }
+
+    fn create_dir(&mut self, path: Path) -> FsResult<()> {
+        self.insert(&path, Content::Dir(BTreeMap::new()))
+    }
+
+    fn rename(&mut self, from: Path, to: Path) -> FsResult<()> {
+        let (from_parent, from_name) = self.parent(&from)?;
+        let (to_parent, to_name) = self.parent(&to)?;
+        let number = *self.entries(from_parent)?.get(&from_name).ok_or(FsError::NotFound)?;
+
+        if to.clone().relative_to(from.clone()).is_some() {
+            return Err(FsError::IllegalOperation(String::from("Can't move a directory into itself")));
+        }
+        if self.entries(to_parent)?.contains_key(&to_name) {
+            return Err(FsError::AlreadyExists);
+        }
+
+        self.entries_mut(from_parent)?.remove(&from_name);
+        self.entries_mut(to_parent)?.insert(to_name, number);
+        self.touch(from_parent)?;
+        self.touch(to_parent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn path(path: &str) -> Path {
+        Path::from_str(path).unwrap()
+    }
+
+    fn read_all(fs: &Tmpfs, name: &str) -> Vec<u8> {
+        let mut progress = fs.open_read(path(name)).unwrap();
+        let mut content = vec![0u8; fs.file_size(path(name)).unwrap() + 1];
+        let len = fs.read(&mut progress, &mut content).unwrap();
+        content.truncate(len);
+        content
+    }
+
+    fn write(fs: &mut Tmpfs, name: &str, data: &[u8]) {
+        let mut progress = fs.open_write(path(name)).unwrap();
+        fs.write(&mut progress, data).unwrap();
+    }
+
+    #[test]
+    fn create_write_read_delete() {
+        let mut fs = Tmpfs::new(1024);
+        fs.create_dir(path("/docs")).unwrap();
+        fs.create_file(path("/docs/a")).unwrap();
+        assert!(fs.exists_file(path("/docs/a")).unwrap());
+        assert!(fs.exists_dir(path("/docs")).unwrap());
+        match fs.create_file(path("/docs/a")) {
+            Err(FsError::AlreadyExists) => (),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+
+        write(&mut fs, "/docs/a", b"hello world");
+        assert_eq!(read_all(&fs, "/docs/a"), b"hello world");
+
+        // an update overwrites the middle and appends after the end
+        let mut progress = fs.open_update(path("/docs/a"), 6).unwrap();
+        fs.write(&mut progress, b"there!").unwrap();
+        assert_eq!(read_all(&fs, "/docs/a"), b"hello there!");
+
+        let mut progress = fs.open_read(path("/docs/a")).unwrap();
+        fs.seek(&mut progress, 6).unwrap();
+        let mut buffer = [0u8; 3];
+        assert_eq!(fs.read(&mut progress, &mut buffer).unwrap(), 3);
+        assert_eq!(&buffer, b"the");
+
+        // reopening for writing truncates
+        write(&mut fs, "/docs/a", b"short");
+        assert_eq!(read_all(&fs, "/docs/a"), b"short");
+        assert_eq!(fs.used, 5);
+
+        fs.delete(path("/docs")).unwrap();
+        assert!(!fs.exists_file(path("/docs/a")).unwrap());
+        assert_eq!(fs.used, 0);
+        match fs.open_read(path("/docs/a")) {
+            Err(FsError::NotFound) => (),
+            _ => panic!("deleted file was found"),
+        }
+    }
+
+    #[test]
+    fn limit() {
+        let mut fs = Tmpfs::new(8);
+        fs.create_file(path("/a")).unwrap();
+        fs.create_file(path("/b")).unwrap();
+        write(&mut fs, "/a", b"12345");
+        let mut progress = fs.open_write(path("/b")).unwrap();
+        match fs.write(&mut progress, b"6789") {
+            Err(FsError::NotEnoughSpace) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        fs.write(&mut progress, b"678").unwrap();
+        // overwriting doesn't need more space
+        let mut progress = fs.open_update(path("/a"), 0).unwrap();
+        fs.write(&mut progress, b"abcde").unwrap();
+        assert_eq!(read_all(&fs, "/a"), b"abcde");
+    }
+
+    #[test]
+    fn rename() {
+        let mut fs = Tmpfs::new(1024);
+        fs.create_dir(path("/a")).unwrap();
+        fs.create_dir(path("/b")).unwrap();
+        fs.create_file(path("/a/file")).unwrap();
+        write(&mut fs, "/a/file", b"data");
+
+        // an open file follows its node
+        let mut progress = fs.open_read(path("/a/file")).unwrap();
+        fs.rename(path("/a/file"), path("/b/moved")).unwrap();
+        assert!(!fs.exists_file(path("/a/file")).unwrap());
+        assert_eq!(read_all(&fs, "/b/moved"), b"data");
+        let mut buffer = [0u8; 4];
+        assert_eq!(fs.read(&mut progress, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer, b"data");
+
+        fs.create_file(path("/a/file")).unwrap();
+        match fs.rename(path("/a/file"), path("/b/moved")) {
+            Err(FsError::AlreadyExists) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        match fs.rename(path("/a"), path("/a/inner")) {
+            Err(FsError::IllegalOperation(_)) => (),
+            other => panic!("unexpected {:?}", other),
+        }
+        fs.rename(path("/b"), path("/a/b")).unwrap();
+        assert_eq!(read_all(&fs, "/a/b/moved"), b"data");
+    }
+
+    #[test]
+    fn read_dir() {
+        let mut fs = Tmpfs::new(1024);
+        fs.create_dir(path("/dir")).unwrap();
+        fs.create_file(path("/dir/b")).unwrap();
+        fs.create_file(path("/dir/a")).unwrap();
+        fs.create_dir(path("/dir/c")).unwrap();
+        assert_eq!(fs.read_dir(path("/dir")).unwrap(), vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(fs.read_dir(path("/")).unwrap(), vec![b"dir".to_vec()]);
+        assert!(fs.read_dir(path("/dir/c")).unwrap().is_empty());
+        match fs.read_dir(path("/dir/a")) {
+            Err(FsError::NotADirectory) => (),
+            _ => panic!("listed a file"),
+        }
+
+        let meta = fs.metadata(path("/dir")).unwrap();
+        assert!(meta.is_dir);
+        assert_eq!(meta.len, 3);
+
+        fs.clear(path("/dir")).unwrap();
+        assert!(fs.read_dir(path("/dir")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn timestamps() {
+        static TIME: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(10);
+        fn clock() -> u64 {
+            TIME.load(core::sync::atomic::Ordering::SeqCst)
+        }
+        let mut fs = Tmpfs::new(1024).with_clock(clock);
+        fs.create_file(path("/a")).unwrap();
+        TIME.store(20, core::sync::atomic::Ordering::SeqCst);
+        write(&mut fs, "/a", b"x");
+        let meta = fs.metadata(path("/a")).unwrap();
+        assert_eq!((meta.created, meta.modified), (10, 20));
+        assert_eq!(fs.metadata(path("/")).unwrap().created, 10);
+    }
+}
@@ -8,6 +8,7 @@ use bit_fs::filesystem::*;
 use bit_fs::block::*;
 use bit_fs::memory_devices::*;
 use bit_fs::ffat::*;
+use bit_fs::archive::{self, NewcWriter};
 use std::io::{Read, Write};
 
 use clap::{Arg, App};
@@ -27,20 +28,73 @@ fn main() {
             .help("specifies binary image file")
             .takes_value(true)
             .required(true))
+        .arg(Arg::with_name("size")
+            .short("s")
+            .long("size")
+            .help("size of the image in KiB")
+            .takes_value(true)
+            .default_value("1024"))
+        .arg(Arg::with_name("block-size")
+            .short("b")
+            .long("block-size")
+            .help("size of a file system block in bytes, a power of two from 512 to 65536")
+            .takes_value(true)
+            .default_value("4096"))
+        .arg(Arg::with_name("label")
+            .short("l")
+            .long("label")
+            .help("volume label")
+            .takes_value(true)
+            .default_value("bitOS"))
+        .arg(Arg::with_name("format")
+            .short("f")
+            .long("format")
+            .help("format of the image, an FFAT disk image or a cpio archive for the initramfs")
+            .takes_value(true)
+            .possible_values(&["ffat", "cpio"])
+            .default_value("ffat"))
+        .arg(Arg::with_name("kernel")
+            .short("k")
+            .long("kernel")
+            .help("kernel binary the image is appended to, replacing a previously appended image")
+            .takes_value(true))
         .get_matches();
 
     let path = matches.value_of("directory").unwrap();
     let binary = matches.value_of("image").unwrap();
-    let size = 256 * 1; // number of fs blocks (4096 bytes each)
+
+    if matches.value_of("format").unwrap() == "cpio" {
+        let path = std_path::Path::new(path);
+        assert!(path.is_dir());
+
+        let mut writer = NewcWriter::new();
+        create_archive(&mut writer, path, Vec::new());
+        let archive = writer.finish();
+        std_fs::write(binary, &archive).unwrap();
+
+        if let Some(kernel) = matches.value_of("kernel") {
+            append_to_kernel(kernel, &archive);
+        }
+        return;
+    }
+
+    let size: usize = matches.value_of("size").unwrap().parse().expect("size must be a number");
+    let options = FormatOptions {
+        block_size: matches.value_of("block-size").unwrap().parse().expect("block size must be a number"),
+        label: matches.value_of("label").unwrap().as_bytes().to_vec(),
+    };
 
     let path = std_path::Path::new(path); 
     assert!(path.exists());
     assert!(path.is_dir());
 
-    let mut disk = vec![0u8; 4096 * size];
+    let mut disk = vec![0u8; 1024 * size];
     let ram_disk = RamDisk{ data: &mut disk };
+    if let Err(err) = options.validate(&ram_disk) {
+        panic!("invalid format options: {:?}", err);
+    }
     let mut fat = {
-        if let Ok(fat) = FFAT::format(Box::new(ram_disk)) {
+        if let Ok(fat) = FFAT::format_with(Box::new(ram_disk), &options) {
             fat
         } else {
             panic!("could not format ram disk")
@@ -61,6 +115,11 @@ fn main() {
             pos += bytes_written;
         }
     }
+
+    // the kernel mounts an FFAT image read-only below an in-memory file system
+    if let Some(kernel) = matches.value_of("kernel") {
+        append_to_kernel(kernel, &disk);
+    }
 }
 
 fn create_image<FS, B>(disk: &mut FS, path: &std_path::Path, disk_path: bit_fs::path::Path)
@@ -85,9 +144,41 @@ where FS: CompleteFileSystem<B>, B: ?Sized + RWBlockDevice {
             let entry = entry.unwrap();
             let path = entry.path();
             let name = path.file_name().unwrap().to_str().unwrap().as_bytes().to_vec();
-            let disk_path = disk_path.concat(name);
+            let disk_path = disk_path.join(name).unwrap();
             create_image(disk, &path, disk_path);
         }
     }
 }
 
+
+fn create_archive(writer: &mut NewcWriter, path: &std_path::Path, name: Vec<u8>) {
+    if !name.is_empty() {
+        if path.is_dir() {
+            writer.add_dir(&name);
+        } else {
+            writer.add_file(&name, &std_fs::read(path).unwrap());
+        }
+    }
+
+    if path.is_dir() {
+        let mut entries: Vec<_> = std_fs::read_dir(path).unwrap().map(|entry| entry.unwrap().path()).collect();
+        entries.sort();
+        for path in entries {
+            let mut child = name.clone();
+            if !child.is_empty() {
+                child.push(b'/');
+            }
+            child.extend_from_slice(path.file_name().unwrap().to_str().unwrap().as_bytes());
+            create_archive(writer, &path, child);
+        }
+    }
+}
+
+/// appends an image to the kernel binary, which the bootloader loads as a whole
+fn append_to_kernel(kernel: &str, image: &[u8]) {
+    let mut binary = std_fs::read(kernel).unwrap();
+    let len = archive::elf_len(&binary).expect("kernel is not an ELF file");
+    binary.truncate(len);
+    binary.extend_from_slice(image);
+    std_fs::write(kernel, &binary).unwrap();
+}
@@ -0,0 +1,308 @@
+//! ATA disks accessed with programmed I/O.
+//!
+//! Drives on the two legacy IDE buses are detected with IDENTIFY and addressed with 28-bit LBA,
+//! or 48-bit LBA for sectors the 28-bit commands can't reach. Interrupts of the controllers are
+//! disabled, the driver polls the status register instead.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use fs::block::*;
+use fs::error::*;
+
+/// Size of a sector, the unit in which ATA disks are addressed
+pub const SECTOR_SIZE: usize = 512;
+
+/// Number of times the status register is polled before a command is given up
+const TIMEOUT: usize = 1_000_000;
+
+const STATUS_ERR: u8 = 0x01;
+const STATUS_DRQ: u8 = 0x08;
+const STATUS_DF: u8 = 0x20;
+const STATUS_BSY: u8 = 0x80;
+
+/// Device control bit that disables interrupts
+const CONTROL_NIEN: u8 = 0x02;
+
+const CMD_READ: u8 = 0x20;
+const CMD_READ_EXT: u8 = 0x24;
+const CMD_WRITE: u8 = 0x30;
+const CMD_WRITE_EXT: u8 = 0x34;
+const CMD_FLUSH: u8 = 0xe7;
+const CMD_FLUSH_EXT: u8 = 0xea;
+const CMD_IDENTIFY: u8 = 0xec;
+
+/// First sector that 28-bit LBA can't address
+const LBA28_LIMIT: u64 = 1 << 28;
+
+/// Registers of an IDE bus
+struct Bus {
+    /// first register of the command block
+    io: u16,
+    /// device control and alternate status register
+    control: u16,
+    /// drive that was selected last, `None` before the first selection
+    selected: Option<bool>,
+}
+
+static PRIMARY: Mutex<Bus> = Mutex::new(Bus::new(0x1f0, 0x3f6));
+static SECONDARY: Mutex<Bus> = Mutex::new(Bus::new(0x170, 0x376));
+
+impl Bus {
+    const fn new(io: u16, control: u16) -> Self {
+        Self { io, control, selected: None }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + register).write(value) }
+    }
+
+    fn read_data(&self) -> u16 {
+        unsafe { Port::<u16>::new(self.io).read() }
+    }
+
+    fn write_data(&self, value: u16) {
+        unsafe { Port::<u16>::new(self.io).write(value) }
+    }
+
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn status(&self) -> u8 {
+        self.read(7)
+    }
+
+    /// waits the 400ns a drive needs to update its status
+    fn delay(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn disable_interrupts(&self) {
+        unsafe { Port::<u8>::new(self.control).write(CONTROL_NIEN) }
+    }
+
+    /// selects a drive, `lba_high` are the bits 24 to 27 of a 28-bit address
+    fn select(&mut self, slave: bool, lba_high: u8) {
+        self.write(6, 0xe0 | (slave as u8) << 4 | (lba_high & 0x0f));
+        if self.selected != Some(slave) {
+            self.delay();
+            self.selected = Some(slave);
+        }
+    }
+
+    /// waits until the drive isn't busy anymore
+    fn wait_ready(&self) -> FsResult<u8> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(FsError::BlockDeviceError)
+    }
+
+    /// waits until the drive is ready to transfer data
+    fn wait_data(&self) -> FsResult<()> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                if status & (STATUS_ERR | STATUS_DF) != 0 {
+                    return Err(FsError::BlockDeviceError);
+                }
+                if status & STATUS_DRQ != 0 {
+                    return Ok(());
+                }
+            }
+        }
+        Err(FsError::BlockDeviceError)
+    }
+
+    /// sends a command with a sector address and a sector count of one
+    fn command(&mut self, slave: bool, lba: u64, lba48: bool, command: u8) -> FsResult<()> {
+        self.wait_ready()?;
+        if lba48 {
+            self.select(slave, 0);
+            // the high bytes are written first, both go through the same registers
+            self.write(2, 0);
+            self.write(3, (lba >> 24) as u8);
+            self.write(4, (lba >> 32) as u8);
+            self.write(5, (lba >> 40) as u8);
+        } else {
+            self.select(slave, (lba >> 24) as u8);
+        }
+        self.write(2, 1);
+        self.write(3, lba as u8);
+        self.write(4, (lba >> 8) as u8);
+        self.write(5, (lba >> 16) as u8);
+        self.write(7, command);
+        self.delay();
+        Ok(())
+    }
+
+    /// identifies a drive, `None` if there is none or if it isn't an ATA disk
+    fn identify(&mut self, slave: bool) -> Option<[u16; 256]> {
+        self.disable_interrupts();
+        self.select(slave, 0);
+        for register in 2..6 {
+            self.write(register, 0);
+        }
+        self.write(7, CMD_IDENTIFY);
+        self.delay();
+
+        // a status of zero means that there is no drive, 0xff that there is no controller
+        let status = self.status();
+        if status == 0 || status == 0xff {
+            return None;
+        }
+        self.wait_ready().ok()?;
+        // ATAPI and SATA devices identify themselves through these registers
+        if self.read(4) != 0 || self.read(5) != 0 {
+            return None;
+        }
+        self.wait_data().ok()?;
+
+        let mut data = [0u16; 256];
+        for word in data.iter_mut() {
+            *word = self.read_data();
+        }
+        Some(data)
+    }
+}
+
+/// ATA disk on one of the legacy IDE buses
+pub struct AtaDisk {
+    bus: &'static Mutex<Bus>,
+    slave: bool,
+    sectors: u64,
+    lba48: bool,
+    model: String,
+}
+
+impl AtaDisk {
+    /// probes a drive, `None` if there is no ATA disk
+    pub fn probe(secondary: bool, slave: bool) -> Option<Self> {
+        let bus = if secondary { &SECONDARY } else { &PRIMARY };
+        let identity = bus.lock().identify(slave)?;
+
+        let lba48 = identity[83] & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).fold(0, |sectors, i| sectors | (identity[100 + i] as u64) << (16 * i))
+        } else {
+            identity[60] as u64 | (identity[61] as u64) << 16
+        };
+        if sectors == 0 {
+            return None;
+        }
+
+        // the model name is stored with the bytes of each word swapped
+        let model = identity[27..47].iter()
+            .flat_map(|word| word.to_be_bytes().to_vec())
+            .map(|byte| byte as char)
+            .collect::<String>();
+
+        Some(Self {
+            bus,
+            slave,
+            sectors,
+            lba48,
+            model: String::from(model.trim()),
+        })
+    }
+
+    /// model name reported by the drive
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// `true` if the sector has to be addressed with a 48-bit command
+    fn needs_lba48(&self, lba: u64) -> bool {
+        self.lba48 && lba >= LBA28_LIMIT
+    }
+
+    fn check(&self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        if buffer.len() != SECTOR_SIZE {
+            Err(FsError::InternalError(String::from("invalid buffer size")))
+        } else if index as u64 >= self.sectors {
+            Err(FsError::InternalError(String::from("out of bounds block address")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// all ATA disks on the legacy IDE buses
+pub fn disks() -> Vec<AtaDisk> {
+    [(false, false), (false, true), (true, false), (true, true)].iter()
+        .filter_map(|&(secondary, slave)| AtaDisk::probe(secondary, slave))
+        .collect()
+}
+
+impl BlockDevice for AtaDisk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        self.sectors as usize
+    }
+}
+
+impl ReadBlockDevice for AtaDisk {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.check(index, buffer)?;
+        let lba = index as u64;
+        let lba48 = self.needs_lba48(lba);
+
+        let mut bus = self.bus.lock();
+        bus.command(self.slave, lba, lba48, if lba48 { CMD_READ_EXT } else { CMD_READ })?;
+        bus.wait_data()?;
+        for bytes in buffer.chunks_mut(2) {
+            bytes.copy_from_slice(&bus.read_data().to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+impl WriteBlockDevice for AtaDisk {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        self.check(index, buffer)?;
+        let lba = index as u64;
+        let lba48 = self.needs_lba48(lba);
+
+        let mut bus = self.bus.lock();
+        bus.command(self.slave, lba, lba48, if lba48 { CMD_WRITE_EXT } else { CMD_WRITE })?;
+        bus.wait_data()?;
+        for bytes in buffer.chunks(2) {
+            bus.write_data(u16::from_le_bytes([bytes[0], bytes[1]]));
+        }
+        bus.delay();
+        let status = bus.wait_ready()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(FsError::BlockDeviceError);
+        }
+        Ok(())
+    }
+
+    /// the writes are only persistent once they left the cache of the drive
+    fn flush(&mut self) -> FsResult<()> {
+        let mut bus = self.bus.lock();
+        bus.wait_ready()?;
+        bus.select(self.slave, 0);
+        bus.write(7, if self.lba48 { CMD_FLUSH_EXT } else { CMD_FLUSH });
+        bus.delay();
+        let status = bus.wait_ready()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(FsError::BlockDeviceError);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Character devices: `null`, `zero`, the serial interface and the console.
+
+use alloc::sync::Arc;
+use alloc::string::String;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use fs::error::*;
+
+use super::*;
+
+/// Discards writes, reading is always at the end
+pub struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buffer: &mut [u8]) -> FsResult<usize> {
+        Ok(0)
+    }
+
+    fn write(&self, _buffer: &[u8]) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+/// Discards writes, reads zeros
+pub struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        for byte in buffer.iter_mut() {
+            *byte = 0;
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&self, _buffer: &[u8]) -> FsResult<()> {
+        Ok(())
+    }
+}
+
+/// First serial interface
+pub struct Serial;
+
+impl CharDevice for Serial {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        let mut len = 0;
+        while len < buffer.len() {
+            match crate::serial::try_receive() {
+                Some(byte) => buffer[len] = byte,
+                None => break,
+            }
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<()> {
+        crate::serial_print!("{}", String::from_utf8_lossy(buffer));
+        Ok(())
+    }
+}
+
+/// Size of the buffer of keyboard input that hasn't been read yet
+const INPUT_SIZE: usize = 256;
+
+/// Ring buffer of keyboard input
+struct Input {
+    buffer: [u8; INPUT_SIZE],
+    start: usize,
+    len: usize,
+}
+
+static CONSOLE_INPUT: Mutex<Input> = Mutex::new(Input {
+    buffer: [0; INPUT_SIZE],
+    start: 0,
+    len: 0,
+});
+
+/// adds keyboard input for the console, input that doesn't fit anymore is dropped
+/// called by the keyboard interrupt handler
+pub fn console_input(bytes: &[u8]) {
+    let mut input = CONSOLE_INPUT.lock();
+    for &byte in bytes {
+        if input.len == INPUT_SIZE {
+            break;
+        }
+        let end = (input.start + input.len) % INPUT_SIZE;
+        input.buffer[end] = byte;
+        input.len += 1;
+    }
+}
+
+/// Screen and keyboard
+pub struct Console;
+
+impl CharDevice for Console {
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize> {
+        // the keyboard interrupt handler takes the lock as well
+        interrupts::without_interrupts(|| {
+            let mut input = CONSOLE_INPUT.lock();
+            let len = input.len.min(buffer.len());
+            for byte in buffer[..len].iter_mut() {
+                *byte = input.buffer[input.start];
+                input.start = (input.start + 1) % INPUT_SIZE;
+            }
+            input.len -= len;
+            Ok(len)
+        })
+    }
+
+    fn write(&self, buffer: &[u8]) -> FsResult<()> {
+        crate::print!("{}", String::from_utf8_lossy(buffer));
+        Ok(())
+    }
+}
+
+/// registers the character devices with their numbers on Linux
+pub fn register_all() {
+    let devices: [(&str, DeviceNumber, Arc<dyn CharDevice>); 4] = [
+        ("null", DeviceNumber::new(1, 3), Arc::new(Null)),
+        ("zero", DeviceNumber::new(1, 5), Arc::new(Zero)),
+        ("serial0", DeviceNumber::new(4, 64), Arc::new(Serial)),
+        ("console", DeviceNumber::new(5, 1), Arc::new(Console)),
+    ];
+    for (name, number, device) in devices.iter().cloned() {
+        if register(name, number, Device::Char(device)).is_err() {
+            crate::println!("could not register character device {}", name);
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Disks and their partitions as block devices that file systems can be mounted on.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::format;
+use spin::Mutex;
+
+use fs::block::*;
+use fs::error::*;
+use fs::partition::*;
+
+use super::DeviceNumber;
+
+/// Handle to a disk that its partitions, the device registry and file systems share
+#[derive(Clone)]
+pub struct SharedDisk(Arc<Mutex<Box<dyn RWBlockDevice>>>);
+
+impl SharedDisk {
+    pub fn new<D: 'static + RWBlockDevice>(disk: D) -> Self {
+        SharedDisk(Arc::new(Mutex::new(Box::new(disk))))
+    }
+}
+
+impl BlockDevice for SharedDisk {
+    fn block_size(&self) -> usize {
+        self.0.lock().block_size()
+    }
+
+    fn blocks(&self) -> usize {
+        self.0.lock().blocks()
+    }
+}
+
+impl ReadBlockDevice for SharedDisk {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.0.lock().read_block(index, buffer)
+    }
+
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.0.lock().read_blocks(first, buffer)
+    }
+}
+
+impl WriteBlockDevice for SharedDisk {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        self.0.lock().write_block(index, buffer)
+    }
+
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        self.0.lock().write_blocks(first, buffer)
+    }
+
+    fn flush(&mut self) -> FsResult<()> {
+        self.0.lock().flush()
+    }
+}
+
+/// Disk or partition with its name, e.g. `vda` for a disk or `vda1` for its first partition
+pub struct NamedDisk {
+    pub name: String,
+    pub number: DeviceNumber,
+    pub disk: SharedDisk,
+}
+
+/// Major number and number of minors per disk of the disk drivers
+const VIRTIO_MAJOR: u32 = 254;
+const VIRTIO_MINORS: u32 = 16;
+const ATA_MAJOR: u32 = 3;
+const ATA_MINORS: u32 = 64;
+
+/// A disk followed by its partitions, numbered from one in the order of the partition table.
+/// The minor number of a partition is the one of the disk plus the number of the partition.
+pub fn partitions<D: 'static + RWBlockDevice>(name: &str, number: DeviceNumber, disk: D, minors: u32) -> Vec<NamedDisk> {
+    let table = match read_table(&disk) {
+        Ok(table) => table.unwrap_or_default(),
+        Err(err) => {
+            crate::println!("could not read the partition table of {}: {:?}", name, err);
+            Vec::new()
+        },
+    };
+
+    let disk = SharedDisk::new(disk);
+    let mut disks = Vec::new();
+    // partitions that don't fit into the minor numbers of the disk aren't accessible
+    for (i, partition) in table.iter().enumerate().take(minors as usize - 1) {
+        if let Ok(device) = PartitionDevice::new(disk.clone(), partition) {
+            disks.push(NamedDisk {
+                name: format!("{}{}", name, i + 1),
+                number: DeviceNumber::new(number.major, number.minor + i as u32 + 1),
+                disk: SharedDisk::new(device),
+            });
+        }
+    }
+    disks.insert(0, NamedDisk { name: String::from(name), number, disk });
+    disks
+}
+
+/// name of the `index`-th disk with the prefix, `a` for the first, `b` for the second...
+fn disk_name(prefix: &str, index: usize) -> String {
+    format!("{}{}", prefix, (b'a' + index as u8) as char)
+}
+
+/// all disks and their partitions, virtio disks first as they are much faster than ATA disks
+pub fn disks() -> Vec<NamedDisk> {
+    let mut disks = Vec::new();
+    for (i, disk) in crate::virtio::disks().into_iter().enumerate() {
+        let number = DeviceNumber::new(VIRTIO_MAJOR, i as u32 * VIRTIO_MINORS);
+        disks.extend(partitions(&disk_name("vd", i), number, disk, VIRTIO_MINORS));
+    }
+    for (i, disk) in crate::ata::disks().into_iter().enumerate() {
+        let number = DeviceNumber::new(ATA_MAJOR, i as u32 * ATA_MINORS);
+        disks.extend(partitions(&disk_name("hd", i), number, disk, ATA_MINORS));
+    }
+    disks
+}
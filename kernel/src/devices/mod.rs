@@ -0,0 +1,114 @@
+//! Registry of the devices of the kernel.
+//!
+//! Every device has a unique name and a unique pair of major and minor numbers, which follow the
+//! numbering of Linux. Block devices are disks and their partitions, character devices are
+//! streams like the serial interface. The registry is what `/dev` shows.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::sync::Arc;
+use spin::Mutex;
+
+use fs::error::*;
+
+pub mod disk;
+pub mod character;
+
+use disk::SharedDisk;
+
+/// Major and minor number of a device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceNumber {
+    pub major: u32,
+    pub minor: u32,
+}
+
+impl DeviceNumber {
+    pub const fn new(major: u32, minor: u32) -> Self {
+        Self { major, minor }
+    }
+}
+
+/// Device that is read and written as a stream of bytes
+pub trait CharDevice: Send + Sync {
+    /// reads the bytes that are available without waiting for more, zero if there are none
+    fn read(&self, buffer: &mut [u8]) -> FsResult<usize>;
+    /// writes all bytes of `buffer`
+    fn write(&self, buffer: &[u8]) -> FsResult<()>;
+}
+
+#[derive(Clone)]
+pub enum Device {
+    Block(SharedDisk),
+    Char(Arc<dyn CharDevice>),
+}
+
+struct Entry {
+    name: String,
+    number: DeviceNumber,
+    device: Device,
+}
+
+static DEVICES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// registers the character devices and all disks with their partitions
+pub fn init() {
+    character::register_all();
+
+    for disk in disk::disks() {
+        let name = disk.name.clone();
+        if register(&disk.name, disk.number, Device::Block(disk.disk)).is_err() {
+            crate::println!("could not register block device {}", name);
+        }
+    }
+}
+
+/// adds a device to the registry, fails if its name or number is already taken
+pub fn register(name: &str, number: DeviceNumber, device: Device) -> Result<(), Device> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|entry| entry.name == name || entry.number == number) {
+        return Err(device);
+    }
+    devices.push(Entry {
+        name: String::from(name),
+        number,
+        device,
+    });
+    Ok(())
+}
+
+/// device with the name
+pub fn find(name: &str) -> Option<Device> {
+    DEVICES.lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.device.clone())
+}
+
+/// Whether the block devices named `a` and `b` share blocks, which is the case if they are the
+/// same device or one is a partition of the other disk, e.g. `vda` and `vda1`
+pub fn overlapping(a: &str, b: &str) -> bool {
+    let is_partition_of = |partition: &str, disk: &str| partition.len() > disk.len()
+        && partition.starts_with(disk)
+        && partition[disk.len()..].bytes().all(|c| c.is_ascii_digit());
+    a == b || is_partition_of(a, b) || is_partition_of(b, a)
+}
+
+/// names and numbers of all devices in the order they were registered
+pub fn list() -> Vec<(String, DeviceNumber)> {
+    DEVICES.lock()
+        .iter()
+        .map(|entry| (entry.name.clone(), entry.number))
+        .collect()
+}
+
+/// all block devices in the order they were registered
+pub fn block_devices() -> Vec<(String, SharedDisk)> {
+    DEVICES.lock()
+        .iter()
+        .filter_map(|entry| match &entry.device {
+            Device::Block(disk) => Some((entry.name.clone(), disk.clone())),
+            Device::Char(_) => None,
+        })
+        .collect()
+}
@@ -0,0 +1,111 @@
+//! File system that shows the devices of the registry as files, usually attached at `/dev`.
+//!
+//! Character devices are read and written as streams. Block devices are read from the start to
+//! their end, they can't be written as file systems may be mounted on them.
+
+use alloc::vec::Vec;
+use alloc::sync::Arc;
+use alloc::string::String;
+
+use fs::block::*;
+use fs::error::*;
+use fs::filesystem::*;
+use fs::path::Path;
+use dep::fs::stats::FsStats;
+
+use crate::devices::{self, CharDevice, Device, disk::SharedDisk};
+
+pub struct DevFs;
+
+/// Open device
+pub enum Node {
+    Block { disk: SharedDisk, offset: usize },
+    Char(Arc<dyn CharDevice>),
+}
+
+impl DevFs {
+    pub fn new() -> Self {
+        DevFs
+    }
+
+    /// device of a path with a single component
+    fn device(&self, path: Path) -> FsResult<Device> {
+        if path.len() != 1 {
+            return Err(FsError::NotFound);
+        }
+        let name = String::from_utf8(path.name().ok_or(FsError::NotFound)?).map_err(|_| FsError::NotFound)?;
+        devices::find(&name).ok_or(FsError::NotFound)
+    }
+}
+
+impl BaseFileSystem for DevFs {
+    fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        if !path.is_root() {
+            return Err(FsError::NotFound);
+        }
+        Ok(devices::list().into_iter().map(|(name, _)| name.into_bytes()).collect())
+    }
+
+    fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        Ok(path.is_root())
+    }
+
+    fn exists_file(&self, path: Path) -> FsResult<bool> {
+        Ok(self.device(path).is_ok())
+    }
+
+    fn statfs(&self) -> FsResult<FsStats> {
+        Ok(FsStats::new("devfs", 0, 0, 0))
+    }
+}
+
+impl ReadFileSystem for DevFs {
+    type ReadProgress = Node;
+
+    fn open_read(&self, path: Path) -> FsResult<Node> {
+        Ok(match self.device(path)? {
+            Device::Block(disk) => Node::Block { disk, offset: 0 },
+            Device::Char(device) => Node::Char(device),
+        })
+    }
+
+    fn read(&self, progress: &mut Node, buffer: &mut [u8]) -> FsResult<usize> {
+        match progress {
+            Node::Block { disk, offset } => {
+                let size = disk.blocks() * disk.block_size();
+                let len = buffer.len().min(size.saturating_sub(*offset));
+                read_bytes(disk, *offset, &mut buffer[..len])?;
+                *offset += len;
+                Ok(len)
+            },
+            Node::Char(device) => device.read(buffer),
+        }
+    }
+
+    fn seek(&self, progress: &mut Node, seek: usize) -> FsResult<()> {
+        match progress {
+            Node::Block { offset, .. } => {
+                *offset += seek;
+                Ok(())
+            },
+            Node::Char(_) => Err(FsError::IllegalOperation(String::from("can't seek in a character device"))),
+        }
+    }
+}
+
+impl WriteFileSystem for DevFs {
+    type WriteProgress = Arc<dyn CharDevice>;
+
+    fn open_write(&mut self, path: Path) -> FsResult<Self::WriteProgress> {
+        match self.device(path)? {
+            Device::Block(_) => Err(FsError::AccessViolation),
+            Device::Char(device) => Ok(device),
+        }
+    }
+
+    fn write(&mut self, progress: &mut Self::WriteProgress, buffer: &[u8]) -> FsResult<()> {
+        progress.write(buffer)
+    }
+}
+
+impl ManageFileSystem for DevFs {}
@@ -0,0 +1,38 @@
+//! Locates the initial root file system that is appended to the kernel binary.
+//!
+//! The bootloader has no modules, but it loads the complete kernel file into memory that is
+//! marked as `Kernel` in the memory map. `fsimg --kernel` appends a cpio archive or an FFAT image
+//! to the kernel binary, so it is found in that memory right behind the ELF data. It can be
+//! replaced without recompiling the kernel.
+
+use bootloader::bootinfo::MemoryRegionType;
+use fs::archive;
+
+use crate::memory::boot_info;
+
+/// the ELF magic is searched within this many bytes from the start of the kernel region
+const PAGE_SIZE: usize = 4096;
+
+/// the data appended to the kernel binary, if there is any
+pub fn find() -> Option<&'static [u8]> {
+    let boot_info = boot_info();
+    boot_info.memory_map.iter()
+        .filter(|region| region.region_type == MemoryRegionType::Kernel)
+        .filter_map(|region| {
+            let start = region.range.start_addr();
+            let len = (region.range.end_addr() - start) as usize;
+            let memory = unsafe {
+                core::slice::from_raw_parts((boot_info.physical_memory_offset + start) as *const u8, len)
+            };
+
+            // the region starts at the frame that contains the beginning of the file
+            let file = (0..PAGE_SIZE.min(len))
+                .find(|&offset| memory[offset..].starts_with(b"\x7fELF"))
+                .map(|offset| &memory[offset..])?;
+
+            // the rest of the last frame is padding
+            let data = file.get(archive::elf_len(file)?..)?;
+            if data.iter().all(|&byte| byte == 0) { None } else { Some(data) }
+        })
+        .next()
+}
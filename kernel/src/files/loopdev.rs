@@ -0,0 +1,110 @@
+//! Block devices backed by a file of the VFS, so that the image of a file system stored in a file
+//! can be mounted without loading it into memory.
+
+use alloc::string::ToString;
+
+use fs::block::*;
+use fs::error::*;
+use fs::path::Path;
+
+use super::fs;
+
+/// Block size of loop devices, the size of a disk sector
+const LOOP_BLOCK_SIZE: usize = 512;
+
+/// Block device on a file, the file stays open until the device is dropped, so its file system
+/// can't be detached in the meantime. Bytes after the last whole block of the file are ignored.
+pub struct LoopDevice {
+    /// file descriptor of the image
+    fd: i64,
+    blocks: usize,
+    /// `false` if the device was opened read-only, its writes fail
+    writable: bool,
+}
+
+impl LoopDevice {
+    /// opens the file at `path` as a device that is only read
+    pub fn read_only(path: Path) -> FsResult<Self> {
+        Self::open(path, false)
+    }
+
+    /// opens the file at `path` as a device that is read and written,
+    /// writing fails if the file system of the file can't overwrite parts of a file
+    pub fn read_write(path: Path) -> FsResult<Self> {
+        Self::open(path, true)
+    }
+
+    fn open(path: Path, writable: bool) -> FsResult<Self> {
+        let fd = fs().open_image(path, writable)?;
+        match fs().size(fd) {
+            Ok(size) => Ok(Self { fd, blocks: size / LOOP_BLOCK_SIZE, writable }),
+            Err(err) => {
+                fs().close(fd)?;
+                Err(err)
+            },
+        }
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        // nothing else closes the file descriptor, so this can't fail
+        let _ = fs().close(self.fd);
+    }
+}
+
+impl BlockDevice for LoopDevice {
+    fn block_size(&self) -> usize {
+        LOOP_BLOCK_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        self.blocks
+    }
+}
+
+impl LoopDevice {
+    /// checks that the blocks of `buffer` starting at `first` are in the image
+    fn check_range(&self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        let count = block_count(LOOP_BLOCK_SIZE, buffer.len())?;
+        if first.checked_add(count).map_or(true, |end| end > self.blocks) {
+            Err(FsError::InternalError("out of bounds block address".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl ReadBlockDevice for LoopDevice {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        check_args(self, buffer, index)?;
+        self.read_blocks(index, buffer)
+    }
+
+    /// reads the blocks with a single read of the image
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.check_range(first, buffer)?;
+        let read = fs().read_at(self.fd, first * LOOP_BLOCK_SIZE, buffer)?;
+        if read < buffer.len() {
+            // the image was truncated after it was opened
+            return Err(FsError::BlockDeviceError);
+        }
+        Ok(())
+    }
+}
+
+impl WriteBlockDevice for LoopDevice {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        check_args(self, buffer, index)?;
+        self.write_blocks(index, buffer)
+    }
+
+    /// writes the blocks with a single write of the image
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        self.check_range(first, buffer)?;
+        if !self.writable {
+            return Err(FsError::AccessViolation);
+        }
+        fs().write_at(self.fd, first * LOOP_BLOCK_SIZE, buffer)
+    }
+}
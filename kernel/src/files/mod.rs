@@ -1,65 +1,179 @@
-use alloc::{vec, vec::Vec};
-use alloc::collections::BTreeMap;
+use alloc::{vec, vec::Vec, format};
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::string::*;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use spin::*;
-use crate::{print, println, fs::{*, ffat::*}};
+use crate::{print, println, serial_println, fs::{*, ffat::*, fat::FAT, ext2::Ext2, tmpfs::Tmpfs, overlay::Overlay}};
 use core::ops::DerefMut;
 use core::marker::*;
+use core::sync::atomic::{AtomicI64, Ordering};
 
 use fs::error::*;
 use fs::filesystem::*;
 use fs::block::*;
-use fs::memory_devices::*;
+use fs::memory_devices::RomDisk;
 use fs::path::Path;
+use dep::fs::stats::FsStats;
+use dep::fs::watch::{Event, EventKind};
 
 pub mod virt;
+pub mod initramfs;
+pub mod devfs;
+pub mod procfs;
+pub mod loopdev;
 
 use virt::*;
+use devfs::DevFs;
+use procfs::ProcFs;
+use loopdev::LoopDevice;
 
-static DISK_IMAGE: &'static [u8] = include_bytes!("../../../disk.img");
+/// maximum size of all files in the root file system together
+const ROOT_SIZE: usize = 64 * 1024 * 1024;
 
-static FS: Once<Mutex<RootFileSystem>> = Once::new();
+/// maximum size of all files in `/tmp` together
+const TMP_SIZE: usize = 4 * 1024 * 1024;
+
+static FS: Once<RootFileSystem> = Once::new();
 
 static FILE_SYSTEMS: Once<Mutex<Vec<MountData>>> = Once::new();
 
+static VIRT: Once<VirtualFileSystem> = Once::new();
+
+/// attached file systems with their sources, kept apart from the root file system so that they
+/// can be listed without looking at the file systems, e.g. by the procfs
+static MOUNT_TABLE: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+/// sources of the mounts that are running, they count as mounted until the mount is done,
+/// so that the same blocks can't be mounted twice at the same time
+static MOUNTING: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Entry of the mount table
+#[derive(Clone)]
+pub struct Mount {
+    /// device the file system is mounted from, `None` for file systems that don't have one
+    pub source: Option<String>,
+    pub attach_point: Path,
+    /// name of the file system
+    pub name: String,
+}
+
+/// Reason mounting a block device failed
+pub enum MountError {
+    /// no file system accepted the device, which is given back
+    NoFileSystem(Box<dyn RWBlockDevice>),
+    /// a file system accepted the device, but it couldn't be attached
+    Attach(FsError),
+}
+
 
 /// initializes the file system if it isn't already
 pub fn init() {
     // register all file system types that can mount disks
-    // currently only FFAT
     register_fs::<FFAT<_>>();
+    register_fs::<FAT<_>>();
+    register_fs::<Ext2<_>>();
 
     // do something with the DISK so that it gets initialized
     fs();
 
-    // copy the entire read-only DISK_IMAGE to be able to write to it
-    // does not persist accross reboots
-    let mut img = vec![0u8; DISK_IMAGE.len()];
-    for i in 0..img.len() {
-        img[i] = DISK_IMAGE[i];
+    // a partition with a known file system is mounted at root, e.g. `-drive file=disk.img,format=raw`
+    let mut root_on_disk = false;
+    for (name, disk) in crate::devices::block_devices() {
+        if mount_device(Box::new(disk), Path::root(), Some(&name), None).is_ok() {
+            println!("mounted {} at root", name);
+            root_on_disk = true;
+            break;
+        }
     }
 
-    mount_disk(OwnedDisk{ data: img }, Path::root()).expect("could not mount root file system");
+    // otherwise the root file system lives in memory and does not persist accross reboots
+    if !root_on_disk {
+        let upper = Tmpfs::new(ROOT_SIZE).with_clock(crate::interrupts::ticks);
+        match initramfs::find() {
+            Some(data) if archive::is_archive(data) => {
+                let mut root = upper;
+                let entries = archive::unpack(data, &mut root).expect("could not unpack initramfs");
+                serial_println!("unpacked {} entries from the initramfs", entries);
+                fs().attach(root, Path::root()).map_err(|_| "could not attach root file system").unwrap();
+            },
+            Some(data) => {
+                // the image stays where the bootloader put it, only changed files take up memory
+                let lower = FFAT::mount(Box::new(RomDisk { data })).map_err(|_| "appended data is neither an archive nor an FFAT image").unwrap();
+                fs().attach(Overlay::new(lower, upper), Path::root()).map_err(|_| "could not attach root file system").unwrap();
+            },
+            None => {
+                println!("no initramfs found, the root file system is empty");
+                fs().attach(upper, Path::root()).map_err(|_| "could not attach root file system").unwrap();
+            },
+        }
+    }
 
     // creating and mounting virtual file system
-    let mut vfs = VirtualFileSystem::new();
+    let vfs = virt();
     vfs.register_file(Path::from_str("/vfs-working").unwrap(), || b"yes".to_vec()).unwrap();
+    vfs.register_rw_file(
+        Path::from_str("/trace-scheduler").unwrap(),
+        || if crate::process::tracing() { b"1\n".to_vec() } else { b"0\n".to_vec() },
+        |data| {
+            match data.first() {
+                Some(b'0') => crate::process::set_tracing(false),
+                Some(b'1') => crate::process::set_tracing(true),
+                _ => return Err(FsError::IllegalOperation("expected 0 or 1".to_string())),
+            }
+            Ok(())
+        },
+    ).unwrap();
 
     let vfs_path = Path::new("/virt/").unwrap();
-    fs().create_dir(vfs_path.clone()).unwrap();
+    // the directories already exist when the root file system is persistent
+    if !fs().exists_dir(vfs_path.clone()).unwrap() {
+        fs().create_dir(vfs_path.clone()).unwrap();
+    }
     fs().attach(vfs, vfs_path).map_err(|_| "could not attach virtual fs").unwrap();
+
+    // devices of the registry
+    let dev_path = Path::new("/dev/").unwrap();
+    if !fs().exists_dir(dev_path.clone()).unwrap() {
+        fs().create_dir(dev_path.clone()).unwrap();
+    }
+    fs().attach(DevFs::new(), dev_path).map_err(|_| "could not attach devfs").unwrap();
+
+    // information about the kernel and the processes
+    let proc_path = Path::new("/proc/").unwrap();
+    if !fs().exists_dir(proc_path.clone()).unwrap() {
+        fs().create_dir(proc_path.clone()).unwrap();
+    }
+    fs().attach(ProcFs::new(), proc_path).map_err(|_| "could not attach procfs").unwrap();
+
+    // scratch space that only lives in memory
+    let tmp_path = Path::new("/tmp/").unwrap();
+    if !fs().exists_dir(tmp_path.clone()).unwrap() {
+        fs().create_dir(tmp_path.clone()).unwrap();
+    }
+    let tmpfs = Tmpfs::new(TMP_SIZE).with_clock(crate::interrupts::ticks);
+    fs().attach(tmpfs, tmp_path).map_err(|_| "could not attach tmpfs").unwrap();
+
+    serial_println!("{}", fs().df());
 }
 
 struct MountData {
     name: String,
-    mount: Box<dyn Fn(Box<dyn RWBlockDevice>, Path) -> Result<(), Box<dyn RWBlockDevice>> + Send>,
-    format: Box<dyn Fn(Box<dyn RWBlockDevice>, Path) -> Result<(), Box<dyn RWBlockDevice>> + Send>,
+    mount: Box<dyn Fn(Box<dyn RWBlockDevice>, Path, Option<String>) -> Result<(), MountError> + Send>,
+    format: Box<dyn Fn(Box<dyn RWBlockDevice>, Path, Option<String>) -> Result<(), MountError> + Send>,
+}
+
+impl MountData {
+    /// `true` if `name` is the name of the file system or its first word, ignoring case
+    fn is_named(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self.name.split(' ').next().map_or(false, |word| word.eq_ignore_ascii_case(name))
+    }
 }
 
-/// returns an exclusive handle to the file system
-pub fn fs() -> impl DerefMut<Target = RootFileSystem> {
-    FS.call_once(|| Mutex::new(RootFileSystem::new())).lock()
+/// returns the file system, the attached file systems and open files are locked separately
+pub fn fs() -> &'static RootFileSystem {
+    FS.call_once(RootFileSystem::new)
 }
 
 /// returns an exclusive handle to the registered mountable file systems
@@ -68,14 +182,15 @@ fn file_systems() -> impl DerefMut<Target = Vec<MountData>> {
 }
 
 pub fn register_fs<FS: 'static + CompleteFileSystem<dyn RWBlockDevice> + Send>() {
-    let mount = Box::new(|dev, path| {
-        fs().attach(FS::mount(dev)?, path).map_err(|fs| fs.inner())?;
-        Ok(())
+    // the attach point is the only reason attaching fails
+    let mount = Box::new(|dev, path, source| {
+        let file_system = FS::mount(dev).map_err(MountError::NoFileSystem)?;
+        fs().attach_source(file_system, path, source).map_err(|_| MountError::Attach(FsError::NotFound))
     });
 
-    let format = Box::new(|dev, path| {
-        fs().attach(FS::format(dev)?, path).map_err(|fs| fs.inner())?;
-        Ok(())
+    let format = Box::new(|dev, path, source| {
+        let file_system = FS::format(dev).map_err(MountError::NoFileSystem)?;
+        fs().attach_source(file_system, path, source).map_err(|_| MountError::Attach(FsError::NotFound))
     });
         
     let data = MountData {
@@ -89,221 +204,520 @@ pub fn register_fs<FS: 'static + CompleteFileSystem<dyn RWBlockDevice> + Send>()
 
 /// mounts a disk partition by trying for each file system if it fits
 pub fn mount_disk<D: 'static + RWBlockDevice>(disk: D, path: Path) -> Result<(), ()> {
-    let mut disk: Box<dyn RWBlockDevice> = Box::new(disk);
-    for fs in file_systems().iter() {
-        match (fs.mount)(disk, path.clone()) {
-            Ok(_) => return Ok(()),
-            Err(d) => disk = d,
+    mount_device(Box::new(disk), path, None, None).map_err(|_| ())
+}
+
+/// Mounts a block device with the file system named `fs_type`, or the first one that fits.
+/// `source` is the name of the device in the mount table. Returns the device if no file system
+/// accepts it.
+pub fn mount_device(mut device: Box<dyn RWBlockDevice>, path: Path, source: Option<&str>, fs_type: Option<&str>)
+    -> Result<(), MountError>
+{
+    for fs in file_systems().iter().filter(|fs| fs_type.map_or(true, |name| fs.is_named(name))) {
+        match (fs.mount)(device, path.clone(), source.map(String::from)) {
+            Err(MountError::NoFileSystem(d)) => device = d,
+            result => return result,
         }
     }
-    Err(())
+    Err(MountError::NoFileSystem(device))
+}
+
+/// Mounts the block device of the registry named `source`, optionally prefixed with `/dev/`,
+/// at the directory `target`. Any other `source` is the path of a file with the image of a file
+/// system, which is mounted through a loop device that is only written if `writable` is set.
+/// The file system is the one named `fs_type`, or the first that fits.
+pub fn mount(source: &str, target: Path, fs_type: Option<&str>, writable: bool) -> FsResult<()> {
+    let name = if source.starts_with("/dev/") { &source[5..] } else { source };
+    let (device, source, is_device): (Box<dyn RWBlockDevice>, String, bool) = match crate::devices::find(name) {
+        Some(crate::devices::Device::Block(disk)) => (Box::new(disk), String::from(name), true),
+        Some(_) => return Err(FsError::IllegalOperation(format!("{} is not a block device", name))),
+        None => {
+            let image = crate::process::current_dir().resolve(source)?;
+            let device = if writable {
+                LoopDevice::read_write(image.clone())?
+            } else {
+                LoopDevice::read_only(image.clone())?
+            };
+            (Box::new(device), image.to_string(), false)
+        },
+    };
+    if let Some(name) = fs_type {
+        if !file_systems().iter().any(|fs| fs.is_named(name)) {
+            return Err(FsError::InvalidArgument(format!("unknown file system {}", name)));
+        }
+    }
+    // two file systems on the same blocks would overwrite each other's data,
+    // a disk can't be mounted while one of its partitions is and the other way round
+    let overlapping = |mounted: &String| if is_device {
+        crate::devices::overlapping(mounted, &source)
+    } else {
+        *mounted == source
+    };
+    {
+        // the source is reserved while the mount table is read, so no other mount can take it
+        // before it is in the table
+        let mut mounting = MOUNTING.lock();
+        let busy = mount_table().iter()
+            .filter_map(|mount| mount.source.as_ref())
+            .chain(mounting.iter())
+            .any(|mounted| overlapping(mounted));
+        if busy {
+            return Err(FsError::Busy);
+        }
+        mounting.push(source.clone());
+    }
+    let result = match fs().exists_dir(target.clone()) {
+        Ok(true) => match mount_device(device, target, Some(&source), fs_type) {
+            Ok(()) => Ok(()),
+            Err(MountError::NoFileSystem(_)) => Err(FsError::InvalidArgument(format!("no file system found on {}", source))),
+            Err(MountError::Attach(err)) => Err(err),
+        },
+        Ok(false) => Err(FsError::NotFound),
+        Err(err) => Err(err),
+    };
+    let mut mounting = MOUNTING.lock();
+    if let Some(index) = mounting.iter().position(|reserved| *reserved == source) {
+        mounting.remove(index);
+    }
+    result
+}
+
+/// handle to the virtual file system attached at `/virt`, where kernel subsystems publish files
+pub fn virt() -> VirtualFileSystem {
+    VIRT.call_once(VirtualFileSystem::new).clone()
+}
+
+/// attached file systems in the order they were attached
+pub fn mount_table() -> Vec<Mount> {
+    MOUNT_TABLE.lock().clone()
 }
 
 /// reads the entire file specified by the path and returns it in a vec
 pub fn read_all(path: Path) -> FsResult<Vec<u8>> {
-    let mut fs = fs();
+    let fs = fs();
     let handle = fs.open_read(path)?;
 
     let mut vec = Vec::new();
     let mut buffer = [0u8; 4096];
 
-    loop {
-        let bytes_read = fs.read(handle, &mut buffer)?;
+    let result = loop {
+        let bytes_read = match fs.read(handle, &mut buffer) {
+            Ok(bytes_read) => bytes_read,
+            Err(err) => break Err(err),
+        };
         if bytes_read == 0 {
-            break;
+            break Ok(vec);
         }
-        vec.append(&mut buffer[..bytes_read as usize].to_vec());
-    }
-    Ok(vec)
+        vec.extend_from_slice(&buffer[..bytes_read]);
+    };
+    fs.close(handle)?;
+    result
 }
 
-pub struct RootFileSystem {
-    /// attached file systems
-    file_systems: Vec<Option<Box<dyn 'static + Attached + Send>>>,
-
-    /// next unique file descriptor
-    next_fd: i64,
+/// Open file, locked on its own so that reading it doesn't block other files
+type SharedFile = Arc<Mutex<Box<dyn OpenFile>>>;
 
-    /// open 'read' file descriptors mapped to index of file system
-    files_read: BTreeMap<i64, usize>,
+/// Kind of an advisory lock
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LockKind {
+    /// any number of open files can hold it at the same time
+    Shared,
+    /// only one open file can hold it
+    Exclusive,
+}
 
-    /// open 'write' file descriptors mapped to index of file system
-    files_write: BTreeMap<i64, usize>,
+/// Advisory lock of a file and the file descriptors holding it
+enum FileLock {
+    Shared(Vec<i64>),
+    Exclusive(i64),
 }
 
-pub fn map_err(result: FsResult<i64>) -> i64 {
-    match result {
-        Ok(fd) => fd,
-        Err(err) => error_to_const(err),
-    }
+/// events of a watch that weren't read yet, newer events are dropped when there are this many
+const MAX_QUEUED_EVENTS: usize = 256;
+
+/// Watch of a file or directory, its events are read from the file descriptor `fd`
+struct Watch {
+    fd: i64,
+    path: Path,
+    events: Arc<Mutex<VecDeque<Event>>>,
 }
 
-use dep::fs::error::*;
-pub fn error_to_const(err: FsError) -> i64 {
-    match err {
-        FsError::NotFound => NOT_FOUND,
-        FsError::AccessViolation => ACCESS_VIOLATION,
-        FsError::IllegalOperation(_) => ILLEGAL,
-        _ => OTHER,
-    }
+/// All attached file systems with the files opened in them.
+/// Each attached file system and each open file has its own lock, so independent operations
+/// don't wait for each other. The list of attached file systems is read-locked while an operation
+/// runs in one of them, only attaching and detaching wait for that.
+pub struct RootFileSystem {
+    /// attached file systems in the order they were attached
+    file_systems: RwLock<Vec<Arc<dyn Attached>>>,
+
+    /// next unique file descriptor
+    next_fd: AtomicI64,
+
+    /// open files with their paths by file descriptor
+    files: RwLock<BTreeMap<i64, (Path, SharedFile)>>,
+
+    /// advisory locks by path of the locked file
+    locks: Mutex<BTreeMap<Path, FileLock>>,
+
+    /// watched files and directories
+    watches: Mutex<Vec<Watch>>,
 }
 
 impl RootFileSystem {
     pub fn new() -> Self {
         Self {
-            file_systems: Vec::new(),
-            next_fd: 1,
-            files_read: BTreeMap::new(),
-            files_write: BTreeMap::new(),
+            file_systems: RwLock::new(Vec::new()),
+            next_fd: AtomicI64::new(1),
+            files: RwLock::new(BTreeMap::new()),
+            locks: Mutex::new(BTreeMap::new()),
+            watches: Mutex::new(Vec::new()),
         }
     }
 
-    /// attaches a file system at a given path or fails if
-    /// the attach directory has conflicting entries
-    pub fn attach<T> (&mut self, mut fs: T, attach_point: Path) -> Result<(), T>
+    /// Attaches a file system at a directory, or at root if nothing is attached yet.
+    /// The entries of the directory are shadowed by the file system until it is detached.
+    pub fn attach<T> (&self, fs: T, attach_point: Path) -> Result<(), T>
     where T: 'static + FunctionalFileSystem + Send
     {
-        if attach_point.is_root() && self.attach_count() == 0 {
-            self.file_systems.push(Some(Box::new(AttachedFileSystem::new(fs, attach_point))));
-            Ok(())
-        } else {
-            match fs.read_dir(Path::root()) {
-                Ok(fs_root_entries) => {
-                    match self.read_dir(attach_point.clone()) {
-                        Ok(mounted_root_entries) => {
-                            for mounted_entry in mounted_root_entries {
-                                if fs_root_entries.contains(&mounted_entry) {
-                                    return Err(fs);
-                                }
-                            }
-                            self.file_systems.push(Some(Box::new(AttachedFileSystem::new(fs, attach_point))));
-                            Ok(())
-                        },
-                        _ => Err(fs),
-                    }
-                },
-                Err(err) => {
-                    println!("Failed to read root entries of file system: {:?}", err);
-                    Err(fs)
-                }
-            }
+        self.attach_source(fs, attach_point, None)
+    }
+
+    /// attaches a file system that is mounted from the device named `source`
+    pub fn attach_source<T> (&self, fs: T, attach_point: Path, source: Option<String>) -> Result<(), T>
+    where T: 'static + FunctionalFileSystem + Send
+    {
+        let first_root = attach_point.is_root() && self.attach_count() == 0;
+        if !first_root && !self.exists_dir(attach_point.clone()).unwrap_or(false) {
+            return Err(fs);
         }
+
+        let name = match fs.statfs() {
+            Ok(stats) => String::from_utf8_lossy(stats.name()).into_owned(),
+            Err(_) => String::from("?"),
+        };
+        MOUNT_TABLE.lock().push(Mount { source, attach_point: attach_point.clone(), name });
+        self.file_systems.write().push(Arc::new(AttachedFileSystem::new(fs, attach_point)));
+        Ok(())
     }
 
-    pub fn attach_count(&self) -> usize {
-        self.file_systems
+    /// Detaches the file system that was attached last at the path, after flushing it.
+    /// Fails while it has open files or while other file systems are attached inside of it.
+    pub fn detach(&self, attach_point: Path) -> FsResult<()> {
+        if attach_point.is_root() {
+            return Err(FsError::IllegalOperation("can't detach the root file system".to_string()));
+        }
+        // opening a file holds the lock of the list, so no file can be opened in the file system
+        // between checking that it is not busy and removing it
+        let mut file_systems = self.file_systems.write();
+        let index = file_systems
             .iter()
-            .filter(|fs| fs.is_some())
-            .count()
+            .rposition(|fs| *fs.attach_point() == attach_point)
+            .ok_or(FsError::NotFound)?;
+
+        let nested = file_systems
+            .iter()
+            .any(|fs| fs.attach_point().strip_prefix(&attach_point).map_or(false, |rel| !rel.is_root()));
+        if file_systems[index].is_busy() || nested {
+            return Err(FsError::Busy);
+        }
+
+        // the file system stays attached if its data can't be written
+        file_systems[index].with_fs(&mut |fs| fs.flush())?;
+        file_systems.remove(index);
+
+        let mut table = MOUNT_TABLE.lock();
+        if let Some(entry) = table.iter().rposition(|mount| mount.attach_point == attach_point) {
+            table.remove(entry);
+        }
+        Ok(())
     }
 
-    fn get_free_fd(&mut self) -> i64 {
-        let fd = self.next_fd;
-        self.next_fd += 1;
-        fd
+    pub fn attach_count(&self) -> usize {
+        self.file_systems.read().len()
     }
 
-    pub fn open_write(&mut self, path: Path) -> FsResult<i64> {
-        let fd = self.get_free_fd();
-        let (fs, path) = self.suitable_fs(path)?;
-        self.file_systems[fs].as_mut().unwrap().open_write(fd, path)?;
-        self.files_write.insert(fd, fs);
-        Ok(fd)
+    fn get_free_fd(&self) -> i64 {
+        self.next_fd.fetch_add(1, Ordering::SeqCst)
     }
 
-    pub fn open_read(&mut self, path: Path) -> FsResult<i64> {
+    /// adds a file opened at `path` to the open files
+    fn insert_file(&self, path: Path, file: Box<dyn OpenFile>) -> i64 {
         let fd = self.get_free_fd();
-        let (fs, path) = self.suitable_fs(path)?;
-        self.file_systems[fs].as_mut().unwrap().open_read(fd, path)?;
-        self.files_read.insert(fd, fs);
-        Ok(fd)
+        self.files.write().insert(fd, (path, Arc::new(Mutex::new(file))));
+        fd
     }
 
-    pub fn read_dir(&mut self, path: Path) -> FsResult<Vec<Filename>> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().read_dir(path))
+    /// open file of a file descriptor, the list of open files is only locked during the lookup
+    fn file(&self, fd: i64) -> FsResult<SharedFile> {
+        self.files.read()
+            .get(&fd)
+            .map(|(_, file)| Arc::clone(file))
+            .ok_or(FsError::BadFileDescriptor)
     }
 
-    pub fn write(&mut self, fd: i64, buffer: &[u8]) -> FsResult<()> {
-        if let Some(&fs) = self.files_write.get(&fd) {
-            if let Some(fs) = self.file_systems[fs].as_mut() {
-                fs.write(fd, buffer)
-            } else {
-                Err(FsError::IllegalOperation("write after detached".to_string()))
-            }
-        } else {
-            Err(FsError::IllegalOperation("no such file descriptor".to_string()))
+    /// path an open file was opened at
+    fn path_of(&self, fd: i64) -> FsResult<Path> {
+        self.files.read()
+            .get(&fd)
+            .map(|(path, _)| path.clone())
+            .ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn open_write(&self, path: Path) -> FsResult<i64> {
+        self.with_suitable_fs(path.clone(), |fs, rel| {
+            let file = fs.open_write(rel)?;
+            Ok(self.insert_file(path, file))
+        })
+    }
+
+    pub fn open_read(&self, path: Path) -> FsResult<i64> {
+        self.with_suitable_fs(path.clone(), |fs, rel| {
+            let file = fs.open_read(rel)?;
+            Ok(self.insert_file(path, file))
+        })
+    }
+
+    /// Opens a file that is read and written at any offset with `read_at` and `write_at`,
+    /// like the image of a disk. Writing fails unless it is `writable`.
+    pub fn open_image(&self, path: Path, writable: bool) -> FsResult<i64> {
+        self.with_suitable_fs(path.clone(), |fs, rel| {
+            let file = fs.open_image(rel, writable)?;
+            Ok(self.insert_file(path, file))
+        })
+    }
+
+    /// Acquires an advisory lock of the file opened as `fd`, or converts the lock it holds.
+    /// Fails with `WouldBlock` if another open file holds a conflicting lock.
+    /// Locks belong to the open file and are released when it is closed.
+    pub fn lock(&self, fd: i64, kind: LockKind) -> FsResult<()> {
+        let path = self.path_of(fd)?;
+
+        let mut locks = self.locks.lock();
+        let lock = match (locks.get(&path), kind) {
+            (None, LockKind::Shared) => FileLock::Shared(vec![fd]),
+            (None, LockKind::Exclusive) => FileLock::Exclusive(fd),
+            (Some(FileLock::Shared(holders)), LockKind::Shared) => {
+                let mut holders = holders.clone();
+                if !holders.contains(&fd) {
+                    holders.push(fd);
+                }
+                FileLock::Shared(holders)
+            },
+            // the only holder can upgrade its lock
+            (Some(FileLock::Shared(holders)), LockKind::Exclusive) if holders[..] == [fd] => FileLock::Exclusive(fd),
+            (Some(FileLock::Exclusive(holder)), LockKind::Shared) if *holder == fd => FileLock::Shared(vec![fd]),
+            (Some(FileLock::Exclusive(holder)), LockKind::Exclusive) if *holder == fd => FileLock::Exclusive(fd),
+            _ => return Err(FsError::WouldBlock),
+        };
+        locks.insert(path, lock);
+        Ok(())
+    }
+
+    /// releases the advisory lock held by the file opened as `fd`, if it holds one
+    pub fn unlock(&self, fd: i64) -> FsResult<()> {
+        let path = self.path_of(fd)?;
+        self.release_lock(&path, fd);
+        Ok(())
+    }
+
+    fn release_lock(&self, path: &Path, fd: i64) {
+        let mut locks = self.locks.lock();
+        let unlocked = match locks.get_mut(path) {
+            Some(FileLock::Shared(holders)) => {
+                holders.retain(|&holder| holder != fd);
+                holders.is_empty()
+            },
+            Some(FileLock::Exclusive(holder)) => *holder == fd,
+            None => false,
+        };
+        if unlocked {
+            locks.remove(path);
         }
     }
 
-    pub fn read(&mut self, fd: i64, buffer: &mut [u8]) -> FsResult<usize> {
-        if let Some(&fs) = self.files_read.get(&fd) {
-            if let Some(fs) = self.file_systems[fs].as_mut() {
-                fs.read(fd, buffer)
-            } else {
-                Err(FsError::IllegalOperation("read after detached".to_string()))
+    pub fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        self.apply_to_suitable_fs(path, |fs, path| fs.read_dir(path))
+    }
+
+    /// Watches an existing file or directory. Changes to it, and to the entries of a directory,
+    /// that are made through the VFS are read as `Event`s from the returned file descriptor.
+    /// Reading returns only whole events and nothing if there are none, closing ends the watch.
+    pub fn watch(&self, path: Path) -> FsResult<i64> {
+        if !self.exists_file(path.clone())? && !self.exists_dir(path.clone())? {
+            return Err(FsError::NotFound);
+        }
+        let events = Arc::new(Mutex::new(VecDeque::new()));
+        let fd = self.insert_file(path.clone(), Box::new(WatchFile { events: Arc::clone(&events) }));
+        self.watches.lock().push(Watch { fd, path, events });
+        Ok(fd)
+    }
+
+    /// queues an event for the watches of the path and of its directory
+    fn notify(&self, kind: EventKind, path: &Path) {
+        let parent = path.parent_dir();
+        for watch in self.watches.lock().iter() {
+            if watch.path == *path || parent.as_ref() == Some(&watch.path) {
+                let mut events = watch.events.lock();
+                if events.len() < MAX_QUEUED_EVENTS {
+                    events.push_back(Event { kind, path: path.clone() });
+                }
             }
-        } else {
-            Err(FsError::IllegalOperation("no such file descriptor".to_string()))
         }
     }
 
-    pub fn seek(&mut self, fd: i64, seek: usize) -> FsResult<()> {
-        if let Some(&fs) = self.files_read.get(&fd) {
-            if let Some(fs) = self.file_systems[fs].as_mut() {
-                fs.seek(fd, seek)
-            } else {
-                Err(FsError::IllegalOperation("seek after detached".to_string()))
+    pub fn write(&self, fd: i64, buffer: &[u8]) -> FsResult<()> {
+        self.file(fd)?.lock().write(buffer)?;
+        self.notify(EventKind::Modify, &self.path_of(fd)?);
+        Ok(())
+    }
+
+    pub fn read(&self, fd: i64, buffer: &mut [u8]) -> FsResult<usize> {
+        self.file(fd)?.lock().read(buffer)
+    }
+
+    /// reads from the image opened as `fd` starting at `offset`, less than the length of the
+    /// buffer only at the end of the file
+    pub fn read_at(&self, fd: i64, offset: usize, buffer: &mut [u8]) -> FsResult<usize> {
+        self.file(fd)?.lock().read_at(offset, buffer)
+    }
+
+    /// size of the file opened as `fd` with `open_image` in bytes
+    pub fn size(&self, fd: i64) -> FsResult<usize> {
+        self.file(fd)?.lock().size()
+    }
+
+    /// overwrites the image opened as `fd` starting at `offset`
+    pub fn write_at(&self, fd: i64, offset: usize, buffer: &[u8]) -> FsResult<()> {
+        self.file(fd)?.lock().write_at(offset, buffer)?;
+        self.notify(EventKind::Modify, &self.path_of(fd)?);
+        Ok(())
+    }
+
+    /// closes a file and releases its lock, its file system can't be accessed through it anymore
+    pub fn close(&self, fd: i64) -> FsResult<()> {
+        let (path, _) = self.files.write()
+            .remove(&fd)
+            .ok_or(FsError::BadFileDescriptor)?;
+        self.release_lock(&path, fd);
+        self.watches.lock().retain(|watch| watch.fd != fd);
+        Ok(())
+    }
+
+    pub fn seek(&self, fd: i64, seek: usize) -> FsResult<()> {
+        self.file(fd)?.lock().seek(seek)
+    }
+
+    pub fn delete(&self, path: Path) -> FsResult<()> {
+        self.apply_to_suitable_fs(path.clone(), |fs, path| fs.delete(path))?;
+        self.notify(EventKind::Delete, &path);
+        Ok(())
+    }
+
+    pub fn clear(&self, path: Path) -> FsResult<()> {
+        self.apply_to_suitable_fs(path.clone(), |fs, path| fs.clear(path))?;
+        self.notify(EventKind::Modify, &path);
+        Ok(())
+    }
+
+    pub fn create_file(&self, path: Path) -> FsResult<()> {
+        self.apply_to_suitable_fs(path.clone(), |fs, path| fs.create_file(path))?;
+        self.notify(EventKind::Create, &path);
+        Ok(())
+    }
+
+    pub fn create_dir(&self, path: Path) -> FsResult<()> {
+        self.apply_to_suitable_fs(path.clone(), |fs, path| fs.create_dir(path))?;
+        self.notify(EventKind::Create, &path);
+        Ok(())
+    }
+
+    pub fn rename(&self, from: Path, to: Path) -> FsResult<()> {
+        {
+            let file_systems = self.file_systems.read();
+            let (from_fs, from_rel) = Self::suitable_fs(&file_systems, from.clone())?;
+            let (to_fs, to_rel) = Self::suitable_fs(&file_systems, to.clone())?;
+            if !Arc::ptr_eq(from_fs, to_fs) {
+                return Err(FsError::CrossDevice);
             }
-        } else {
-            Err(FsError::IllegalOperation("no such file descriptor".to_string()))
+            from_fs.with_fs(&mut |fs| fs.rename(from_rel.clone(), to_rel.clone()))?;
         }
+        self.notify(EventKind::MovedFrom, &from);
+        self.notify(EventKind::MovedTo, &to);
+        Ok(())
     }
 
-    pub fn delete(&mut self, path: Path) -> FsResult<()> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().delete(path))
+    pub fn statfs(&self, path: Path) -> FsResult<FsStats> {
+        self.apply_to_suitable_fs(path, |fs, _| fs.statfs())
     }
 
-    pub fn clear(&mut self, path: Path) -> FsResult<()> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().clear(path))
+    /// usage of every attached file system together with its attach point
+    pub fn mounts(&self) -> Vec<(Path, FsResult<FsStats>)> {
+        let file_systems = self.file_systems.read().clone();
+        file_systems
+            .iter()
+            .map(|fs| (fs.attach_point().clone(), fs.with_fs(&mut |fs| fs.statfs())))
+            .collect()
     }
 
-    pub fn create_file(&mut self, path: Path) -> FsResult<()> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().create_file(path))
+    /// `df`-like table of the attached file systems
+    pub fn df(&self) -> String {
+        let mut table = format!("{:<16} {:>10} {:>10} {:>10} {:>6}  {}\n",
+            "Filesystem", "Blocks", "Used", "Available", "Bsize", "Mounted on");
+        for (attach_point, stats) in self.mounts() {
+            let line = match stats {
+                Ok(stats) => format!("{:<16} {:>10} {:>10} {:>10} {:>6}  {}\n",
+                    String::from_utf8_lossy(stats.name()),
+                    stats.total_blocks,
+                    stats.used_blocks(),
+                    stats.free_blocks,
+                    stats.block_size,
+                    attach_point.to_string()),
+                Err(_) => format!("{:<16} {:>10} {:>10} {:>10} {:>6}  {}\n",
+                    "?", "-", "-", "-", "-", attach_point.to_string()),
+            };
+            table.push_str(&line);
+        }
+        table
     }
 
-    pub fn create_dir(&mut self, path: Path) -> FsResult<()> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().create_dir(path))
+    pub fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        self.apply_to_suitable_fs(path, |fs, path| fs.exists_dir(path))
     }
 
-    pub fn exists_dir(&mut self, path: Path) -> FsResult<bool> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().exists_dir(path))
+    pub fn exists_file(&self, path: Path) -> FsResult<bool> {
+        self.apply_to_suitable_fs(path, |fs, path| fs.exists_file(path))
     }
 
-    pub fn exists_file(&mut self, path: Path) -> FsResult<bool> {
-        self.apply_to_suitable_fs(path, |fs, path| fs.inner_fs_mut().exists_file(path))
+    /// finds the suitable attached file system and applies a function at the given path,
+    /// only that file system is locked while the function runs
+    fn apply_to_suitable_fs<R, F> (&self, path: Path, fun: F) -> FsResult<R>
+        where F: Fn(&mut dyn NonGenericFileSystem, Path) -> FsResult<R>
+    {
+        self.with_suitable_fs(path, |suitable, path| suitable.with_fs(&mut |fs| fun(fs, path.clone())))
     }
 
-    /// finds the suitable attached file system and applies a function at the given path
-    fn apply_to_suitable_fs<R, F> (&mut self, path: Path, fun: F) -> FsResult<R> 
-        where F: Fn(&mut Box<dyn Attached + Send>, Path) -> FsResult<R>
+    /// Finds the suitable attached file system and calls `fun` with it and the path inside of it.
+    /// The list of attached file systems stays read-locked, so the file system can't be
+    /// detached while `fun` runs.
+    fn with_suitable_fs<R, F> (&self, path: Path, fun: F) -> FsResult<R>
+        where F: FnOnce(&Arc<dyn Attached>, Path) -> FsResult<R>
     {
-        let (suitable, path) = self.suitable_fs(path)?;
-        let mut fs = self.file_systems[suitable].as_mut().unwrap();
-        fun(fs, path)
+        let file_systems = self.file_systems.read();
+        let (suitable, path) = Self::suitable_fs(&file_systems, path)?;
+        fun(suitable, path)
     }
 
-    /// returns the suitable attached file system for the given path
-    fn suitable_fs(&mut self, path: Path) -> FsResult<(usize, Path)> {
+    /// returns the suitable file system of `file_systems` for the given path
+    fn suitable_fs(file_systems: &[Arc<dyn Attached>], path: Path) -> FsResult<(&Arc<dyn Attached>, Path)> {
         let mut shortest_dist = usize::MAX;
         let mut suitable_fs = None;
 
-        for (i, fs) in self.file_systems.iter_mut().enumerate() {
-            if let Some(fs) = fs {
-                if let Some(rel) = path.clone().relative_to(fs.attach_point().clone()) {
-                    if rel.len() < shortest_dist {
-                        shortest_dist = rel.len();
-                        suitable_fs = Some((i, rel));
-                    }
+        for fs in file_systems.iter() {
+            if let Some(rel) = path.strip_prefix(fs.attach_point()) {
+                // the file system attached last shadows the ones attached at the same path before
+                if rel.len() <= shortest_dist {
+                    shortest_dist = rel.len();
+                    suitable_fs = Some((fs, rel.to_path()));
                 }
             }
         }
@@ -312,83 +726,184 @@ impl RootFileSystem {
     }
 }
 
-trait Attached {
-    fn attach_point(&mut self) -> &mut Path;
-    fn open_read(&mut self, fd: i64, path: Path) -> FsResult<()>;
-    fn open_write(&mut self, fd: i64, path: Path) -> FsResult<()>;
-    fn read(&mut self, fd: i64, buffer: &mut [u8]) -> FsResult<usize>;
-    fn write(&mut self, fd: i64, buffer: &[u8]) -> FsResult<()>;
-    fn seek(&mut self, fd: i64, seek: usize) -> FsResult<()>;
-    fn inner_fs_mut(&mut self) -> Box<&mut dyn NonGenericFileSystem>;
+/// Attached file system without its type
+trait Attached: Send + Sync {
+    fn attach_point(&self) -> &Path;
+    fn open_read(&self, path: Path) -> FsResult<Box<dyn OpenFile>>;
+    fn open_write(&self, path: Path) -> FsResult<Box<dyn OpenFile>>;
+    fn open_image(&self, path: Path, writable: bool) -> FsResult<Box<dyn OpenFile>>;
+    /// locks the file system and applies `fun` to it
+    fn lock_fs(&self, fun: &mut dyn FnMut(&mut dyn NonGenericFileSystem));
+    /// `true` while files of the file system are open
+    fn is_busy(&self) -> bool;
+}
+
+impl dyn Attached {
+    /// locks the file system and returns what `fun` returns for it
+    fn with_fs<R>(&self, fun: &mut dyn FnMut(&mut dyn NonGenericFileSystem) -> FsResult<R>) -> FsResult<R> {
+        let mut result = None;
+        self.lock_fs(&mut |fs| result = Some(fun(fs)));
+        result.unwrap()
+    }
+}
+
+/// File opened for reading or writing, the file system is only locked during an operation
+trait OpenFile: Send {
+    fn read(&mut self, _buffer: &mut [u8]) -> FsResult<usize> { no_such_fd() }
+    fn write(&mut self, _buffer: &[u8]) -> FsResult<()> { no_such_fd() }
+    fn seek(&mut self, _seek: usize) -> FsResult<()> { no_such_fd() }
+    fn read_at(&mut self, _offset: usize, _buffer: &mut [u8]) -> FsResult<usize> { no_such_fd() }
+    fn write_at(&mut self, _offset: usize, _buffer: &[u8]) -> FsResult<()> { no_such_fd() }
+    fn size(&mut self) -> FsResult<usize> { no_such_fd() }
 }
 
 trait NonGenericFileSystem: BaseFileSystem + ManageFileSystem {}
 impl<FS> NonGenericFileSystem for FS where FS: BaseFileSystem + ManageFileSystem {}
 
 struct AttachedFileSystem<T: FunctionalFileSystem> {
-    fs: T,
+    /// shared with the files opened in it
+    fs: Arc<Mutex<T>>,
     attach_point: Path,
-    files_read: BTreeMap<i64, T::ReadProgress>,
-    files_write: BTreeMap<i64, T::WriteProgress>,
 }
 
 impl<T: FunctionalFileSystem> AttachedFileSystem<T> {
     fn new(fs: T, attach_point: Path) -> Self {
         Self {
-            fs,
+            fs: Arc::new(Mutex::new(fs)),
             attach_point,
-            files_read: BTreeMap::new(),
-            files_write: BTreeMap::new(),
         }
     }
 }
 
+struct ReadFile<T: FunctionalFileSystem> {
+    fs: Arc<Mutex<T>>,
+    progress: T::ReadProgress,
+}
+
+struct WriteFile<T: FunctionalFileSystem> {
+    fs: Arc<Mutex<T>>,
+    progress: T::WriteProgress,
+}
+
+/// File that is read and written at any offset. Reads continue with the progress of the previous
+/// one if they don't go backwards, otherwise and for every write the file is opened again.
+struct ImageFile<T: FunctionalFileSystem> {
+    fs: Arc<Mutex<T>>,
+    path: Path,
+    writable: bool,
+    /// progress of the last read and the offset it reached
+    read: Option<(T::ReadProgress, usize)>,
+}
+
+/// File descriptor of a watch, reading it takes the queued events
+struct WatchFile {
+    events: Arc<Mutex<VecDeque<Event>>>,
+}
+
+impl OpenFile for WatchFile {
+    fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize> {
+        let mut events = self.events.lock();
+        let mut len = 0;
+        while let Some(event) = events.front() {
+            let bytes = event.to_bytes();
+            if len + bytes.len() > buffer.len() {
+                if len == 0 {
+                    return Err(FsError::InvalidArgument("buffer is too small for the next event".to_string()));
+                }
+                break;
+            }
+            buffer[len..len + bytes.len()].copy_from_slice(&bytes);
+            len += bytes.len();
+            events.pop_front();
+        }
+        Ok(len)
+    }
+}
+
 fn no_such_fd<T>() -> FsResult<T> {
-    Err(FsError::IllegalOperation("This file descriptor does not exist".to_string()))
+    Err(FsError::BadFileDescriptor)
 }
 
-impl<T: 'static + FunctionalFileSystem> Attached for AttachedFileSystem<T> {
-    fn attach_point(&mut self) -> &mut Path {
-        &mut self.attach_point
+impl<T: 'static + FunctionalFileSystem + Send> Attached for AttachedFileSystem<T> {
+    fn attach_point(&self) -> &Path {
+        &self.attach_point
     }
 
-    fn open_read(&mut self, fd: i64, path: Path) -> FsResult<()> {
-        let rp = self.fs.open_read(path)?;
-        self.files_read.insert(fd, rp);
-        Ok(())
+    fn open_read(&self, path: Path) -> FsResult<Box<dyn OpenFile>> {
+        let progress = self.fs.lock().open_read(path)?;
+        Ok(Box::new(ReadFile { fs: Arc::clone(&self.fs), progress }))
     }
 
-    fn open_write(&mut self, fd: i64, path: Path) -> FsResult<()> {
-        let wp = self.fs.open_write(path)?;
-        self.files_write.insert(fd, wp);
-        Ok(())
+    fn open_write(&self, path: Path) -> FsResult<Box<dyn OpenFile>> {
+        let progress = self.fs.lock().open_write(path)?;
+        Ok(Box::new(WriteFile { fs: Arc::clone(&self.fs), progress }))
     }
 
-    fn read(&mut self, fd: i64, buffer: &mut [u8]) -> FsResult<usize> {
-        match self.files_read.get_mut(&fd) {
-            None => no_such_fd(),
-            Some(mut rp) => {
-                Ok(self.fs.read(&mut rp, buffer)? as usize)
-            }
+    fn open_image(&self, path: Path, writable: bool) -> FsResult<Box<dyn OpenFile>> {
+        if !self.fs.lock().exists_file(path.clone())? {
+            return Err(FsError::NotFound);
         }
+        Ok(Box::new(ImageFile { fs: Arc::clone(&self.fs), path, writable, read: None }))
+    }
+
+    fn lock_fs(&self, fun: &mut dyn FnMut(&mut dyn NonGenericFileSystem)) {
+        fun(&mut *self.fs.lock())
+    }
+
+    fn is_busy(&self) -> bool {
+        // every open file holds a reference to the file system
+        Arc::strong_count(&self.fs) > 1
     }
+}
+
+impl<T: 'static + FunctionalFileSystem + Send> OpenFile for ReadFile<T> {
+    fn read(&mut self, buffer: &mut [u8]) -> FsResult<usize> {
+        self.fs.lock().read(&mut self.progress, buffer)
+    }
+
+    fn seek(&mut self, seek: usize) -> FsResult<()> {
+        self.fs.lock().seek(&mut self.progress, seek)
+    }
+}
 
-    fn write(&mut self, fd: i64, buffer: &[u8]) -> FsResult<()> {
-        match self.files_write.get_mut(&fd) {
-            None => no_such_fd(),
-            Some(mut wp) => self.fs.write(&mut wp, buffer),
+impl<T: 'static + FunctionalFileSystem + Send> OpenFile for WriteFile<T> {
+    fn write(&mut self, buffer: &[u8]) -> FsResult<()> {
+        self.fs.lock().write(&mut self.progress, buffer)
+    }
+}
+
+impl<T: 'static + FunctionalFileSystem + Send> OpenFile for ImageFile<T> {
+    fn read_at(&mut self, offset: usize, buffer: &mut [u8]) -> FsResult<usize> {
+        let fs = self.fs.lock();
+        let (mut progress, position) = match self.read.take() {
+            Some((progress, position)) if position <= offset => (progress, position),
+            _ => (fs.open_read(self.path.clone())?, 0),
+        };
+        fs.seek(&mut progress, offset - position)?;
+        let mut len = 0;
+        while len < buffer.len() {
+            let read = fs.read(&mut progress, &mut buffer[len..])?;
+            if read == 0 {
+                break;
+            }
+            len += read;
         }
+        self.read = Some((progress, offset + len));
+        Ok(len)
     }
 
-    fn seek(&mut self, fd: i64, seek: usize) -> FsResult<()> {
-        match self.files_read.get_mut(&fd) {
-            None => no_such_fd(),
-            Some(mut rp) => self.fs.seek(&mut rp, seek as _),
+    fn write_at(&mut self, offset: usize, buffer: &[u8]) -> FsResult<()> {
+        if !self.writable {
+            return Err(FsError::AccessViolation);
         }
+        // a read progress can keep the layout of the file from before the write
+        self.read = None;
+        let mut fs = self.fs.lock();
+        let mut progress = fs.open_update(self.path.clone(), offset)?;
+        fs.write(&mut progress, buffer)
     }
 
-    fn inner_fs_mut(&mut self) -> Box<&mut dyn NonGenericFileSystem> {
-        Box::new(&mut self.fs)
+    fn size(&mut self) -> FsResult<usize> {
+        self.fs.lock().file_size(self.path.clone())
     }
 }
-
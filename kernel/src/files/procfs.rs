@@ -0,0 +1,185 @@
+//! File system with information about the kernel, usually attached at `/proc`.
+//!
+//! The files are generated when they are opened:
+//! * `meminfo`: usage of physical frames and of the kernel heap
+//! * `mounts`: attached file systems as device, attach point and name of the file system
+//! * `uptime`: seconds since boot
+//! * `<pid>/status`, `<pid>/maps` and `<pid>/fd/<fd>` for every process
+
+use alloc::vec::Vec;
+use alloc::string::{String, ToString};
+use alloc::format;
+
+use fs::error::*;
+use fs::filesystem::*;
+use fs::path::Path;
+use dep::fs::stats::FsStats;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::PhysAddr;
+
+use crate::process::{self, ProcessInfo};
+use crate::memory;
+
+const FILES: [&str; 3] = ["meminfo", "mounts", "uptime"];
+const PROCESS_FILES: [&str; 3] = ["status", "maps", "fd"];
+
+pub struct ProcFs;
+
+enum Node {
+    Dir(Vec<Filename>),
+    File(Vec<u8>),
+}
+
+fn names(names: &[&str]) -> Vec<Filename> {
+    names.iter().map(|name| name.as_bytes().to_vec()).collect()
+}
+
+impl ProcFs {
+    pub fn new() -> Self {
+        ProcFs
+    }
+
+    /// directory or generated file at the path
+    fn lookup(&self, path: Path) -> Option<Node> {
+        let components = path.components()
+            .map(|name| core::str::from_utf8(name).ok())
+            .collect::<Option<Vec<&str>>>()?;
+        let component = |i: usize| components.get(i).cloned();
+
+        match (component(0), component(1)) {
+            (None, _) => {
+                let mut entries = names(&FILES);
+                entries.extend(process::info().iter().map(|p| p.id.to_string().into_bytes()));
+                return Some(Node::Dir(entries));
+            },
+            (Some("meminfo"), None) => return Some(Node::File(meminfo().into_bytes())),
+            (Some("mounts"), None) => return Some(Node::File(mounts().into_bytes())),
+            (Some("uptime"), None) => return Some(Node::File(uptime().into_bytes())),
+            _ => (),
+        }
+
+        let pid: u64 = component(0)?.parse().ok()?;
+        let process = process::info().into_iter().find(|p| p.id == pid)?;
+        match (component(1), component(2), component(3)) {
+            (None, _, _) => Some(Node::Dir(names(&PROCESS_FILES))),
+            (Some("status"), None, _) => Some(Node::File(status(&process).into_bytes())),
+            (Some("maps"), None, _) => Some(Node::File(maps(&process).into_bytes())),
+            (Some("fd"), None, _) => Some(Node::Dir(process.files.iter().map(|(fd, _)| fd.to_string().into_bytes()).collect())),
+            (Some("fd"), Some(fd), None) => {
+                let fd: i64 = fd.parse().ok()?;
+                let (_, path) = process.files.iter().find(|(open, _)| *open == fd)?;
+                Some(Node::File(format!("{}\n", path).into_bytes()))
+            },
+            _ => None,
+        }
+    }
+}
+
+fn meminfo() -> String {
+    let (total, allocated) = {
+        let allocator = memory::allocator();
+        (allocator.total_frames(), allocator.allocated_frames())
+    };
+    let (heap_size, heap_used) = memory::heap::usage();
+    format!("MemTotal:  {:>10} kB\nMemUsed:   {:>10} kB\nMemFree:   {:>10} kB\nHeapTotal: {:>10} kB\nHeapUsed:  {:>10} kB\nHeapFree:  {:>10} kB\n",
+        total * 4,
+        allocated * 4,
+        total.saturating_sub(allocated) * 4,
+        heap_size / 1024,
+        heap_used / 1024,
+        (heap_size - heap_used) / 1024)
+}
+
+fn mounts() -> String {
+    crate::files::mount_table()
+        .iter()
+        .map(|mount| format!("{} {} {}\n",
+            mount.source.as_ref().map_or("none", |source| source.as_str()),
+            mount.attach_point.to_string(),
+            mount.name))
+        .collect()
+}
+
+fn uptime() -> String {
+    let ms = crate::interrupts::uptime_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+/// there is no state line, the scheduler runs every process in turn and doesn't keep one
+fn status(process: &ProcessInfo) -> String {
+    format!("Name:\t{}\nPid:\t{}\nCwd:\t{}\nFiles:\t{}\n",
+        String::from_utf8_lossy(&process.name),
+        process.id,
+        process.cwd.to_string(),
+        process.files.len())
+}
+
+fn maps(process: &ProcessInfo) -> String {
+    memory::user_regions(PhysAddr::new(process.table))
+        .iter()
+        .map(|&(start, end, flags)| format!("{:016x}-{:016x} r{}{}\n",
+            start,
+            end,
+            if flags.contains(PageTableFlags::WRITABLE) { 'w' } else { '-' },
+            if flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' }))
+        .collect()
+}
+
+impl BaseFileSystem for ProcFs {
+    fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
+        match self.lookup(path) {
+            Some(Node::Dir(entries)) => Ok(entries),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn exists_dir(&self, path: Path) -> FsResult<bool> {
+        Ok(match self.lookup(path) {
+            Some(Node::Dir(_)) => true,
+            _ => false,
+        })
+    }
+
+    fn exists_file(&self, path: Path) -> FsResult<bool> {
+        Ok(match self.lookup(path) {
+            Some(Node::File(_)) => true,
+            _ => false,
+        })
+    }
+
+    fn statfs(&self) -> FsResult<FsStats> {
+        Ok(FsStats::new("procfs", 0, 0, 0))
+    }
+}
+
+impl ReadFileSystem for ProcFs {
+    /// contents generated when the file was opened and how far they have been read
+    type ReadProgress = (Vec<u8>, usize);
+
+    fn open_read(&self, path: Path) -> FsResult<Self::ReadProgress> {
+        match self.lookup(path) {
+            Some(Node::File(contents)) => Ok((contents, 0)),
+            _ => Err(FsError::NotFound),
+        }
+    }
+
+    fn read(&self, progress: &mut Self::ReadProgress, buffer: &mut [u8]) -> FsResult<usize> {
+        let (contents, offset) = progress;
+        let start = (*offset).min(contents.len());
+        let len = buffer.len().min(contents.len() - start);
+        buffer[..len].copy_from_slice(&contents[start..start + len]);
+        *offset = start + len;
+        Ok(len)
+    }
+
+    fn seek(&self, progress: &mut Self::ReadProgress, seek: usize) -> FsResult<()> {
+        progress.1 += seek;
+        Ok(())
+    }
+}
+
+impl WriteFileSystem for ProcFs {
+    type WriteProgress = ();
+}
+
+impl ManageFileSystem for ProcFs {}
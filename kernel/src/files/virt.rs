@@ -1,6 +1,7 @@
 use alloc::string::*;
 use alloc::boxed::*;
 use alloc::vec::*;
+use alloc::sync::Arc;
 use alloc::collections::BTreeMap;
 use spin::RwLock;
 use dep::fs::*;
@@ -9,15 +10,20 @@ use fs::error::*;
 use fs::filesystem::*;
 use fs::path::*;
 
-pub type VirtualFile = Box<RwLock<dyn Fn() -> Vec<u8> + Send>>;
+/// File whose contents are generated by a callback when it is opened,
+/// writes are passed to another callback if there is one
+pub struct VirtualFile {
+    read: Box<dyn Fn() -> Vec<u8> + Send + Sync>,
+    write: Option<Box<dyn Fn(&[u8]) -> FsResult<()> + Send + Sync>>,
+}
 
 enum Entry<DIR, FILE> {
     Directory(DIR),
     File(FILE),
 }
 
-type OwnedEntry = Entry<VirtualFileSystem, VirtualFile>;
-type RefEntry<'a> = Entry<&'a VirtualFileSystem, &'a VirtualFile>;
+type OwnedEntry = Entry<VirtualFileSystem, Arc<VirtualFile>>;
+type RefEntry<'a> = Entry<&'a VirtualFileSystem, &'a Arc<VirtualFile>>;
 
 impl OwnedEntry {
     fn as_ref(& self) -> RefEntry<'_> {
@@ -33,7 +39,7 @@ impl<DIR, FILE> Entry<DIR, FILE> {
         if let Self::Directory(_) = self {
             true
         } else {
-            false 
+            false
         }
     }
 
@@ -41,56 +47,91 @@ impl<DIR, FILE> Entry<DIR, FILE> {
         if let Self::File(_) = self {
             true
         } else {
-            false 
+            false
         }
     }
 }
 
+/// File system of callback files that kernel subsystems use to publish their state.
+/// Clones share the same files, so files can be registered after the file system is attached.
+#[derive(Clone)]
 pub struct VirtualFileSystem {
-    entries: RwLock<BTreeMap<Filename, OwnedEntry>>,
+    entries: Arc<RwLock<BTreeMap<Filename, OwnedEntry>>>,
 }
 
 impl VirtualFileSystem {
     pub fn new() -> Self {
         Self {
-            entries: RwLock::new(BTreeMap::new()),
+            entries: Arc::new(RwLock::new(BTreeMap::new())),
         }
     }
 
-    pub fn register_file<F>(&self, path: Path, file: F) -> Result<(), ()> 
-        where F: 'static + Fn() -> Vec<u8> + Send
+    /// registers a read-only file, its contents are generated by `read` whenever it is opened
+    pub fn register_file<F>(&self, path: Path, read: F) -> Result<(), ()>
+        where F: 'static + Fn() -> Vec<u8> + Send + Sync
+    {
+        self.register(path, VirtualFile { read: Box::new(read), write: None })
+    }
+
+    /// registers a file that is also writable, every write is passed to `write`
+    pub fn register_rw_file<F, W>(&self, path: Path, read: F, write: W) -> Result<(), ()>
+        where F: 'static + Fn() -> Vec<u8> + Send + Sync,
+              W: 'static + Fn(&[u8]) -> FsResult<()> + Send + Sync,
     {
+        self.register(path, VirtualFile { read: Box::new(read), write: Some(Box::new(write)) })
+    }
+
+    fn register(&self, path: Path, file: VirtualFile) -> Result<(), ()> {
         let (head, tail) = path.head_tail();
         let head = match head {
             Some(head) => head,
             None => return Err(()),
         };
 
+        let mut entries = self.entries.write();
         if tail.is_root() {
             // file
-            if self.entries.read().contains_key(&head) {
+            if entries.contains_key(&head) {
                 return Err(());
             }
-            let file = OwnedEntry::File(Box::new(RwLock::new(file)));
-            self.entries.write().insert(head, file);
+            entries.insert(head, OwnedEntry::File(Arc::new(file)));
             Ok(())
         } else {
             // directory
-            if let Some(dir) = self.entries.read().get(&head) {
+            if let Some(dir) = entries.get(&head) {
                 if let Entry::Directory(dir) = dir {
-                    dir.register_file(tail, file)
+                    dir.register(tail, file)
                 } else {
                     Err(())
                 }
             } else {
                 let dir = Self::new();
-                dir.register_file(tail, file)?;
-                self.entries.write().insert(head, Entry::Directory(dir));
+                dir.register(tail, file)?;
+                entries.insert(head, Entry::Directory(dir));
                 Ok(())
             }
         }
     }
 
+    /// removes a file or a directory with everything in it
+    pub fn unregister(&self, path: Path) -> Result<(), ()> {
+        let (head, tail) = path.head_tail();
+        let head = match head {
+            Some(head) => head,
+            None => return Err(()),
+        };
+
+        let mut entries = self.entries.write();
+        if tail.is_root() {
+            entries.remove(&head).map(|_| ()).ok_or(())
+        } else {
+            match entries.get(&head) {
+                Some(Entry::Directory(dir)) => dir.unregister(tail),
+                _ => Err(()),
+            }
+        }
+    }
+
     fn find_entry<R, F: Fn(&RefEntry) -> R>(&self, path: Path, default: R, fun: F) -> R {
         if path.is_root() {
             return fun(&RefEntry::Directory(self));
@@ -120,17 +161,23 @@ impl VirtualFileSystem {
         }
     }
 
-    fn find_entry_mut<R, F: Fn(&mut OwnedEntry) -> R>(fun: F, path: Path) -> Result<R, ()> {
-        todo!();
+    /// the file at the path, the callbacks are called without holding a lock of the file system
+    fn find_file(&self, path: Path) -> FsResult<Arc<VirtualFile>> {
+        self.find_entry(path, Err(FsError::NotFound), |e| {
+            if let Entry::File(file) = e {
+                Ok(Arc::clone(file))
+            } else {
+                Err(FsError::NotFound)
+            }
+        })
     }
-
 }
 
 impl BaseFileSystem for VirtualFileSystem {
     fn read_dir(&self, path: Path) -> FsResult<Vec<Filename>> {
         self.find_entry(path, Err(FsError::NotFound), |e| {
             if let Entry::Directory(dir) = e {
-                Ok(dir.entries.read().iter().map(|(k, v)| k.clone()).collect())
+                Ok(dir.entries.read().keys().cloned().collect())
             } else {
                 Err(FsError::NotFound)
             }
@@ -142,17 +189,27 @@ impl BaseFileSystem for VirtualFileSystem {
     fn exists_file(&self, path: Path) -> FsResult<bool> {
         Ok(self.find_entry(path, false, |e| e.is_file()))
     }
+    fn statfs(&self) -> FsResult<stats::FsStats> {
+        Ok(stats::FsStats::new("virtfs", 0, 0, 0))
+    }
 }
 
 impl ReadFileSystem for VirtualFileSystem {
-    type ReadProgress = (usize, VirtualFile);
+    /// how far the contents have been read and the contents generated when the file was opened
+    type ReadProgress = (usize, Vec<u8>);
 
     fn open_read(&self, path: Path) -> FsResult<Self::ReadProgress> {
-        todo!();
+        let file = self.find_file(path)?;
+        Ok((0, (file.read)()))
     }
 
     fn read(&self, progress: &mut Self::ReadProgress, buffer: &mut [u8]) -> FsResult<usize> {
-        todo!();
+        let (offset, contents) = progress;
+        let start = (*offset).min(contents.len());
+        let len = buffer.len().min(contents.len() - start);
+        buffer[..len].copy_from_slice(&contents[start..start + len]);
+        *offset = start + len;
+        Ok(len)
     }
 
     fn seek(&self, progress: &mut Self::ReadProgress, seeking: usize) -> FsResult<()> {
@@ -162,8 +219,22 @@ impl ReadFileSystem for VirtualFileSystem {
 }
 
 impl WriteFileSystem for VirtualFileSystem {
-    type WriteProgress = ();
+    type WriteProgress = Arc<VirtualFile>;
+
+    fn open_write(&mut self, path: Path) -> FsResult<Self::WriteProgress> {
+        let file = self.find_file(path)?;
+        if file.write.is_none() {
+            return Err(FsError::AccessViolation);
+        }
+        Ok(file)
+    }
+
+    fn write(&mut self, progress: &mut Self::WriteProgress, buffer: &[u8]) -> FsResult<()> {
+        match &progress.write {
+            Some(write) => write(buffer),
+            None => Err(FsError::AccessViolation),
+        }
+    }
 }
 
 impl ManageFileSystem for VirtualFileSystem {}
-
@@ -5,6 +5,7 @@ use crate::gdt;
 use pic8259_simple::ChainedPics;
 use spin;
 use crate::hlt_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
@@ -174,8 +175,26 @@ impl InterruptIndex {
     }
 }
 
+/// number of timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// number of timer interrupts since boot, used as clock
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// frequency of the programmable interval timer, which is left at its default divisor of 65536
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVISOR: u64 = 65536;
+
+/// milliseconds since boot
+pub fn uptime_ms() -> u64 {
+    ticks() * PIT_DIVISOR * 1000 / PIT_FREQUENCY
+}
+
 use crate::process;
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
     unsafe {
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
         process::update();
@@ -201,9 +220,14 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: &mut Interrup
         if scancode == 14 {
             // backspace
             vga_buffer::backspace();
+            crate::devices::character::console_input(b"\x08");
         } else if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
+                DecodedKey::Unicode(character) => {
+                    print!("{}", character);
+                    let mut bytes = [0u8; 4];
+                    crate::devices::character::console_input(character.encode_utf8(&mut bytes).as_bytes());
+                },
                 DecodedKey::RawKey(key) => print!("{:?}", key),
             }
         }
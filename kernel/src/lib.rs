@@ -21,10 +21,14 @@ pub extern crate fs;
 use core::panic::PanicInfo;
 
 pub mod serial;
+pub mod ata;
+pub mod pci;
+pub mod virtio;
 pub mod vga_buffer;
 pub mod interrupts;
 pub mod gdt;
 pub mod memory;
+pub mod devices;
 pub mod files;
 pub mod syscall;
 pub mod elf;
@@ -7,7 +7,7 @@
 
 use core::panic::PanicInfo;
 use dep::consts::*;
-use bit_os::{print, println, serial_println, memory, vga_buffer, vga_buffer::*, files, elf, syscall, process::{self, *}};
+use bit_os::{print, println, serial_println, memory, vga_buffer, vga_buffer::*, devices, files, elf, syscall, process::{self, *}};
 use bootloader::{BootInfo, entry_point};
 use lazy_static::*;
 
@@ -32,6 +32,7 @@ pub fn kernel_main(boot_info: &'static BootInfo) -> ! {
         memory::init_allocator();
         memory::heap::init_heap().expect("Heap initialization failed");
     }, "kernel heap");
+    load_feature(devices::init, "devices");
     load_feature(files::init, "file system");
     load_feature(syscall::init_syscall_stack, "syscall stack");
     load_feature(process::init, "processes");
@@ -13,6 +13,8 @@ pub struct BootInfoFrameAllocator {
     pub memory_map: &'static MemoryMap,
     region: usize,
     frame: u64,
+    /// number of frames handed out so far
+    allocated: u64,
 }
 
 impl BootInfoFrameAllocator {
@@ -21,8 +23,22 @@ impl BootInfoFrameAllocator {
             memory_map,
             region: 0,
             frame: 0,
+            allocated: 0,
         }
     }
+
+    /// number of usable frames in the memory map
+    pub fn total_frames(&self) -> u64 {
+        self.memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| (r.range.end_addr() - r.range.start_addr()) / 4096)
+            .sum()
+    }
+
+    /// number of frames that have been allocated, frames are never freed
+    pub fn allocated_frames(&self) -> u64 {
+        self.allocated
+    }
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         let regions = self.memory_map.iter();
         let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
@@ -65,6 +81,7 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         if self.region_oob() {
             None
         } else {
+            self.allocated += 1;
             Some(PhysFrame::containing_address(PhysAddr::new(
                 self.memory_map[self.region].range.start_addr() + 4096 * self.frame
             )))
@@ -42,3 +42,9 @@ pub fn init_heap() -> Result<(), MapToError> {
     Ok(())
 }
 
+
+/// size of the kernel heap and the number of bytes in use
+pub fn usage() -> (usize, usize) {
+    let heap = HEAP.lock();
+    (heap.size(), heap.used())
+}
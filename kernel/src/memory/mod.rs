@@ -2,12 +2,14 @@ use x86_64::{
     structures::paging::{
         *,
         page_table::*,
+        mapper::MapToError,
     },
     VirtAddr,
     PhysAddr,
 };
 use spin::{Mutex, MutexGuard, Once};
 use bootloader::BootInfo;
+use alloc::vec::Vec;
 
 use dep::consts::*;
 
@@ -100,6 +102,90 @@ fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
     mem_offset + phys.as_u64()
 }
 
+/// allocates physically contiguous, zeroed frames for devices that access memory directly
+/// returns the physical address and the address at which the kernel accesses the frames
+pub fn allocate_dma(frames: usize) -> Option<(PhysAddr, VirtAddr)> {
+    let mut allocator = allocator();
+    let mut start = allocator.allocate_frame()?;
+    let mut count = 1;
+    while count < frames {
+        let frame = allocator.allocate_frame()?;
+        if frame.start_address() == start.start_address() + count as u64 * PAGE_SIZE {
+            count += 1;
+        } else {
+            start = frame;
+            count = 1;
+        }
+    }
+
+    let phys = start.start_address();
+    let virt = phys_to_virt(phys);
+    unsafe {
+        core::ptr::write_bytes(virt.as_mut_ptr::<u8>(), 0, frames * PAGE_SIZE as usize);
+    }
+    Some((phys, virt))
+}
+
+/// makes device memory accessible at the physical memory offset
+/// the bootloader only maps the physical memory that is in its memory map
+pub fn map_device_memory(phys: PhysAddr, len: usize) -> Result<VirtAddr, MapToError> {
+    let virt = phys_to_virt(phys);
+    let first = Page::<Size4KiB>::containing_address(virt);
+    let last = Page::<Size4KiB>::containing_address(virt + len.max(1) as u64 - 1u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
+    let mut mapper = unsafe { mapper() };
+    for page in Page::range_inclusive(first, last) {
+        if mapper.translate_addr(page.start_address()).is_none() {
+            let frame = PhysFrame::containing_address(PhysAddr::new(page.start_address().as_u64() - boot_info().physical_memory_offset));
+            unsafe {
+                mapper.map_to(page, frame, flags, &mut *allocator())?.flush();
+            }
+        }
+    }
+    Ok(virt)
+}
+
+/// contiguous regions of memory that the level 4 table at `table` maps in the upper half, where
+/// userspace lives, as start, end and flags of the pages
+pub fn user_regions(table: PhysAddr) -> Vec<(u64, u64, PageTableFlags)> {
+    let p4: &PageTable = unsafe { &*phys_to_virt(table).as_ptr() };
+    let mut regions = Vec::new();
+    for i in 256..512 {
+        if p4[i].is_unused() {
+            continue;
+        }
+        // addresses in the upper half are sign extended
+        let offset = 0xffff_0000_0000_0000 | (i as u64) << 39;
+        let table: *const PageTable = phys_to_virt(p4[i].addr()).as_ptr();
+        collect_regions(3, offset, unsafe { &*table }, &mut regions);
+    }
+    regions
+}
+
+fn collect_regions(lvl: usize, offset: u64, table: &PageTable, regions: &mut Vec<(u64, u64, PageTableFlags)>) {
+    let span = 4096u64 << (9 * (lvl - 1));
+    // accessed and dirty bits differ between pages of the same region
+    let relevant = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+
+    for (i, entry) in table.iter().enumerate() {
+        if entry.is_unused() {
+            continue;
+        }
+        let start = offset + i as u64 * span;
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) || lvl == 1 {
+            let flags = entry.flags() & relevant;
+            match regions.last_mut() {
+                Some(last) if last.1 == start && last.2 == flags => last.1 = start.wrapping_add(span),
+                _ => regions.push((start, start.wrapping_add(span), flags)),
+            }
+        } else {
+            let table: *const PageTable = phys_to_virt(entry.addr()).as_ptr();
+            collect_regions(lvl - 1, start, unsafe { &*table }, regions);
+        }
+    }
+}
+
 use crate::serial_println;
 /// prints map of physical memory used
 pub fn print_memory_map() {
@@ -0,0 +1,163 @@
+//! PCI devices, found through the configuration space access mechanism of the legacy I/O ports.
+
+use alloc::vec::Vec;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+const COMMAND: u8 = 0x04;
+const COMMAND_IO: u16 = 0x01;
+const COMMAND_MEMORY: u16 = 0x02;
+const COMMAND_BUS_MASTER: u16 = 0x04;
+const COMMAND_INTERRUPT_DISABLE: u16 = 0x400;
+
+const STATUS: u8 = 0x06;
+const STATUS_CAPABILITIES: u16 = 0x10;
+
+const HEADER_TYPE: u8 = 0x0e;
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+const FIRST_BAR: u8 = 0x10;
+const CAPABILITIES: u8 = 0x34;
+
+/// Function on the PCI bus
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+}
+
+/// Base address register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar {
+    /// first port of an I/O space region
+    Io(u16),
+    /// physical address of a memory region
+    Memory(u64),
+}
+
+impl PciDevice {
+    fn address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        0x8000_0000 | (bus as u32) << 16 | (device as u32) << 11 | (function as u32) << 8 | (offset & 0xfc) as u32
+    }
+
+    fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(Self::address(bus, device, function, offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    }
+
+    /// the function at the address, `None` if there is none
+    fn probe(bus: u8, device: u8, function: u8) -> Option<Self> {
+        let id = Self::read_config(bus, device, function, 0);
+        let vendor_id = id as u16;
+        if vendor_id == 0xffff {
+            return None;
+        }
+        Some(Self {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (id >> 16) as u16,
+        })
+    }
+
+    /// reads a double word of the configuration space, `offset` is aligned to four bytes
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        Self::read_config(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    /// writes a double word of the configuration space, `offset` is aligned to four bytes
+    pub fn write_u32(&self, offset: u8, value: u32) {
+        unsafe {
+            Port::<u32>::new(CONFIG_ADDRESS).write(Self::address(self.bus, self.device, self.function, offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    }
+
+    pub fn write_u16(&self, offset: u8, value: u16) {
+        let shift = (offset & 2) * 8;
+        let mut old = self.read_u32(offset) & !(0xffff << shift);
+        // the status bits are cleared by writing ones, writing them back would clear them
+        if offset & !3 == COMMAND & !3 {
+            old &= !(0xffff << (((STATUS & 2) * 8) as u32));
+        }
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    /// class, subclass and programming interface
+    pub fn class(&self) -> (u8, u8, u8) {
+        let class = self.read_u32(0x08);
+        ((class >> 24) as u8, (class >> 16) as u8, (class >> 8) as u8)
+    }
+
+    /// lets the device decode its regions and access memory, its interrupts are disabled
+    pub fn enable(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTERRUPT_DISABLE);
+    }
+
+    /// base address register `index`, `None` if it is unused or the upper half of a 64-bit BAR
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        if index >= 6 {
+            return None;
+        }
+        let bar = self.read_u32(FIRST_BAR + 4 * index);
+        if bar & 1 == 1 {
+            let port = (bar & !0x3) as u16;
+            return if port == 0 { None } else { Some(Bar::Io(port)) };
+        }
+
+        let mut address = (bar & !0xf) as u64;
+        // memory type 2 is a 64-bit BAR, the next register holds the upper half
+        if (bar >> 1) & 0x3 == 2 && index < 5 {
+            address |= (self.read_u32(FIRST_BAR + 4 * (index + 1)) as u64) << 32;
+        }
+        if address == 0 { None } else { Some(Bar::Memory(address)) }
+    }
+
+    /// capabilities as pairs of their id and their offset in the configuration space
+    pub fn capabilities(&self) -> Vec<(u8, u8)> {
+        let mut capabilities = Vec::new();
+        if self.read_u16(STATUS) & STATUS_CAPABILITIES == 0 {
+            return capabilities;
+        }
+        let mut offset = self.read_u8(CAPABILITIES) & !0x3;
+        // the list is bounded by the size of the configuration space, even if it is a loop
+        while offset != 0 && capabilities.len() < 64 {
+            capabilities.push((self.read_u8(offset), offset));
+            offset = self.read_u8(offset + 1) & !0x3;
+        }
+        capabilities
+    }
+}
+
+/// all functions on all PCI buses
+pub fn devices() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let first = match PciDevice::probe(bus, device, 0) {
+                Some(first) => first,
+                None => continue,
+            };
+            let functions = if first.read_u8(HEADER_TYPE) & HEADER_MULTIFUNCTION != 0 { 8 } else { 1 };
+            devices.push(first);
+            devices.extend((1..functions).filter_map(|function| PciDevice::probe(bus, device, function)));
+        }
+    }
+    devices
+}
@@ -6,11 +6,14 @@ use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::*;
 
 use x86_64::structures::paging::{PageTableFlags};
+use x86_64::instructions::interrupts::without_interrupts;
 
 use dep::consts::*;
+use dep::fs::Path;
 
 use crate::memory;
 use crate::elf;
+use crate::files::{fs, LockKind};
 
 pub mod schedule;
 
@@ -18,6 +21,8 @@ static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
 static NEXT_PID: AtomicU64 = AtomicU64::new(1);
 static PROCESSES: Once<Mutex<BTreeMap<u64, Process>>> = Once::new();
 static PROCESSES_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// whether process switches are printed to the serial interface
+static TRACING: AtomicBool = AtomicBool::new(true);
 
 pub fn init() {
     {
@@ -37,6 +42,70 @@ pub unsafe fn update() {
     }
 }
 
+/// Work a syscall leaves to `__syscall_return`, which runs on the stack of the process where
+/// switching to other processes is safe
+pub enum Pending {
+    /// waiting until the open file can be locked
+    Lock(i64, LockKind),
+    /// the process exited and is removed once it is off the syscall stack
+    Exit,
+}
+
+/// leaves `pending` to the end of the running syscall of the running process,
+/// false if no process is running
+pub fn set_pending(pending: Pending) -> bool {
+    without_interrupts(|| {
+        match processes().get_mut(&current_pid()) {
+            Some(process) => {
+                process.pending = Some(pending);
+                true
+            },
+            None => false,
+        }
+    })
+}
+
+/// takes the work the running syscall of the running process left
+pub fn take_pending() -> Option<Pending> {
+    without_interrupts(|| processes().get_mut(&current_pid()).and_then(|process| process.pending.take()))
+}
+
+/// Closes all files of the running process, which releases their locks, and marks it as exited.
+/// The process stops running at the end of the syscall.
+pub fn exit() {
+    let files = without_interrupts(|| {
+        processes().get_mut(&current_pid())
+            .map_or(BTreeMap::new(), |process| core::mem::replace(&mut process.files.open, BTreeMap::new()))
+    });
+    for fd in files.keys() {
+        // the file system of a file may have failed, the other files are closed anyway
+        let _ = fs().close(*fd);
+    }
+    set_pending(Pending::Exit);
+}
+
+/// removes the exited running process and runs the others, it never runs again
+pub unsafe fn remove_exited() -> ! {
+    without_interrupts(|| processes().remove(&current_pid()));
+    // the timer switches to the next process, if there is none the CPU waits here
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop()
+}
+
+/// gives the rest of the time slice to the next process, false if there is no other process
+/// that could run instead
+pub unsafe fn yield_now() -> bool {
+    if !PROCESSES_ACTIVE.load(Ordering::SeqCst) {
+        return false;
+    }
+    let next_pid = schedule::next_turn();
+    if next_pid == current_pid() {
+        return false;
+    }
+    switch_process(next_pid);
+    true
+}
+
 pub struct Registers {
     rsp: u64,
     cr3: u64,
@@ -44,21 +113,104 @@ pub struct Registers {
 
 pub struct FileDescriptors {
     next_fd: i64,
+    /// paths of the files the process opened, by file descriptor
+    open: BTreeMap<i64, String>,
 }
 
 impl FileDescriptors {
     fn new() -> Self { 
         Self {
             next_fd: 0,
+            open: BTreeMap::new(),
         } 
     }
 }
 
+/// whether process switches are printed to the serial interface
+pub fn tracing() -> bool {
+    TRACING.load(Ordering::Relaxed)
+}
+
+pub fn set_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// id of the process that is running
+pub fn current_pid() -> u64 {
+    CURRENT_PID.load(Ordering::SeqCst)
+}
+
+/// remembers that the running process opened the file at `path` as `fd`
+pub fn record_open(fd: i64, path: String) {
+    // the scheduler locks the processes in the timer interrupt
+    without_interrupts(|| {
+        if let Some(process) = processes().get_mut(&current_pid()) {
+            process.files.open.insert(fd, path);
+        }
+    });
+}
+
+/// forgets the file the running process opened as `fd`
+pub fn record_close(fd: i64) {
+    without_interrupts(|| {
+        if let Some(process) = processes().get_mut(&current_pid()) {
+            process.files.open.remove(&fd);
+        }
+    });
+}
+
+/// working directory of the running process, relative paths are resolved against it
+pub fn current_dir() -> Path {
+    without_interrupts(|| {
+        processes().get(&current_pid()).map_or(Path::root(), |process| process.cwd.clone())
+    })
+}
+
+/// changes the working directory of the running process, the caller checks that it exists
+pub fn set_current_dir(path: Path) {
+    without_interrupts(|| {
+        if let Some(process) = processes().get_mut(&current_pid()) {
+            process.cwd = path;
+        }
+    });
+}
+
+/// Snapshot of a process for introspection
+pub struct ProcessInfo {
+    pub id: u64,
+    pub name: Vec<u8>,
+    /// physical address of the level 4 page table
+    pub table: u64,
+    /// working directory
+    pub cwd: Path,
+    /// open files with their file descriptors
+    pub files: Vec<(i64, String)>,
+}
+
+/// snapshots of all processes ordered by their id
+pub fn info() -> Vec<ProcessInfo> {
+    without_interrupts(|| {
+        processes().values()
+            .map(|process| ProcessInfo {
+                id: process.id,
+                name: process.name.clone(),
+                table: process.regs.cr3,
+                cwd: process.cwd.clone(),
+                files: process.files.open.iter().map(|(&fd, path)| (fd, path.clone())).collect(),
+            })
+            .collect()
+    })
+}
+
 pub struct Process {
     pub id: u64,
     pub name: Vec<u8>,
     pub regs: Registers,
     pub files: FileDescriptors,
+    /// working directory, relative paths in syscalls start here
+    pub cwd: Path,
+    /// work the running syscall left to `__syscall_return`
+    pub pending: Option<Pending>,
 }
 
 impl Process {
@@ -72,6 +224,8 @@ impl Process {
                 cr3: memory::new_table(id),
             },
             files: FileDescriptors::new(),
+            cwd: Path::root(),
+            pending: None,
         };
 
         let old_table = memory::load_table(proc.regs.cr3);
@@ -118,7 +272,9 @@ pub unsafe fn switch_process(next_pid: u64) {
     let current_pid = CURRENT_PID.load(Ordering::SeqCst);
 
     use crate::serial_println;
-    serial_println!("Switching from {} to {}", current_pid, next_pid);
+    if tracing() {
+        serial_println!("Switching from {} to {}", current_pid, next_pid);
+    }
 
     if current_pid == next_pid {
         return;
@@ -20,6 +20,23 @@ pub fn _print(args: ::core::fmt::Arguments) {
     });
 }
 
+/// Receives a byte from the host through the serial interface if one has arrived, without waiting.
+pub fn try_receive() -> Option<u8> {
+    use x86_64::instructions::{interrupts, port::Port};
+
+    interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        unsafe {
+            // bit 0 of the line status register is set when data is ready
+            if Port::<u8>::new(0x3F8 + 5).read() & 1 != 0 {
+                Some(Port::<u8>::new(0x3F8).read())
+            } else {
+                None
+            }
+        }
+    })
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -19,62 +19,219 @@ pub fn init_syscall_stack() {
 
 #[no_mangle]
 pub unsafe extern "C" fn __syscall(syscall_number: u64, arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> i64 {
-    match syscall_number {
+    let result = match syscall_number {
         KPRINT => kprint(arg0, arg1),
         OPEN => open(arg0, arg1, arg2),
         CLOSE => close(arg0),
         READ => read(arg0, arg1, arg2),
         WRITE => write(arg0, arg1, arg2),
+        STATFS => statfs(arg0, arg1, arg2),
+        MOUNT => mount(arg0, arg1, arg2, arg3, arg4),
+        UMOUNT => umount(arg0, arg1),
+        CHDIR => chdir(arg0, arg1),
+        GETCWD => getcwd(arg0, arg1),
+        FLOCK => flock(arg0, arg1),
+        WATCH => watch(arg0, arg1),
+        EXIT => exit(),
         _ => {
             println!("unknown syscall {}", syscall_number);
-            0
+            Err(Errno::NotSupported)
+        }
+    };
+    result.unwrap_or_else(Errno::code)
+}
+
+/// Called with the result of `__syscall` once the handler is back on the stack of the process,
+/// where switching to other processes is safe unlike on the shared syscall stack.
+/// A blocking `FLOCK` gives the other processes their turns here until the lock is free,
+/// an exited process is removed.
+#[no_mangle]
+pub unsafe extern "C" fn __syscall_return(result: i64) -> i64 {
+    let (fd, kind) = match crate::process::take_pending() {
+        Some(crate::process::Pending::Lock(fd, kind)) => (fd, kind),
+        Some(crate::process::Pending::Exit) => crate::process::remove_exited(),
+        None => return result,
+    };
+    loop {
+        match fs().lock(fd, kind) {
+            // the lock is held by another file of this process if no other process can run
+            Err(FsError::WouldBlock) if crate::process::yield_now() => (),
+            result => return result.map(|_| 0).map_err(Errno::from).unwrap_or_else(Errno::code),
         }
     }
 }
 
-unsafe fn kprint(ptr: u64, len: u64) -> i64 {
-    let slice = core::slice::from_raw_parts(ptr as _, len as usize);
-    let s = core::str::from_utf8_unchecked(slice);
+/// Return value of a syscall, errors are returned as their negative code
+type SyscallResult = Result<i64, Errno>;
+
+unsafe fn kprint(ptr: u64, len: u64) -> SyscallResult {
+    let s = core::str::from_utf8(user_slice(ptr, len)?).map_err(|_| Errno::InvalidArgument)?;
     print!("{}", s);
-    0
+    Ok(0)
 }
 
-use dep::fs::{*, error::*};
+use dep::fs::{*, error::Errno};
 use crate::files::*;
-use core::ptr::*;
-use alloc::string::*;
+use fs::error::*;
+use alloc::string::ToString;
 
-/// open a file for the current process and return an integer representing the file
-unsafe fn open(path: u64, path_len: u64, flags: u64) -> i64 {
-    let path = &*slice_from_raw_parts::<u8>(path as *const u8, path_len as _);
-    if let Ok(path) = String::from_utf8(path.to_vec()) {
-        let path = Path::new(path);
-        if let Some(path) = path {
-            let result = match flags {
-                0 => fs().open_read(path),
-                1 => fs().open_write(path),
-                _ => return OTHER,
-            };
-            return map_err(result);
-        }
+/// memory of the process at `ptr`, null pointers and ranges that wrap around are refused
+unsafe fn user_slice<'a>(ptr: u64, len: u64) -> Result<&'a [u8], Errno> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    if ptr == 0 || ptr.checked_add(len).is_none() {
+        return Err(Errno::Fault);
     }
-    return OTHER;
+    Ok(core::slice::from_raw_parts(ptr as *const u8, len as usize))
+}
+
+/// writable memory of the process at `ptr`, checked like `user_slice`
+unsafe fn user_slice_mut<'a>(ptr: u64, len: u64) -> Result<&'a mut [u8], Errno> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    if ptr == 0 || ptr.checked_add(len).is_none() {
+        return Err(Errno::Fault);
+    }
+    Ok(core::slice::from_raw_parts_mut(ptr as *mut u8, len as usize))
+}
+
+/// reads a path from userspace memory, relative paths are resolved against the working
+/// directory of the process
+unsafe fn user_path(path: u64, path_len: u64) -> Result<Path, Errno> {
+    let path = user_slice(path, path_len)?;
+    crate::process::current_dir().resolve(path).map_err(|err| FsError::from(err).errno())
+}
+
+/// open a file for the current process and return an integer representing the file
+unsafe fn open(path: u64, path_len: u64, flags: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    let name = path.to_string();
+    let fd = match flags {
+        0 => fs().open_read(path)?,
+        1 => fs().open_write(path)?,
+        _ => return Err(Errno::InvalidArgument),
+    };
+    crate::process::record_open(fd, name);
+    Ok(fd)
 }
 
 /// close a file given by the file descriptor
-unsafe fn close(fd: u64) -> i64 {
-    0 // TODO
+unsafe fn close(fd: u64) -> SyscallResult {
+    fs().close(fd as _)?;
+    crate::process::record_close(fd as _);
+    Ok(0)
 }
 
 /// read an opened file given by the file descriptor
-unsafe fn read(fd: u64, bytes: u64, bytes_len: u64) -> i64 {
-    let bytes = &mut *slice_from_raw_parts_mut::<u8>(bytes as *mut u8, bytes_len as _);
-    map_err(fs().read(fd as _, bytes).map(|x| x as i64))
+unsafe fn read(fd: u64, bytes: u64, bytes_len: u64) -> SyscallResult {
+    let bytes = user_slice_mut(bytes, bytes_len)?;
+    Ok(fs().read(fd as _, bytes)? as i64)
 }
 
 /// write to an opened file 
-unsafe fn write(fd: u64, bytes: u64, bytes_len: u64) -> i64 {
-    let bytes = &*slice_from_raw_parts::<u8>(bytes as *const u8, bytes_len as _);
-    map_err(fs().write(fd as _, bytes).map(|_| 0))
+unsafe fn write(fd: u64, bytes: u64, bytes_len: u64) -> SyscallResult {
+    let bytes = user_slice(bytes, bytes_len)?;
+    fs().write(fd as _, bytes)?;
+    Ok(0)
+}
+
+/// write the usage statistics of the file system containing the path to `stats`
+unsafe fn statfs(path: u64, path_len: u64, stats: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    let result = fs().statfs(path)?;
+    let stats = user_slice_mut(stats, core::mem::size_of::<stats::FsStats>() as u64)?;
+    // the buffer of the user program doesn't have to be aligned
+    core::ptr::write_unaligned(stats.as_mut_ptr() as *mut stats::FsStats, result);
+    Ok(0)
 }
 
+/// mount the block device or the image file named `source` at the directory `target`,
+/// `options` points to the `MountOptions`, the file system is probed and an image is only read
+/// if it is null
+unsafe fn mount(source: u64, source_len: u64, target: u64, target_len: u64, options: u64) -> SyscallResult {
+    let source = core::str::from_utf8(user_slice(source, source_len)?).map_err(|_| Errno::InvalidArgument)?;
+    let target = user_path(target, target_len)?;
+    if options == 0 {
+        crate::files::mount(source, target, None, false)?;
+        return Ok(0);
+    }
+    let options = user_slice(options, core::mem::size_of::<MountOptions>() as u64)?;
+    // the options of the user program don't have to be aligned
+    let options = core::ptr::read_unaligned(options.as_ptr() as *const MountOptions);
+    let len = options.fs_type.iter().position(|&byte| byte == 0).unwrap_or(options.fs_type.len());
+    let fs_type = match len {
+        0 => None,
+        _ => Some(core::str::from_utf8(&options.fs_type[..len]).map_err(|_| Errno::InvalidArgument)?),
+    };
+    crate::files::mount(source, target, fs_type, options.flags & MOUNT_WRITABLE != 0)?;
+    Ok(0)
+}
+
+/// unmount the file system attached last at the directory `target`
+unsafe fn umount(target: u64, target_len: u64) -> SyscallResult {
+    fs().detach(user_path(target, target_len)?)?;
+    Ok(0)
+}
+
+/// change the working directory of the current process to an existing directory
+unsafe fn chdir(path: u64, path_len: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    if fs().exists_dir(path.clone())? {
+        crate::process::set_current_dir(path);
+        Ok(0)
+    } else if fs().exists_file(path)? {
+        Err(Errno::NotADirectory)
+    } else {
+        Err(Errno::NotFound)
+    }
+}
+
+/// write the working directory of the current process to `buffer` and return its length,
+/// nothing is written if it doesn't fit
+unsafe fn getcwd(buffer: u64, buffer_len: u64) -> SyscallResult {
+    let cwd = crate::process::current_dir().to_bytes();
+    if cwd.len() > buffer_len as usize {
+        return Err(Errno::InvalidArgument);
+    }
+    user_slice_mut(buffer, cwd.len() as u64)?.copy_from_slice(&cwd);
+    Ok(cwd.len() as i64)
+}
+
+/// acquire, convert or release the advisory lock of an opened file, `operation` is one of the
+/// `LOCK_*` operations, optionally with `LOCK_NONBLOCKING`.
+/// Without `LOCK_NONBLOCKING` a lock held by another process is waited for in
+/// `__syscall_return`, as the process can't be switched on the syscall stack.
+unsafe fn flock(fd: u64, operation: u64) -> SyscallResult {
+    let kind = match operation & !LOCK_NONBLOCKING {
+        LOCK_SHARED => LockKind::Shared,
+        LOCK_EXCLUSIVE => LockKind::Exclusive,
+        LOCK_UNLOCK if operation == LOCK_UNLOCK => {
+            fs().unlock(fd as _)?;
+            return Ok(0);
+        }
+        _ => return Err(Errno::InvalidArgument),
+    };
+    match fs().lock(fd as _, kind) {
+        // the kernel itself has no process that could wait
+        Err(FsError::WouldBlock) if operation & LOCK_NONBLOCKING == 0
+            && crate::process::set_pending(crate::process::Pending::Lock(fd as _, kind)) => Ok(0),
+        result => result.map(|_| 0).map_err(Errno::from),
+    }
+}
+
+/// watch a file or directory for changes and return a file descriptor to read the events from
+unsafe fn watch(path: u64, path_len: u64) -> SyscallResult {
+    let path = user_path(path, path_len)?;
+    let name = path.to_string();
+    let fd = fs().watch(path)?;
+    crate::process::record_open(fd, name);
+    Ok(fd)
+}
+
+/// end the process, its files are closed, which releases their locks
+unsafe fn exit() -> SyscallResult {
+    crate::process::exit();
+    Ok(0)
+}
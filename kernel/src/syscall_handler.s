@@ -15,7 +15,13 @@ syscall_handler:
     call __syscall
     pop r15
     mov rsp, r15
-    
+
+    # back on the stack of the process, a syscall waiting for a resource can switch processes
+    sub rsp, 8
+    mov rdi, rax
+    call __syscall_return
+    add rsp, 8
+
     pop r15
     pop r14
     pop r13
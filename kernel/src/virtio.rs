@@ -0,0 +1,576 @@
+//! virtio block devices on the PCI bus.
+//!
+//! Both the legacy interface in I/O space and the modern interface that is found through PCI
+//! capabilities are supported. Requests go through one split virtqueue and several of them can be
+//! in flight at once. Data is copied through bounce buffers in physically contiguous memory. The
+//! driver polls the used ring, the device is asked not to send interrupts.
+
+use alloc::vec::Vec;
+use alloc::string::String;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::{PhysAddr, instructions::port::Port};
+
+use fs::block::*;
+use fs::error::*;
+
+use crate::memory;
+use crate::pci::{self, Bar, PciDevice};
+
+/// Size of a sector, the unit in which virtio block devices are addressed
+pub const SECTOR_SIZE: usize = 512;
+
+const VENDOR: u16 = 0x1af4;
+/// Block device of a legacy or transitional device
+const DEVICE_LEGACY_BLOCK: u16 = 0x1001;
+/// Block device that only has the modern interface
+const DEVICE_MODERN_BLOCK: u16 = 0x1042;
+
+const STATUS_ACKNOWLEDGE: u8 = 0x01;
+const STATUS_DRIVER: u8 = 0x02;
+const STATUS_DRIVER_OK: u8 = 0x04;
+const STATUS_FEATURES_OK: u8 = 0x08;
+const STATUS_FAILED: u8 = 0x80;
+
+const FEATURE_READ_ONLY: u64 = 1 << 5;
+const FEATURE_FLUSH: u64 = 1 << 9;
+const FEATURE_VERSION_1: u64 = 1 << 32;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+const REQUEST_OK: u8 = 0;
+
+const DESC_NEXT: u16 = 1;
+const DESC_WRITE: u16 = 2;
+const AVAIL_NO_INTERRUPT: u16 = 1;
+
+/// Vendor specific PCI capability that describes the regions of a modern device
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CONFIG_COMMON: u8 = 1;
+const CONFIG_NOTIFY: u8 = 2;
+const CONFIG_DEVICE: u8 = 4;
+
+/// Largest queue a modern device is set up with
+const MAX_QUEUE_SIZE: u16 = 128;
+/// Maximum number of requests in flight, every request has its own bounce buffer
+const MAX_REQUESTS: usize = 32;
+/// Bytes per request in the bounce buffers: header, status and a sector of data
+const SLOT_SIZE: usize = 2 * SECTOR_SIZE;
+const PAGE_SIZE: usize = 4096;
+
+/// Number of times the used ring is polled before the device is given up
+const TIMEOUT: usize = 10_000_000;
+
+unsafe fn read<T>(address: u64) -> T {
+    read_volatile(address as *const T)
+}
+
+unsafe fn write<T>(address: u64, value: T) {
+    write_volatile(address as *mut T, value)
+}
+
+fn align_up(n: usize, align: usize) -> usize {
+    (n + align - 1) / align * align
+}
+
+/// Registers of a device
+enum Transport {
+    /// registers in I/O space
+    Legacy { io: u16 },
+    /// addresses of the memory mapped configuration structures
+    Modern { common: u64, notify: u64, notify_multiplier: u32, device: u64 },
+}
+
+impl Transport {
+    fn legacy(pci: &PciDevice) -> Option<Self> {
+        match pci.bar(0)? {
+            Bar::Io(io) => Some(Transport::Legacy { io }),
+            Bar::Memory(_) => None,
+        }
+    }
+
+    fn modern(pci: &PciDevice) -> Option<Self> {
+        let (mut common, mut notify, mut device) = (None, None, None);
+        let mut notify_multiplier = 0;
+
+        for (id, offset) in pci.capabilities() {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let config_type = pci.read_u8(offset + 3);
+            let base = match pci.bar(pci.read_u8(offset + 4)) {
+                Some(Bar::Memory(base)) => base,
+                _ => continue,
+            };
+            let region = base + pci.read_u32(offset + 8) as u64;
+            let len = pci.read_u32(offset + 12) as usize;
+            let address = || memory::map_device_memory(PhysAddr::new(region), len).ok().map(|virt| virt.as_u64());
+
+            match config_type {
+                CONFIG_COMMON if common.is_none() => common = address(),
+                CONFIG_NOTIFY if notify.is_none() => {
+                    notify = address();
+                    notify_multiplier = pci.read_u32(offset + 16);
+                },
+                CONFIG_DEVICE if device.is_none() => device = address(),
+                _ => (),
+            }
+        }
+
+        Some(Transport::Modern {
+            common: common?,
+            notify: notify?,
+            notify_multiplier,
+            device: device?,
+        })
+    }
+
+    fn is_modern(&self) -> bool {
+        match self {
+            Transport::Legacy { .. } => false,
+            Transport::Modern { .. } => true,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::<u8>::new(io + 18).read() },
+            Transport::Modern { common, .. } => unsafe { read(common + 20) },
+        }
+    }
+
+    fn set_status(&self, status: u8) {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::<u8>::new(io + 18).write(status) },
+            Transport::Modern { common, .. } => unsafe { write(common + 20, status) },
+        }
+    }
+
+    fn device_features(&self) -> u64 {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::<u32>::new(io).read() as u64 },
+            Transport::Modern { common, .. } => unsafe {
+                write(common, 0u32);
+                let low: u32 = read(common + 4);
+                write(common, 1u32);
+                let high: u32 = read(common + 4);
+                (high as u64) << 32 | low as u64
+            },
+        }
+    }
+
+    fn set_driver_features(&self, features: u64) {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::<u32>::new(io + 4).write(features as u32) },
+            Transport::Modern { common, .. } => unsafe {
+                write(common + 8, 0u32);
+                write(common + 12, features as u32);
+                write(common + 8, 1u32);
+                write(common + 12, (features >> 32) as u32);
+            },
+        }
+    }
+
+    /// size of the first queue offered by the device
+    fn max_queue_size(&self) -> u16 {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                Port::<u16>::new(io + 14).write(0);
+                Port::<u16>::new(io + 12).read()
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write(common + 22, 0u16);
+                read(common + 24)
+            },
+        }
+    }
+
+    /// tells the device where the first queue is and enables it
+    fn activate_queue(&self, queue: &Queue) {
+        match *self {
+            Transport::Legacy { io } => unsafe {
+                // legacy devices expect the whole queue in one place, given by its frame number
+                Port::<u16>::new(io + 14).write(0);
+                Port::<u32>::new(io + 8).write((queue.phys / PAGE_SIZE as u64) as u32);
+            },
+            Transport::Modern { common, .. } => unsafe {
+                write(common + 22, 0u16);
+                write(common + 24, queue.size);
+                for &(offset, address) in [(32, queue.phys), (40, queue.phys + queue.avail_offset() as u64), (48, queue.phys + queue.used_offset as u64)].iter() {
+                    write(common + offset, address as u32);
+                    write(common + offset + 4, (address >> 32) as u32);
+                }
+                write(common + 28, 1u16);
+            },
+        }
+    }
+
+    /// tells the device that there are new requests in the first queue
+    fn notify(&self) {
+        match *self {
+            Transport::Legacy { io } => unsafe { Port::<u16>::new(io + 16).write(0) },
+            Transport::Modern { common, notify, notify_multiplier, .. } => unsafe {
+                write(common + 22, 0u16);
+                let offset: u16 = read(common + 30);
+                write(notify + offset as u64 * notify_multiplier as u64, 0u16);
+            },
+        }
+    }
+
+    /// number of sectors of the device
+    fn capacity(&self) -> u64 {
+        // the device configuration follows the common registers of legacy devices without MSI-X
+        let (low, high): (u32, u32) = match *self {
+            Transport::Legacy { io } => unsafe { (Port::<u32>::new(io + 20).read(), Port::<u32>::new(io + 24).read()) },
+            Transport::Modern { device, .. } => unsafe { (read(device), read(device + 4)) },
+        };
+        (high as u64) << 32 | low as u64
+    }
+}
+
+/// Split virtqueue: descriptor table, available ring and used ring in one memory block
+struct Queue {
+    size: u16,
+    phys: u64,
+    virt: u64,
+    used_offset: usize,
+    /// descriptors that aren't part of a request in flight
+    free: Vec<u16>,
+    /// index in the available ring where the next request goes
+    next_avail: u16,
+    /// index in the used ring of the next completion
+    next_used: u16,
+}
+
+impl Queue {
+    fn new(size: u16) -> Option<Self> {
+        let n = size as usize;
+        // the used ring is aligned as the legacy interface requires it
+        let used_offset = align_up(16 * n + 6 + 2 * n, PAGE_SIZE);
+        let frames = (used_offset + align_up(6 + 8 * n, PAGE_SIZE)) / PAGE_SIZE;
+        let (phys, virt) = memory::allocate_dma(frames)?;
+
+        let queue = Self {
+            size,
+            phys: phys.as_u64(),
+            virt: virt.as_u64(),
+            used_offset,
+            free: (0..size).rev().collect(),
+            next_avail: 0,
+            next_used: 0,
+        };
+        unsafe { write(queue.avail(), AVAIL_NO_INTERRUPT) };
+        Some(queue)
+    }
+
+    fn avail_offset(&self) -> usize {
+        16 * self.size as usize
+    }
+
+    fn avail(&self) -> u64 {
+        self.virt + self.avail_offset() as u64
+    }
+
+    fn used(&self) -> u64 {
+        self.virt + self.used_offset as u64
+    }
+
+    fn descriptor(&self, index: u16) -> u64 {
+        self.virt + 16 * index as u64
+    }
+
+    /// makes a chain of buffers available to the device, given by their physical address, length
+    /// and whether the device writes them, returns the first descriptor
+    fn push(&mut self, buffers: &[(u64, u32, bool)]) -> Option<u16> {
+        if self.free.len() < buffers.len() {
+            return None;
+        }
+        let descriptors: Vec<u16> = (0..buffers.len()).filter_map(|_| self.free.pop()).collect();
+        for (i, &(address, len, device_writes)) in buffers.iter().enumerate() {
+            let descriptor = self.descriptor(descriptors[i]);
+            let mut flags = if device_writes { DESC_WRITE } else { 0 };
+            let next = if i + 1 < buffers.len() {
+                flags |= DESC_NEXT;
+                descriptors[i + 1]
+            } else {
+                0
+            };
+            unsafe {
+                write(descriptor, address);
+                write(descriptor + 8, len);
+                write(descriptor + 12, flags);
+                write(descriptor + 14, next);
+            }
+        }
+
+        let head = descriptors[0];
+        unsafe {
+            write(self.avail() + 4 + 2 * (self.next_avail % self.size) as u64, head);
+            // the device may only see the new index after the descriptors
+            fence(Ordering::SeqCst);
+            self.next_avail = self.next_avail.wrapping_add(1);
+            write(self.avail() + 2, self.next_avail);
+        }
+        Some(head)
+    }
+
+    /// first descriptor of a completed request and frees its chain, `None` if none is completed
+    fn pop(&mut self) -> Option<u16> {
+        let used_index: u16 = unsafe { read(self.used() + 2) };
+        if used_index == self.next_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = self.used() + 4 + 8 * (self.next_used % self.size) as u64;
+        let head = unsafe { read::<u32>(element) } as u16;
+        self.next_used = self.next_used.wrapping_add(1);
+
+        let mut descriptor = head;
+        loop {
+            self.free.push(descriptor);
+            let flags: u16 = unsafe { read(self.descriptor(descriptor) + 12) };
+            if flags & DESC_NEXT == 0 {
+                break;
+            }
+            descriptor = unsafe { read(self.descriptor(descriptor) + 14) };
+        }
+        Some(head)
+    }
+}
+
+/// Request to the device
+enum Request<'a> {
+    Read(u64, &'a mut [u8]),
+    Write(u64, &'a [u8]),
+    Flush,
+}
+
+struct Inner {
+    transport: Transport,
+    queue: Queue,
+    /// bounce buffers, one slot per request in flight
+    buffers_phys: u64,
+    buffers_virt: u64,
+    /// set when the device didn't complete requests, their buffers can't be reused
+    broken: bool,
+}
+
+impl Inner {
+    /// runs requests that fit into the queue at the same time
+    fn run(&mut self, requests: &mut [Request]) -> FsResult<()> {
+        if self.broken {
+            return Err(FsError::BlockDeviceError);
+        }
+
+        for (slot, request) in requests.iter().enumerate() {
+            let phys = self.buffers_phys + (slot * SLOT_SIZE) as u64;
+            let virt = self.buffers_virt + (slot * SLOT_SIZE) as u64;
+            let (kind, sector) = match request {
+                Request::Read(sector, _) => (REQUEST_IN, *sector),
+                Request::Write(sector, data) => {
+                    unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), (virt + SECTOR_SIZE as u64) as *mut u8, SECTOR_SIZE) };
+                    (REQUEST_OUT, *sector)
+                },
+                Request::Flush => (REQUEST_FLUSH, 0),
+            };
+            unsafe {
+                write(virt, kind);
+                write(virt + 4, 0u32);
+                write(virt + 8, sector);
+                write(virt + 16, 0xffu8);
+            }
+
+            let header = (phys, 16, false);
+            let status = (phys + 16, 1, true);
+            let data = (phys + SECTOR_SIZE as u64, SECTOR_SIZE as u32, kind == REQUEST_IN);
+            let chain = if kind == REQUEST_FLUSH { [header, status, status] } else { [header, data, status] };
+            let len = if kind == REQUEST_FLUSH { 2 } else { 3 };
+            self.queue.push(&chain[..len]).ok_or(FsError::InternalError(String::from("virtqueue is full")))?;
+        }
+
+        fence(Ordering::SeqCst);
+        self.transport.notify();
+
+        let mut completed = 0;
+        let mut polls = 0;
+        while completed < requests.len() {
+            if self.queue.pop().is_some() {
+                completed += 1;
+            } else {
+                polls += 1;
+                if polls > TIMEOUT {
+                    self.broken = true;
+                    return Err(FsError::BlockDeviceError);
+                }
+                core::sync::atomic::spin_loop_hint();
+            }
+        }
+
+        for (slot, request) in requests.iter_mut().enumerate() {
+            let virt = self.buffers_virt + (slot * SLOT_SIZE) as u64;
+            if unsafe { read::<u8>(virt + 16) } != REQUEST_OK {
+                return Err(FsError::BlockDeviceError);
+            }
+            if let Request::Read(_, buffer) = request {
+                unsafe { core::ptr::copy_nonoverlapping((virt + SECTOR_SIZE as u64) as *const u8, buffer.as_mut_ptr(), SECTOR_SIZE) };
+            }
+        }
+        Ok(())
+    }
+}
+
+/// virtio block device
+pub struct VirtioBlock {
+    inner: Mutex<Inner>,
+    sectors: u64,
+    read_only: bool,
+    flush: bool,
+    /// number of requests that are sent to the device at once
+    batch: usize,
+}
+
+impl VirtioBlock {
+    /// sets up a virtio block device, `None` if that fails
+    pub fn new(pci: &PciDevice) -> Option<Self> {
+        pci.enable();
+        let transport = Transport::modern(pci).or_else(|| Transport::legacy(pci))?;
+
+        transport.set_status(0);
+        transport.set_status(STATUS_ACKNOWLEDGE);
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        transport.set_status(status);
+
+        let offered = transport.device_features();
+        let mut features = offered & (FEATURE_READ_ONLY | FEATURE_FLUSH);
+        if transport.is_modern() {
+            features |= offered & FEATURE_VERSION_1;
+        }
+        transport.set_driver_features(features);
+        if transport.is_modern() {
+            status |= STATUS_FEATURES_OK;
+            transport.set_status(status);
+            if features & FEATURE_VERSION_1 == 0 || transport.status() & STATUS_FEATURES_OK == 0 {
+                transport.set_status(STATUS_FAILED);
+                return None;
+            }
+        }
+
+        let offered_size = transport.max_queue_size();
+        let size = if transport.is_modern() { offered_size.min(MAX_QUEUE_SIZE) } else { offered_size };
+        // a read or write needs three descriptors
+        if size < 3 {
+            transport.set_status(STATUS_FAILED);
+            return None;
+        }
+        let queue = Queue::new(size)?;
+        transport.activate_queue(&queue);
+
+        let batch = MAX_REQUESTS.min(size as usize / 3);
+        let (buffers_phys, buffers_virt) = memory::allocate_dma(align_up(batch * SLOT_SIZE, PAGE_SIZE) / PAGE_SIZE)?;
+
+        transport.set_status(status | STATUS_DRIVER_OK);
+        let sectors = transport.capacity();
+
+        Some(Self {
+            inner: Mutex::new(Inner {
+                transport,
+                queue,
+                buffers_phys: buffers_phys.as_u64(),
+                buffers_virt: buffers_virt.as_u64(),
+                broken: false,
+            }),
+            sectors,
+            read_only: features & FEATURE_READ_ONLY != 0,
+            flush: features & FEATURE_FLUSH != 0,
+            batch,
+        })
+    }
+
+    /// runs the requests, as many at once as the device allows
+    fn execute(&self, requests: &mut [Request]) -> FsResult<()> {
+        let mut inner = self.inner.lock();
+        for batch in requests.chunks_mut(self.batch) {
+            inner.run(batch)?;
+        }
+        Ok(())
+    }
+
+    fn check(&self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        if buffer.len() % SECTOR_SIZE != 0 {
+            Err(FsError::InternalError(String::from("invalid buffer size")))
+        } else if first as u64 + (buffer.len() / SECTOR_SIZE) as u64 > self.sectors {
+            Err(FsError::InternalError(String::from("out of bounds block address")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// all virtio block devices on the PCI bus
+pub fn disks() -> Vec<VirtioBlock> {
+    pci::devices().iter()
+        .filter(|pci| pci.vendor_id == VENDOR && (pci.device_id == DEVICE_LEGACY_BLOCK || pci.device_id == DEVICE_MODERN_BLOCK))
+        .filter_map(VirtioBlock::new)
+        .collect()
+}
+
+impl BlockDevice for VirtioBlock {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn blocks(&self) -> usize {
+        self.sectors as usize
+    }
+}
+
+impl ReadBlockDevice for VirtioBlock {
+    fn read_block(&self, index: usize, buffer: &mut [u8]) -> FsResult<()> {
+        if buffer.len() != SECTOR_SIZE {
+            return Err(FsError::InternalError(String::from("invalid buffer size")));
+        }
+        self.read_blocks(index, buffer)
+    }
+
+    /// reads consecutive sectors with requests that are processed in parallel
+    fn read_blocks(&self, first: usize, buffer: &mut [u8]) -> FsResult<()> {
+        self.check(first, buffer)?;
+        let mut requests: Vec<Request> = buffer.chunks_mut(SECTOR_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| Request::Read((first + i) as u64, chunk))
+            .collect();
+        self.execute(&mut requests)
+    }
+}
+
+impl WriteBlockDevice for VirtioBlock {
+    fn write_block(&mut self, index: usize, buffer: &[u8]) -> FsResult<()> {
+        if buffer.len() != SECTOR_SIZE {
+            return Err(FsError::InternalError(String::from("invalid buffer size")));
+        }
+        self.write_blocks(index, buffer)
+    }
+
+    /// writes consecutive sectors with requests that are processed in parallel
+    fn write_blocks(&mut self, first: usize, buffer: &[u8]) -> FsResult<()> {
+        self.check(first, buffer)?;
+        if self.read_only {
+            return Err(FsError::AccessViolation);
+        }
+        let mut requests: Vec<Request> = buffer.chunks(SECTOR_SIZE)
+            .enumerate()
+            .map(|(i, chunk)| Request::Write((first + i) as u64, chunk))
+            .collect();
+        self.execute(&mut requests)
+    }
+
+    /// the flush only covers finished writes, so it is sent on its own after they completed
+    fn flush(&mut self) -> FsResult<()> {
+        if self.flush {
+            self.execute(&mut [Request::Flush])?;
+        }
+        Ok(())
+    }
+}
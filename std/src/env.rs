@@ -0,0 +1,45 @@
+//! Environment of the process
+
+use alloc::vec::Vec;
+
+use dep::syscall;
+use dep::fs::MAX_PATH_LEN;
+use crate::syscall::*;
+use crate::fs::path::Path;
+use crate::fs::structs::*;
+use crate::fs::AsPathBytes;
+
+/// Returns the working directory of the process
+pub fn current_dir() -> FsResult<Path> {
+    let mut buffer = Vec::new();
+    buffer.resize(64, 0u8);
+    loop {
+        let len = unsafe {
+            syscall!(syscall::GETCWD, buffer.as_mut_ptr(), buffer.len())
+        };
+        match Errno::check(len) {
+            Ok(len) => {
+                buffer.truncate(len as usize);
+                break;
+            },
+            // the buffer is too small
+            Err(Errno::InvalidArgument) if buffer.len() < MAX_PATH_LEN => {
+                let len = buffer.len() * 2;
+                buffer.resize(len, 0);
+            },
+            Err(err) => return Err(err),
+        }
+    }
+    Path::parse(buffer).map_err(|_| Errno::NameTooLong)
+}
+
+/// Changes the working directory of the process to `path`,
+/// which is relative to the current working directory unless it starts with `/`
+pub fn set_current_dir<P: ?Sized + AsPathBytes>(path: &P) -> FsResult<()> {
+    let path = path.as_path_bytes();
+    let status_code = unsafe {
+        syscall!(syscall::CHDIR, path.as_ptr(), path.len())
+    };
+    Errno::check(status_code)?;
+    Ok(())
+}
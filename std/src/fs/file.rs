@@ -1,7 +1,6 @@
 extern crate alloc;
 
 use core::marker::PhantomData;
-use core::convert::*;
 use alloc::string::String;
 
 use dep::syscall;
@@ -9,6 +8,7 @@ use dep::fs::*;
 
 use crate::syscall::*;
 use crate::fs::structs::*;
+use crate::fs::AsPathBytes;
 
 /// Struct that represents a file handle.
 pub struct File<T: Access> {
@@ -19,32 +19,65 @@ pub struct File<T: Access> {
 }
 
 fn fd_to_file<T: Access>(fd: i64) -> FsResult<File<T>> {
-    let error = FsError::try_from(fd);
-    if let Ok(err) = error {
-        Err(err)
-    } else {
-        Ok(File::<T> {
-            fd,
-            _phantom: PhantomData,
-            is_open: true,
-        })
-    }
+    Ok(File::<T> {
+        fd: Errno::check(fd)?,
+        _phantom: PhantomData,
+        is_open: true,
+    })
 }
 
 impl<T: Access> File<T> {
-    /// Open the file at the location given by the path.
+    /// Open the file at the location given by the path, relative paths start at the working directory.
     /// If opening the file succeeded (process has permissions and file exists),
     /// this returns a `File` struct.
-    pub fn open(path: &Path) -> FsResult<File<T>> {
-        let path = path.to_string();
-        let path = path.as_bytes();
+    pub fn open<P: ?Sized + AsPathBytes>(path: &P) -> FsResult<File<T>> {
+        let path = path.as_path_bytes();
         let fd = unsafe {
             syscall!(syscall::OPEN, path.as_ptr(), path.len(), T::flags())
         };
         fd_to_file::<T>(fd)
     }
 
-    /// closes the file
+    /// Waits until the file is locked exclusively, no other open file can lock it until
+    /// `unlock` is called or the file is closed. Locks are advisory, they don't prevent access.
+    /// Fails with `WouldBlock` if another file of this process holds the lock and no other
+    /// process could release it.
+    pub fn lock(&self) -> FsResult<()> {
+        self.flock(LOCK_EXCLUSIVE)
+    }
+
+    /// Waits until the file is locked shared, other open files can hold shared locks as well
+    pub fn lock_shared(&self) -> FsResult<()> {
+        self.flock(LOCK_SHARED)
+    }
+
+    /// Locks the file exclusively, fails with `WouldBlock` if another open file holds a lock
+    pub fn try_lock(&self) -> FsResult<()> {
+        self.flock(LOCK_EXCLUSIVE | LOCK_NONBLOCKING)
+    }
+
+    /// Locks the file shared, fails with `WouldBlock` if another open file holds an exclusive lock
+    pub fn try_lock_shared(&self) -> FsResult<()> {
+        self.flock(LOCK_SHARED | LOCK_NONBLOCKING)
+    }
+
+    /// Releases the lock of the file
+    pub fn unlock(&self) -> FsResult<()> {
+        self.flock(LOCK_UNLOCK)
+    }
+
+    fn flock(&self, operation: u64) -> FsResult<()> {
+        if !self.is_open {
+            return Err(Errno::BadFileDescriptor);
+        }
+        let status_code = unsafe {
+            syscall!(syscall::FLOCK, self.fd, operation)
+        };
+        Errno::check(status_code)?;
+        Ok(())
+    }
+
+    /// closes the file, its lock is released
     pub fn close(&mut self) {
         if self.is_open {
             unsafe {
@@ -66,18 +99,13 @@ impl File<Read> {
     /// Returns the number of bytes that were read.
     pub fn read(&mut self, bytes: &mut [u8]) -> FsResult<usize> {
         if !self.is_open {
-            return Err(FsError::IllegalOperation);
+            return Err(Errno::BadFileDescriptor);
         }
 
         let bytes_read = unsafe {
             syscall!(syscall::READ, self.fd, bytes.as_ptr(), bytes.len())
         };
-        if bytes_read < 0 {
-            // read after close or similar stuff
-            Err(FsError::IllegalOperation)
-        } else {
-            Ok(bytes_read as _)
-        }
+        Ok(Errno::check(bytes_read)? as _)
     }
 }
 
@@ -96,16 +124,13 @@ impl File<Write> {
     /// Writes the buffer passed as argument to the end of the file
     pub fn write(&mut self, bytes: &[u8]) -> FsResult<()> {
         if !self.is_open {
-            return Err(FsError::IllegalOperation);
+            return Err(Errno::BadFileDescriptor);
         }
         let status_code = unsafe {
             syscall!(syscall::WRITE, self.fd, bytes.as_ptr(), bytes.len())
         };
-        if status_code != 0 {
-            Err(FsError::try_from(status_code).unwrap_or(FsError::IllegalOperation))
-        } else {
-            Ok(())
-        }
+        Errno::check(status_code)?;
+        Ok(())
     }
 }
 
@@ -1,9 +1,107 @@
 pub mod structs;
 pub mod file;
+pub mod watch;
 
 pub use dep::fs::SEPARATOR;
+pub use dep::fs::stats::{self, FsStats};
+
+extern crate alloc;
+use alloc::vec::Vec;
+use alloc::string::String;
+
+use dep::syscall;
+use dep::fs::{MountOptions, MOUNT_WRITABLE};
+use crate::syscall::*;
+use structs::*;
 
 pub mod path {
-    pub use dep::fs::Path;
+    pub use dep::fs::{Path, PathSlice, PathError, Components};
+}
+
+/// Path argument of a syscall, either an absolute `Path` or a string or bytes, which are relative
+/// to the working directory unless they start with `/`
+pub trait AsPathBytes {
+    /// the path as the kernel reads it
+    fn as_path_bytes(&self) -> Vec<u8>;
+}
+
+impl AsPathBytes for path::Path {
+    fn as_path_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+}
+
+impl AsPathBytes for str {
+    fn as_path_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl AsPathBytes for String {
+    fn as_path_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
 }
 
+impl AsPathBytes for [u8] {
+    fn as_path_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+/// Returns usage statistics of the file system that contains `path`
+pub fn statfs<P: ?Sized + AsPathBytes>(path: &P) -> FsResult<FsStats> {
+    let path = path.as_path_bytes();
+    let mut stats = FsStats::default();
+    let status_code = unsafe {
+        syscall!(syscall::STATFS, path.as_ptr(), path.len(), &mut stats as *mut FsStats)
+    };
+    Errno::check(status_code)?;
+    Ok(stats)
+}
+
+/// Mounts the block device `source`, e.g. `/dev/vda1`, at the directory `target`.
+/// Any other `source` is a file with the image of a file system, e.g. `/images/pkg.img`, which is
+/// only written if `writable` is set. The file system is the one named `fs_type`, e.g. `ext2`,
+/// or the first one that fits.
+pub fn mount<P: ?Sized + AsPathBytes>(source: &str, target: &P, fs_type: Option<&str>, writable: bool) -> FsResult<()> {
+    let target = target.as_path_bytes();
+    let mut options = MountOptions {
+        fs_type: [0u8; stats::FS_NAME_LEN],
+        flags: if writable { MOUNT_WRITABLE } else { 0 },
+    };
+    if let Some(fs_type) = fs_type {
+        let len = fs_type.len().min(options.fs_type.len());
+        options.fs_type[..len].copy_from_slice(&fs_type.as_bytes()[..len]);
+    }
+    let status_code = unsafe {
+        syscall!(syscall::MOUNT, source.as_ptr(), source.len(), target.as_ptr(), target.len(), &options as *const MountOptions)
+    };
+    Errno::check(status_code)?;
+    Ok(())
+}
+
+/// Unmounts the file system attached at `target`, fails while files of it are open
+pub fn umount<P: ?Sized + AsPathBytes>(target: &P) -> FsResult<()> {
+    let target = target.as_path_bytes();
+    let status_code = unsafe {
+        syscall!(syscall::UMOUNT, target.as_ptr(), target.len())
+    };
+    Errno::check(status_code)?;
+    Ok(())
+}
+
+/// Lines of `/proc/mounts`: device, attach point and name of every attached file system
+pub fn mounts() -> FsResult<Vec<String>> {
+    let mut file = file::File::<Read>::open("/proc/mounts")?;
+    let mut contents = Vec::new();
+    let mut buffer = [0u8; 512];
+    loop {
+        let len = file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        contents.extend_from_slice(&buffer[..len]);
+    }
+    Ok(String::from_utf8_lossy(&contents).lines().map(String::from).collect())
+}
@@ -1,5 +1,4 @@
-use core::convert::TryFrom;
-use dep::fs::error::*;
+pub use dep::fs::error::Errno;
 
 pub struct Read;
 pub struct Write;
@@ -20,30 +19,4 @@ impl Access for Write {
     }
 }
 
-#[derive(Debug)]
-#[repr(i64)]
-pub enum FsError {
-    /// file or directory not found
-    NotFound,
-    /// no access rights
-    AccessViolation,
-    /// an illegal operation, e.g. reading a file after closing it
-    IllegalOperation,
-}
-
-use FsError::*;
-impl TryFrom<i64> for FsError {
-    type Error = ();
-
-    fn try_from(val: i64) -> Result<Self, Self::Error> {
-        match val {
-            NOT_FOUND => Ok(NotFound),
-            ACCESS_VIOLATION => Ok(AccessViolation),
-            ILLEGAL => Ok(IllegalOperation),
-            _ => Err(()),
-        }
-    }
-}
-
-pub type FsResult<T> = Result<T, FsError>;
-
+pub type FsResult<T> = Result<T, Errno>;
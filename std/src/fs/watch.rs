@@ -0,0 +1,66 @@
+//! Notifications about changes to files and directories
+
+use alloc::vec::Vec;
+
+use dep::syscall;
+pub use dep::fs::watch::{Event, EventKind, MAX_EVENT_LEN};
+
+use crate::syscall::*;
+use super::AsPathBytes;
+use super::structs::*;
+
+/// Watch of a file or directory, it ends when it is dropped
+pub struct Watcher {
+    fd: i64,
+}
+
+impl Watcher {
+    /// Watches an existing file or directory. Creating, deleting, writing and renaming it or,
+    /// for a directory, its entries produces events.
+    pub fn new<P: ?Sized + AsPathBytes>(path: &P) -> FsResult<Self> {
+        let path = path.as_path_bytes();
+        let fd = unsafe {
+            syscall!(syscall::WATCH, path.as_ptr(), path.len())
+        };
+        Ok(Self { fd: Errno::check(fd)? })
+    }
+
+    /// events since the last call, empty if nothing changed
+    pub fn events(&mut self) -> FsResult<Vec<Event>> {
+        let mut buffer = [0u8; MAX_EVENT_LEN];
+        let mut events = Vec::new();
+        loop {
+            let len = unsafe {
+                syscall!(syscall::READ, self.fd, buffer.as_mut_ptr(), buffer.len())
+            };
+            let len = Errno::check(len)?;
+            if len == 0 {
+                return Ok(events);
+            }
+            let mut bytes = &buffer[..len as usize];
+            while let Some((event, size)) = Event::from_bytes(bytes) {
+                events.push(event);
+                bytes = &bytes[size..];
+            }
+        }
+    }
+
+    /// waits until something changes and returns the events, by asking the kernel repeatedly
+    pub fn wait(&mut self) -> FsResult<Vec<Event>> {
+        loop {
+            let events = self.events()?;
+            if !events.is_empty() {
+                return Ok(events);
+            }
+            core::sync::atomic::spin_loop_hint();
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe {
+            syscall!(syscall::CLOSE, self.fd);
+        }
+    }
+}
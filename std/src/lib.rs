@@ -9,6 +9,8 @@ use core::alloc::Layout;
 pub mod syscall;
 pub mod kprint;
 pub mod fs;
+pub mod env;
+pub mod process;
 mod allocator;
 
 #[no_mangle]